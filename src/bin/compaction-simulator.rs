@@ -85,6 +85,8 @@ impl MockStorage {
             l0_sstables: Vec::new(),
             levels: Vec::new(),
             sstables: Default::default(),
+            blob_files: Default::default(),
+            blob_garbage: Default::default(),
         };
         Self {
             snapshot,
@@ -10,7 +10,11 @@ use mini_lsm_wrapper::compact::{
 };
 use mini_lsm_wrapper::iterators::StorageIterator;
 use mini_lsm_wrapper::lsm_storage::{LsmStorageOptions, MiniLsm};
+use mini_lsm_wrapper::mvcc::HistoryRetention;
+use mini_lsm_wrapper::table::CompressionOptions;
+use mini_lsm_wrapper::wal::WalRecoveryMode;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Clone, ValueEnum)]
 enum CompactionStrategy {
@@ -67,6 +71,17 @@ fn main() -> Result<()> {
             },
             enable_wal: args.enable_wal,
             serializable: args.serializable,
+            merge_operator: None,
+            compression: CompressionOptions::default(),
+            blob: None,
+            history_retention: HistoryRetention::default(),
+            max_txn_key_set_size: 1 << 20,
+            lock_timeout: Duration::from_secs(5),
+            hybrid_clock: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_segment_size: 64 << 20,
+            max_manifest_size: 4 << 20,
+            quarantine_orphan_files: false,
         },
     )?;
     let mut epoch = 0;
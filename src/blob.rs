@@ -0,0 +1,275 @@
+mod gc;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+use serde::{Deserialize, Serialize};
+
+use crate::key::{KeySlice, KeyVec};
+use crate::table::FileObject;
+
+const BLOB_FORMAT_VERSION: u32 = 1;
+const BLOB_MAGIC: u32 = 0xB10B_F11E;
+
+/// Key-value separation. The values of puts that are at least `min_blob_size` bytes are moved into
+/// a blob file when a memtable is flushed, and the SST only stores pointers to them, so that
+/// compactions do not rewrite large values. Values with a TTL and merge operands are kept in SSTs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobOptions {
+    /// The minimum size of a value that is stored in a blob file.
+    pub min_blob_size: usize,
+    /// A blob file is rewritten without the values that are no longer referenced by SSTs once the
+    /// ratio of the referenced bytes falls below this threshold.
+    pub gc_live_ratio: f64,
+}
+
+/// Points to a record in a blob file. The file id is the id of the first version of the blob file,
+/// and stays the same when the file is rewritten by the garbage collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPointer {
+    pub file_id: usize,
+    /// The sequence number of the record in the blob file.
+    pub seq: u32,
+    /// The size of the record in the blob file, which becomes garbage once the pointer is dropped.
+    pub size: u32,
+}
+
+impl BlobPointer {
+    pub(crate) const ENCODED_LEN: usize =
+        std::mem::size_of::<u64>() + std::mem::size_of::<u32>() * 2;
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.file_id as u64);
+        buf.put_u32(self.seq);
+        buf.put_u32(self.size);
+    }
+
+    pub(crate) fn decode(mut buf: &[u8]) -> Result<Self> {
+        if buf.len() != Self::ENCODED_LEN {
+            bail!("invalid blob pointer");
+        }
+        Ok(Self {
+            file_id: buf.get_u64() as usize,
+            seq: buf.get_u32(),
+            size: buf.get_u32(),
+        })
+    }
+}
+
+/// Builds a blob file. The format of a blob file is:
+///
+/// ```plaintext
+/// | record | ... | record | index | index offset (u64) | version (u32) | magic (u32) |
+/// ```
+///
+/// where a record is `| key_len (u32) | key | ts (u64) | value_len (u32) | value | checksum (u32) |`,
+/// and the index is the sequence number, the offset and the size of each record, followed by a
+/// checksum. The key of a record is used by the garbage collector to find the pointer to it.
+pub struct BlobFileBuilder {
+    file_id: usize,
+    data: Vec<u8>,
+    /// The sequence number, the offset and the size of each record.
+    index: Vec<(u32, u64, u32)>,
+}
+
+impl BlobFileBuilder {
+    /// Create a builder of a blob file with the id in its pointers.
+    pub fn new(file_id: usize) -> Self {
+        Self {
+            file_id,
+            data: Vec::new(),
+            index: Vec::new(),
+        }
+    }
+
+    /// Add a value and return the pointer to it.
+    pub fn add(&mut self, key: KeySlice, value: &[u8]) -> BlobPointer {
+        let seq = self.index.len() as u32;
+        self.add_with_seq(seq, key, value)
+    }
+
+    /// Add a value with the sequence number it had in the previous version of the blob file.
+    pub(crate) fn add_with_seq(&mut self, seq: u32, key: KeySlice, value: &[u8]) -> BlobPointer {
+        let offset = self.data.len();
+        self.data.put_u32(key.key_len() as u32);
+        self.data.put_slice(key.key_ref());
+        self.data.put_u64(key.ts());
+        self.data.put_u32(value.len() as u32);
+        self.data.put_slice(value);
+        let checksum = crc32fast::hash(&self.data[offset..]);
+        self.data.put_u32(checksum);
+        let size = (self.data.len() - offset) as u32;
+        self.index.push((seq, offset as u64, size));
+        BlobPointer {
+            file_id: self.file_id,
+            seq,
+            size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Write the blob file to the path. `id` is the id of this version of the blob file, which is
+    /// different from the id in the pointers once the file is rewritten.
+    pub fn build(mut self, id: usize, path: impl AsRef<Path>) -> Result<BlobFile> {
+        let index_offset = self.data.len();
+        self.data.put_u32(self.index.len() as u32);
+        for (seq, offset, size) in &self.index {
+            self.data.put_u32(*seq);
+            self.data.put_u64(*offset);
+            self.data.put_u32(*size);
+        }
+        let checksum = crc32fast::hash(&self.data[index_offset..]);
+        self.data.put_u32(checksum);
+        self.data.put_u64(index_offset as u64);
+        self.data.put_u32(BLOB_FORMAT_VERSION);
+        self.data.put_u32(BLOB_MAGIC);
+        let file = FileObject::create(path.as_ref(), self.data)?;
+        let size = self.index.iter().map(|(_, _, size)| *size as u64).sum();
+        Ok(BlobFile {
+            id,
+            file,
+            index: self
+                .index
+                .into_iter()
+                .map(|(seq, offset, size)| (seq, (offset, size)))
+                .collect(),
+            size,
+        })
+    }
+}
+
+/// A record read from a blob file.
+pub(crate) struct BlobRecord {
+    pub(crate) seq: u32,
+    pub(crate) key: KeyVec,
+    pub(crate) value: Vec<u8>,
+}
+
+/// A blob file, with the index of its records in memory.
+pub struct BlobFile {
+    id: usize,
+    file: FileObject,
+    /// The offset and the size of each record by sequence number.
+    index: HashMap<u32, (u64, u32)>,
+    /// The total size of the records.
+    size: u64,
+}
+
+impl BlobFile {
+    pub fn open(id: usize, file: FileObject) -> Result<Self> {
+        let footer_len = std::mem::size_of::<u64>() + std::mem::size_of::<u32>() * 2;
+        if file.size() < footer_len as u64 {
+            bail!("invalid blob file");
+        }
+        let mut footer = &file.read(file.size() - footer_len as u64, footer_len as u64)?[..];
+        let index_offset = footer.get_u64();
+        let version = footer.get_u32();
+        if footer.get_u32() != BLOB_MAGIC {
+            bail!("invalid blob file");
+        }
+        if version > BLOB_FORMAT_VERSION {
+            bail!("unsupported blob file format version {}", version);
+        }
+        let index_len = file.size() - footer_len as u64 - index_offset;
+        let raw_index = file.read(index_offset, index_len)?;
+        let checksum = (&raw_index[raw_index.len() - std::mem::size_of::<u32>()..]).get_u32();
+        let mut raw_index = &raw_index[..raw_index.len() - std::mem::size_of::<u32>()];
+        if checksum != crc32fast::hash(raw_index) {
+            bail!("blob file index checksum mismatched");
+        }
+        let num_records = raw_index.get_u32() as usize;
+        let mut index = HashMap::with_capacity(num_records);
+        let mut size = 0;
+        for _ in 0..num_records {
+            let seq = raw_index.get_u32();
+            let offset = raw_index.get_u64();
+            let record_size = raw_index.get_u32();
+            index.insert(seq, (offset, record_size));
+            size += record_size as u64;
+        }
+        Ok(Self {
+            id,
+            file,
+            index,
+            size,
+        })
+    }
+
+    /// The id of this version of the blob file.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The total size of the records, which is compared against the size of the garbage.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Decode a record and check its checksum.
+    fn decode_record(seq: u32, raw: &[u8]) -> Result<BlobRecord> {
+        let (mut data, mut checksum) = raw.split_at(raw.len() - std::mem::size_of::<u32>());
+        if checksum.get_u32() != crc32fast::hash(data) {
+            bail!("blob record checksum mismatched");
+        }
+        let key_len = data.get_u32() as usize;
+        let key = &data[..key_len];
+        data.advance(key_len);
+        let ts = data.get_u64();
+        let value_len = data.get_u32() as usize;
+        Ok(BlobRecord {
+            seq,
+            key: KeyVec::from_vec_with_ts(key.to_vec(), ts),
+            value: data[..value_len].to_vec(),
+        })
+    }
+
+    /// Read the value of a record.
+    pub fn read(&self, seq: u32) -> Result<Vec<u8>> {
+        let Some((offset, size)) = self.index.get(&seq) else {
+            bail!("blob record {} not found in {}.blob", seq, self.id);
+        };
+        let raw = self.file.read(*offset, *size as u64)?;
+        Ok(Self::decode_record(seq, &raw)?.value)
+    }
+
+    /// Read all records, ordered by their offsets.
+    pub(crate) fn records(&self) -> Result<Vec<BlobRecord>> {
+        let mut index = self.index.iter().collect::<Vec<_>>();
+        index.sort_by_key(|(_, (offset, _))| *offset);
+        let data_len = index
+            .last()
+            .map(|(_, (offset, size))| offset + *size as u64)
+            .unwrap_or_default();
+        let data = self.file.read(0, data_len)?;
+        index
+            .into_iter()
+            .map(|(seq, (offset, size))| {
+                Self::decode_record(
+                    *seq,
+                    &data[*offset as usize..(*offset + *size as u64) as usize],
+                )
+            })
+            .collect()
+    }
+}
+
+/// Read the value that a pointer refers to from the blob files of a column family.
+pub(crate) fn read_blob(
+    blob_files: &HashMap<usize, Arc<BlobFile>>,
+    pointer: BlobPointer,
+) -> Result<Vec<u8>> {
+    let Some(blob_file) = blob_files.get(&pointer.file_id) else {
+        bail!("blob file {} not found", pointer.file_id);
+    };
+    blob_file.read(pointer.seq)
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::BlobFileBuilder;
+use crate::column_family::ColumnFamily;
+use crate::iterators::StorageIterator;
+use crate::key::KeySlice;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageState};
+use crate::manifest::ManifestRecord;
+use crate::table::SsTableIterator;
+use crate::value::Value;
+
+/// Find the value of an exact version of a key in the SSTs, which are searched from the latest to
+/// the earliest. Memtables are skipped, as they never contain pointers to blob files.
+fn find_version_in_ssts(snapshot: &LsmStorageState, key: KeySlice) -> Result<Option<Vec<u8>>> {
+    let ssts = snapshot
+        .l0_sstables
+        .iter()
+        .chain(snapshot.levels.iter().flat_map(|(_, ssts)| ssts));
+    for sst_id in ssts {
+        let table = snapshot.sstables[sst_id].clone();
+        if table.num_of_blocks() == 0
+            || key.key_ref() < table.first_key().key_ref()
+            || key.key_ref() > table.last_key().key_ref()
+        {
+            continue;
+        }
+        if let Some(bloom) = &table.bloom {
+            if !bloom.may_contain(farmhash::fingerprint32(key.key_ref())) {
+                continue;
+            }
+        }
+        let iter = SsTableIterator::create_and_seek_to_key(table, key)?;
+        if iter.is_valid() && iter.key().key_ref() == key.key_ref() && iter.key().ts() == key.ts() {
+            return Ok(Some(iter.value().to_vec()));
+        }
+    }
+    Ok(None)
+}
+
+impl LsmStorageInner {
+    /// Rewrite the blob file of a column family with the lowest ratio of referenced bytes, if the
+    /// ratio is below the threshold. A record is referenced if the version of its key is still in
+    /// the SSTs and points to it. The pointers keep working as the new file takes the id in them
+    /// and keeps the sequence numbers of the records.
+    pub(crate) fn trigger_blob_gc(&self, column_family: &ColumnFamily) -> Result<()> {
+        let Some(blob_options) = &column_family.options.blob else {
+            return Ok(());
+        };
+        let snapshot = {
+            let state = column_family.state.read();
+            state.clone()
+        };
+        let candidate = snapshot
+            .blob_files
+            .iter()
+            .map(|(blob_id, blob_file)| {
+                let garbage = snapshot
+                    .blob_garbage
+                    .get(blob_id)
+                    .copied()
+                    .unwrap_or_default();
+                let live_ratio = 1.0 - garbage as f64 / blob_file.size() as f64;
+                (live_ratio, *blob_id, blob_file.clone())
+            })
+            .filter(|(live_ratio, _, _)| *live_ratio < blob_options.gc_live_ratio)
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        let Some((live_ratio, blob_id, blob_file)) = candidate else {
+            return Ok(());
+        };
+        println!(
+            "running blob gc in column family {}: blob file {} ({}.blob), live ratio={:.2}",
+            column_family.name,
+            blob_id,
+            blob_file.id(),
+            live_ratio
+        );
+
+        let mut builder = BlobFileBuilder::new(blob_id);
+        for record in blob_file.records()? {
+            let is_live = match find_version_in_ssts(&snapshot, record.key.as_key_slice())? {
+                Some(value) => matches!(
+                    Value::decode(&value)?,
+                    Value::Blob(pointer) if pointer.file_id == blob_id && pointer.seq == record.seq
+                ),
+                None => false,
+            };
+            if is_live {
+                builder.add_with_seq(record.seq, record.key.as_key_slice(), &record.value);
+            }
+        }
+        let new_blob_file = if builder.is_empty() {
+            None
+        } else {
+            let id = self.next_sst_id();
+            Some(Arc::new(builder.build(id, self.path_of_blob(id))?))
+        };
+
+        {
+            let state_lock = self.state_lock.lock();
+            if !self
+                .column_families
+                .read()
+                .contains_key(&column_family.name)
+            {
+                // The column family is dropped while collecting the blob file.
+                drop(state_lock);
+                if let Some(new_blob_file) = new_blob_file {
+                    std::fs::remove_file(self.path_of_blob(new_blob_file.id()))?;
+                }
+                return Ok(());
+            }
+            let mut snapshot = column_family.state.read().as_ref().clone();
+            match &new_blob_file {
+                Some(new_blob_file) => {
+                    snapshot.blob_files.insert(blob_id, new_blob_file.clone());
+                    snapshot.blob_garbage.insert(blob_id, 0);
+                }
+                None => {
+                    snapshot.blob_files.remove(&blob_id);
+                    snapshot.blob_garbage.remove(&blob_id);
+                }
+            }
+            *column_family.state.write() = Arc::new(snapshot);
+            self.sync_dir()?;
+            self.manifest().add_record(
+                &state_lock,
+                ManifestRecord::GcBlobFile(
+                    column_family.id,
+                    blob_id,
+                    new_blob_file.as_ref().map(|x| x.id()),
+                ),
+            )?;
+        }
+        println!(
+            "blob gc finished: {} of {} records kept",
+            new_blob_file.as_ref().map_or(0, |x| x.num_records()),
+            blob_file.num_records()
+        );
+        std::fs::remove_file(self.path_of_blob(blob_file.id()))?;
+        self.sync_dir()?;
+
+        Ok(())
+    }
+}
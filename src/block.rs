@@ -5,13 +5,17 @@ pub use builder::BlockBuilder;
 use bytes::{Buf, BufMut, Bytes};
 pub use iterator::BlockIterator;
 
+use crate::table::SST_FORMAT_V3;
+use crate::value::Value;
+
 pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+pub(crate) const SIZEOF_U32: usize = std::mem::size_of::<u32>();
 
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted
 /// key-value pairs.
 pub struct Block {
     pub(crate) data: Vec<u8>,
-    pub(crate) offsets: Vec<u16>,
+    pub(crate) offsets: Vec<u32>,
 }
 
 impl Block {
@@ -19,25 +23,61 @@ impl Block {
         let mut buf = self.data.clone();
         let offsets_len = self.offsets.len();
         for offset in &self.offsets {
-            buf.put_u16(*offset);
+            buf.put_u32(*offset);
         }
         // Adds number of elements at the end of the block
-        buf.put_u16(offsets_len as u16);
+        buf.put_u32(offsets_len as u32);
         buf.into()
     }
 
     pub fn decode(data: &[u8]) -> Self {
         // get number of elements in the block
-        let entry_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
-        let data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
-        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16];
+        let entry_offsets_len = (&data[data.len() - SIZEOF_U32..]).get_u32() as usize;
+        let data_end = data.len() - SIZEOF_U32 - entry_offsets_len * SIZEOF_U32;
+        let offsets_raw = &data[data_end..data.len() - SIZEOF_U32];
         // get offset array
         let offsets = offsets_raw
-            .chunks(SIZEOF_U16)
-            .map(|mut x| x.get_u16())
+            .chunks(SIZEOF_U32)
+            .map(|mut x| x.get_u32())
             .collect();
         // retrieve data
         let data = data[0..data_end].to_vec();
         Self { data, offsets }
     }
+
+    /// Decode a block of an SST before v6, where the lengths and offsets are `u16`, and convert
+    /// the entries to the current format. The values of an SST before v3 have no kind, and the
+    /// kind is added to them, see `Value::upgrade_legacy`.
+    pub fn decode_legacy(data: &[u8], version: u32) -> Self {
+        let entry_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
+        let data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
+        let mut entries = &data[..data_end];
+        let mut block = Self {
+            data: Vec::with_capacity(data_end + entry_offsets_len * (SIZEOF_U16 * 3 + 1)),
+            offsets: Vec::with_capacity(entry_offsets_len),
+        };
+        // The entries are stored in order, so the offset array is not needed.
+        for _ in 0..entry_offsets_len {
+            block.offsets.push(block.data.len() as u32);
+            let overlap_len = entries.get_u16();
+            let key_len = entries.get_u16() as usize;
+            block.data.put_u32(overlap_len as u32);
+            block.data.put_u32(key_len as u32);
+            block.data.put(&entries[..key_len]);
+            entries.advance(key_len);
+            block.data.put_u64(entries.get_u64());
+            let value_len = entries.get_u16() as usize;
+            let value = &entries[..value_len];
+            if version < SST_FORMAT_V3 {
+                let value = Value::upgrade_legacy(value);
+                block.data.put_u32(value.len() as u32);
+                block.data.put(&value[..]);
+            } else {
+                block.data.put_u32(value_len as u32);
+                block.data.put(value);
+            }
+            entries.advance(value_len);
+        }
+        block
+    }
 }
@@ -2,12 +2,12 @@ use bytes::BufMut;
 
 use crate::key::{KeySlice, KeyVec};
 
-use super::{Block, SIZEOF_U16};
+use super::{Block, SIZEOF_U32};
 
 /// Builds a block.
 pub struct BlockBuilder {
     /// Offsets of each key-value entries.
-    offsets: Vec<u16>,
+    offsets: Vec<u32>,
     /// All serialized key-value pairs in the block.
     data: Vec<u8>,
     /// The expected block size.
@@ -42,7 +42,7 @@ impl BlockBuilder {
     }
 
     fn estimated_size(&self) -> usize {
-        SIZEOF_U16 /* number of key-value pairs in the block */ +  self.offsets.len() * SIZEOF_U16 /* offsets */ + self.data.len()
+        SIZEOF_U32 /* number of key-value pairs in the block */ +  self.offsets.len() * SIZEOF_U32 /* offsets */ + self.data.len()
         // key-value pairs
     }
 
@@ -50,24 +50,24 @@ impl BlockBuilder {
     #[must_use]
     pub fn add(&mut self, key: KeySlice, value: &[u8]) -> bool {
         assert!(!key.is_empty(), "key must not be empty");
-        if self.estimated_size() + key.raw_len() + value.len() + SIZEOF_U16 * 3 /* key_len, value_len and offset */ > self.block_size
+        if self.estimated_size() + key.raw_len() + value.len() + SIZEOF_U32 * 3 /* key_len, value_len and offset */ > self.block_size
             && !self.is_empty()
         {
             return false;
         }
         // Add the offset of the data into the offset array.
-        self.offsets.push(self.data.len() as u16);
+        self.offsets.push(self.data.len() as u32);
         let overlap = compute_overlap(self.first_key.as_key_slice(), key);
         // Encode key overlap.
-        self.data.put_u16(overlap as u16);
+        self.data.put_u32(overlap as u32);
         // Encode key length.
-        self.data.put_u16((key.key_len() - overlap) as u16);
+        self.data.put_u32((key.key_len() - overlap) as u32);
         // Encode key content.
         self.data.put(&key.key_ref()[overlap..]);
         // Encode key ts
         self.data.put_u64(key.ts());
         // Encode value length.
-        self.data.put_u16(value.len() as u16);
+        self.data.put_u32(value.len() as u32);
         // Encode value content.
         self.data.put(value);
 
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use bytes::Buf;
 
 use crate::{
-    block::SIZEOF_U16,
+    block::SIZEOF_U32,
     key::{KeySlice, KeyVec},
 };
 
@@ -26,8 +26,8 @@ pub struct BlockIterator {
 impl Block {
     fn get_first_key(&self) -> KeyVec {
         let mut buf = &self.data[..];
-        buf.get_u16();
-        let key_len = buf.get_u16() as usize;
+        buf.get_u32();
+        let key_len = buf.get_u32() as usize;
         let key = &buf[..key_len];
         buf.advance(key_len);
         KeyVec::from_vec_with_ts(key.to_vec(), buf.get_u64())
@@ -37,7 +37,11 @@ impl Block {
 impl BlockIterator {
     fn new(block: Arc<Block>) -> Self {
         Self {
-            first_key: block.get_first_key(),
+            first_key: if block.offsets.is_empty() {
+                KeyVec::new()
+            } else {
+                block.get_first_key()
+            },
             block,
             key: KeyVec::new(),
             value_range: (0, 0),
@@ -52,6 +56,13 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a block iterator and seek to the last entry.
+    pub fn create_and_seek_to_last(block: Arc<Block>) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_last();
+        iter
+    }
+
     /// Creates a block iterator and seek to the first key that >= `key`.
     pub fn create_and_seek_to_key(block: Arc<Block>, key: KeySlice) -> Self {
         let mut iter = Self::new(block);
@@ -81,8 +92,14 @@ impl BlockIterator {
         self.seek_to(0);
     }
 
+    /// Seeks to the last key in the block.
+    pub fn seek_to_last(&mut self) {
+        self.seek_to(self.block.offsets.len() - 1);
+    }
+
     /// Seeks to the idx-th key in the block.
     fn seek_to(&mut self, idx: usize) {
+        self.idx = idx;
         if idx >= self.block.offsets.len() {
             self.key.clear();
             self.value_range = (0, 0);
@@ -90,7 +107,6 @@ impl BlockIterator {
         }
         let offset = self.block.offsets[idx] as usize;
         self.seek_to_offset(offset);
-        self.idx = idx;
     }
 
     /// Move to the next key in the block.
@@ -99,14 +115,26 @@ impl BlockIterator {
         self.seek_to(self.idx);
     }
 
+    /// Move to the previous key in the block. The iterator becomes invalid when moving before the
+    /// first key.
+    pub fn prev(&mut self) {
+        if self.idx == 0 {
+            self.key.clear();
+            self.value_range = (0, 0);
+            return;
+        }
+        self.idx -= 1;
+        self.seek_to(self.idx);
+    }
+
     /// Seek to the specified position and update the current `key` and `value`
     /// Index update will be handled by caller
     fn seek_to_offset(&mut self, offset: usize) {
         let mut entry = &self.block.data[offset..];
-        // Since `get_u16()` will automatically move the ptr 2 bytes ahead here,
+        // Since `get_u32()` will automatically move the ptr 4 bytes ahead here,
         // we don't need to manually advance it
-        let overlap_len = entry.get_u16() as usize;
-        let key_len = entry.get_u16() as usize;
+        let overlap_len = entry.get_u32() as usize;
+        let key_len = entry.get_u32() as usize;
         let key = &entry[..key_len];
         self.key.clear();
         self.key.append(&self.first_key.key_ref()[..overlap_len]);
@@ -114,10 +142,10 @@ impl BlockIterator {
         entry.advance(key_len);
         let ts = entry.get_u64();
         self.key.set_ts(ts);
-        let value_len = entry.get_u16() as usize;
+        let value_len = entry.get_u32() as usize;
         // REMEMBER TO CHANGE THIS every time you change the encoding!
         let value_offset_begin =
-            offset + SIZEOF_U16 + SIZEOF_U16 + std::mem::size_of::<u64>() + key_len + SIZEOF_U16;
+            offset + SIZEOF_U32 + SIZEOF_U32 + std::mem::size_of::<u64>() + key_len + SIZEOF_U32;
         let value_offset_end = value_offset_begin + value_len;
         self.value_range = (value_offset_begin, value_offset_end);
         entry.advance(value_len);
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::blob::BlobOptions;
+use crate::compact::{CompactionController, CompactionOptions};
+use crate::lsm_storage::{LsmStorageOptions, LsmStorageState};
+use crate::table::CompressionOptions;
+
+/// The name of the column family that always exists, which is the one used by transactions and the
+/// methods without a column family.
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+pub(crate) const DEFAULT_COLUMN_FAMILY_ID: usize = 0;
+
+/// The options of a column family. The options of the default column family are taken from
+/// `LsmStorageOptions`, and the others are recorded in the manifest when they are created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnFamilyOptions {
+    // Block size in bytes
+    pub block_size: usize,
+    // SST size in bytes, also the approximate memtable capacity limit
+    pub target_sst_size: usize,
+    pub compaction_options: CompactionOptions,
+    pub compression: CompressionOptions,
+    #[serde(default)]
+    pub blob: Option<BlobOptions>,
+}
+
+impl ColumnFamilyOptions {
+    pub(crate) fn from_storage_options(options: &LsmStorageOptions) -> Self {
+        Self {
+            block_size: options.block_size,
+            target_sst_size: options.target_sst_size,
+            compaction_options: options.compaction_options.clone(),
+            compression: options.compression.clone(),
+            blob: options.blob.clone(),
+        }
+    }
+}
+
+/// A keyspace with its own memtables, SSTs and compaction. All column families share the WAL and
+/// the manifest, and the memtables of all column families are frozen and flushed together, so that
+/// a WAL can be removed once its memtables are flushed.
+pub(crate) struct ColumnFamily {
+    pub(crate) id: usize,
+    pub(crate) name: String,
+    pub(crate) options: ColumnFamilyOptions,
+    pub(crate) compaction_controller: CompactionController,
+    pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(
+        id: usize,
+        name: String,
+        options: ColumnFamilyOptions,
+        state: LsmStorageState,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            compaction_controller: CompactionController::new(&options.compaction_options),
+            options,
+            state: Arc::new(RwLock::new(Arc::new(state))),
+        }
+    }
+
+    /// Get the state to modify it when recovering, before the column family is shared.
+    pub(crate) fn state_mut(&mut self) -> &mut LsmStorageState {
+        Arc::make_mut(
+            Arc::get_mut(&mut self.state)
+                .expect("column family is shared")
+                .get_mut(),
+        )
+    }
+}
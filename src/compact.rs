@@ -2,7 +2,8 @@ mod leveled;
 mod simple_leveled;
 mod tiered;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -14,6 +15,7 @@ pub use simple_leveled::{
 };
 pub use tiered::{TieredCompactionController, TieredCompactionOptions, TieredCompactionTask};
 
+use crate::column_family::{ColumnFamily, DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_ID};
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
@@ -21,7 +23,10 @@ use crate::iterators::StorageIterator;
 use crate::key::KeySlice;
 use crate::lsm_storage::{CompactionFilter, LsmStorageInner, LsmStorageState};
 use crate::manifest::ManifestRecord;
-use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+use crate::merge_operator::{merge_into_value, MergeOperator};
+use crate::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
+use crate::table::{CompressionOptions, CompressionType, SsTable, SsTableBuilder, SsTableIterator};
+use crate::value::{now_millis, Value};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CompactionTask {
@@ -32,6 +37,15 @@ pub enum CompactionTask {
         l0_sstables: Vec<usize>,
         l1_sstables: Vec<usize>,
     },
+    /// Rewrite an SST in place once all of its values with a TTL have expired, so that they are
+    /// dropped even if the compaction controller never picks the SST.
+    Expired {
+        /// The level (or tier) id of the SST, or `None` if it is in L0.
+        level: Option<usize>,
+        sst_id: usize,
+        /// Whether there is no older data below the SST.
+        is_bottom_level: bool,
+    },
 }
 
 impl CompactionTask {
@@ -41,6 +55,48 @@ impl CompactionTask {
             CompactionTask::Leveled(task) => task.is_lower_level_bottom_level,
             CompactionTask::Simple(task) => task.is_lower_level_bottom_level,
             CompactionTask::Tiered(task) => task.bottom_tier_included,
+            CompactionTask::Expired {
+                is_bottom_level, ..
+            } => *is_bottom_level,
+        }
+    }
+
+    /// The codec of the output SSTs. An SST rewritten in L0 keeps the L0 codec.
+    fn compression(&self, options: &CompressionOptions) -> CompressionType {
+        match self {
+            _ if self.compact_to_bottom_level() => options.bottom_level,
+            CompactionTask::Expired { level: None, .. } => options.l0,
+            _ => options.middle_levels,
+        }
+    }
+
+    fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::ForceFullCompaction {
+                l0_sstables,
+                l1_sstables,
+            } => l0_sstables.iter().chain(l1_sstables).copied().collect(),
+            CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            })
+            | CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            }) => upper_level_sst_ids
+                .iter()
+                .chain(lower_level_sst_ids)
+                .copied()
+                .collect(),
+            CompactionTask::Tiered(task) => task
+                .tiers
+                .iter()
+                .flat_map(|(_, ssts)| ssts)
+                .copied()
+                .collect(),
+            CompactionTask::Expired { sst_id, .. } => vec![*sst_id],
         }
     }
 }
@@ -53,6 +109,21 @@ pub(crate) enum CompactionController {
 }
 
 impl CompactionController {
+    pub fn new(compaction_options: &CompactionOptions) -> Self {
+        match compaction_options {
+            CompactionOptions::Leveled(options) => {
+                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Simple(options) => CompactionController::Simple(
+                SimpleLeveledCompactionController::new(options.clone()),
+            ),
+            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
+        }
+    }
+
     pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<CompactionTask> {
         match self {
             CompactionController::Leveled(ctrl) => ctrl
@@ -84,11 +155,65 @@ impl CompactionController {
             (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output)
             }
+            (_, CompactionTask::Expired { level, sst_id, .. }) => {
+                let mut snapshot = snapshot.clone();
+                let ssts = match level {
+                    None => &mut snapshot.l0_sstables,
+                    Some(level) => {
+                        &mut snapshot
+                            .levels
+                            .iter_mut()
+                            .find(|(id, _)| id == level)
+                            .expect("level not found")
+                            .1
+                    }
+                };
+                let idx = ssts
+                    .iter()
+                    .position(|id| id == sst_id)
+                    .expect("sst not found");
+                ssts.splice(idx..=idx, output.iter().copied());
+                // Unlike levels, tiers are removed once they are empty.
+                if !self.flush_to_l0() {
+                    snapshot.levels.retain(|(_, ssts)| !ssts.is_empty());
+                }
+                (snapshot, vec![*sst_id])
+            }
             _ => unreachable!(),
         }
     }
 }
 
+/// Find an SST in which all values with a TTL have expired at `now`. The SST is rewritten on its
+/// own, so that it only needs to be older than every SST below it to be at the bottom level.
+fn generate_expired_compaction_task(
+    snapshot: &LsmStorageState,
+    now: u64,
+) -> Option<CompactionTask> {
+    let is_expired = |id: &usize| {
+        let max_expire_at = snapshot.sstables[id].max_expire_at();
+        max_expire_at != 0 && max_expire_at <= now
+    };
+    let levels_empty = |from: usize| snapshot.levels[from..].iter().all(|(_, x)| x.is_empty());
+    if let Some(idx) = snapshot.l0_sstables.iter().position(is_expired) {
+        return Some(CompactionTask::Expired {
+            level: None,
+            sst_id: snapshot.l0_sstables[idx],
+            is_bottom_level: idx + 1 == snapshot.l0_sstables.len() && levels_empty(0),
+        });
+    }
+    for (idx, (level, ssts)) in snapshot.levels.iter().enumerate() {
+        if let Some(sst_id) = ssts.iter().copied().find(is_expired) {
+            return Some(CompactionTask::Expired {
+                level: Some(*level),
+                sst_id,
+                is_bottom_level: levels_empty(idx + 1),
+            });
+        }
+    }
+    None
+}
+
 impl CompactionController {
     pub fn flush_to_l0(&self) -> bool {
         matches!(
@@ -98,7 +223,7 @@ impl CompactionController {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompactionOptions {
     /// Leveled compaction with partial compaction + dynamic level support (= RocksDB's Leveled
     /// Compaction)
@@ -111,21 +236,118 @@ pub enum CompactionOptions {
     NoCompaction,
 }
 
+/// Range tombstones below the watermark are visible to all readers, so the versions they delete can
+/// be removed.
+fn range_tombstones_below(
+    range_tombstones: &[RangeTombstone],
+    watermark: u64,
+) -> FragmentedRangeTombstones {
+    let range_tombstones = range_tombstones
+        .iter()
+        .filter(|x| x.ts <= watermark)
+        .cloned()
+        .collect::<Vec<_>>();
+    FragmentedRangeTombstones::new(&range_tombstones)
+}
+
+fn is_range_deleted(range_tombstones: &FragmentedRangeTombstones, key: KeySlice) -> bool {
+    range_tombstones.covers(key.key_ref(), key.ts())
+}
+
+/// At the bottom level, a range tombstone below the watermark can be removed once no other SST has
+/// keys in its range.
+fn retain_range_tombstones_at_bottom_level(
+    range_tombstones: &[RangeTombstone],
+    other_ssts: &[Arc<SsTable>],
+    watermark: u64,
+) -> Vec<RangeTombstone> {
+    range_tombstones
+        .iter()
+        .filter(|x| {
+            x.ts > watermark
+                || other_ssts.iter().any(|sst| {
+                    sst.num_of_blocks() > 0
+                        && x.overlaps(
+                            Bound::Included(sst.first_key().key_ref()),
+                            Bound::Included(sst.last_key().key_ref()),
+                        )
+                })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Collapse the merge operands of a key, from the earliest to the latest. The operands are applied
+/// if the encoded version before them is known, and are combined into one merge operand otherwise.
+fn collapse_merge_operands(
+    merge_operator: Option<&Arc<dyn MergeOperator>>,
+    key: &[u8],
+    existing_value: &Option<Vec<u8>>,
+    payloads: &[Vec<u8>],
+    now: u64,
+) -> Result<Vec<u8>> {
+    let Some(existing_value) = existing_value else {
+        return Ok(Value::Merge(&payloads.concat()).encode());
+    };
+    merge_into_value(
+        merge_operator,
+        key,
+        Value::decode_at(existing_value, now)?,
+        payloads,
+    )
+}
+
+/// The size of the records in each blob file that are no longer referenced after a compaction.
+type BlobGarbage = HashMap<usize, u64>;
+
+/// Add the garbage of a compaction to the blob files that still exist, and return the garbage to
+/// be recorded in the manifest.
+fn apply_blob_garbage(
+    snapshot: &mut LsmStorageState,
+    blob_garbage: BlobGarbage,
+) -> Vec<(usize, u64)> {
+    let mut applied = Vec::new();
+    for (blob_id, size) in blob_garbage {
+        if size == 0 {
+            continue;
+        }
+        if let Some(garbage) = snapshot.blob_garbage.get_mut(&blob_id) {
+            *garbage += size;
+            applied.push((blob_id, size));
+        }
+    }
+    applied
+}
+
 impl LsmStorageInner {
+    /// Compact the key-value pairs from the iterator into new SSTs. `range_tombstones` are the range
+    /// tombstones of the SSTs being compacted, and `other_ssts` are the SSTs not being compacted.
+    /// Values in blob files are not read, and the pointers to them that are dropped are returned as
+    /// garbage.
     fn compact_generate_sst_from_iter(
         &self,
+        column_family: &ColumnFamily,
         mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
         compact_to_bottom_level: bool,
-    ) -> Result<Vec<Arc<SsTable>>> {
+        compression: CompressionType,
+        range_tombstones: &[RangeTombstone],
+        other_ssts: &[Arc<SsTable>],
+    ) -> Result<(Vec<Arc<SsTable>>, BlobGarbage)> {
         let mut builder = None;
         let mut new_sst = Vec::new();
-        let watermark = self.mvcc().watermark();
+        let mut blob_garbage = BlobGarbage::new();
+        let watermark = self.mvcc().gc_watermark();
+        let visible_range_tombstones = range_tombstones_below(range_tombstones, watermark);
         let mut last_key = Vec::<u8>::new();
         let mut first_key_below_watermark = false;
         let compaction_filters = self.compaction_filters.lock().clone();
+        let now = now_millis();
         'outer: while iter.is_valid() {
             if builder.is_none() {
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+                builder = Some(SsTableBuilder::new_with_compression(
+                    column_family.options.block_size,
+                    compression,
+                ));
             }
 
             let same_as_last_key = iter.key().key_ref() == last_key;
@@ -133,10 +355,26 @@ impl LsmStorageInner {
                 first_key_below_watermark = true;
             }
 
+            // Every pointer is garbage until it is written into the new SSTs.
+            let blob_pointer = match Value::decode(iter.value())? {
+                Value::Blob(pointer) => {
+                    *blob_garbage.entry(pointer.file_id).or_default() += pointer.size as u64;
+                    Some(pointer)
+                }
+                _ => None,
+            };
+
+            if is_range_deleted(&visible_range_tombstones, iter.key()) {
+                iter.next()?;
+                continue;
+            }
+
+            // An expired value is the same as a delete tombstone to all readers.
+            let is_delete = Value::decode_at(iter.value(), now)? == Value::Delete;
             if compact_to_bottom_level
                 && !same_as_last_key
                 && iter.key().ts() <= watermark
-                && iter.value().is_empty()
+                && is_delete
             {
                 last_key.clear();
                 last_key.extend(iter.key().key_ref());
@@ -145,6 +383,7 @@ impl LsmStorageInner {
                 continue;
             }
 
+            let mut merged = None;
             if iter.key().ts() <= watermark {
                 if same_as_last_key && !first_key_below_watermark {
                     iter.next()?;
@@ -165,11 +404,52 @@ impl LsmStorageInner {
                         }
                     }
                 }
+
+                if let Value::Merge(_) = Value::decode(iter.value())? {
+                    // Collect the merge operands until a version that is not a merge operand, which
+                    // is unknown if the compaction does not go to the bottom level.
+                    let key = iter.key().to_key_vec();
+                    let mut payloads = Vec::new();
+                    let mut existing_value = compact_to_bottom_level.then(Vec::new);
+                    let mut keep_existing_value = false;
+                    while iter.is_valid() && iter.key().key_ref() == key.key_ref() {
+                        if is_range_deleted(&visible_range_tombstones, iter.key()) {
+                            existing_value = Some(Vec::new());
+                            break;
+                        }
+                        match Value::decode_at(iter.value(), now)? {
+                            Value::Merge(payload) => payloads.push(payload.to_vec()),
+                            // The operands outlive a value that has not expired yet, so they are
+                            // kept apart from it. A value in a blob file is not read by compactions.
+                            Value::PutWithTtl(..) | Value::Blob(_) => {
+                                existing_value = None;
+                                keep_existing_value = true;
+                                break;
+                            }
+                            Value::Put(_) | Value::Delete => {
+                                existing_value = Some(iter.value().to_vec());
+                                break;
+                            }
+                        }
+                        iter.next()?;
+                    }
+                    payloads.reverse();
+                    let value = collapse_merge_operands(
+                        self.options.merge_operator.as_ref(),
+                        key.key_ref(),
+                        &existing_value,
+                        &payloads,
+                        now,
+                    )?;
+                    merged = Some((key, value, keep_existing_value));
+                }
             }
 
             let builder_inner = builder.as_mut().unwrap();
 
-            if builder_inner.estimated_size() >= self.options.target_sst_size && !same_as_last_key {
+            if builder_inner.estimated_size() >= column_family.options.target_sst_size
+                && !same_as_last_key
+            {
                 let sst_id = self.next_sst_id();
                 let old_builder = builder.take().unwrap();
                 let sst = Arc::new(old_builder.build(
@@ -178,11 +458,34 @@ impl LsmStorageInner {
                     self.path_of_sst(sst_id),
                 )?);
                 new_sst.push(sst);
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+                builder = Some(SsTableBuilder::new_with_compression(
+                    column_family.options.block_size,
+                    compression,
+                ));
             }
 
             let builder_inner = builder.as_mut().unwrap();
-            builder_inner.add(iter.key(), iter.value());
+            if let Some((key, value, keep_existing_value)) = merged {
+                // The iterator has already been moved past the merge operands.
+                if !value.is_empty() || !compact_to_bottom_level {
+                    builder_inner.add(key.as_key_slice(), &value);
+                }
+                // The version below the operands is kept as if it were the first one.
+                first_key_below_watermark = keep_existing_value;
+                if !same_as_last_key {
+                    last_key.clear();
+                    last_key.extend(key.key_ref());
+                }
+                continue;
+            }
+            if is_delete {
+                builder_inner.add(iter.key(), &[]);
+            } else {
+                builder_inner.add(iter.key(), iter.value());
+            }
+            if let Some(pointer) = blob_pointer {
+                *blob_garbage.get_mut(&pointer.file_id).unwrap() -= pointer.size as u64;
+            }
 
             if !same_as_last_key {
                 last_key.clear();
@@ -191,6 +494,25 @@ impl LsmStorageInner {
 
             iter.next()?;
         }
+
+        let retained_range_tombstones = if compact_to_bottom_level {
+            retain_range_tombstones_at_bottom_level(range_tombstones, other_ssts, watermark)
+        } else {
+            range_tombstones.to_vec()
+        };
+        if !retained_range_tombstones.is_empty() {
+            if builder.is_none() {
+                builder = Some(SsTableBuilder::new_with_compression(
+                    column_family.options.block_size,
+                    compression,
+                ));
+            }
+            let builder_inner = builder.as_mut().unwrap();
+            for range_tombstone in retained_range_tombstones {
+                builder_inner.add_range_tombstone(range_tombstone);
+            }
+        }
+
         if let Some(builder) = builder {
             let sst_id = self.next_sst_id(); // lock dropped here
             let sst = Arc::new(builder.build(
@@ -200,14 +522,31 @@ impl LsmStorageInner {
             )?);
             new_sst.push(sst);
         }
-        Ok(new_sst)
+        Ok((new_sst, blob_garbage))
     }
 
-    fn compact(&self, task: &CompactionTask) -> Result<Vec<Arc<SsTable>>> {
+    fn compact(
+        &self,
+        column_family: &ColumnFamily,
+        task: &CompactionTask,
+    ) -> Result<(Vec<Arc<SsTable>>, BlobGarbage)> {
         let snapshot = {
-            let state = self.state.read();
+            let state = column_family.state.read();
             state.clone()
         };
+        let input_sst_ids = task.input_sst_ids().into_iter().collect::<HashSet<_>>();
+        let compression = task.compression(&column_family.options.compression);
+        let range_tombstones = input_sst_ids
+            .iter()
+            .flat_map(|id| snapshot.sstables[id].range_tombstones())
+            .cloned()
+            .collect::<Vec<_>>();
+        let other_ssts = snapshot
+            .sstables
+            .iter()
+            .filter(|(id, _)| !input_sst_ids.contains(id))
+            .map(|(_, sst)| sst.clone())
+            .collect::<Vec<_>>();
         match task {
             CompactionTask::ForceFullCompaction {
                 l0_sstables,
@@ -227,7 +566,14 @@ impl LsmStorageInner {
                     MergeIterator::create(l0_iters),
                     SstConcatIterator::create_and_seek_to_first(l1_iters)?,
                 )?;
-                self.compact_generate_sst_from_iter(iter, task.compact_to_bottom_level())
+                self.compact_generate_sst_from_iter(
+                    column_family,
+                    iter,
+                    task.compact_to_bottom_level(),
+                    compression,
+                    &range_tombstones,
+                    &other_ssts,
+                )
             }
             CompactionTask::Simple(SimpleLeveledCompactionTask {
                 upper_level,
@@ -255,8 +601,12 @@ impl LsmStorageInner {
                     }
                     let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
                     self.compact_generate_sst_from_iter(
+                        column_family,
                         TwoMergeIterator::create(upper_iter, lower_iter)?,
                         task.compact_to_bottom_level(),
+                        compression,
+                        &range_tombstones,
+                        &other_ssts,
                     )
                 }
                 None => {
@@ -273,11 +623,23 @@ impl LsmStorageInner {
                     }
                     let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
                     self.compact_generate_sst_from_iter(
+                        column_family,
                         TwoMergeIterator::create(upper_iter, lower_iter)?,
                         task.compact_to_bottom_level(),
+                        compression,
+                        &range_tombstones,
+                        &other_ssts,
                     )
                 }
             },
+            CompactionTask::Expired { sst_id, .. } => self.compact_generate_sst_from_iter(
+                column_family,
+                SsTableIterator::create_and_seek_to_first(snapshot.sstables[sst_id].clone())?,
+                task.compact_to_bottom_level(),
+                compression,
+                &range_tombstones,
+                &other_ssts,
+            ),
             CompactionTask::Tiered(TieredCompactionTask { tiers, .. }) => {
                 let mut iters = Vec::with_capacity(tiers.len());
                 for (_, tier_sst_ids) in tiers {
@@ -288,8 +650,12 @@ impl LsmStorageInner {
                     iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(ssts)?));
                 }
                 self.compact_generate_sst_from_iter(
+                    column_family,
                     MergeIterator::create(iters),
                     task.compact_to_bottom_level(),
+                    compression,
+                    &range_tombstones,
+                    &other_ssts,
                 )
             }
         }
@@ -314,12 +680,14 @@ impl LsmStorageInner {
 
         println!("force full compaction: {:?}", compaction_task);
 
-        let sstables = self.compact(&compaction_task)?;
+        let column_family = self.column_family(DEFAULT_COLUMN_FAMILY)?;
+        let (sstables, blob_garbage) = self.compact(&column_family, &compaction_task)?;
         let mut ids = Vec::with_capacity(sstables.len());
 
         {
             let state_lock = self.state_lock.lock();
-            let mut state = self.state.read().as_ref().clone();
+            let old_state = self.state.read().clone();
+            let mut state = old_state.as_ref().clone();
             for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
                 let result = state.sstables.remove(sst);
                 assert!(result.is_some());
@@ -339,12 +707,22 @@ impl LsmStorageInner {
                 .copied()
                 .collect::<Vec<_>>();
             assert!(l0_sstables_map.is_empty());
+            let blob_garbage = apply_blob_garbage(&mut state, blob_garbage);
+            let edit = state.sst_edit(&old_state, DEFAULT_COLUMN_FAMILY_ID);
             *self.state.write() = Arc::new(state);
             self.sync_dir()?;
-            self.manifest.as_ref().unwrap().add_record(
-                &state_lock,
-                ManifestRecord::Compaction(compaction_task, ids.clone()),
-            )?;
+            self.record_timestamps(&state_lock)?;
+            self.manifest
+                .as_ref()
+                .unwrap()
+                .add_record(&state_lock, ManifestRecord::CompactionEdit(edit))?;
+            if !blob_garbage.is_empty() {
+                self.manifest().add_record(
+                    &state_lock,
+                    ManifestRecord::BlobGarbage(DEFAULT_COLUMN_FAMILY_ID, blob_garbage),
+                )?;
+            }
+            self.maybe_rotate_manifest(&state_lock)?;
         }
         for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
             std::fs::remove_file(self.path_of_sst(*sst))?;
@@ -356,30 +734,66 @@ impl LsmStorageInner {
     }
 
     fn trigger_compaction(&self) -> Result<()> {
+        let column_families = self
+            .column_families
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for column_family in column_families {
+            if !matches!(
+                column_family.options.compaction_options,
+                CompactionOptions::NoCompaction
+            ) {
+                self.trigger_column_family_compaction(&column_family)?;
+            }
+            // Blob files are collected between compactions, so that the pointers are not moved
+            // while the garbage collector looks for them.
+            self.trigger_blob_gc(&column_family)?;
+        }
+        Ok(())
+    }
+
+    fn trigger_column_family_compaction(&self, column_family: &ColumnFamily) -> Result<()> {
         let snapshot = {
-            let state = self.state.read();
+            let state = column_family.state.read();
             state.clone()
         };
-        let task = self
+        let task = column_family
             .compaction_controller
-            .generate_compaction_task(&snapshot);
+            .generate_compaction_task(&snapshot)
+            .or_else(|| generate_expired_compaction_task(&snapshot, now_millis()));
         let Some(task) = task else {
             return Ok(());
         };
-        self.dump_structure();
-        println!("running compaction task: {:?}", task);
-        let sstables = self.compact(&task)?;
+        self.dump_column_family_structure(column_family);
+        println!(
+            "running compaction task in column family {}: {:?}",
+            column_family.name, task
+        );
+        let (sstables, blob_garbage) = self.compact(column_family, &task)?;
         let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
         let ssts_to_remove = {
             let state_lock = self.state_lock.lock();
-            let mut snapshot = self.state.read().as_ref().clone();
-            let mut new_sst_ids = Vec::new();
+            if !self
+                .column_families
+                .read()
+                .contains_key(&column_family.name)
+            {
+                // The column family is dropped while compacting.
+                drop(state_lock);
+                for sst_id in &output {
+                    std::fs::remove_file(self.path_of_sst(*sst_id))?;
+                }
+                return Ok(());
+            }
+            let old_state = column_family.state.read().clone();
+            let mut snapshot = old_state.as_ref().clone();
             for file_to_add in sstables {
-                new_sst_ids.push(file_to_add.sst_id());
                 let result = snapshot.sstables.insert(file_to_add.sst_id(), file_to_add);
                 assert!(result.is_none());
             }
-            let (mut snapshot, files_to_remove) = self
+            let (mut snapshot, files_to_remove) = column_family
                 .compaction_controller
                 .apply_compaction_result(&snapshot, &task, &output);
             let mut ssts_to_remove = Vec::with_capacity(files_to_remove.len());
@@ -388,12 +802,22 @@ impl LsmStorageInner {
                 assert!(result.is_some(), "cannot remove {}.sst", file_to_remove);
                 ssts_to_remove.push(result.unwrap());
             }
-            let mut state = self.state.write();
+            let blob_garbage = apply_blob_garbage(&mut snapshot, blob_garbage);
+            let edit = snapshot.sst_edit(&old_state, column_family.id);
+            let mut state = column_family.state.write();
             *state = Arc::new(snapshot);
             drop(state);
             self.sync_dir()?;
+            self.record_timestamps(&state_lock)?;
             self.manifest()
-                .add_record(&state_lock, ManifestRecord::Compaction(task, new_sst_ids))?;
+                .add_record(&state_lock, ManifestRecord::CompactionEdit(edit))?;
+            if !blob_garbage.is_empty() {
+                self.manifest().add_record(
+                    &state_lock,
+                    ManifestRecord::BlobGarbage(column_family.id, blob_garbage),
+                )?;
+            }
+            self.maybe_rotate_manifest(&state_lock)?;
             ssts_to_remove
         };
         println!(
@@ -410,29 +834,25 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    /// The compaction thread is always spawned, as column families may be created with compaction
+    /// options different from the default column family.
     pub(crate) fn spawn_compaction_thread(
         self: &Arc<Self>,
         rx: crossbeam_channel::Receiver<()>,
     ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        if let CompactionOptions::Leveled(_)
-        | CompactionOptions::Simple(_)
-        | CompactionOptions::Tiered(_) = self.options.compaction_options
-        {
-            let this = self.clone();
-            let handle = std::thread::spawn(move || {
-                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
-                loop {
-                    crossbeam_channel::select! {
-                        recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
-                            eprintln!("compaction failed: {}", e);
-                        },
-                        recv(rx) -> _ => return
-                    }
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
+                        eprintln!("compaction failed: {}", e);
+                    },
+                    recv(rx) -> _ => return
                 }
-            });
-            return Ok(Some(handle));
-        }
-        Ok(None)
+            }
+        });
+        Ok(Some(handle))
     }
 
     fn trigger_flush(&self) -> Result<()> {
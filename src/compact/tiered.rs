@@ -10,7 +10,7 @@ pub struct TieredCompactionTask {
     pub bottom_tier_included: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TieredCompactionOptions {
     pub num_tiers: usize,
     pub max_size_amplification_percent: usize,
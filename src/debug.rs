@@ -1,8 +1,16 @@
+use crate::column_family::ColumnFamily;
 use crate::lsm_storage::{LsmStorageInner, MiniLsm};
 
 impl LsmStorageInner {
     pub fn dump_structure(&self) {
-        let snapshot = self.state.read();
+        for column_family in self.column_families.read().values() {
+            self.dump_column_family_structure(column_family);
+        }
+    }
+
+    pub(crate) fn dump_column_family_structure(&self, column_family: &ColumnFamily) {
+        println!("column family {}:", column_family.name);
+        let snapshot = column_family.state.read();
         if !snapshot.l0_sstables.is_empty() {
             println!(
                 "L0 ({}): {:?}",
@@ -2,8 +2,15 @@ pub mod concat_iterator;
 pub mod merge_iterator;
 pub mod two_merge_iterator;
 
+/// The direction an iterator is currently moving in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
 pub trait StorageIterator {
-    type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord
+    type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord + Copy
     where
         Self: 'a;
 
@@ -19,8 +26,36 @@ pub trait StorageIterator {
     /// Move to the next position.
     fn next(&mut self) -> anyhow::Result<()>;
 
+    /// Move to the previous position. An iterator that has moved past its last key can be moved
+    /// back with `prev`, and an iterator that has moved before its first key can be moved forward
+    /// again with `next`.
+    fn prev(&mut self) -> anyhow::Result<()> {
+        anyhow::bail!("backward iteration is not supported by this iterator")
+    }
+
+    /// Move to the first position. The iterator moves forward afterwards.
+    fn seek_to_first(&mut self) -> anyhow::Result<()> {
+        anyhow::bail!("seeking is not supported by this iterator")
+    }
+
+    /// Move to the first position whose key is >= `key`. The iterator moves forward afterwards.
+    fn seek_to_key(&mut self, _key: Self::KeyType<'_>) -> anyhow::Result<()> {
+        anyhow::bail!("seeking is not supported by this iterator")
+    }
+
     /// Number of underlying active iterators for this iterator.
     fn num_active_iterators(&self) -> usize {
         1
     }
 }
+
+/// Move the iterator by one position in the given direction.
+pub(crate) fn step<I: StorageIterator + ?Sized>(
+    iter: &mut I,
+    direction: Direction,
+) -> anyhow::Result<()> {
+    match direction {
+        Direction::Forward => iter.next(),
+        Direction::Backward => iter.prev(),
+    }
+}
@@ -10,7 +10,8 @@ use crate::{
 use super::StorageIterator;
 
 /// Concat multiple iterators ordered in key order and their key ranges do not overlap. We do not want to create the
-/// iterators when initializing this iterator to reduce the overhead of seeking.
+/// iterators when initializing this iterator to reduce the overhead of seeking. SSTs with only range tombstones are
+/// skipped.
 pub struct SstConcatIterator {
     current: Option<SsTableIterator>,
     next_sst_idx: usize,
@@ -18,6 +19,13 @@ pub struct SstConcatIterator {
 }
 
 impl SstConcatIterator {
+    fn data_sstables(sstables: Vec<Arc<SsTable>>) -> Vec<Arc<SsTable>> {
+        sstables
+            .into_iter()
+            .filter(|sst| sst.num_of_blocks() > 0)
+            .collect()
+    }
+
     fn check_sst_valid(sstables: &[Arc<SsTable>]) {
         for sst in sstables {
             assert!(sst.first_key() <= sst.last_key());
@@ -30,6 +38,19 @@ impl SstConcatIterator {
     }
 
     pub fn create_and_seek_to_first(sstables: Vec<Arc<SsTable>>) -> Result<Self> {
+        let sstables = Self::data_sstables(sstables);
+        Self::check_sst_valid(&sstables);
+        let mut iter = Self {
+            current: None,
+            next_sst_idx: 0,
+            sstables,
+        };
+        iter.seek_to_first()?;
+        Ok(iter)
+    }
+
+    pub fn create_and_seek_to_last(sstables: Vec<Arc<SsTable>>) -> Result<Self> {
+        let sstables = Self::data_sstables(sstables);
         Self::check_sst_valid(&sstables);
         if sstables.is_empty() {
             return Ok(Self {
@@ -39,37 +60,25 @@ impl SstConcatIterator {
             });
         }
         let mut iter = Self {
-            current: Some(SsTableIterator::create_and_seek_to_first(
-                sstables[0].clone(),
+            current: Some(SsTableIterator::create_and_seek_to_last(
+                sstables[sstables.len() - 1].clone(),
             )?),
-            next_sst_idx: 1,
+            next_sst_idx: sstables.len(),
             sstables,
         };
-        iter.move_until_valid()?;
+        iter.move_until_valid_backward()?;
         Ok(iter)
     }
 
     pub fn create_and_seek_to_key(sstables: Vec<Arc<SsTable>>, key: KeySlice) -> Result<Self> {
+        let sstables = Self::data_sstables(sstables);
         Self::check_sst_valid(&sstables);
-        let idx: usize = sstables
-            .partition_point(|table| table.first_key().as_key_slice() <= key)
-            .saturating_sub(1);
-        if idx >= sstables.len() {
-            return Ok(Self {
-                current: None,
-                next_sst_idx: sstables.len(),
-                sstables,
-            });
-        }
         let mut iter = Self {
-            current: Some(SsTableIterator::create_and_seek_to_key(
-                sstables[idx].clone(),
-                key,
-            )?),
-            next_sst_idx: idx + 1,
+            current: None,
+            next_sst_idx: 0,
             sstables,
         };
-        iter.move_until_valid()?;
+        iter.seek_to_key(key)?;
         Ok(iter)
     }
 
@@ -89,6 +98,26 @@ impl SstConcatIterator {
         }
         Ok(())
     }
+
+    /// The SST being visited is always at `next_sst_idx - 1`. When moving before the first SST,
+    /// `next_sst_idx` is reset to 0 so that `next` can tell it apart from moving past the last SST.
+    fn move_until_valid_backward(&mut self) -> Result<()> {
+        while let Some(iter) = self.current.as_mut() {
+            if iter.is_valid() {
+                break;
+            }
+            if self.next_sst_idx <= 1 {
+                self.current = None;
+                self.next_sst_idx = 0;
+            } else {
+                self.next_sst_idx -= 1;
+                self.current = Some(SsTableIterator::create_and_seek_to_last(
+                    self.sstables[self.next_sst_idx - 1].clone(),
+                )?);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl StorageIterator for SstConcatIterator {
@@ -112,7 +141,65 @@ impl StorageIterator for SstConcatIterator {
     }
 
     fn next(&mut self) -> Result<()> {
-        self.current.as_mut().unwrap().next()?;
+        match self.current.as_mut() {
+            Some(current) => current.next()?,
+            // Moved before the first SST, start over from the first one.
+            None if self.next_sst_idx == 0 && !self.sstables.is_empty() => {
+                self.current = Some(SsTableIterator::create_and_seek_to_first(
+                    self.sstables[0].clone(),
+                )?);
+                self.next_sst_idx = 1;
+            }
+            None => return Ok(()),
+        }
+        self.move_until_valid()?;
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        match self.current.as_mut() {
+            Some(current) => current.prev()?,
+            // Moved past the last SST, start over from the last one.
+            None if self.next_sst_idx == self.sstables.len() && !self.sstables.is_empty() => {
+                self.current = Some(SsTableIterator::create_and_seek_to_last(
+                    self.sstables[self.sstables.len() - 1].clone(),
+                )?);
+            }
+            None => return Ok(()),
+        }
+        self.move_until_valid_backward()?;
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        if self.sstables.is_empty() {
+            self.current = None;
+            self.next_sst_idx = 0;
+            return Ok(());
+        }
+        self.current = Some(SsTableIterator::create_and_seek_to_first(
+            self.sstables[0].clone(),
+        )?);
+        self.next_sst_idx = 1;
+        self.move_until_valid()?;
+        Ok(())
+    }
+
+    fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        let idx: usize = self
+            .sstables
+            .partition_point(|table| table.first_key().as_key_slice() <= key)
+            .saturating_sub(1);
+        if idx >= self.sstables.len() {
+            self.current = None;
+            self.next_sst_idx = self.sstables.len();
+            return Ok(());
+        }
+        self.current = Some(SsTableIterator::create_and_seek_to_key(
+            self.sstables[idx].clone(),
+            key,
+        )?);
+        self.next_sst_idx = idx + 1;
         self.move_until_valid()?;
         Ok(())
     }
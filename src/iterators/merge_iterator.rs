@@ -6,9 +6,9 @@ use anyhow::Result;
 
 use crate::key::KeySlice;
 
-use super::StorageIterator;
+use super::{step, Direction, StorageIterator};
 
-struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>);
+struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>, pub Direction);
 
 impl<I: StorageIterator> PartialEq for HeapWrapper<I> {
     fn eq(&self, other: &Self) -> bool {
@@ -22,11 +22,15 @@ impl<I: StorageIterator> PartialOrd for HeapWrapper<I> {
     #[allow(clippy::non_canonical_partial_ord_impl)]
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
         match self.1.key().cmp(&other.1.key()) {
+            // When moving forward, the smallest key should be at the top of the heap. When moving
+            // backward, the largest one.
+            cmp::Ordering::Greater if self.2 == Direction::Forward => Some(cmp::Ordering::Less),
             cmp::Ordering::Greater => Some(cmp::Ordering::Greater),
+            cmp::Ordering::Less if self.2 == Direction::Forward => Some(cmp::Ordering::Greater),
             cmp::Ordering::Less => Some(cmp::Ordering::Less),
-            cmp::Ordering::Equal => self.0.partial_cmp(&other.0),
+            // Prefer the iterator with the smaller index in both directions.
+            cmp::Ordering::Equal => self.0.partial_cmp(&other.0).map(|x| x.reverse()),
         }
-        .map(|x| x.reverse())
     }
 }
 
@@ -41,92 +45,88 @@ impl<I: StorageIterator> Ord for HeapWrapper<I> {
 pub struct MergeIterator<I: StorageIterator> {
     iters: BinaryHeap<HeapWrapper<I>>,
     current: Option<HeapWrapper<I>>,
+    /// Iterators that have run out of keys in the current direction. They are kept so that they
+    /// can be moved back when the direction changes.
+    exhausted: Vec<HeapWrapper<I>>,
+    direction: Direction,
 }
 
 impl<I: StorageIterator> MergeIterator<I> {
     pub fn create(iters: Vec<Box<I>>) -> Self {
-        if iters.is_empty() {
-            return Self {
-                iters: BinaryHeap::new(),
-                current: None,
-            };
-        }
+        Self::create_with_direction(iters, Direction::Forward)
+    }
 
-        let mut heap = BinaryHeap::new();
+    /// Merge iterators that are positioned at the last key of their range, so that the merge
+    /// iterator yields keys in descending order using `prev`.
+    pub fn create_reverse(iters: Vec<Box<I>>) -> Self {
+        Self::create_with_direction(iters, Direction::Backward)
+    }
 
-        if iters.iter().all(|x| !x.is_valid()) {
-            // All invalid, select the last one as the current.
-            let mut iters = iters;
-            return Self {
-                iters: heap,
-                current: Some(HeapWrapper(0, iters.pop().unwrap())),
-            };
-        }
+    pub(crate) fn create_with_direction(iters: Vec<Box<I>>, direction: Direction) -> Self {
+        let mut heap = BinaryHeap::new();
+        let mut exhausted = Vec::new();
 
         for (idx, iter) in iters.into_iter().enumerate() {
             if iter.is_valid() {
-                heap.push(HeapWrapper(idx, iter));
+                heap.push(HeapWrapper(idx, iter, direction));
+            } else {
+                exhausted.push(HeapWrapper(idx, iter, direction));
             }
         }
 
-        let current = heap.pop().unwrap();
+        // If all iterators are invalid, select the last one as the current.
+        let current = heap.pop().or_else(|| exhausted.pop());
         Self {
             iters: heap,
-            current: Some(current),
+            current,
+            exhausted,
+            direction,
         }
     }
 }
 
-impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIterator
-    for MergeIterator<I>
-{
-    type KeyType<'a> = KeySlice<'a>;
-
-    fn key(&self) -> KeySlice {
-        self.current.as_ref().unwrap().1.key()
-    }
-
-    fn value(&self) -> &[u8] {
-        self.current.as_ref().unwrap().1.value()
-    }
-
-    fn is_valid(&self) -> bool {
-        self.current
-            .as_ref()
-            .map(|x| x.1.is_valid())
-            .unwrap_or(false)
-    }
-
-    fn next(&mut self) -> Result<()> {
-        let current = self.current.as_mut().unwrap();
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> MergeIterator<I> {
+    /// Move all iterators in the current direction, skipping the keys that are the same as the
+    /// current one.
+    fn step(&mut self) -> Result<()> {
+        let direction = self.direction;
+        let Some(current) = self.current.as_mut() else {
+            return Ok(());
+        };
+        if !current.1.is_valid() {
+            return Ok(());
+        }
         // Pop the item out of the heap if they have the same value.
         while let Some(mut inner_iter) = self.iters.peek_mut() {
             debug_assert!(
-                inner_iter.1.key() >= current.1.key(),
+                match direction {
+                    Direction::Forward => inner_iter.1.key() >= current.1.key(),
+                    Direction::Backward => inner_iter.1.key() <= current.1.key(),
+                },
                 "heap invariant violated"
             );
             if inner_iter.1.key() == current.1.key() {
                 // Case 1: an error occurred when calling `next`.
-                if let e @ Err(_) = inner_iter.1.next() {
+                if let e @ Err(_) = step(&mut *inner_iter.1, direction) {
                     PeekMut::pop(inner_iter);
                     return e;
                 }
 
                 // Case 2: iter is no longer valid.
                 if !inner_iter.1.is_valid() {
-                    PeekMut::pop(inner_iter);
+                    self.exhausted.push(PeekMut::pop(inner_iter));
                 }
             } else {
                 break;
             }
         }
 
-        current.1.next()?;
+        step(&mut *current.1, direction)?;
 
         // If the current iterator is invalid, pop it out of the heap and select the next one.
         if !current.1.is_valid() {
             if let Some(iter) = self.iters.pop() {
-                *current = iter;
+                self.exhausted.push(std::mem::replace(current, iter));
             }
             return Ok(());
         }
@@ -141,6 +141,100 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
         Ok(())
     }
 
+    /// Change the direction of the merge iterator. Every underlying iterator is moved to the other
+    /// side of the current key, and the current key is then the one next to it in the new direction.
+    fn switch_direction(&mut self, direction: Direction) -> Result<()> {
+        let key = if self.is_valid() {
+            Some(self.key().to_key_vec())
+        } else {
+            None
+        };
+        self.reposition(direction, |iter| {
+            if !iter.is_valid() {
+                step(iter, direction)?;
+            }
+            if let Some(key) = &key {
+                while iter.is_valid()
+                    && match direction {
+                        Direction::Forward => iter.key() <= key.as_key_slice(),
+                        Direction::Backward => iter.key() >= key.as_key_slice(),
+                    }
+                {
+                    step(iter, direction)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Move every underlying iterator with `f`, and rebuild the heap for the given direction.
+    fn reposition(
+        &mut self,
+        direction: Direction,
+        mut f: impl FnMut(&mut I) -> Result<()>,
+    ) -> Result<()> {
+        self.direction = direction;
+        let iters = self
+            .iters
+            .drain()
+            .chain(self.current.take())
+            .chain(self.exhausted.drain(..))
+            .collect::<Vec<_>>();
+        for HeapWrapper(idx, mut iter, _) in iters {
+            f(&mut iter)?;
+            if iter.is_valid() {
+                self.iters.push(HeapWrapper(idx, iter, direction));
+            } else {
+                self.exhausted.push(HeapWrapper(idx, iter, direction));
+            }
+        }
+        self.current = self.iters.pop().or_else(|| self.exhausted.pop());
+        Ok(())
+    }
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIterator
+    for MergeIterator<I>
+{
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn key(&self) -> KeySlice {
+        self.current.as_ref().unwrap().1.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().1.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current
+            .as_ref()
+            .map(|x| x.1.is_valid())
+            .unwrap_or(false)
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.direction == Direction::Backward {
+            return self.switch_direction(Direction::Forward);
+        }
+        self.step()
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if self.direction == Direction::Forward {
+            return self.switch_direction(Direction::Backward);
+        }
+        self.step()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.reposition(Direction::Forward, |iter| iter.seek_to_first())
+    }
+
+    fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        self.reposition(Direction::Forward, |iter| iter.seek_to_key(key))
+    }
+
     fn num_active_iterators(&self) -> usize {
         self.iters
             .iter()
@@ -1,6 +1,6 @@
 use anyhow::Result;
 
-use super::StorageIterator;
+use super::{step, Direction, StorageIterator};
 
 /// Merges two iterators of different types into one. If the two iterators have the same key, only
 /// produce the key once and prefer the entry from A.
@@ -8,6 +8,7 @@ pub struct TwoMergeIterator<A: StorageIterator, B: StorageIterator> {
     a: A,
     b: B,
     choose_a: bool,
+    direction: Direction,
 }
 
 impl<
@@ -15,33 +16,110 @@ impl<
         B: 'static + for<'a> StorageIterator<KeyType<'a> = A::KeyType<'a>>,
     > TwoMergeIterator<A, B>
 {
-    fn choose_a(a: &A, b: &B) -> bool {
+    fn choose_a(a: &A, b: &B, direction: Direction) -> bool {
         if !a.is_valid() {
             return false;
         }
         if !b.is_valid() {
             return true;
         }
-        a.key() < b.key()
+        match direction {
+            Direction::Forward => a.key() < b.key(),
+            Direction::Backward => a.key() > b.key(),
+        }
     }
 
     fn skip_b(&mut self) -> Result<()> {
         if self.a.is_valid() && self.b.is_valid() && self.b.key() == self.a.key() {
-            self.b.next()?;
+            step(&mut self.b, self.direction)?;
         }
         Ok(())
     }
 
     pub fn create(a: A, b: B) -> Result<Self> {
+        Self::create_with_direction(a, b, Direction::Forward)
+    }
+
+    /// Merge two iterators that are positioned at the last key of their range, so that the merged
+    /// iterator yields keys in descending order using `prev`.
+    pub fn create_reverse(a: A, b: B) -> Result<Self> {
+        Self::create_with_direction(a, b, Direction::Backward)
+    }
+
+    pub(crate) fn create_with_direction(a: A, b: B, direction: Direction) -> Result<Self> {
         let mut iter = Self {
             choose_a: false,
             a,
             b,
+            direction,
         };
         iter.skip_b()?;
-        iter.choose_a = Self::choose_a(&iter.a, &iter.b);
+        iter.choose_a = Self::choose_a(&iter.a, &iter.b, direction);
         Ok(iter)
     }
+
+    fn step(&mut self) -> Result<()> {
+        if self.choose_a {
+            step(&mut self.a, self.direction)?;
+        } else {
+            step(&mut self.b, self.direction)?;
+        }
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&self.a, &self.b, self.direction);
+        Ok(())
+    }
+
+    /// Change the direction of the iterator. The iterator that is not chosen is moved to the other
+    /// side of the current key before the chosen one moves on.
+    fn switch_direction(&mut self, direction: Direction) -> Result<()> {
+        self.direction = direction;
+        if !self.is_valid() {
+            step(&mut self.a, direction)?;
+            step(&mut self.b, direction)?;
+        } else if self.choose_a {
+            if !self.b.is_valid() {
+                step(&mut self.b, direction)?;
+            }
+            while self.b.is_valid()
+                && match direction {
+                    Direction::Forward => self.b.key() <= self.a.key(),
+                    Direction::Backward => self.b.key() >= self.a.key(),
+                }
+            {
+                step(&mut self.b, direction)?;
+            }
+            step(&mut self.a, direction)?;
+        } else {
+            if !self.a.is_valid() {
+                step(&mut self.a, direction)?;
+            }
+            while self.a.is_valid()
+                && match direction {
+                    Direction::Forward => self.a.key() <= self.b.key(),
+                    Direction::Backward => self.a.key() >= self.b.key(),
+                }
+            {
+                step(&mut self.a, direction)?;
+            }
+            step(&mut self.b, direction)?;
+        }
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&self.a, &self.b, direction);
+        Ok(())
+    }
+
+    /// Check if the current entry is from A.
+    pub(crate) fn is_from_a(&self) -> bool {
+        self.choose_a
+    }
+
+    /// Update the chosen iterator after both iterators have been moved by a seek.
+    fn after_seek(&mut self) -> Result<()> {
+        self.direction = Direction::Forward;
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&self.a, &self.b, self.direction);
+        Ok(())
+    }
 }
 
 impl<
@@ -78,14 +156,29 @@ impl<
     }
 
     fn next(&mut self) -> Result<()> {
-        if self.choose_a {
-            self.a.next()?;
-        } else {
-            self.b.next()?;
+        if self.direction == Direction::Backward {
+            return self.switch_direction(Direction::Forward);
         }
-        self.skip_b()?;
-        self.choose_a = Self::choose_a(&self.a, &self.b);
-        Ok(())
+        self.step()
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if self.direction == Direction::Forward {
+            return self.switch_direction(Direction::Backward);
+        }
+        self.step()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.a.seek_to_first()?;
+        self.b.seek_to_first()?;
+        self.after_seek()
+    }
+
+    fn seek_to_key(&mut self, key: A::KeyType<'_>) -> Result<()> {
+        self.a.seek_to_key(key)?;
+        self.b.seek_to_key(key)?;
+        self.after_seek()
     }
 
     fn num_active_iterators(&self) -> usize {
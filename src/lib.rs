@@ -1,4 +1,6 @@
+pub mod blob;
 pub mod block;
+pub mod column_family;
 pub mod compact;
 pub mod debug;
 pub mod iterators;
@@ -7,8 +9,11 @@ pub mod lsm_iterator;
 pub mod lsm_storage;
 pub mod manifest;
 pub mod mem_table;
+pub mod merge_operator;
 pub mod mvcc;
+pub mod range_tombstone;
 pub mod table;
+pub mod value;
 pub mod wal;
 
 #[cfg(test)]
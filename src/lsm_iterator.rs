@@ -1,14 +1,21 @@
+use std::collections::HashMap;
 use std::ops::Bound;
+use std::sync::Arc;
 
 use anyhow::{bail, Result};
 use bytes::Bytes;
 
+use crate::blob::{read_blob, BlobFile};
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
-use crate::iterators::StorageIterator;
+use crate::iterators::{Direction, StorageIterator};
+use crate::key::{KeySlice, TS_RANGE_BEGIN};
 use crate::mem_table::MemTableIterator;
+use crate::merge_operator::{full_merge, MergeOperator};
+use crate::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
 use crate::table::SsTableIterator;
+use crate::value::{now_millis, Value};
 
 /// Represents the internal type for an LSM iterator. This type will be changed across the tutorial for multiple times.
 type LsmIteratorInner = TwoMergeIterator<
@@ -18,71 +25,296 @@ type LsmIteratorInner = TwoMergeIterator<
 
 pub struct LsmIterator {
     inner: LsmIteratorInner,
+    start_bound: Bound<Bytes>,
     end_bound: Bound<Bytes>,
     is_valid: bool,
     read_ts: u64,
     prev_key: Vec<u8>,
+    /// The value of the current key when moving backward, when it is merged from multiple versions
+    /// or when it is read from a blob file. When moving forward, the inner iterator may have moved
+    /// past the versions of the current key.
+    prev_value: Vec<u8>,
+    /// Whether the current value is in `prev_value` when moving forward.
+    buffered: bool,
+    direction: Direction,
+    /// Range tombstones visible at `read_ts`.
+    range_tombstones: FragmentedRangeTombstones,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// The time at which expiration is checked, so that the iterator sees a consistent view.
+    now: u64,
+    /// The blob files that the values in SSTs may point to.
+    blob_files: HashMap<usize, Arc<BlobFile>>,
 }
 
 impl LsmIterator {
     pub(crate) fn new(
         iter: LsmIteratorInner,
+        start_bound: Bound<Bytes>,
         end_bound: Bound<Bytes>,
         read_ts: u64,
+        range_tombstones: Vec<RangeTombstone>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        blob_files: HashMap<usize, Arc<BlobFile>>,
     ) -> Result<Self> {
-        let mut iter = Self {
-            is_valid: iter.is_valid(),
+        let mut iter = Self::new_inner(
+            iter,
+            start_bound,
+            end_bound,
+            read_ts,
+            range_tombstones,
+            merge_operator,
+            blob_files,
+        );
+        iter.move_to_first_key()?;
+        Ok(iter)
+    }
+
+    /// Create an iterator from an inner iterator that is positioned at the last key within the end
+    /// bound. The iterator yields keys in descending order using `prev`.
+    pub(crate) fn new_reverse(
+        iter: LsmIteratorInner,
+        start_bound: Bound<Bytes>,
+        end_bound: Bound<Bytes>,
+        read_ts: u64,
+        range_tombstones: Vec<RangeTombstone>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        blob_files: HashMap<usize, Arc<BlobFile>>,
+    ) -> Result<Self> {
+        let mut iter = Self::new_inner(
+            iter,
+            start_bound,
+            end_bound,
+            read_ts,
+            range_tombstones,
+            merge_operator,
+            blob_files,
+        );
+        iter.direction = Direction::Backward;
+        // The inner iterator may still yield some versions of an excluded end key.
+        while iter.inner.is_valid() && !iter.before_end(iter.inner.key().key_ref()) {
+            iter.inner.prev()?;
+        }
+        iter.move_to_key_backward()?;
+        Ok(iter)
+    }
+
+    fn new_inner(
+        iter: LsmIteratorInner,
+        start_bound: Bound<Bytes>,
+        end_bound: Bound<Bytes>,
+        read_ts: u64,
+        range_tombstones: Vec<RangeTombstone>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        blob_files: HashMap<usize, Arc<BlobFile>>,
+    ) -> Self {
+        Self {
+            is_valid: false,
             inner: iter,
+            start_bound,
             end_bound,
             read_ts,
             prev_key: Vec::new(),
-        };
-        iter.move_to_key()?;
-        Ok(iter)
+            prev_value: Vec::new(),
+            buffered: false,
+            direction: Direction::Forward,
+            range_tombstones: FragmentedRangeTombstones::new(&range_tombstones),
+            merge_operator,
+            now: now_millis(),
+            blob_files,
+        }
     }
 
-    fn next_inner(&mut self) -> Result<()> {
-        self.inner.next()?;
-        if !self.inner.is_valid() {
-            self.is_valid = false;
-            return Ok(());
+    /// Check if the version of `key` at `ts` is deleted by a range tombstone.
+    fn is_range_deleted(&self, key: &[u8], ts: u64) -> bool {
+        self.range_tombstones.covers(key, ts)
+    }
+
+    fn after_start(&self, key: &[u8]) -> bool {
+        match self.start_bound.as_ref() {
+            Bound::Unbounded => true,
+            Bound::Included(start) => key >= start.as_ref(),
+            Bound::Excluded(start) => key > start.as_ref(),
         }
+    }
+
+    fn before_end(&self, key: &[u8]) -> bool {
         match self.end_bound.as_ref() {
-            Bound::Unbounded => {}
-            Bound::Included(key) => self.is_valid = self.inner.key().key_ref() <= key.as_ref(),
-            Bound::Excluded(key) => self.is_valid = self.inner.key().key_ref() < key.as_ref(),
+            Bound::Unbounded => true,
+            Bound::Included(end) => key <= end.as_ref(),
+            Bound::Excluded(end) => key < end.as_ref(),
         }
+    }
+
+    /// Check if the inner iterator is valid and its key is within the bounds.
+    fn inner_in_range(&self) -> bool {
+        self.inner.is_valid()
+            && self.after_start(self.inner.key().key_ref())
+            && self.before_end(self.inner.key().key_ref())
+    }
+
+    fn next_inner(&mut self) -> Result<()> {
+        self.inner.next()?;
+        self.is_valid = self.inner_in_range();
         Ok(())
     }
 
+    /// Move forward to the first key visible at `read_ts` once the inner iterator has been moved to
+    /// the start of the range. The inner iterator may still yield some versions of an excluded start
+    /// key, which are skipped.
+    fn move_to_first_key(&mut self) -> Result<()> {
+        self.direction = Direction::Forward;
+        while self.inner.is_valid() && !self.after_start(self.inner.key().key_ref()) {
+            self.inner.next()?;
+        }
+        self.is_valid = self.inner_in_range();
+        self.prev_key.clear();
+        self.move_to_key()
+    }
+
     fn move_to_key(&mut self) -> Result<()> {
+        self.buffered = false;
         loop {
-            while self.inner.is_valid() && self.inner.key().key_ref() == self.prev_key {
+            while self.is_valid && self.inner.key().key_ref() == self.prev_key {
                 self.next_inner()?;
             }
-            if !self.inner.is_valid() {
+            if !self.is_valid {
                 break;
             }
             self.prev_key.clear();
             self.prev_key.extend(self.inner.key().key_ref());
-            while self.inner.is_valid()
+            while self.is_valid
                 && self.inner.key().key_ref() == self.prev_key
                 && self.inner.key().ts() > self.read_ts
             {
                 self.next_inner()?;
             }
-            if !self.inner.is_valid() {
+            if !self.is_valid {
                 break;
             }
             if self.inner.key().key_ref() != self.prev_key {
                 continue;
             }
-            if !self.inner.value().is_empty() {
-                break;
+            if self.is_range_deleted(self.inner.key().key_ref(), self.inner.key().ts()) {
+                continue;
+            }
+            match Value::decode_at(self.inner.value(), self.now)? {
+                Value::Delete => continue,
+                Value::Merge(_) => {
+                    if self.merge_forward()? {
+                        self.is_valid = true;
+                        self.buffered = true;
+                        break;
+                    }
+                    continue;
+                }
+                Value::Blob(pointer) => {
+                    self.prev_value = read_blob(&self.blob_files, pointer)?;
+                    self.buffered = true;
+                    break;
+                }
+                Value::Put(_) | Value::PutWithTtl(..) => break,
             }
         }
         Ok(())
     }
+
+    /// Apply the merge operands from the current version of the inner iterator to the earlier
+    /// versions, until a version that is not a merge operand. The inner iterator is moved past the
+    /// merge operands. Returns whether the merged value exists.
+    fn merge_forward(&mut self) -> Result<bool> {
+        // The payloads of the merge operands, from the latest to the earliest.
+        let mut payloads = Vec::new();
+        let mut existing_value = None;
+        while let Value::Merge(payload) = Value::decode(self.inner.value())? {
+            payloads.push(payload.to_vec());
+            self.next_inner()?;
+            if !self.inner.is_valid()
+                || self.inner.key().key_ref() != self.prev_key
+                || self.is_range_deleted(&self.prev_key, self.inner.key().ts())
+            {
+                break;
+            }
+            match Value::decode_at(self.inner.value(), self.now)? {
+                Value::Put(value) | Value::PutWithTtl(_, value) => {
+                    existing_value = Some(value.to_vec());
+                }
+                Value::Blob(pointer) => {
+                    existing_value = Some(read_blob(&self.blob_files, pointer)?);
+                }
+                Value::Delete | Value::Merge(_) => {}
+            }
+        }
+        payloads.reverse();
+        self.prev_value = full_merge(
+            self.merge_operator.as_ref(),
+            &self.prev_key,
+            existing_value.as_deref(),
+            &payloads,
+        )?;
+        Ok(!self.prev_value.is_empty())
+    }
+
+    /// Move backward to the closest key that is visible at `read_ts`. The inner iterator should be
+    /// positioned at the earliest version of a key, and will be positioned before all versions of the
+    /// key once the key is found.
+    fn move_to_key_backward(&mut self) -> Result<()> {
+        loop {
+            if !self.inner_in_range() {
+                self.is_valid = false;
+                return Ok(());
+            }
+            self.prev_key.clear();
+            self.prev_key.extend(self.inner.key().key_ref());
+            let mut exists = false;
+            // The payloads of the merge operands after the latest version that is not a merge
+            // operand, from the earliest to the latest.
+            let mut payloads = Vec::new();
+            // Versions are visited from the earliest to the latest, so the last version that is
+            // visible at `read_ts` is the one to return.
+            while self.inner.is_valid() && self.inner.key().key_ref() == self.prev_key {
+                let ts = self.inner.key().ts();
+                if ts <= self.read_ts {
+                    let value = if self.is_range_deleted(&self.prev_key, ts) {
+                        Value::Delete
+                    } else {
+                        Value::decode_at(self.inner.value(), self.now)?
+                    };
+                    match value {
+                        Value::Delete => {
+                            exists = false;
+                            payloads.clear();
+                        }
+                        Value::Put(value) | Value::PutWithTtl(_, value) => {
+                            self.prev_value.clear();
+                            self.prev_value.extend(value);
+                            exists = true;
+                            payloads.clear();
+                        }
+                        Value::Blob(pointer) => {
+                            self.prev_value = read_blob(&self.blob_files, pointer)?;
+                            exists = true;
+                            payloads.clear();
+                        }
+                        Value::Merge(payload) => payloads.push(payload.to_vec()),
+                    }
+                }
+                self.inner.prev()?;
+            }
+            if !payloads.is_empty() {
+                self.prev_value = full_merge(
+                    self.merge_operator.as_ref(),
+                    &self.prev_key,
+                    exists.then_some(&self.prev_value[..]),
+                    &payloads,
+                )?;
+                exists = !self.prev_value.is_empty();
+            }
+            if exists {
+                self.is_valid = true;
+                return Ok(());
+            }
+        }
+    }
 }
 
 impl StorageIterator for LsmIterator {
@@ -93,19 +325,86 @@ impl StorageIterator for LsmIterator {
     }
 
     fn key(&self) -> &[u8] {
-        self.inner.key().key_ref()
+        &self.prev_key
     }
 
     fn value(&self) -> &[u8] {
-        self.inner.value()
+        if self.direction == Direction::Backward || self.buffered {
+            &self.prev_value
+        } else {
+            Value::put_value(self.inner.value())
+        }
     }
 
     fn next(&mut self) -> Result<()> {
-        self.next_inner()?;
+        if self.direction == Direction::Backward {
+            self.direction = Direction::Forward;
+            if self.is_valid {
+                // The inner iterator is before the current key. Move it to the current key, which
+                // will then be skipped by `move_to_key`.
+                self.next_inner()?;
+            } else {
+                // The inner iterator is before the start bound. Move it into the range.
+                self.inner.next()?;
+                return self.move_to_first_key();
+            }
+        } else if !self.is_valid {
+            return Ok(());
+        } else {
+            // The inner iterator is at or after a version of the current key, which will be
+            // skipped by `move_to_key`.
+            self.is_valid = self.inner_in_range();
+        }
         self.move_to_key()?;
         Ok(())
     }
 
+    fn prev(&mut self) -> Result<()> {
+        if self.direction == Direction::Forward {
+            self.direction = Direction::Backward;
+            if self.is_valid {
+                // The inner iterator is at or after a version of the current key. Move it before
+                // all versions of the current key.
+                if !self.inner.is_valid() {
+                    // A merged value has used the last versions of the inner iterator.
+                    self.inner.prev()?;
+                }
+                while self.inner.is_valid() && self.inner.key().key_ref() >= &self.prev_key[..] {
+                    self.inner.prev()?;
+                }
+            } else {
+                // The inner iterator is after the end bound. Move it back into the range.
+                self.inner.prev()?;
+                while self.inner.is_valid() && !self.before_end(self.inner.key().key_ref()) {
+                    self.inner.prev()?;
+                }
+            }
+        } else if !self.is_valid {
+            return Ok(());
+        }
+        self.move_to_key_backward()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        match self.start_bound.as_ref() {
+            Bound::Included(start) | Bound::Excluded(start) => self
+                .inner
+                .seek_to_key(KeySlice::from_slice(start, TS_RANGE_BEGIN))?,
+            Bound::Unbounded => self.inner.seek_to_first()?,
+        }
+        self.move_to_first_key()
+    }
+
+    fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
+        // Never move before the start bound of the scan.
+        if !self.after_start(key) {
+            return self.seek_to_first();
+        }
+        self.inner
+            .seek_to_key(KeySlice::from_slice(key, TS_RANGE_BEGIN))?;
+        self.move_to_first_key()
+    }
+
     fn num_active_iterators(&self) -> usize {
         self.inner.num_active_iterators()
     }
@@ -114,9 +413,13 @@ impl StorageIterator for LsmIterator {
 /// A wrapper around existing iterator, will prevent users from calling `next` when the iterator is
 /// invalid. If an iterator is already invalid, `next` does not do anything. If `next` returns an error,
 /// `is_valid` should return false, and `next` should always return an error.
+///
+/// An iterator that became invalid by moving backward can still be moved forward with `next`, and an
+/// iterator that became invalid by moving forward can still be moved backward with `prev`.
 pub struct FusedIterator<I: StorageIterator> {
     iter: I,
     has_errored: bool,
+    direction: Direction,
 }
 
 impl<I: StorageIterator> FusedIterator<I> {
@@ -124,12 +427,16 @@ impl<I: StorageIterator> FusedIterator<I> {
         Self {
             iter,
             has_errored: false,
+            direction: Direction::Forward,
         }
     }
 }
 
 impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
-    type KeyType<'a> = I::KeyType<'a> where Self: 'a;
+    type KeyType<'a>
+        = I::KeyType<'a>
+    where
+        Self: 'a;
 
     fn is_valid(&self) -> bool {
         !self.has_errored && self.iter.is_valid()
@@ -150,11 +457,12 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
     }
 
     fn next(&mut self) -> Result<()> {
-        // only move when the iterator is valid and not errored
+        // only move when the iterator is not errored, and is either valid or exhausted backward
         if self.has_errored {
             bail!("the iterator is tainted");
         }
-        if self.iter.is_valid() {
+        if self.iter.is_valid() || self.direction == Direction::Backward {
+            self.direction = Direction::Forward;
             if let Err(e) = self.iter.next() {
                 self.has_errored = true;
                 return Err(e);
@@ -163,6 +471,44 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
         Ok(())
     }
 
+    fn prev(&mut self) -> Result<()> {
+        if self.has_errored {
+            bail!("the iterator is tainted");
+        }
+        if self.iter.is_valid() || self.direction == Direction::Forward {
+            self.direction = Direction::Backward;
+            if let Err(e) = self.iter.prev() {
+                self.has_errored = true;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        if self.has_errored {
+            bail!("the iterator is tainted");
+        }
+        self.direction = Direction::Forward;
+        if let Err(e) = self.iter.seek_to_first() {
+            self.has_errored = true;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn seek_to_key(&mut self, key: Self::KeyType<'_>) -> Result<()> {
+        if self.has_errored {
+            bail!("the iterator is tainted");
+        }
+        self.direction = Direction::Forward;
+        if let Err(e) = self.iter.seek_to_key(key) {
+            self.has_errored = true;
+            return Err(e);
+        }
+        Ok(())
+    }
+
     fn num_active_iterators(&self) -> usize {
         self.iter.num_active_iterators()
     }
@@ -1,30 +1,45 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
 use parking_lot::{Mutex, MutexGuard, RwLock};
 
+use crate::blob::{BlobFile, BlobFileBuilder, BlobOptions};
 use crate::block::Block;
+use crate::column_family::{
+    ColumnFamily, ColumnFamilyOptions, DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_ID,
+};
 use crate::compact::{
-    CompactionController, CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
-    SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionController,
+    CompactionOptions, CompactionTask, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
 };
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
-use crate::iterators::StorageIterator;
+use crate::iterators::{Direction, StorageIterator};
 use crate::key::{self, KeySlice};
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
-use crate::manifest::{Manifest, ManifestRecord};
+use crate::manifest::{
+    ColumnFamilySnapshot, Manifest, ManifestRecord, ManifestSnapshot, SstEdit, SstMeta,
+};
 use crate::mem_table::{map_bound, map_key_bound_plus_ts, MemTable};
-use crate::mvcc::txn::{Transaction, TxnIterator};
-use crate::mvcc::LsmMvccInner;
-use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::merge_operator::{merge_value, MergeOperator};
+use crate::mvcc::conflict::KeySet;
+use crate::mvcc::snapshot::Snapshot;
+use crate::mvcc::txn::{txn_write_batch, Transaction, TxnError, TxnIterator};
+use crate::mvcc::{
+    hlc_ts, CommittedTxnData, HistoryRetention, IsolationLevel, LsmMvccInner, PreparedTxn,
+};
+use crate::range_tombstone::RangeTombstone;
+use crate::table::{CompressionOptions, FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::value::{now_millis, Value};
+use crate::wal::{BatchRecord, TxnRecord, Wal, WalRecoveryMode};
 
 pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 
@@ -42,16 +57,36 @@ pub struct LsmStorageState {
     pub levels: Vec<(usize, Vec<usize>)>,
     /// SST objects.
     pub sstables: HashMap<usize, Arc<SsTable>>,
+    /// Blob files by the id in the pointers to them.
+    pub blob_files: HashMap<usize, Arc<BlobFile>>,
+    /// The size of the records in each blob file that are no longer referenced by SSTs.
+    pub blob_garbage: HashMap<usize, u64>,
 }
 
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
     Put(T, T),
     Del(T),
+    /// Delete all keys in `[start, end)`. Keys written in the same batch are not deleted.
+    DelRange(T, T),
+    /// Apply a merge operand to the key with the merge operator in `LsmStorageOptions`.
+    Merge(T, T),
+    /// Put a key-value pair that expires after the given duration.
+    PutWithTtl(T, T, Duration),
+}
+
+/// The durability of a write. Concurrent writes with `sync` are synced together, so that the WAL
+/// is synced once for the whole group.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Sync the WAL before the write returns, so that the write survives a crash.
+    pub sync: bool,
+    /// Write only to the memtables. The write is lost on a crash before the memtables are flushed.
+    pub disable_wal: bool,
 }
 
 impl LsmStorageState {
-    fn create(options: &LsmStorageOptions) -> Self {
-        let levels = match &options.compaction_options {
+    pub(crate) fn create(compaction_options: &CompactionOptions) -> Self {
+        let levels = match compaction_options {
             CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
             | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => (1
                 ..=*max_levels)
@@ -66,6 +101,32 @@ impl LsmStorageState {
             l0_sstables: Vec::new(),
             levels,
             sstables: Default::default(),
+            blob_files: Default::default(),
+            blob_garbage: Default::default(),
+        }
+    }
+
+    /// The SSTs in L0 and in the levels, with the level as in `SstEdit`.
+    fn sst_levels(&self) -> impl Iterator<Item = (Option<usize>, usize)> + '_ {
+        self.l0_sstables.iter().map(|sst_id| (None, *sst_id)).chain(
+            self.levels
+                .iter()
+                .flat_map(|(level, ssts)| ssts.iter().map(move |sst_id| (Some(*level), *sst_id))),
+        )
+    }
+
+    /// The SSTs removed from the levels of `old` and added to the ones of this state.
+    pub(crate) fn sst_edit(&self, old: &Self, column_family_id: usize) -> SstEdit {
+        let old_ssts = old.sst_levels().collect::<HashSet<_>>();
+        let new_ssts = self.sst_levels().collect::<HashSet<_>>();
+        SstEdit {
+            column_family_id,
+            removed: old.sst_levels().filter(|x| !new_ssts.contains(x)).collect(),
+            added: self
+                .sst_levels()
+                .filter(|x| !old_ssts.contains(x))
+                .map(|(level, sst_id)| (level, SstMeta::new(&self.sstables[&sst_id])))
+                .collect(),
         }
     }
 }
@@ -81,9 +142,44 @@ pub struct LsmStorageOptions {
     pub compaction_options: CompactionOptions,
     pub enable_wal: bool,
     pub serializable: bool,
+    /// Combines the operands written with `WriteBatchRecord::Merge`.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// The codecs of the data blocks in L0, the middle levels and the bottom level.
+    pub compression: CompressionOptions,
+    /// Moves large values into blob files if set.
+    pub blob: Option<BlobOptions>,
+    /// Keeps the history of keys for `get_at` and `scan_at`.
+    pub history_retention: HistoryRetention,
+    /// The maximum total size of the keys in the read set or the write set of a transaction.
+    /// Larger sets fall back to key hashes, which may cause false conflicts.
+    pub max_txn_key_set_size: usize,
+    /// How long a transaction waits for a key lock before giving up.
+    pub lock_timeout: Duration,
+    /// Take the commit timestamps from a hybrid logical clock, so that they are the wall-clock time
+    /// of the commits, see `mvcc::hlc_ts`.
+    pub hybrid_clock: bool,
+    /// How to recover the WALs with corrupted records.
+    pub wal_recovery_mode: WalRecoveryMode,
+    /// The size of a WAL segment file, after which the records go to a new segment.
+    pub wal_segment_size: usize,
+    /// The size of the manifest, after which it is rotated into a new one that starts with a
+    /// snapshot of the state.
+    pub max_manifest_size: usize,
+    /// Move the files that the recovered state does not reference into the `quarantine` directory
+    /// when the DB is opened, instead of removing them.
+    pub quarantine_orphan_files: bool,
 }
 
 impl LsmStorageOptions {
+    /// The isolation level of the transactions created with `new_txn`.
+    pub fn isolation(&self) -> IsolationLevel {
+        if self.serializable {
+            IsolationLevel::Serializable
+        } else {
+            IsolationLevel::SnapshotReads
+        }
+    }
+
     pub fn default_for_week1_test() -> Self {
         Self {
             block_size: 4096,
@@ -92,6 +188,17 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 50,
             serializable: false,
+            merge_operator: None,
+            compression: CompressionOptions::default(),
+            blob: None,
+            history_retention: HistoryRetention::default(),
+            max_txn_key_set_size: 1 << 20,
+            lock_timeout: Duration::from_secs(5),
+            hybrid_clock: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_segment_size: 64 << 20,
+            max_manifest_size: 4 << 20,
+            quarantine_orphan_files: false,
         }
     }
 
@@ -103,6 +210,17 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            merge_operator: None,
+            compression: CompressionOptions::default(),
+            blob: None,
+            history_retention: HistoryRetention::default(),
+            max_txn_key_set_size: 1 << 20,
+            lock_timeout: Duration::from_secs(5),
+            hybrid_clock: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_segment_size: 64 << 20,
+            max_manifest_size: 4 << 20,
+            quarantine_orphan_files: false,
         }
     }
 
@@ -114,6 +232,17 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            merge_operator: None,
+            compression: CompressionOptions::default(),
+            blob: None,
+            history_retention: HistoryRetention::default(),
+            max_txn_key_set_size: 1 << 20,
+            lock_timeout: Duration::from_secs(5),
+            hybrid_clock: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_segment_size: 64 << 20,
+            max_manifest_size: 4 << 20,
+            quarantine_orphan_files: false,
         }
     }
 }
@@ -149,6 +278,74 @@ fn key_within(user_key: &[u8], table_begin: KeySlice, table_end: KeySlice) -> bo
     table_begin.key_ref() <= user_key && user_key <= table_end.key_ref()
 }
 
+/// Create an iterator over `tables` positioned at the first key in the range when moving forward,
+/// or at the last one when moving backward. `seek_to_key` positions it at the first key >= a key,
+/// and `seek_to_end` at the first or the last key of the tables.
+fn seek_to_range<T, I>(
+    tables: T,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    direction: Direction,
+    seek_to_key: fn(T, KeySlice) -> Result<I>,
+    seek_to_end: fn(T) -> Result<I>,
+) -> Result<I>
+where
+    I: for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
+{
+    let iter = match (direction, lower, upper) {
+        (Direction::Forward, Bound::Included(key), _) => {
+            seek_to_key(tables, KeySlice::from_slice(key, key::TS_RANGE_BEGIN))?
+        }
+        (Direction::Forward, Bound::Excluded(key), _) => {
+            let mut iter = seek_to_key(tables, KeySlice::from_slice(key, key::TS_RANGE_BEGIN))?;
+            while iter.is_valid() && iter.key().key_ref() == key {
+                iter.next()?;
+            }
+            iter
+        }
+        (Direction::Backward, _, Bound::Included(key)) => {
+            let mut iter = seek_to_key(tables, KeySlice::from_slice(key, key::TS_RANGE_END))?;
+            if !iter.is_valid() || iter.key().key_ref() > key {
+                iter.prev()?;
+            }
+            iter
+        }
+        (Direction::Backward, _, Bound::Excluded(key)) => {
+            let mut iter = seek_to_key(tables, KeySlice::from_slice(key, key::TS_RANGE_BEGIN))?;
+            iter.prev()?;
+            iter
+        }
+        (Direction::Forward, Bound::Unbounded, _) | (Direction::Backward, _, Bound::Unbounded) => {
+            seek_to_end(tables)?
+        }
+    };
+    Ok(iter)
+}
+
+/// Collect the range tombstones that are visible at `read_ts` and may delete keys in the range.
+/// Range tombstones are not bound to the key range of an SST, so all SSTs are checked, but an SST
+/// is skipped if the span of its range tombstones does not overlap the range.
+fn collect_range_tombstones(
+    snapshot: &LsmStorageState,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    read_ts: u64,
+) -> Vec<RangeTombstone> {
+    let mut range_tombstones = Vec::new();
+    for memtable in std::iter::once(&snapshot.memtable).chain(snapshot.imm_memtables.iter()) {
+        range_tombstones.extend(memtable.range_tombstones());
+    }
+    range_tombstones.retain(|x| x.ts <= read_ts && x.overlaps(lower, upper));
+    for table in snapshot.sstables.values() {
+        range_tombstones.extend(
+            table
+                .range_tombstones_within(lower, upper, read_ts)
+                .cloned(),
+        );
+    }
+    range_tombstones
+}
+
 #[derive(Clone, Debug)]
 pub enum CompactionFilter {
     Prefix(Bytes),
@@ -156,16 +353,21 @@ pub enum CompactionFilter {
 
 /// The storage interface of the LSM tree.
 pub(crate) struct LsmStorageInner {
+    /// The state of the default column family.
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
     pub(crate) state_lock: Mutex<()>,
     path: PathBuf,
     pub(crate) block_cache: Arc<BlockCache>,
     next_sst_id: AtomicUsize,
     pub(crate) options: Arc<LsmStorageOptions>,
-    pub(crate) compaction_controller: CompactionController,
+    /// All column families by name, including the default one.
+    pub(crate) column_families: RwLock<HashMap<String, Arc<ColumnFamily>>>,
+    next_column_family_id: AtomicUsize,
     pub(crate) manifest: Option<Manifest>,
     pub(crate) mvcc: Option<LsmMvccInner>,
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    /// The names of the orphan files removed or quarantined when the storage is opened.
+    orphan_files: Vec<String>,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
@@ -214,11 +416,10 @@ impl MiniLsm {
         }
 
         // create memtable and skip updating manifest
-        if !self.inner.state.read().memtable.is_empty() {
+        if !self.inner.memtables_are_empty() {
+            let write_lock = self.inner.mvcc().write_lock.lock();
             self.inner
-                .freeze_memtable_with_memtable(Arc::new(MemTable::create(
-                    self.inner.next_sst_id(),
-                )))?;
+                .freeze_memtables(&write_lock, self.inner.next_sst_id())?;
         }
 
         while {
@@ -261,31 +462,176 @@ impl MiniLsm {
         self.inner.write_batch(batch)
     }
 
+    pub fn write_batch_with_options<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.inner.write_batch_with_options(batch, options)
+    }
+
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
         self.inner.put(key, value)
     }
 
+    /// Put a key-value pair that expires after `ttl`.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.inner.put_with_ttl(key, value, ttl)
+    }
+
     pub fn delete(&self, key: &[u8]) -> Result<()> {
         self.inner.delete(key)
     }
 
+    /// Delete all keys in `[start, end)`.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.inner.delete_range(start, end)
+    }
+
+    /// Apply a merge operand to a key without reading it.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.inner.merge(key, operand)
+    }
+
     pub fn sync(&self) -> Result<()> {
         self.inner.sync()
     }
 
+    /// Create a column family with its own memtables, SSTs and compaction.
+    pub fn create_column_family(&self, name: &str, options: ColumnFamilyOptions) -> Result<()> {
+        self.inner.create_column_family(name, options)
+    }
+
+    /// Drop a column family and delete its SSTs. The default column family cannot be dropped.
+    pub fn drop_column_family(&self, name: &str) -> Result<()> {
+        self.inner.drop_column_family(name)
+    }
+
+    pub fn list_column_families(&self) -> Vec<String> {
+        self.inner.list_column_families()
+    }
+
+    pub fn get_cf(&self, column_family: &str, key: &[u8]) -> Result<Option<Bytes>> {
+        self.inner.get_cf(column_family, key)
+    }
+
+    /// Write a batch of records into their column families atomically.
+    pub fn write_batch_cf<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&str, WriteBatchRecord<T>)],
+    ) -> Result<()> {
+        self.inner.write_batch_cf(batch)
+    }
+
+    pub fn put_cf(&self, column_family: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.inner
+            .write_batch_cf(&[(column_family, WriteBatchRecord::Put(key, value))])
+    }
+
+    pub fn delete_cf(&self, column_family: &str, key: &[u8]) -> Result<()> {
+        self.inner
+            .write_batch_cf(&[(column_family, WriteBatchRecord::Del(key))])
+    }
+
+    pub fn scan_cf(
+        &self,
+        column_family: &str,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<TxnIterator> {
+        self.inner.scan_cf(column_family, lower, upper)
+    }
+
     pub fn new_txn(&self) -> Result<Arc<Transaction>> {
         self.inner.new_txn()
     }
 
+    /// Create a transaction with an isolation level other than the one in the options.
+    pub fn new_txn_with_isolation(&self, isolation: IsolationLevel) -> Result<Arc<Transaction>> {
+        self.inner.new_txn_with_isolation(isolation)
+    }
+
+    /// Create a pessimistic transaction, which locks the keys it writes when they are written and
+    /// the keys read with `get_for_update`, so that it waits for other transactions instead of
+    /// aborting.
+    pub fn new_pessimistic_txn(&self) -> Result<Arc<Transaction>> {
+        self.inner.new_pessimistic_txn()
+    }
+
+    /// The ids of the transactions prepared with `Transaction::prepare` and not resolved yet,
+    /// including the ones recovered from the WAL.
+    pub fn prepared_txns(&self) -> Vec<String> {
+        self.inner.prepared_txns()
+    }
+
+    pub fn commit_prepared(&self, xid: &str) -> Result<()> {
+        self.inner.commit_prepared(xid)
+    }
+
+    pub fn rollback_prepared(&self, xid: &str) -> Result<()> {
+        self.inner.rollback_prepared(xid)
+    }
+
+    /// Create a read-only snapshot of the storage, which is lighter than a transaction.
+    pub fn snapshot(&self) -> Snapshot {
+        self.inner.snapshot()
+    }
+
+    /// The names of the files left by a crash that are removed, or moved into the `quarantine`
+    /// directory, when the storage is opened.
+    pub fn orphan_files(&self) -> &[String] {
+        self.inner.orphan_files()
+    }
+
+    /// Create a read-only snapshot at a past commit ts in the history retention window.
+    pub fn snapshot_at(&self, ts: u64) -> Result<Snapshot> {
+        self.inner.snapshot_at(ts)
+    }
+
+    /// Create a read-only snapshot at a past wall-clock time, with the commit timestamps taken from
+    /// the hybrid clock.
+    pub fn snapshot_at_time(&self, time: SystemTime) -> Result<Snapshot> {
+        if !self.inner.options.hybrid_clock {
+            bail!("reading at a time requires the hybrid clock");
+        }
+        let millis = time.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        // The last ts in the millisecond, which may be ahead of the latest commit.
+        let ts = (hlc_ts(millis + 1) - 1).min(self.inner.mvcc().latest_commit_ts());
+        self.inner.snapshot_at(ts)
+    }
+
+    /// Get a key as it was at a past commit ts.
+    pub fn get_at(&self, key: &[u8], ts: u64) -> Result<Option<Bytes>> {
+        self.inner.snapshot_at(ts)?.get(key)
+    }
+
+    /// Scan a range of keys as they were at a past commit ts.
+    pub fn scan_at(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        ts: u64,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.inner.snapshot_at(ts)?.scan(lower, upper)
+    }
+
     pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
         self.inner.scan(lower, upper)
     }
 
+    /// Scan a range of keys in descending order, starting from the last key in the range. The
+    /// returned iterator can change direction at any time with `next` and `prev`.
+    pub fn scan_rev(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        self.inner.scan_rev(lower, upper)
+    }
+
     /// Only call this in test cases due to race conditions
     pub fn force_flush(&self) -> Result<()> {
-        if !self.inner.state.read().memtable.is_empty() {
+        if !self.inner.memtables_are_empty() {
+            // Freeze between write batches, so that each batch is in one memtable.
+            let write_lock = self.inner.mvcc().write_lock.lock();
             self.inner
-                .force_freeze_memtable(&self.inner.state_lock.lock())?;
+                .force_freeze_memtable(&write_lock, &self.inner.state_lock.lock())?;
         }
         if !self.inner.state.read().imm_memtables.is_empty() {
             self.inner.force_flush_next_imm_memtable()?;
@@ -315,139 +661,767 @@ impl LsmStorageInner {
     /// Start the storage engine by either loading an existing directory or creating a new one if the directory does
     /// not exist.
     pub(crate) fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
-        let mut state = LsmStorageState::create(&options);
         let path = path.as_ref();
         let mut next_sst_id = 1;
         let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache,
         let manifest;
 
-        let compaction_controller = match &options.compaction_options {
-            CompactionOptions::Leveled(options) => {
-                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Tiered(options) => {
-                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Simple(options) => CompactionController::Simple(
-                SimpleLeveledCompactionController::new(options.clone()),
+        let mut column_families = BTreeMap::new();
+        column_families.insert(
+            DEFAULT_COLUMN_FAMILY_ID,
+            ColumnFamily::new(
+                DEFAULT_COLUMN_FAMILY_ID,
+                DEFAULT_COLUMN_FAMILY.to_string(),
+                ColumnFamilyOptions::from_storage_options(&options),
+                LsmStorageState::create(&options.compaction_options),
             ),
-            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
-        };
+        );
+        let mut next_column_family_id = DEFAULT_COLUMN_FAMILY_ID + 1;
 
         if !path.exists() {
             std::fs::create_dir_all(path).context("failed to create DB dir")?;
         }
-        let manifest_path = path.join("MANIFEST");
+        // The latest commit ts found in the data, and the one recorded in the manifest.
         let mut last_commit_ts = 0;
-        if !manifest_path.exists() {
+        let mut recorded_commit_ts = 0;
+        let mut recovered_gc_watermark = 0;
+        // The writes and range deletions of the prepared transactions by id.
+        let mut prepared_txns = BTreeMap::new();
+        if !Manifest::exists(path) {
+            let state = column_families
+                .get_mut(&DEFAULT_COLUMN_FAMILY_ID)
+                .unwrap()
+                .state_mut();
             if options.enable_wal {
-                state.memtable = Arc::new(MemTable::create_with_wal(
+                let wal = Arc::new(Wal::create(path, options.wal_segment_size)?);
+                state.memtable = Arc::new(MemTable::create_with_shared_wal(
                     state.memtable.id(),
-                    Self::path_of_wal_static(path, state.memtable.id()),
-                )?);
+                    DEFAULT_COLUMN_FAMILY_ID,
+                    wal.clone(),
+                    wal.lsn(),
+                ));
             }
-            manifest = Manifest::create(&manifest_path).context("failed to create manifest")?;
-            manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            manifest = Manifest::create(path).context("failed to create manifest")?;
+            manifest.add_record_when_init(Self::new_memtable_record(&state.memtable))?;
         } else {
-            let (m, records) = Manifest::recover(&manifest_path)?;
+            let (m, records) = Manifest::recover(path)?;
             let mut memtables = BTreeSet::new();
+            // The first LSN in the shared WAL of each memtable that does not have a WAL file.
+            let mut memtable_lsns = HashMap::new();
+            // The id of the current version of each blob file by the column family id and the id in
+            // the pointers.
+            let mut blob_files = HashMap::new();
+            // The SSTs recorded with their key ranges and sizes, which are not in JSON manifests.
+            let mut sst_metas = HashMap::new();
             for record in records {
                 match record {
                     ManifestRecord::Flush(sst_id) => {
                         let res = memtables.remove(&sst_id);
                         assert!(res, "memtable not exist?");
-                        if compaction_controller.flush_to_l0() {
-                            state.l0_sstables.insert(0, sst_id);
-                        } else {
-                            state.levels.insert(0, (sst_id, vec![sst_id]));
-                        }
+                        let column_family =
+                            column_families.get_mut(&DEFAULT_COLUMN_FAMILY_ID).unwrap();
+                        Self::recover_flush(column_family, sst_id);
                         next_sst_id = next_sst_id.max(sst_id);
                     }
+                    ManifestRecord::FlushColumnFamilies(memtable_id, ssts) => {
+                        let res = memtables.remove(&memtable_id);
+                        assert!(res, "memtable not exist?");
+                        for (column_family_id, sst_id) in ssts {
+                            let column_family = column_families
+                                .get_mut(&column_family_id)
+                                .expect("column family not exist?");
+                            Self::recover_flush(column_family, sst_id);
+                            next_sst_id = next_sst_id.max(sst_id);
+                        }
+                        next_sst_id = next_sst_id.max(memtable_id);
+                    }
                     ManifestRecord::NewMemtable(x) => {
                         next_sst_id = next_sst_id.max(x);
                         memtables.insert(x);
                     }
+                    ManifestRecord::NewMemtableAtLsn(x, lsn) => {
+                        next_sst_id = next_sst_id.max(x);
+                        memtables.insert(x);
+                        memtable_lsns.insert(x, lsn);
+                    }
                     ManifestRecord::Compaction(task, output) => {
-                        let (new_state, _) =
-                            compaction_controller.apply_compaction_result(&state, &task, &output);
-                        // TODO: apply remove again
-                        state = new_state;
+                        let column_family =
+                            column_families.get_mut(&DEFAULT_COLUMN_FAMILY_ID).unwrap();
+                        Self::recover_compaction(column_family, &task, &output);
                         next_sst_id =
                             next_sst_id.max(output.iter().max().copied().unwrap_or_default());
                     }
+                    ManifestRecord::ColumnFamilyCompaction(column_family_id, task, output) => {
+                        let column_family = column_families
+                            .get_mut(&column_family_id)
+                            .expect("column family not exist?");
+                        Self::recover_compaction(column_family, &task, &output);
+                        next_sst_id =
+                            next_sst_id.max(output.iter().max().copied().unwrap_or_default());
+                    }
+                    ManifestRecord::FlushEdits(memtable_id, edits) => {
+                        let res = memtables.remove(&memtable_id);
+                        assert!(res, "memtable not exist?");
+                        for edit in edits {
+                            let column_family = column_families
+                                .get_mut(&edit.column_family_id)
+                                .expect("column family not exist?");
+                            next_sst_id = next_sst_id.max(Self::recover_edit(
+                                column_family,
+                                edit,
+                                &mut sst_metas,
+                            ));
+                        }
+                        next_sst_id = next_sst_id.max(memtable_id);
+                    }
+                    ManifestRecord::CompactionEdit(edit) => {
+                        let column_family = column_families
+                            .get_mut(&edit.column_family_id)
+                            .expect("column family not exist?");
+                        next_sst_id = next_sst_id.max(Self::recover_edit(
+                            column_family,
+                            edit,
+                            &mut sst_metas,
+                        ));
+                    }
+                    ManifestRecord::CreateColumnFamily(id, name, column_family_options) => {
+                        let state =
+                            LsmStorageState::create(&column_family_options.compaction_options);
+                        column_families.insert(
+                            id,
+                            ColumnFamily::new(id, name, column_family_options, state),
+                        );
+                        next_column_family_id = next_column_family_id.max(id + 1);
+                    }
+                    ManifestRecord::DropColumnFamily(id) => {
+                        let res = column_families.remove(&id);
+                        assert!(res.is_some(), "column family not exist?");
+                        blob_files.retain(|(column_family_id, _), _| *column_family_id != id);
+                    }
+                    ManifestRecord::NewBlobFile(column_family_id, blob_id) => {
+                        let column_family = column_families
+                            .get_mut(&column_family_id)
+                            .expect("column family not exist?");
+                        column_family.state_mut().blob_garbage.insert(blob_id, 0);
+                        blob_files.insert((column_family_id, blob_id), blob_id);
+                        next_sst_id = next_sst_id.max(blob_id);
+                    }
+                    ManifestRecord::BlobGarbage(column_family_id, garbage) => {
+                        let column_family = column_families
+                            .get_mut(&column_family_id)
+                            .expect("column family not exist?");
+                        let state = column_family.state_mut();
+                        for (blob_id, size) in garbage {
+                            if let Some(blob_garbage) = state.blob_garbage.get_mut(&blob_id) {
+                                *blob_garbage += size;
+                            }
+                        }
+                    }
+                    ManifestRecord::Timestamps(commit_ts, gc_watermark) => {
+                        recorded_commit_ts = recorded_commit_ts.max(commit_ts);
+                        recovered_gc_watermark = recovered_gc_watermark.max(gc_watermark);
+                    }
+                    ManifestRecord::GcBlobFile(column_family_id, blob_id, new_blob_id) => {
+                        let column_family = column_families
+                            .get_mut(&column_family_id)
+                            .expect("column family not exist?");
+                        let state = column_family.state_mut();
+                        match new_blob_id {
+                            Some(new_blob_id) => {
+                                state.blob_garbage.insert(blob_id, 0);
+                                blob_files.insert((column_family_id, blob_id), new_blob_id);
+                                next_sst_id = next_sst_id.max(new_blob_id);
+                            }
+                            None => {
+                                state.blob_garbage.remove(&blob_id);
+                                blob_files.remove(&(column_family_id, blob_id));
+                            }
+                        }
+                    }
+                    ManifestRecord::Snapshot(snapshot) => {
+                        for column_family in snapshot.column_families {
+                            let id = column_family.id;
+                            // The options of the default column family are taken from the storage
+                            // options.
+                            if id != DEFAULT_COLUMN_FAMILY_ID {
+                                let state = LsmStorageState::create(
+                                    &column_family.options.compaction_options,
+                                );
+                                column_families.insert(
+                                    id,
+                                    ColumnFamily::new(
+                                        id,
+                                        column_family.name,
+                                        column_family.options,
+                                        state,
+                                    ),
+                                );
+                            }
+                            let state = column_families.get_mut(&id).unwrap().state_mut();
+                            state.l0_sstables = column_family.l0_sstables;
+                            state.levels = column_family.levels;
+                            for sst in column_family.sstables {
+                                sst_metas.insert(sst.id, sst);
+                            }
+                            for (blob_id, file_id, garbage) in column_family.blob_files {
+                                state.blob_garbage.insert(blob_id, garbage);
+                                blob_files.insert((id, blob_id), file_id);
+                            }
+                        }
+                        for (id, lsn) in snapshot.memtables {
+                            memtables.insert(id);
+                            if let Some(lsn) = lsn {
+                                memtable_lsns.insert(id, lsn);
+                            }
+                        }
+                        next_sst_id = next_sst_id.max(snapshot.next_sst_id);
+                        next_column_family_id =
+                            next_column_family_id.max(snapshot.next_column_family_id);
+                        recorded_commit_ts = recorded_commit_ts.max(snapshot.commit_ts);
+                        recovered_gc_watermark = recovered_gc_watermark.max(snapshot.gc_watermark);
+                    }
                 }
             }
 
             let mut sst_cnt = 0;
             // recover SSTs
-            for table_id in state
-                .l0_sstables
-                .iter()
-                .chain(state.levels.iter().flat_map(|(_, files)| files))
-            {
-                let table_id = *table_id;
-                let sst = SsTable::open(
-                    table_id,
-                    Some(block_cache.clone()),
-                    FileObject::open(&Self::path_of_sst_static(path, table_id))
-                        .context("failed to open SST")?,
-                )?;
-                last_commit_ts = last_commit_ts.max(sst.max_ts());
-                state.sstables.insert(table_id, Arc::new(sst));
-                sst_cnt += 1;
+            for column_family in column_families.values_mut() {
+                let state = column_family.state_mut();
+                for table_id in state
+                    .l0_sstables
+                    .iter()
+                    .chain(state.levels.iter().flat_map(|(_, files)| files))
+                {
+                    let table_id = *table_id;
+                    let sst = SsTable::open(
+                        table_id,
+                        Some(block_cache.clone()),
+                        FileObject::open(&Self::path_of_sst_static(path, table_id))
+                            .context("failed to open SST")?,
+                    )?;
+                    if let Some(meta) = sst_metas.get(&table_id) {
+                        if meta.size != sst.table_size() {
+                            bail!(
+                                "SST {} has size {}, but {} in the manifest",
+                                table_id,
+                                sst.table_size(),
+                                meta.size
+                            );
+                        }
+                    }
+                    last_commit_ts = last_commit_ts.max(sst.max_ts());
+                    state.sstables.insert(table_id, Arc::new(sst));
+                    sst_cnt += 1;
+                }
             }
             println!("{} SSTs opened", sst_cnt);
 
+            // recover blob files
+            for ((column_family_id, blob_id), file_id) in blob_files {
+                let blob_file = BlobFile::open(
+                    file_id,
+                    FileObject::open(&Self::path_of_blob_static(path, file_id))
+                        .context("failed to open blob file")?,
+                )?;
+                column_families
+                    .get_mut(&column_family_id)
+                    .unwrap()
+                    .state_mut()
+                    .blob_files
+                    .insert(blob_id, Arc::new(blob_file));
+            }
+
             next_sst_id += 1;
 
+            // A manifest in an older format is rotated before any record is added to it.
+            if m.needs_upgrade() {
+                let snapshot = ManifestSnapshot {
+                    column_families: column_families
+                        .values()
+                        .map(Self::column_family_snapshot)
+                        .collect(),
+                    memtables: memtables
+                        .iter()
+                        .map(|id| (*id, memtable_lsns.get(id).copied()))
+                        .collect(),
+                    next_sst_id,
+                    next_column_family_id,
+                    commit_ts: last_commit_ts.max(recorded_commit_ts),
+                    gc_watermark: recovered_gc_watermark,
+                };
+                m.rotate_when_init(snapshot)?;
+                println!("manifest upgraded to the current format");
+            }
+
             // recover memtables
             if options.enable_wal {
+                let column_family_ids = column_families.keys().copied().collect::<Vec<_>>();
                 let mut wal_cnt = 0;
-                for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
-                    let max_ts = memtable
-                        .map
-                        .iter()
-                        .map(|x| x.key().ts())
+                let mut txn_records = Vec::new();
+                // Set if a WAL is recovered up to a corrupted record in point-in-time mode, and
+                // then the records in the later WALs are discarded.
+                let mut stopped = false;
+                let mut recovered_memtables = Vec::new();
+                // The memtables with their own WAL files are written before the WAL is shared, so
+                // they are older than the others.
+                for id in memtables
+                    .iter()
+                    .filter(|id| !memtable_lsns.contains_key(id))
+                {
+                    let wal_path = Self::path_of_wal_static(path, *id);
+                    if stopped {
+                        Wal::discard_records(&wal_path)?;
+                    }
+                    let (recovered, corrupted) = MemTable::recover_column_families_from_wal(
+                        *id,
+                        wal_path,
+                        &column_family_ids,
+                        &mut txn_records,
+                        options.wal_recovery_mode,
+                    )?;
+                    stopped |= corrupted;
+                    recovered_memtables.push((*id, recovered));
+                }
+                let shared_memtables = memtables
+                    .iter()
+                    .filter_map(|id| Some((*id, *memtable_lsns.get(id)?)))
+                    .collect::<Vec<_>>();
+                let mut recovered_shared = shared_memtables
+                    .iter()
+                    .map(|(_, lsn)| {
+                        let recovered = column_family_ids
+                            .iter()
+                            .map(|id| (*id, (Arc::new(SkipMap::new()), Vec::new())))
+                            .collect::<HashMap<_, _>>();
+                        (*lsn, recovered)
+                    })
+                    .collect::<Vec<_>>();
+                let wal = Arc::new(Wal::recover(
+                    path,
+                    options.wal_segment_size,
+                    &mut recovered_shared,
+                    &mut txn_records,
+                    options.wal_recovery_mode,
+                    stopped,
+                )?);
+                for ((id, lsn), (_, recovered)) in
+                    shared_memtables.into_iter().zip(recovered_shared)
+                {
+                    let recovered = recovered
+                        .into_iter()
+                        .map(|(column_family_id, recovered)| {
+                            let memtable = MemTable::from_recovered(
+                                id,
+                                column_family_id,
+                                recovered,
+                                Some(wal.clone()),
+                                lsn,
+                            );
+                            (column_family_id, memtable)
+                        })
+                        .collect::<HashMap<_, _>>();
+                    recovered_memtables.push((id, recovered));
+                }
+                for (id, recovered) in recovered_memtables {
+                    let max_ts = recovered
+                        .values()
+                        .flat_map(|memtable| {
+                            memtable
+                                .map
+                                .iter()
+                                .map(|x| x.key().ts())
+                                .chain(memtable.range_tombstones().iter().map(|x| x.ts))
+                                .collect::<Vec<_>>()
+                        })
                         .max()
                         .unwrap_or_default();
                     last_commit_ts = last_commit_ts.max(max_ts);
-                    if !memtable.is_empty() {
-                        state.imm_memtables.insert(0, Arc::new(memtable));
+                    // The memtables of all column families are kept to be flushed together.
+                    if recovered.values().any(|memtable| !memtable.is_empty()) {
+                        for (column_family_id, memtable) in recovered {
+                            column_families
+                                .get_mut(&column_family_id)
+                                .unwrap()
+                                .state_mut()
+                                .imm_memtables
+                                .insert(0, Arc::new(memtable));
+                        }
                         wal_cnt += 1;
+                    } else {
+                        // An empty memtable is recorded as flushed, so that it does not keep its
+                        // records in the WAL.
+                        m.add_record_when_init(ManifestRecord::FlushEdits(id, Vec::new()))?;
+                        let wal_path = Self::path_of_wal_static(path, id);
+                        if wal_path.exists() {
+                            std::fs::remove_file(wal_path)?;
+                        }
                     }
                 }
-                println!("{} WALs recovered", wal_cnt);
-                state.memtable = Arc::new(MemTable::create_with_wal(
-                    next_sst_id,
-                    Self::path_of_wal_static(path, next_sst_id),
-                )?);
+                println!("{} memtables recovered from WAL", wal_cnt);
+                // A prepared transaction is logged in every WAL until it is resolved. If it is
+                // committed but its writes are not recovered, it is still prepared.
+                let mut committed_txns = HashMap::new();
+                for record in txn_records {
+                    match record {
+                        TxnRecord::Prepare {
+                            xid,
+                            writes,
+                            range_deletes,
+                        } => {
+                            committed_txns.remove(&xid);
+                            prepared_txns.insert(xid, (writes, range_deletes));
+                        }
+                        TxnRecord::Commit { xid, commit_ts } => {
+                            if let Some(txn) = prepared_txns.remove(&xid) {
+                                committed_txns.insert(xid, (txn, commit_ts));
+                            }
+                        }
+                        TxnRecord::Rollback { xid } => {
+                            prepared_txns.remove(&xid);
+                        }
+                    }
+                }
+                for (xid, (txn, commit_ts)) in committed_txns {
+                    if commit_ts > last_commit_ts {
+                        prepared_txns.insert(xid, txn);
+                    }
+                }
+                println!("{} prepared transactions recovered", prepared_txns.len());
+                for column_family in column_families.values_mut() {
+                    column_family.state_mut().memtable =
+                        Arc::new(MemTable::create_with_shared_wal(
+                            next_sst_id,
+                            column_family.id,
+                            wal.clone(),
+                            wal.lsn(),
+                        ));
+                }
             } else {
-                state.memtable = Arc::new(MemTable::create(next_sst_id));
+                for column_family in column_families.values_mut() {
+                    column_family.state_mut().memtable = Arc::new(MemTable::create(next_sst_id));
+                }
             }
-            m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            m.add_record_when_init(Self::new_memtable_record(
+                &column_families[&DEFAULT_COLUMN_FAMILY_ID]
+                    .state
+                    .read()
+                    .memtable,
+            ))?;
             next_sst_id += 1;
             manifest = m;
         };
 
-        let storage = Self {
-            state: Arc::new(RwLock::new(Arc::new(state))),
+        let mut storage = Self {
+            state: column_families[&DEFAULT_COLUMN_FAMILY_ID].state.clone(),
             state_lock: Mutex::new(()),
             path: path.to_path_buf(),
             block_cache,
             next_sst_id: AtomicUsize::new(next_sst_id),
-            compaction_controller,
+            column_families: RwLock::new(
+                column_families
+                    .into_values()
+                    .map(|column_family| (column_family.name.clone(), Arc::new(column_family)))
+                    .collect(),
+            ),
+            next_column_family_id: AtomicUsize::new(next_column_family_id),
             manifest: Some(manifest),
+            mvcc: Some(LsmMvccInner::new(
+                last_commit_ts.max(recorded_commit_ts),
+                options.history_retention.clone(),
+                options.hybrid_clock,
+                recovered_gc_watermark,
+            )),
             options: options.into(),
-            mvcc: Some(LsmMvccInner::new(last_commit_ts)),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            orphan_files: Vec::new(),
         };
+        // The prepared transactions are logged again for the new memtables, as the records of the
+        // recovered ones are removed once flushed.
+        for (xid, (writes, range_deletes)) in prepared_txns {
+            let txn = PreparedTxn::new(
+                writes,
+                range_deletes,
+                storage.mvcc().latest_commit_ts(),
+                storage.options.max_txn_key_set_size,
+            );
+            storage.log_txn_record(&txn.record(&xid))?;
+            storage.mvcc().prepared_txns.lock().insert(xid, txn);
+        }
         storage.sync_dir()?;
+        storage.maybe_rotate_manifest(&storage.state_lock.lock())?;
+        storage.orphan_files = storage.remove_orphan_files()?;
 
         Ok(storage)
     }
 
+    /// Remove the files left by a crash that the recovered state does not reference: the SSTs and
+    /// blob files written by a flush or a compaction that is not recorded, or removed by one that is
+    /// recorded, the WAL files of flushed memtables, and the manifests replaced by a rotation. They
+    /// are moved into the `quarantine` directory instead if `quarantine_orphan_files` is set. The
+    /// WAL segments are removed by `Wal::recover`, and no WAL file is removed if the WAL is
+    /// disabled, as its records are not recovered. Returns the names of the files, in order.
+    fn remove_orphan_files(&self) -> Result<Vec<String>> {
+        let mut ssts = HashSet::new();
+        let mut blob_files = HashSet::new();
+        for column_family in self.column_families.read().values() {
+            let state = column_family.state.read();
+            ssts.extend(state.sstables.keys().copied());
+            blob_files.extend(state.blob_files.values().map(|blob_file| blob_file.id()));
+        }
+        let memtables = {
+            let state = self.state.read();
+            state
+                .imm_memtables
+                .iter()
+                .chain(std::iter::once(&state.memtable))
+                .map(|memtable| memtable.id())
+                .collect::<HashSet<_>>()
+        };
+        let manifest_path = self.manifest().path();
+        let mut orphans = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let id_of = |extension: &str| {
+                name.strip_suffix(extension)
+                    .and_then(|stem| stem.parse::<usize>().ok())
+            };
+            let orphan = if let Some(id) = id_of(".sst") {
+                !ssts.contains(&id)
+            } else if let Some(id) = id_of(".blob") {
+                !blob_files.contains(&id)
+            } else if let Some(id) = id_of(".wal") {
+                self.options.enable_wal && !memtables.contains(&id)
+            } else if name == "MANIFEST" || name.starts_with("MANIFEST-") || name == "CURRENT.tmp" {
+                path != manifest_path
+            } else {
+                false
+            };
+            if orphan {
+                orphans.push(name.to_string());
+            }
+        }
+        if orphans.is_empty() {
+            return Ok(orphans);
+        }
+        orphans.sort();
+        if self.options.quarantine_orphan_files {
+            let quarantine = self.path.join("quarantine");
+            std::fs::create_dir_all(&quarantine)?;
+            for name in &orphans {
+                std::fs::rename(self.path.join(name), quarantine.join(name))?;
+            }
+            println!("{} orphan files quarantined: {:?}", orphans.len(), orphans);
+        } else {
+            for name in &orphans {
+                std::fs::remove_file(self.path.join(name))?;
+            }
+            println!("{} orphan files removed: {:?}", orphans.len(), orphans);
+        }
+        self.sync_dir()?;
+        Ok(orphans)
+    }
+
+    /// The names of the files left by a crash that are removed, or moved into the `quarantine`
+    /// directory, when the storage is opened.
+    pub fn orphan_files(&self) -> &[String] {
+        &self.orphan_files
+    }
+
+    /// Add a flushed SST to a column family when recovering.
+    fn recover_flush(column_family: &mut ColumnFamily, sst_id: usize) {
+        let flush_to_l0 = column_family.compaction_controller.flush_to_l0();
+        let state = column_family.state_mut();
+        if flush_to_l0 {
+            state.l0_sstables.insert(0, sst_id);
+        } else {
+            state.levels.insert(0, (sst_id, vec![sst_id]));
+        }
+    }
+
+    /// Apply the SSTs removed and added by a flush or a compaction to a column family when
+    /// recovering, and return the largest id added. The SSTs in a level are sorted by their first
+    /// keys, and the ones added to L0 or to a new tier take the place of the ones removed from it,
+    /// or go to the front if none is removed.
+    fn recover_edit(
+        column_family: &mut ColumnFamily,
+        edit: SstEdit,
+        sst_metas: &mut HashMap<usize, SstMeta>,
+    ) -> usize {
+        let tiered = !column_family.compaction_controller.flush_to_l0();
+        let state = column_family.state_mut();
+        let mut l0_position = None;
+        let mut level_position = None;
+        for (level, sst_id) in edit.removed {
+            sst_metas.remove(&sst_id);
+            match level {
+                None => {
+                    let idx = state
+                        .l0_sstables
+                        .iter()
+                        .position(|x| *x == sst_id)
+                        .expect("SST not exist?");
+                    state.l0_sstables.remove(idx);
+                    l0_position = Some(l0_position.map_or(idx, |x: usize| x.min(idx)));
+                }
+                Some(level) => {
+                    let idx = state
+                        .levels
+                        .iter()
+                        .position(|(id, _)| *id == level)
+                        .expect("level not exist?");
+                    let ssts = &mut state.levels[idx].1;
+                    let idx_in_level = ssts.iter().position(|x| *x == sst_id);
+                    ssts.remove(idx_in_level.expect("SST not exist?"));
+                    level_position = Some(level_position.map_or(idx, |x: usize| x.min(idx)));
+                }
+            }
+        }
+        let mut l0_position = l0_position.unwrap_or_default();
+        // The tiers emptied by a compaction are removed, and a new tier takes their place.
+        let mut tier_position = 0;
+        if tiered {
+            if let Some(idx) = level_position {
+                tier_position = state.levels[..idx]
+                    .iter()
+                    .filter(|(_, ssts)| !ssts.is_empty())
+                    .count();
+            }
+            state.levels.retain(|(_, ssts)| !ssts.is_empty());
+        }
+        let mut max_sst_id = 0;
+        let mut levels_to_sort = Vec::new();
+        for (level, sst) in edit.added {
+            max_sst_id = max_sst_id.max(sst.id);
+            match level {
+                None => {
+                    state.l0_sstables.insert(l0_position, sst.id);
+                    l0_position += 1;
+                }
+                Some(level) => {
+                    let idx = match state.levels.iter().position(|(id, _)| *id == level) {
+                        Some(idx) => idx,
+                        None => {
+                            state.levels.insert(tier_position, (level, Vec::new()));
+                            tier_position
+                        }
+                    };
+                    state.levels[idx].1.push(sst.id);
+                    if !levels_to_sort.contains(&level) {
+                        levels_to_sort.push(level);
+                    }
+                }
+            }
+            sst_metas.insert(sst.id, sst);
+        }
+        for level in levels_to_sort {
+            let (_, ssts) = state
+                .levels
+                .iter_mut()
+                .find(|(id, _)| *id == level)
+                .unwrap();
+            ssts.sort_by(|x, y| sst_metas[x].first_key.cmp(&sst_metas[y].first_key));
+        }
+        max_sst_id
+    }
+
+    /// Apply a compaction to a column family when recovering. The SSTs it removes are removed again
+    /// by `remove_orphan_files` if they are left by a crash.
+    fn recover_compaction(
+        column_family: &mut ColumnFamily,
+        task: &CompactionTask,
+        output: &[usize],
+    ) {
+        let (new_state, _) = column_family.compaction_controller.apply_compaction_result(
+            &column_family.state.read(),
+            task,
+            output,
+        );
+        *column_family.state_mut() = new_state;
+    }
+
+    pub(crate) fn column_family(&self, name: &str) -> Result<Arc<ColumnFamily>> {
+        match self.column_families.read().get(name) {
+            Some(column_family) => Ok(column_family.clone()),
+            None => bail!("column family {} does not exist", name),
+        }
+    }
+
+    pub fn list_column_families(&self) -> Vec<String> {
+        let mut names = self
+            .column_families
+            .read()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Check whether the memtables of all column families are empty.
+    fn memtables_are_empty(&self) -> bool {
+        self.column_families
+            .read()
+            .values()
+            .all(|column_family| column_family.state.read().memtable.is_empty())
+    }
+
+    pub fn create_column_family(&self, name: &str, options: ColumnFamilyOptions) -> Result<()> {
+        let state_lock = self.state_lock.lock();
+        if self.column_families.read().contains_key(name) {
+            bail!("column family {} already exists", name);
+        }
+        let id = self
+            .next_column_family_id
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        // The memtable of the new column family shares the id and the WAL of the current memtables,
+        // and covers the same records of the WAL.
+        let mut state = LsmStorageState::create(&options.compaction_options);
+        let current_memtable = self.state.read().memtable.clone();
+        state.memtable = Arc::new(match current_memtable.wal() {
+            Some(wal) => MemTable::create_with_shared_wal(
+                current_memtable.id(),
+                id,
+                wal.clone(),
+                current_memtable.first_lsn(),
+            ),
+            None => MemTable::create(current_memtable.id()),
+        });
+        self.manifest().add_record(
+            &state_lock,
+            ManifestRecord::CreateColumnFamily(id, name.to_string(), options.clone()),
+        )?;
+        self.column_families.write().insert(
+            name.to_string(),
+            Arc::new(ColumnFamily::new(id, name.to_string(), options, state)),
+        );
+        Ok(())
+    }
+
+    pub fn drop_column_family(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_COLUMN_FAMILY {
+            bail!("cannot drop the default column family");
+        }
+        let state_lock = self.state_lock.lock();
+        let column_family = self.column_family(name)?;
+        self.manifest().add_record(
+            &state_lock,
+            ManifestRecord::DropColumnFamily(column_family.id),
+        )?;
+        self.column_families.write().remove(name);
+        let snapshot = column_family.state.read().clone();
+        for sst_id in snapshot.sstables.keys() {
+            std::fs::remove_file(self.path_of_sst(*sst_id))?;
+        }
+        for blob_file in snapshot.blob_files.values() {
+            std::fs::remove_file(self.path_of_blob(blob_file.id()))?;
+        }
+        self.sync_dir()?;
+        Ok(())
+    }
+
     pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
         let mut compaction_filters = self.compaction_filters.lock();
         compaction_filters.push(compaction_filter);
@@ -459,13 +1433,18 @@ impl LsmStorageInner {
 
     /// Get a key from the storage. In day 7, this can be further optimized by using a bloom filter.
     pub fn get(self: &Arc<Self>, key: &[u8]) -> Result<Option<Bytes>> {
-        let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+        let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
         txn.get(key)
     }
 
-    pub(crate) fn get_with_ts(&self, key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
+    pub(crate) fn get_with_ts(
+        &self,
+        state: &RwLock<Arc<LsmStorageState>>,
+        key: &[u8],
+        read_ts: u64,
+    ) -> Result<Option<Bytes>> {
         let snapshot = {
-            let guard = self.state.read();
+            let guard = state.read();
             Arc::clone(&guard)
         }; // drop global lock here
 
@@ -533,7 +1512,16 @@ impl LsmStorageInner {
                 MergeIterator::create(level_iters),
             )?,
             Bound::Unbounded,
+            Bound::Unbounded,
             read_ts,
+            collect_range_tombstones(
+                &snapshot,
+                Bound::Included(key),
+                Bound::Included(key),
+                read_ts,
+            ),
+            self.options.merge_operator.clone(),
+            snapshot.blob_files.clone(),
         )?;
 
         if iter.is_valid() && iter.key() == key && !iter.value().is_empty() {
@@ -543,70 +1531,402 @@ impl LsmStorageInner {
     }
 
     pub fn write_batch_inner<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<u64> {
-        let _lck = self.mvcc().write_lock.lock();
-        let ts = self.mvcc().latest_commit_ts() + 1;
+        self.write_batch_inner_with_options(batch, &WriteOptions::default())
+    }
+
+    pub fn write_batch_inner_with_options<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<u64> {
+        let (ts, wal) = {
+            let _commit_lock = self.mvcc().commit_lock.lock();
+            let (ts, wal) = self.write_batch_unsynced(batch, options)?;
+            self.record_unchecked_commit(batch, ts);
+            (ts, wal)
+        };
+        Self::sync_wal(wal.as_ref(), options)?;
+        Ok(ts)
+    }
+
+    /// Record the keys written into the default column family by a commit that is not checked for
+    /// conflicts, if a transaction checking for conflicts may have started before it. Must be
+    /// called with the commit lock held, after the commit ts is updated.
+    pub(crate) fn record_unchecked_commit<'a, T: AsRef<[u8]> + 'a>(
+        &self,
+        batch: impl IntoIterator<Item = &'a WriteBatchRecord<T>>,
+        ts: u64,
+    ) {
+        if !self.mvcc().has_conflict_checking_txns() {
+            return;
+        }
+        let mut write_set = KeySet::default();
+        let mut range_deletes = Vec::new();
         for record in batch {
+            match record {
+                WriteBatchRecord::Put(key, _)
+                | WriteBatchRecord::PutWithTtl(key, _, _)
+                | WriteBatchRecord::Del(key)
+                | WriteBatchRecord::Merge(key, _) => {
+                    write_set.insert(key.as_ref(), self.options.max_txn_key_set_size);
+                }
+                WriteBatchRecord::DelRange(start, end) => range_deletes.push((
+                    Bound::Included(Bytes::copy_from_slice(start.as_ref())),
+                    Bound::Excluded(Bytes::copy_from_slice(end.as_ref())),
+                )),
+            }
+        }
+        self.mvcc().add_committed_txn(CommittedTxnData {
+            write_set,
+            range_deletes,
+            read_ts: ts - 1,
+            commit_ts: ts,
+        });
+    }
+
+    /// Write a batch into the default column family without syncing the WAL. Returns the timestamp
+    /// of the batch and the WAL written, which is synced with `sync_wal` after the locks held by the
+    /// caller are released.
+    pub(crate) fn write_batch_unsynced<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<(u64, Option<Arc<Wal>>)> {
+        let column_family = self.column_family(DEFAULT_COLUMN_FAMILY)?;
+        let batch = batch
+            .iter()
+            .map(|record| (column_family.as_ref(), record))
+            .collect::<Vec<_>>();
+        self.write_batch_to_column_families(&batch, options)
+    }
+
+    /// Sync the WAL written by a batch if `options.sync` is set. The writers syncing the WAL at the
+    /// same time share one sync, so it should be called with no lock held.
+    pub(crate) fn sync_wal(wal: Option<&Arc<Wal>>, options: &WriteOptions) -> Result<()> {
+        match wal {
+            Some(wal) if options.sync => wal.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the records of a batch into the memtables of their column families with the same
+    /// timestamp. Returns the timestamp and the WAL written.
+    fn write_batch_to_column_families<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&ColumnFamily, &WriteBatchRecord<T>)],
+        options: &WriteOptions,
+    ) -> Result<(u64, Option<Arc<Wal>>)> {
+        let write_lock = self.mvcc().write_lock.lock();
+        let ts = self.mvcc().next_commit_ts();
+        let wal = self.write_batch_with_ts(&write_lock, batch, ts, !options.disable_wal)?;
+        self.mvcc().update_commit_ts(ts);
+        Ok((ts, wal))
+    }
+
+    /// Write the records of a batch with a timestamp, and to the WAL as one record if `write_wal` is
+    /// set. Returns the WAL written. The write lock keeps the memtables from being frozen in the
+    /// middle of the batch.
+    fn write_batch_with_ts<T: AsRef<[u8]>>(
+        &self,
+        write_lock: &MutexGuard<'_, ()>,
+        batch: &[(&ColumnFamily, &WriteBatchRecord<T>)],
+        ts: u64,
+        write_wal: bool,
+    ) -> Result<Option<Arc<Wal>>> {
+        let has_merge = batch
+            .iter()
+            .any(|(_, record)| matches!(record, WriteBatchRecord::Merge(_, _)));
+        if has_merge && self.options.merge_operator.is_none() {
+            bail!("merge operator is not set");
+        }
+        let empty_range = batch.iter().any(|(_, record)| {
+            matches!(record, WriteBatchRecord::DelRange(start, end) if start.as_ref() >= end.as_ref())
+        });
+        if empty_range {
+            return Err(TxnError::EmptyRange.into());
+        }
+        let now = now_millis();
+        // All records are written with the same timestamp, so a merge operand is applied to the
+        // value written before in the batch.
+        let mut batch_values = HashMap::<(usize, &[u8]), Vec<u8>>::new();
+        let mut records = Vec::with_capacity(batch.len());
+        for (column_family, record) in batch {
             match record {
                 WriteBatchRecord::Del(key) => {
                     let key = key.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(KeySlice::from_slice(key, ts), b"")?;
-                        size = guard.memtable.approximate_size();
+                    records.push((
+                        column_family.id,
+                        BatchRecord::Put(Bytes::copy_from_slice(key), Bytes::new()),
+                    ));
+                    if has_merge {
+                        batch_values.insert((column_family.id, key), Vec::new());
                     }
-                    self.try_freeze(size)?;
                 }
-                WriteBatchRecord::Put(key, value) => {
+                WriteBatchRecord::Put(key, value) | WriteBatchRecord::PutWithTtl(key, value, _) => {
                     let key = key.as_ref();
                     let value = value.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
                     assert!(!value.is_empty(), "value cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(KeySlice::from_slice(key, ts), value)?;
-                        size = guard.memtable.approximate_size();
+                    let value = match record {
+                        WriteBatchRecord::PutWithTtl(_, _, ttl) => {
+                            Value::PutWithTtl(now.saturating_add(ttl.as_millis() as u64), value)
+                                .encode()
+                        }
+                        _ => Value::Put(value).encode(),
+                    };
+                    records.push((
+                        column_family.id,
+                        BatchRecord::Put(Bytes::copy_from_slice(key), Bytes::from(value.clone())),
+                    ));
+                    if has_merge {
+                        batch_values.insert((column_family.id, key), value);
                     }
-                    self.try_freeze(size)?;
+                }
+                WriteBatchRecord::Merge(key, operand) => {
+                    let key = key.as_ref();
+                    assert!(!key.is_empty(), "key cannot be empty");
+                    let existing_value = match batch_values.get(&(column_family.id, key)) {
+                        Some(value) => Some(Value::decode_at(value, now)?),
+                        None => None,
+                    };
+                    let value = merge_value(
+                        self.options.merge_operator.as_ref(),
+                        key,
+                        existing_value,
+                        operand.as_ref(),
+                    )?;
+                    records.push((
+                        column_family.id,
+                        BatchRecord::Put(Bytes::copy_from_slice(key), Bytes::from(value.clone())),
+                    ));
+                    batch_values.insert((column_family.id, key), value);
+                }
+                WriteBatchRecord::DelRange(start, end) => {
+                    let (start, end) = (start.as_ref(), end.as_ref());
+                    records.push((
+                        column_family.id,
+                        BatchRecord::DelRange(
+                            Bytes::copy_from_slice(start),
+                            Bytes::copy_from_slice(end),
+                        ),
+                    ));
                 }
             }
         }
-        self.mvcc().update_commit_ts(ts);
-        Ok(ts)
+        // The column families share the WAL of the current memtables.
+        let wal = match batch.first() {
+            Some((column_family, _)) if write_wal => {
+                column_family.state.read().memtable.wal().cloned()
+            }
+            _ => None,
+        };
+        if let Some(wal) = &wal {
+            wal.put_batch(ts, &records)?;
+        }
+        for ((column_family, _), (_, record)) in batch.iter().zip(&records) {
+            let guard = column_family.state.read();
+            match record {
+                BatchRecord::Put(key, value) => {
+                    guard
+                        .memtable
+                        .put_with_wal(KeySlice::from_slice(key, ts), value, false)?
+                }
+                BatchRecord::DelRange(start, end) => guard
+                    .memtable
+                    .put_range_tombstone_with_wal(start, end, ts, false)?,
+            }
+        }
+        // The memtables are frozen after the whole batch is written, so that the batch is in one
+        // memtable.
+        let mut column_families = batch
+            .iter()
+            .map(|(column_family, _)| *column_family)
+            .collect::<Vec<_>>();
+        column_families.sort_by_key(|column_family| column_family.id);
+        column_families.dedup_by_key(|column_family| column_family.id);
+        for column_family in column_families {
+            let size = column_family.state.read().memtable.approximate_size();
+            self.try_freeze(write_lock, column_family, size)?;
+        }
+        Ok(wal)
     }
 
     pub fn write_batch<T: AsRef<[u8]>>(
         self: &Arc<Self>,
         batch: &[WriteBatchRecord<T>],
+    ) -> Result<()> {
+        self.write_batch_with_options(batch, &WriteOptions::default())
+    }
+
+    /// Write a batch with the durability in `options`.
+    pub fn write_batch_with_options<T: AsRef<[u8]>>(
+        self: &Arc<Self>,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
     ) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(batch)?;
+            self.write_batch_inner_with_options(batch, options)?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+            let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
             for record in batch {
                 match record {
                     WriteBatchRecord::Del(key) => {
-                        txn.delete(key.as_ref());
+                        txn.delete(key.as_ref())?;
                     }
                     WriteBatchRecord::Put(key, value) => {
-                        txn.put(key.as_ref(), value.as_ref());
+                        txn.put(key.as_ref(), value.as_ref())?;
+                    }
+                    WriteBatchRecord::PutWithTtl(key, value, ttl) => {
+                        txn.put_with_ttl(key.as_ref(), value.as_ref(), *ttl)?;
+                    }
+                    WriteBatchRecord::DelRange(start, end) => {
+                        txn.delete_range(start.as_ref(), end.as_ref())?;
+                    }
+                    WriteBatchRecord::Merge(key, operand) => {
+                        txn.merge(key.as_ref(), operand.as_ref())?;
                     }
                 }
             }
-            txn.commit()?;
+            txn.commit_with_options(options)?;
         }
         Ok(())
     }
 
+    /// Write a batch of records into their column families with the same timestamp. The keys written
+    /// into the default column family are checked against by transactions.
+    pub fn write_batch_cf<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&str, WriteBatchRecord<T>)],
+    ) -> Result<()> {
+        let column_families = batch
+            .iter()
+            .map(|(name, _)| self.column_family(name))
+            .collect::<Result<Vec<_>>>()?;
+        let batch = column_families
+            .iter()
+            .zip(batch)
+            .map(|(column_family, (_, record))| (column_family.as_ref(), record))
+            .collect::<Vec<_>>();
+        let _commit_lock = self.mvcc().commit_lock.lock();
+        let (ts, _) = self.write_batch_to_column_families(&batch, &WriteOptions::default())?;
+        self.record_unchecked_commit(
+            batch
+                .iter()
+                .filter(|(column_family, _)| column_family.id == DEFAULT_COLUMN_FAMILY_ID)
+                .map(|(_, record)| *record),
+            ts,
+        );
+        Ok(())
+    }
+
+    /// Write a record of a transaction with two-phase commit to the WAL of the current memtables,
+    /// and sync it.
+    pub(crate) fn log_txn_record(&self, record: &TxnRecord) -> Result<()> {
+        let memtable = self.state.read().memtable.clone();
+        let Some(wal) = memtable.wal() else {
+            bail!("two-phase commit requires the WAL");
+        };
+        wal.put_txn_record(record)?;
+        wal.sync()
+    }
+
+    /// The ids of the prepared transactions that are not committed or rolled back, including the
+    /// ones recovered on startup.
+    pub fn prepared_txns(&self) -> Vec<String> {
+        self.mvcc().prepared_txns.lock().keys().cloned().collect()
+    }
+
+    /// Commit a prepared transaction by its id. The decision is logged with the commit ts before
+    /// the writes, so that the transaction is prepared again on recovery if the writes are lost.
+    pub fn commit_prepared(&self, xid: &str) -> Result<()> {
+        let _commit_lock = self.mvcc().commit_lock.lock();
+        let write_lock = self.mvcc().write_lock.lock();
+        let ts = self.mvcc().next_commit_ts();
+        let txn = {
+            let mut prepared_txns = self.mvcc().prepared_txns.lock();
+            if !prepared_txns.contains_key(xid) {
+                bail!("transaction {:?} is not prepared", xid);
+            }
+            self.log_txn_record(&TxnRecord::Commit {
+                xid: xid.to_string(),
+                commit_ts: ts,
+            })?;
+            prepared_txns.remove(xid).unwrap()
+        };
+        let column_family = self.column_family(DEFAULT_COLUMN_FAMILY)?;
+        let batch = txn_write_batch(&txn.writes, &txn.range_deletes)?;
+        let batch = batch
+            .iter()
+            .map(|record| (column_family.as_ref(), record))
+            .collect::<Vec<_>>();
+        self.write_batch_with_ts(&write_lock, &batch, ts, true)?;
+        self.mvcc().update_commit_ts(ts);
+        self.mvcc().add_committed_txn(CommittedTxnData {
+            range_deletes: txn.key_ranges(),
+            write_set: txn.write_set,
+            read_ts: txn.read_ts,
+            commit_ts: ts,
+        });
+        self.sync()
+    }
+
+    /// Roll back a prepared transaction by its id.
+    pub fn rollback_prepared(&self, xid: &str) -> Result<()> {
+        let mut prepared_txns = self.mvcc().prepared_txns.lock();
+        if !prepared_txns.contains_key(xid) {
+            bail!("transaction {:?} is not prepared", xid);
+        }
+        self.log_txn_record(&TxnRecord::Rollback {
+            xid: xid.to_string(),
+        })?;
+        prepared_txns.remove(xid);
+        Ok(())
+    }
+
+    /// Get a key from a column family.
+    pub fn get_cf(self: &Arc<Self>, column_family: &str, key: &[u8]) -> Result<Option<Bytes>> {
+        let column_family = self.column_family(column_family)?;
+        let txn = self
+            .mvcc()
+            .new_txn(self.clone(), IsolationLevel::SnapshotReads);
+        self.get_with_ts(&column_family.state, key, txn.read_ts)
+    }
+
+    /// Create an iterator over a range of keys in a column family.
+    pub fn scan_cf(
+        self: &Arc<Self>,
+        column_family: &str,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<TxnIterator> {
+        let column_family = self.column_family(column_family)?;
+        let txn = self
+            .mvcc()
+            .new_txn(self.clone(), IsolationLevel::SnapshotReads);
+        txn.scan_column_family(&column_family.state, lower, upper)
+    }
+
     /// Put a key-value pair into the storage by writing into the current memtable.
     pub fn put(self: &Arc<Self>, key: &[u8], value: &[u8]) -> Result<()> {
         if !self.options.serializable {
             self.write_batch_inner(&[WriteBatchRecord::Put(key, value)])?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
-            txn.put(key, value);
+            let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
+            txn.put(key, value)?;
+            txn.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Put a key-value pair that is no longer visible once `ttl` has elapsed. Expired pairs are
+    /// dropped by compaction.
+    pub fn put_with_ttl(self: &Arc<Self>, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        if !self.options.serializable {
+            self.write_batch_inner(&[WriteBatchRecord::PutWithTtl(key, value, ttl)])?;
+        } else {
+            let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
+            txn.put_with_ttl(key, value, ttl)?;
             txn.commit()?;
         }
         Ok(())
@@ -617,21 +1937,51 @@ impl LsmStorageInner {
         if !self.options.serializable {
             self.write_batch_inner(&[WriteBatchRecord::Del(key)])?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
-            txn.delete(key);
+            let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
+            txn.delete(key)?;
             txn.commit()?;
         }
         Ok(())
     }
 
-    fn try_freeze(&self, estimated_size: usize) -> Result<()> {
-        if estimated_size >= self.options.target_sst_size {
+    /// Remove all keys in `[start, end)` from the storage by writing a range tombstone.
+    pub fn delete_range(self: &Arc<Self>, start: &[u8], end: &[u8]) -> Result<()> {
+        if !self.options.serializable {
+            self.write_batch_inner(&[WriteBatchRecord::DelRange(start, end)])?;
+        } else {
+            let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
+            txn.delete_range(start, end)?;
+            txn.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Apply a merge operand to a key by writing it into the current memtable.
+    pub fn merge(self: &Arc<Self>, key: &[u8], operand: &[u8]) -> Result<()> {
+        if !self.options.serializable {
+            self.write_batch_inner(&[WriteBatchRecord::Merge(key, operand)])?;
+        } else {
+            let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
+            txn.merge(key, operand)?;
+            txn.commit()?;
+        }
+        Ok(())
+    }
+
+    fn try_freeze(
+        &self,
+        write_lock: &MutexGuard<'_, ()>,
+        column_family: &ColumnFamily,
+        estimated_size: usize,
+    ) -> Result<()> {
+        let target_sst_size = column_family.options.target_sst_size;
+        if estimated_size >= target_sst_size {
             let state_lock = self.state_lock.lock();
-            let guard = self.state.read();
+            let guard = column_family.state.read();
             // the memtable could have already been frozen, check again to ensure we really need to freeze
-            if guard.memtable.approximate_size() >= self.options.target_sst_size {
+            if guard.memtable.approximate_size() >= target_sst_size {
                 drop(guard);
-                self.force_freeze_memtable(&state_lock)?;
+                self.force_freeze_memtable(write_lock, &state_lock)?;
             }
         }
         Ok(())
@@ -653,13 +2003,34 @@ impl LsmStorageInner {
         Self::path_of_wal_static(&self.path, id)
     }
 
+    pub(crate) fn path_of_blob_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{:05}.blob", id))
+    }
+
+    pub(crate) fn path_of_blob(&self, id: usize) -> PathBuf {
+        Self::path_of_blob_static(&self.path, id)
+    }
+
+    /// Record the latest commit ts and the watermark of compaction in the manifest.
+    pub(crate) fn record_timestamps(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+        self.manifest().add_record(
+            state_lock_observer,
+            ManifestRecord::Timestamps(self.mvcc().latest_commit_ts(), self.mvcc().gc_watermark()),
+        )
+    }
+
     pub(super) fn sync_dir(&self) -> Result<()> {
         File::open(&self.path)?.sync_all()?;
         Ok(())
     }
 
-    fn freeze_memtable_with_memtable(&self, memtable: Arc<MemTable>) -> Result<()> {
-        let mut guard = self.state.write();
+    /// Replace the memtable of a column family, and return the frozen one.
+    fn freeze_memtable_with_memtable(
+        &self,
+        state: &RwLock<Arc<LsmStorageState>>,
+        memtable: Arc<MemTable>,
+    ) -> Arc<MemTable> {
+        let mut guard = state.write();
         // Swap the current memtable with a new one.
         let mut snapshot = guard.as_ref().clone();
         let old_memtable = std::mem::replace(&mut snapshot.memtable, memtable);
@@ -667,95 +2038,298 @@ impl LsmStorageInner {
         snapshot.imm_memtables.insert(0, old_memtable.clone());
         // Update the snapshot.
         *guard = Arc::new(snapshot);
+        old_memtable
+    }
 
-        drop(guard);
-        old_memtable.sync_wal()?;
-
+    /// Freeze the memtables of all column families, and create new memtables with the id, which
+    /// cover the records written to the WAL from now on, if it is enabled.
+    fn freeze_memtables(&self, _write_lock: &MutexGuard<'_, ()>, memtable_id: usize) -> Result<()> {
+        let wal = self.state.read().memtable.wal().cloned();
+        // The write lock keeps the batches from being written until the new memtables are in place,
+        // so the records of the old memtables are all before this LSN. Only transaction records
+        // can be written meanwhile, which do not belong to a memtable.
+        let first_lsn = wal.as_ref().map_or(0, |wal| wal.lsn());
+        for column_family in self.column_families.read().values() {
+            let memtable = match &wal {
+                Some(wal) => MemTable::create_with_shared_wal(
+                    memtable_id,
+                    column_family.id,
+                    wal.clone(),
+                    first_lsn,
+                ),
+                None => MemTable::create(memtable_id),
+            };
+            self.freeze_memtable_with_memtable(&column_family.state, Arc::new(memtable));
+        }
+        // The prepared transactions are logged again, as the records before the new memtables are
+        // removed once the old ones are flushed.
+        if let Some(wal) = &wal {
+            let prepared_txns = self.mvcc().prepared_txns.lock();
+            if !prepared_txns.is_empty() {
+                for (xid, txn) in prepared_txns.iter() {
+                    wal.put_txn_record(&txn.record(xid))?;
+                }
+                wal.sync()?;
+            }
+        }
         Ok(())
     }
 
-    /// Force freeze the current memtable to an immutable memtable
-    pub fn force_freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+    /// Force freeze the current memtable to an immutable memtable. The memtables of all column
+    /// families are frozen together, between write batches as the write lock is held.
+    pub fn force_freeze_memtable(
+        &self,
+        write_lock: &MutexGuard<'_, ()>,
+        state_lock_observer: &MutexGuard<'_, ()>,
+    ) -> Result<()> {
         let memtable_id = self.next_sst_id();
-        let memtable = if self.options.enable_wal {
-            Arc::new(MemTable::create_with_wal(
-                memtable_id,
-                self.path_of_wal(memtable_id),
-            )?)
-        } else {
-            Arc::new(MemTable::create(memtable_id))
-        };
+        self.freeze_memtables(write_lock, memtable_id)?;
 
-        self.freeze_memtable_with_memtable(memtable)?;
-
-        self.manifest().add_record(
-            state_lock_observer,
-            ManifestRecord::NewMemtable(memtable_id),
-        )?;
+        let record = Self::new_memtable_record(&self.state.read().memtable);
+        self.manifest().add_record(state_lock_observer, record)?;
+        self.maybe_rotate_manifest(state_lock_observer)?;
         self.sync_dir()?;
 
         Ok(())
     }
 
-    /// Force flush the earliest-created immutable memtable to disk
+    /// Force flush the earliest-created immutable memtables of all column families to disk
     pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
         let state_lock = self.state_lock.lock();
 
-        let flush_memtable;
-
-        {
-            let guard = self.state.read();
-            flush_memtable = guard
-                .imm_memtables
-                .last()
-                .expect("no imm memtables!")
-                .clone();
-        }
+        let memtable_id = self
+            .state
+            .read()
+            .imm_memtables
+            .last()
+            .expect("no imm memtables!")
+            .id();
+        let column_families = self
+            .column_families
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // The SST of the default column family takes the id of the memtable, and the other column
+        // families take new ids.
+        let mut edits = Vec::new();
+        let mut new_blob_files = Vec::new();
+        for column_family in column_families.iter() {
+            let flush_memtable = match column_family.state.read().imm_memtables.last() {
+                Some(memtable) if memtable.id() == memtable_id => memtable.clone(),
+                // The column family is created after the memtables are frozen.
+                _ => continue,
+            };
 
-        let mut builder = SsTableBuilder::new(self.options.block_size);
-        flush_memtable.flush(&mut builder)?;
-        let sst_id = flush_memtable.id();
-        let sst = Arc::new(builder.build(
-            sst_id,
-            Some(self.block_cache.clone()),
-            self.path_of_sst(sst_id),
-        )?);
+            let mut blob_file = None;
+            let sst = if flush_memtable.is_empty() {
+                None
+            } else {
+                let mut builder = SsTableBuilder::new_with_compression(
+                    column_family.options.block_size,
+                    column_family.options.compression.l0,
+                );
+                match &column_family.options.blob {
+                    Some(blob_options) => {
+                        let blob_id = self.next_sst_id();
+                        let mut blob_builder = BlobFileBuilder::new(blob_id);
+                        flush_memtable.flush_with_blob_file(
+                            &mut builder,
+                            &mut blob_builder,
+                            blob_options.min_blob_size,
+                        )?;
+                        if !blob_builder.is_empty() {
+                            blob_file = Some(Arc::new(
+                                blob_builder.build(blob_id, self.path_of_blob(blob_id))?,
+                            ));
+                        }
+                    }
+                    None => flush_memtable.flush(&mut builder)?,
+                }
+                let sst_id = if column_family.id == DEFAULT_COLUMN_FAMILY_ID {
+                    memtable_id
+                } else {
+                    self.next_sst_id()
+                };
+                Some(Arc::new(builder.build(
+                    sst_id,
+                    Some(self.block_cache.clone()),
+                    self.path_of_sst(sst_id),
+                )?))
+            };
 
-        // Add the flushed L0 table to the list.
-        {
-            let mut guard = self.state.write();
+            // Add the flushed L0 table to the list.
+            let mut guard = column_family.state.write();
             let mut snapshot = guard.as_ref().clone();
             // Remove the memtable from the immutable memtables.
             let mem = snapshot.imm_memtables.pop().unwrap();
-            assert_eq!(mem.id(), sst_id);
-            // Add L0 table
-            if self.compaction_controller.flush_to_l0() {
-                // In leveled compaction or no compaction, simply flush to L0
-                snapshot.l0_sstables.insert(0, sst_id);
-            } else {
-                // In tiered compaction, create a new tier
-                snapshot.levels.insert(0, (sst_id, vec![sst_id]));
+            assert_eq!(mem.id(), memtable_id);
+            if let Some(sst) = sst {
+                let sst_id = sst.sst_id();
+                // Add L0 table
+                let level = if column_family.compaction_controller.flush_to_l0() {
+                    // In leveled compaction or no compaction, simply flush to L0
+                    snapshot.l0_sstables.insert(0, sst_id);
+                    None
+                } else {
+                    // In tiered compaction, create a new tier
+                    snapshot.levels.insert(0, (sst_id, vec![sst_id]));
+                    Some(sst_id)
+                };
+                println!("flushed {}.sst with size={}", sst_id, sst.table_size());
+                edits.push(SstEdit {
+                    column_family_id: column_family.id,
+                    removed: Vec::new(),
+                    added: vec![(level, SstMeta::new(&sst))],
+                });
+                snapshot.sstables.insert(sst_id, sst);
+            }
+            if let Some(blob_file) = blob_file {
+                let blob_id = blob_file.id();
+                snapshot.blob_files.insert(blob_id, blob_file);
+                snapshot.blob_garbage.insert(blob_id, 0);
+                new_blob_files.push((column_family.id, blob_id));
             }
-            println!("flushed {}.sst with size={}", sst_id, sst.table_size());
-            snapshot.sstables.insert(sst_id, sst);
             // Update the snapshot.
             *guard = Arc::new(snapshot);
         }
 
-        if self.options.enable_wal {
-            std::fs::remove_file(self.path_of_wal(sst_id))?;
+        // The blob files are recorded before the SSTs that point to them.
+        for (column_family_id, blob_id) in new_blob_files {
+            self.manifest().add_record(
+                &state_lock,
+                ManifestRecord::NewBlobFile(column_family_id, blob_id),
+            )?;
         }
-
+        self.record_timestamps(&state_lock)?;
         self.manifest()
-            .add_record(&state_lock, ManifestRecord::Flush(sst_id))?;
+            .add_record(&state_lock, ManifestRecord::FlushEdits(memtable_id, edits))?;
+        self.maybe_rotate_manifest(&state_lock)?;
+
+        if self.options.enable_wal {
+            // A memtable recovered from a WAL file of its own removes it.
+            let wal_path = self.path_of_wal(memtable_id);
+            if wal_path.exists() {
+                std::fs::remove_file(wal_path)?;
+            }
+            // The records before the oldest memtable left are all flushed.
+            let (wal, first_lsn) = {
+                let state = self.state.read();
+                let oldest = state.imm_memtables.last().unwrap_or(&state.memtable);
+                (state.memtable.wal().cloned(), oldest.first_lsn())
+            };
+            if let Some(wal) = wal {
+                wal.remove_segments_before(first_lsn)?;
+            }
+        }
 
         self.sync_dir()?;
 
         Ok(())
     }
 
+    /// Rotate the manifest if it is too large, see `Manifest::should_rotate`. Called after the
+    /// records of a change to the state, so that the snapshot has the state they record.
+    pub(crate) fn maybe_rotate_manifest(
+        &self,
+        state_lock_observer: &MutexGuard<'_, ()>,
+    ) -> Result<()> {
+        if !self
+            .manifest()
+            .should_rotate(self.options.max_manifest_size)
+        {
+            return Ok(());
+        }
+        let column_families = self
+            .column_families
+            .read()
+            .values()
+            .map(|column_family| Self::column_family_snapshot(column_family))
+            .collect();
+        let memtables = {
+            let state = self.state.read();
+            state
+                .imm_memtables
+                .iter()
+                .rev()
+                .chain(std::iter::once(&state.memtable))
+                .map(|memtable| {
+                    let lsn = memtable.wal().map(|_| memtable.first_lsn());
+                    (memtable.id(), lsn)
+                })
+                .collect()
+        };
+        let snapshot = ManifestSnapshot {
+            column_families,
+            memtables,
+            next_sst_id: self.next_sst_id.load(std::sync::atomic::Ordering::SeqCst),
+            next_column_family_id: self
+                .next_column_family_id
+                .load(std::sync::atomic::Ordering::SeqCst),
+            commit_ts: self.mvcc().latest_commit_ts(),
+            gc_watermark: self.mvcc().gc_watermark(),
+        };
+        self.manifest().rotate(state_lock_observer, snapshot)
+    }
+
+    /// The state of a column family in a manifest snapshot.
+    fn column_family_snapshot(column_family: &ColumnFamily) -> ColumnFamilySnapshot {
+        let state = column_family.state.read();
+        let blob_files = state
+            .blob_garbage
+            .iter()
+            .map(|(blob_id, garbage)| {
+                let file_id = state.blob_files.get(blob_id).map_or(*blob_id, |x| x.id());
+                (*blob_id, file_id, *garbage)
+            })
+            .collect();
+        let sstables = state
+            .l0_sstables
+            .iter()
+            .chain(state.levels.iter().flat_map(|(_, ssts)| ssts))
+            .map(|sst_id| SstMeta::new(&state.sstables[sst_id]))
+            .collect();
+        ColumnFamilySnapshot {
+            id: column_family.id,
+            name: column_family.name.clone(),
+            options: column_family.options.clone(),
+            l0_sstables: state.l0_sstables.clone(),
+            levels: state.levels.clone(),
+            blob_files,
+            sstables,
+        }
+    }
+
+    /// The manifest record of a new memtable, with its first LSN if it writes to the shared WAL.
+    fn new_memtable_record(memtable: &MemTable) -> ManifestRecord {
+        match memtable.wal() {
+            Some(_) => ManifestRecord::NewMemtableAtLsn(memtable.id(), memtable.first_lsn()),
+            None => ManifestRecord::NewMemtable(memtable.id()),
+        }
+    }
+
     pub fn new_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
-        Ok(self.mvcc().new_txn(self.clone(), self.options.serializable))
+        Ok(self.mvcc().new_txn(self.clone(), self.options.isolation()))
+    }
+
+    pub fn new_txn_with_isolation(
+        self: &Arc<Self>,
+        isolation: IsolationLevel,
+    ) -> Result<Arc<Transaction>> {
+        Ok(self.mvcc().new_txn(self.clone(), isolation))
+    }
+
+    pub fn new_pessimistic_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
+        Ok(self.mvcc().new_pessimistic_txn(self.clone()))
+    }
+
+    pub fn snapshot(self: &Arc<Self>) -> Snapshot {
+        self.mvcc().new_snapshot(self.clone())
+    }
+
+    pub fn snapshot_at(self: &Arc<Self>, ts: u64) -> Result<Snapshot> {
+        self.mvcc().new_snapshot_at(self.clone(), ts)
     }
 
     /// Create an iterator over a range of keys.
@@ -764,33 +2338,81 @@ impl LsmStorageInner {
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
     ) -> Result<TxnIterator> {
-        let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+        let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
         txn.scan(lower, upper)
     }
 
     pub(crate) fn scan_with_ts(
         &self,
+        state: &RwLock<Arc<LsmStorageState>>,
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
         read_ts: u64,
+        local_range_tombstones: &[RangeTombstone],
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.scan_with_direction(
+            state,
+            lower,
+            upper,
+            read_ts,
+            local_range_tombstones,
+            Direction::Forward,
+        )
+    }
+
+    /// Create an iterator over a range of keys that starts from the last key in the range.
+    pub fn scan_rev(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<TxnIterator> {
+        let txn = self.mvcc().new_txn(self.clone(), self.options.isolation());
+        txn.scan_rev(lower, upper)
+    }
+
+    pub(crate) fn scan_rev_with_ts(
+        &self,
+        state: &RwLock<Arc<LsmStorageState>>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_ts: u64,
+        local_range_tombstones: &[RangeTombstone],
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.scan_with_direction(
+            state,
+            lower,
+            upper,
+            read_ts,
+            local_range_tombstones,
+            Direction::Backward,
+        )
+    }
+
+    /// Create an iterator over a range of keys, positioned at the first key in the range when
+    /// moving forward or at the last one when moving backward.
+    fn scan_with_direction(
+        &self,
+        state: &RwLock<Arc<LsmStorageState>>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_ts: u64,
+        local_range_tombstones: &[RangeTombstone],
+        direction: Direction,
     ) -> Result<FusedIterator<LsmIterator>> {
         let snapshot = {
-            let guard = self.state.read();
+            let guard = state.read();
             Arc::clone(&guard)
         }; // drop global lock here
 
         let mut memtable_iters = Vec::with_capacity(snapshot.imm_memtables.len() + 1);
-        memtable_iters.push(Box::new(snapshot.memtable.scan(
-            map_key_bound_plus_ts(lower, key::TS_RANGE_BEGIN),
-            map_key_bound_plus_ts(upper, key::TS_RANGE_END),
-        )));
-        for memtable in snapshot.imm_memtables.iter() {
-            memtable_iters.push(Box::new(memtable.scan(
+        for memtable in std::iter::once(&snapshot.memtable).chain(snapshot.imm_memtables.iter()) {
+            memtable_iters.push(Box::new(memtable.scan_with_direction(
                 map_key_bound_plus_ts(lower, key::TS_RANGE_BEGIN),
                 map_key_bound_plus_ts(upper, key::TS_RANGE_END),
+                direction,
             )));
         }
-        let memtable_iter = MergeIterator::create(memtable_iters);
+        let memtable_iter = MergeIterator::create_with_direction(memtable_iters, direction);
 
         let mut table_iters = Vec::with_capacity(snapshot.l0_sstables.len());
         for table_id in snapshot.l0_sstables.iter() {
@@ -801,29 +2423,22 @@ impl LsmStorageInner {
                 table.first_key().as_key_slice(),
                 table.last_key().as_key_slice(),
             ) {
-                let iter = match lower {
-                    Bound::Included(key) => SsTableIterator::create_and_seek_to_key(
-                        table,
-                        KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
-                    )?,
-                    Bound::Excluded(key) => {
-                        let mut iter = SsTableIterator::create_and_seek_to_key(
-                            table,
-                            KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
-                        )?;
-                        while iter.is_valid() && iter.key().key_ref() == key {
-                            iter.next()?;
-                        }
-                        iter
-                    }
-                    Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table)?,
-                };
-
+                let iter = seek_to_range(
+                    table,
+                    lower,
+                    upper,
+                    direction,
+                    SsTableIterator::create_and_seek_to_key,
+                    match direction {
+                        Direction::Forward => SsTableIterator::create_and_seek_to_first,
+                        Direction::Backward => SsTableIterator::create_and_seek_to_last,
+                    },
+                )?;
                 table_iters.push(Box::new(iter));
             }
         }
 
-        let l0_iter = MergeIterator::create(table_iters);
+        let l0_iter = MergeIterator::create_with_direction(table_iters, direction);
         let mut level_iters = Vec::with_capacity(snapshot.levels.len());
         for (_, level_sst_ids) in &snapshot.levels {
             let mut level_ssts = Vec::with_capacity(level_sst_ids.len());
@@ -839,33 +2454,42 @@ impl LsmStorageInner {
                 }
             }
 
-            let level_iter = match lower {
-                Bound::Included(key) => SstConcatIterator::create_and_seek_to_key(
-                    level_ssts,
-                    KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
-                )?,
-                Bound::Excluded(key) => {
-                    let mut iter = SstConcatIterator::create_and_seek_to_key(
-                        level_ssts,
-                        KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
-                    )?;
-                    while iter.is_valid() && iter.key().key_ref() == key {
-                        iter.next()?;
-                    }
-                    iter
-                }
-                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first(level_ssts)?,
-            };
+            let level_iter = seek_to_range(
+                level_ssts,
+                lower,
+                upper,
+                direction,
+                SstConcatIterator::create_and_seek_to_key,
+                match direction {
+                    Direction::Forward => SstConcatIterator::create_and_seek_to_first,
+                    Direction::Backward => SstConcatIterator::create_and_seek_to_last,
+                },
+            )?;
             level_iters.push(Box::new(level_iter));
         }
 
-        let iter = TwoMergeIterator::create(memtable_iter, l0_iter)?;
-        let iter = TwoMergeIterator::create(iter, MergeIterator::create(level_iters))?;
+        let iter = TwoMergeIterator::create_with_direction(memtable_iter, l0_iter, direction)?;
+        let iter = TwoMergeIterator::create_with_direction(
+            iter,
+            MergeIterator::create_with_direction(level_iters, direction),
+            direction,
+        )?;
+
+        let mut range_tombstones = collect_range_tombstones(&snapshot, lower, upper, read_ts);
+        range_tombstones.extend(local_range_tombstones.iter().cloned());
 
-        Ok(FusedIterator::new(LsmIterator::new(
+        let new_iter = match direction {
+            Direction::Forward => LsmIterator::new,
+            Direction::Backward => LsmIterator::new_reverse,
+        };
+        Ok(FusedIterator::new(new_iter(
             iter,
+            map_bound(lower),
             map_bound(upper),
             read_ts,
+            range_tombstones,
+            self.options.merge_operator.clone(),
+            snapshot.blob_files.clone(),
         )?))
     }
 }
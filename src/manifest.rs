@@ -1,64 +1,702 @@
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes};
 use parking_lot::{Mutex, MutexGuard};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
-use crate::compact::CompactionTask;
+use crate::blob::BlobOptions;
+use crate::column_family::ColumnFamilyOptions;
+use crate::compact::{
+    CompactionOptions, CompactionTask, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+    TieredCompactionOptions,
+};
+use crate::key::KeyBytes;
+use crate::table::{CompressionOptions, CompressionType, SsTable};
 
+/// The first manifest format, which has no header. A record is its length as a `u64`, the record
+/// in JSON and a checksum.
+const MANIFEST_FORMAT_V1: u32 = 1;
+/// Since v2, the manifest starts with a magic number and the version, and a record is its length
+/// as a `u32`, the record in the binary format of `ManifestRecord::encode` and a checksum. A v1
+/// manifest starts with the high half of a length, which is 0 and not the magic.
+const MANIFEST_FORMAT_V2: u32 = 2;
+const MANIFEST_FORMAT_VERSION: u32 = MANIFEST_FORMAT_V2;
+const MANIFEST_MAGIC: u32 = 0x4D4E_4653;
+const MANIFEST_HEADER_SIZE: usize = 8;
+const RECORD_NEW_MEMTABLE: u8 = 0;
+const RECORD_NEW_MEMTABLE_AT_LSN: u8 = 1;
+const RECORD_FLUSH_EDITS: u8 = 2;
+const RECORD_COMPACTION_EDIT: u8 = 3;
+const RECORD_CREATE_COLUMN_FAMILY: u8 = 4;
+const RECORD_DROP_COLUMN_FAMILY: u8 = 5;
+const RECORD_NEW_BLOB_FILE: u8 = 6;
+const RECORD_BLOB_GARBAGE: u8 = 7;
+const RECORD_GC_BLOB_FILE: u8 = 8;
+const RECORD_TIMESTAMPS: u8 = 9;
+const RECORD_SNAPSHOT: u8 = 10;
+const COMPACTION_NONE: u8 = 0;
+const COMPACTION_LEVELED: u8 = 1;
+const COMPACTION_TIERED: u8 = 2;
+const COMPACTION_SIMPLE: u8 = 3;
+
+/// The manifest of the DB. It is rotated into a new `MANIFEST-N` file that starts with a snapshot
+/// of the state, and the `CURRENT` file names the manifest in use. A DB created before rotation has
+/// one `MANIFEST` file and no `CURRENT` file. A manifest in an older format is only read, and is
+/// rotated into the current format when the DB is opened.
 pub struct Manifest {
-    file: Arc<Mutex<File>>,
+    dir: PathBuf,
+    file: Arc<Mutex<ManifestFile>>,
+}
+
+/// The manifest file in use.
+struct ManifestFile {
+    file: File,
+    /// The number of the file, which is 0 for a `MANIFEST` file.
+    number: u64,
+    version: u32,
+    size: u64,
+    /// The size of the snapshot the file starts with.
+    snapshot_size: u64,
+}
+
+/// An SST in an `SstEdit`, with its key range and size.
+#[derive(Debug, Clone)]
+pub struct SstMeta {
+    pub id: usize,
+    pub first_key: KeyBytes,
+    pub last_key: KeyBytes,
+    pub size: u64,
 }
 
-#[derive(Serialize, Deserialize)]
+impl SstMeta {
+    pub fn new(sst: &SsTable) -> Self {
+        Self {
+            id: sst.sst_id(),
+            first_key: sst.first_key().clone(),
+            last_key: sst.last_key().clone(),
+            size: sst.table_size(),
+        }
+    }
+}
+
+/// The SSTs removed from and added to the levels of a column family by a flush or a compaction.
+/// The level is `None` for L0, and the id of the level or the tier otherwise.
+#[derive(Debug, Clone)]
+pub struct SstEdit {
+    pub column_family_id: usize,
+    pub removed: Vec<(Option<usize>, usize)>,
+    pub added: Vec<(Option<usize>, SstMeta)>,
+}
+
+/// The state of a column family in a manifest snapshot.
+#[derive(Deserialize)]
+pub struct ColumnFamilySnapshot {
+    pub id: usize,
+    pub name: String,
+    pub options: ColumnFamilyOptions,
+    pub l0_sstables: Vec<usize>,
+    pub levels: Vec<(usize, Vec<usize>)>,
+    /// The id in the pointers, the id of the current version and the garbage size of each blob
+    /// file.
+    pub blob_files: Vec<(usize, usize, u64)>,
+    /// The SSTs in `l0_sstables` and `levels`, which are not recorded in a JSON manifest.
+    #[serde(skip)]
+    pub sstables: Vec<SstMeta>,
+}
+
+/// The state recorded by all records before it, which starts a rotated manifest.
+#[derive(Deserialize)]
+pub struct ManifestSnapshot {
+    pub column_families: Vec<ColumnFamilySnapshot>,
+    /// The memtables that are not flushed from the earliest, with their first LSNs if they write
+    /// to the shared WAL, see `ManifestRecord::NewMemtableAtLsn`.
+    pub memtables: Vec<(usize, Option<u64>)>,
+    pub next_sst_id: usize,
+    pub next_column_family_id: usize,
+    pub commit_ts: u64,
+    pub gc_watermark: u64,
+}
+
+/// A record of the manifest. The records that store a `CompactionTask` are only read from JSON
+/// manifests, and flushes and compactions are recorded as `SstEdit`s since v2.
+#[derive(Deserialize)]
 pub enum ManifestRecord {
+    /// Only in JSON manifests.
     Flush(usize),
     NewMemtable(usize),
+    /// Only in JSON manifests.
     Compaction(CompactionTask, Vec<usize>),
+    /// A column family is created with its id, name and options.
+    CreateColumnFamily(usize, String, ColumnFamilyOptions),
+    DropColumnFamily(usize),
+    /// The memtables with the id are flushed, with the column family id and the SST id of each
+    /// memtable that is not empty. A flush with only an SST of the default column family is recorded
+    /// as `Flush`. Only in JSON manifests.
+    FlushColumnFamilies(usize, Vec<(usize, usize)>),
+    /// A compaction in a column family other than the default one. Only in JSON manifests.
+    ColumnFamilyCompaction(usize, CompactionTask, Vec<usize>),
+    /// A blob file is written for a column family by a flush, and is recorded before the flush.
+    NewBlobFile(usize, usize),
+    /// The size of the records in the blob files of a column family that are no longer referenced
+    /// after a compaction.
+    BlobGarbage(usize, Vec<(usize, u64)>),
+    /// A blob file of a column family is rewritten into the file with the new id, or removed if
+    /// none of its records is referenced. The pointers keep the id of the first version of the file.
+    GcBlobFile(usize, usize, Option<usize>),
+    /// The latest commit ts and the watermark of compaction, recorded with flushes and compactions.
+    /// The latest commit ts found in the SSTs may be lower, as compaction removes deleted keys.
+    Timestamps(u64, u64),
+    /// A memtable is created with its records from the LSN in the shared WAL. A memtable recorded
+    /// with `NewMemtable` has a WAL file of its own, if the WAL is enabled.
+    NewMemtableAtLsn(usize, u64),
+    /// The whole state, which is the first record of a rotated manifest.
+    Snapshot(ManifestSnapshot),
+    /// The memtables with the id are flushed into the SSTs added by the edits, one for each column
+    /// family whose memtable is not empty.
+    #[serde(skip)]
+    FlushEdits(usize, Vec<SstEdit>),
+    /// The SSTs a compaction removes and adds.
+    #[serde(skip)]
+    CompactionEdit(SstEdit),
+}
+
+/// Reads the fields of a binary record. A read past the end is an error rather than a panic.
+struct RecordReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> RecordReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            bail!("manifest record is truncated");
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.take(4)?.get_u32())
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(self.take(8)?.get_u64())
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        Ok(self.u64()? as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(self.take(8)?.get_f64())
+    }
+
+    fn bytes(&mut self) -> Result<Bytes> {
+        let len = self.u32()? as usize;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+
+    /// Read the payload and the checksum of a record, or `None` if the record ends past the end of
+    /// the manifest.
+    fn payload_and_checksum(&mut self) -> Option<(&'a [u8], u32)> {
+        let len = self.u32().ok()? as usize;
+        let payload = self.take(len).ok()?;
+        Some((payload, self.u32().ok()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+
+    fn key(&mut self) -> Result<KeyBytes> {
+        let key = self.bytes()?;
+        Ok(KeyBytes::from_bytes_with_ts(key, self.u64()?))
+    }
+
+    fn option<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(f(self)?)),
+            x => bail!("invalid option tag {} in manifest record", x),
+        }
+    }
+
+    fn vec<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let len = self.u32()?;
+        (0..len).map(|_| f(self)).collect()
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.put_u32(bytes.len() as u32);
+    buf.put_slice(bytes);
+}
+
+fn put_key(buf: &mut Vec<u8>, key: &KeyBytes) {
+    put_bytes(buf, key.key_ref());
+    buf.put_u64(key.ts());
+}
+
+fn put_option<T>(buf: &mut Vec<u8>, value: &Option<T>, f: impl FnOnce(&mut Vec<u8>, &T)) {
+    match value {
+        Some(value) => {
+            buf.put_u8(1);
+            f(buf, value);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+fn put_vec<T>(buf: &mut Vec<u8>, values: &[T], mut f: impl FnMut(&mut Vec<u8>, &T)) {
+    buf.put_u32(values.len() as u32);
+    for value in values {
+        f(buf, value);
+    }
+}
+
+fn put_level(buf: &mut Vec<u8>, level: &Option<usize>) {
+    put_option(buf, level, |buf, level| buf.put_u64(*level as u64));
+}
+
+fn read_level(reader: &mut RecordReader) -> Result<Option<usize>> {
+    reader.option(RecordReader::usize)
+}
+
+impl SstEdit {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.column_family_id as u64);
+        put_vec(buf, &self.removed, |buf, (level, sst_id)| {
+            put_level(buf, level);
+            buf.put_u64(*sst_id as u64);
+        });
+        put_vec(buf, &self.added, |buf, (level, sst)| {
+            put_level(buf, level);
+            sst.encode(buf);
+        });
+    }
+
+    fn decode(reader: &mut RecordReader) -> Result<Self> {
+        Ok(Self {
+            column_family_id: reader.usize()?,
+            removed: reader.vec(|reader| Ok((read_level(reader)?, reader.usize()?)))?,
+            added: reader.vec(|reader| Ok((read_level(reader)?, SstMeta::decode(reader)?)))?,
+        })
+    }
+}
+
+impl SstMeta {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.id as u64);
+        put_key(buf, &self.first_key);
+        put_key(buf, &self.last_key);
+        buf.put_u64(self.size);
+    }
+
+    fn decode(reader: &mut RecordReader) -> Result<Self> {
+        Ok(Self {
+            id: reader.usize()?,
+            first_key: reader.key()?,
+            last_key: reader.key()?,
+            size: reader.u64()?,
+        })
+    }
+}
+
+/// Encode the options field by field, so that the format does not depend on the shape of the
+/// structs.
+fn encode_options(buf: &mut Vec<u8>, options: &ColumnFamilyOptions) {
+    buf.put_u64(options.block_size as u64);
+    buf.put_u64(options.target_sst_size as u64);
+    match &options.compaction_options {
+        CompactionOptions::NoCompaction => buf.put_u8(COMPACTION_NONE),
+        CompactionOptions::Leveled(options) => {
+            buf.put_u8(COMPACTION_LEVELED);
+            buf.put_u64(options.level_size_multiplier as u64);
+            buf.put_u64(options.level0_file_num_compaction_trigger as u64);
+            buf.put_u64(options.max_levels as u64);
+            buf.put_u64(options.base_level_size_mb as u64);
+        }
+        CompactionOptions::Tiered(options) => {
+            buf.put_u8(COMPACTION_TIERED);
+            buf.put_u64(options.num_tiers as u64);
+            buf.put_u64(options.max_size_amplification_percent as u64);
+            buf.put_u64(options.size_ratio as u64);
+            buf.put_u64(options.min_merge_width as u64);
+        }
+        CompactionOptions::Simple(options) => {
+            buf.put_u8(COMPACTION_SIMPLE);
+            buf.put_u64(options.size_ratio_percent as u64);
+            buf.put_u64(options.level0_file_num_compaction_trigger as u64);
+            buf.put_u64(options.max_levels as u64);
+        }
+    }
+    let compression = &options.compression;
+    for compression_type in [
+        compression.l0,
+        compression.middle_levels,
+        compression.bottom_level,
+    ] {
+        buf.put_u8(compression_type.to_u8());
+    }
+    put_option(buf, &options.blob, |buf, blob| {
+        buf.put_u64(blob.min_blob_size as u64);
+        buf.put_f64(blob.gc_live_ratio);
+    });
+}
+
+fn decode_options(reader: &mut RecordReader) -> Result<ColumnFamilyOptions> {
+    let block_size = reader.usize()?;
+    let target_sst_size = reader.usize()?;
+    let compaction_options = match reader.u8()? {
+        COMPACTION_NONE => CompactionOptions::NoCompaction,
+        COMPACTION_LEVELED => CompactionOptions::Leveled(LeveledCompactionOptions {
+            level_size_multiplier: reader.usize()?,
+            level0_file_num_compaction_trigger: reader.usize()?,
+            max_levels: reader.usize()?,
+            base_level_size_mb: reader.usize()?,
+        }),
+        COMPACTION_TIERED => CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: reader.usize()?,
+            max_size_amplification_percent: reader.usize()?,
+            size_ratio: reader.usize()?,
+            min_merge_width: reader.usize()?,
+        }),
+        COMPACTION_SIMPLE => CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: reader.usize()?,
+            level0_file_num_compaction_trigger: reader.usize()?,
+            max_levels: reader.usize()?,
+        }),
+        x => bail!("unknown compaction options {} in manifest", x),
+    };
+    let compression = CompressionOptions {
+        l0: CompressionType::from_u8(reader.u8()?)?,
+        middle_levels: CompressionType::from_u8(reader.u8()?)?,
+        bottom_level: CompressionType::from_u8(reader.u8()?)?,
+    };
+    let blob = reader.option(|reader| {
+        Ok(BlobOptions {
+            min_blob_size: reader.usize()?,
+            gc_live_ratio: reader.f64()?,
+        })
+    })?;
+    Ok(ColumnFamilyOptions {
+        block_size,
+        target_sst_size,
+        compaction_options,
+        compression,
+        blob,
+    })
+}
+
+impl ColumnFamilySnapshot {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.id as u64);
+        put_bytes(buf, self.name.as_bytes());
+        encode_options(buf, &self.options);
+        put_vec(buf, &self.l0_sstables, |buf, sst_id| {
+            buf.put_u64(*sst_id as u64)
+        });
+        put_vec(buf, &self.levels, |buf, (level, ssts)| {
+            buf.put_u64(*level as u64);
+            put_vec(buf, ssts, |buf, sst_id| buf.put_u64(*sst_id as u64));
+        });
+        put_vec(buf, &self.blob_files, |buf, (blob_id, file_id, garbage)| {
+            buf.put_u64(*blob_id as u64);
+            buf.put_u64(*file_id as u64);
+            buf.put_u64(*garbage);
+        });
+        put_vec(buf, &self.sstables, |buf, sst| sst.encode(buf));
+    }
+
+    fn decode(reader: &mut RecordReader) -> Result<Self> {
+        Ok(Self {
+            id: reader.usize()?,
+            name: reader.string()?,
+            options: decode_options(reader)?,
+            l0_sstables: reader.vec(RecordReader::usize)?,
+            levels: reader.vec(|reader| Ok((reader.usize()?, reader.vec(RecordReader::usize)?)))?,
+            blob_files: reader
+                .vec(|reader| Ok((reader.usize()?, reader.usize()?, reader.u64()?)))?,
+            sstables: reader.vec(SstMeta::decode)?,
+        })
+    }
+}
+
+impl ManifestRecord {
+    /// Encode the record in the binary format. The records only read from JSON manifests are not
+    /// written any more.
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            ManifestRecord::NewMemtable(id) => {
+                buf.put_u8(RECORD_NEW_MEMTABLE);
+                buf.put_u64(*id as u64);
+            }
+            ManifestRecord::NewMemtableAtLsn(id, lsn) => {
+                buf.put_u8(RECORD_NEW_MEMTABLE_AT_LSN);
+                buf.put_u64(*id as u64);
+                buf.put_u64(*lsn);
+            }
+            ManifestRecord::FlushEdits(memtable_id, edits) => {
+                buf.put_u8(RECORD_FLUSH_EDITS);
+                buf.put_u64(*memtable_id as u64);
+                put_vec(buf, edits, |buf, edit| edit.encode(buf));
+            }
+            ManifestRecord::CompactionEdit(edit) => {
+                buf.put_u8(RECORD_COMPACTION_EDIT);
+                edit.encode(buf);
+            }
+            ManifestRecord::CreateColumnFamily(id, name, options) => {
+                buf.put_u8(RECORD_CREATE_COLUMN_FAMILY);
+                buf.put_u64(*id as u64);
+                put_bytes(buf, name.as_bytes());
+                encode_options(buf, options);
+            }
+            ManifestRecord::DropColumnFamily(id) => {
+                buf.put_u8(RECORD_DROP_COLUMN_FAMILY);
+                buf.put_u64(*id as u64);
+            }
+            ManifestRecord::NewBlobFile(column_family_id, blob_id) => {
+                buf.put_u8(RECORD_NEW_BLOB_FILE);
+                buf.put_u64(*column_family_id as u64);
+                buf.put_u64(*blob_id as u64);
+            }
+            ManifestRecord::BlobGarbage(column_family_id, garbage) => {
+                buf.put_u8(RECORD_BLOB_GARBAGE);
+                buf.put_u64(*column_family_id as u64);
+                put_vec(buf, garbage, |buf, (blob_id, size)| {
+                    buf.put_u64(*blob_id as u64);
+                    buf.put_u64(*size);
+                });
+            }
+            ManifestRecord::GcBlobFile(column_family_id, blob_id, new_blob_id) => {
+                buf.put_u8(RECORD_GC_BLOB_FILE);
+                buf.put_u64(*column_family_id as u64);
+                buf.put_u64(*blob_id as u64);
+                put_option(buf, new_blob_id, |buf, id| buf.put_u64(*id as u64));
+            }
+            ManifestRecord::Timestamps(commit_ts, gc_watermark) => {
+                buf.put_u8(RECORD_TIMESTAMPS);
+                buf.put_u64(*commit_ts);
+                buf.put_u64(*gc_watermark);
+            }
+            ManifestRecord::Snapshot(snapshot) => {
+                buf.put_u8(RECORD_SNAPSHOT);
+                put_vec(buf, &snapshot.column_families, |buf, column_family| {
+                    column_family.encode(buf)
+                });
+                put_vec(buf, &snapshot.memtables, |buf, (id, lsn)| {
+                    buf.put_u64(*id as u64);
+                    put_option(buf, lsn, |buf, lsn| buf.put_u64(*lsn));
+                });
+                buf.put_u64(snapshot.next_sst_id as u64);
+                buf.put_u64(snapshot.next_column_family_id as u64);
+                buf.put_u64(snapshot.commit_ts);
+                buf.put_u64(snapshot.gc_watermark);
+            }
+            ManifestRecord::Flush(_)
+            | ManifestRecord::Compaction(..)
+            | ManifestRecord::FlushColumnFamilies(..)
+            | ManifestRecord::ColumnFamilyCompaction(..) => {
+                unreachable!("records of JSON manifests are not written")
+            }
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        let mut reader = RecordReader { buf };
+        let record = match reader.u8()? {
+            RECORD_NEW_MEMTABLE => ManifestRecord::NewMemtable(reader.usize()?),
+            RECORD_NEW_MEMTABLE_AT_LSN => {
+                ManifestRecord::NewMemtableAtLsn(reader.usize()?, reader.u64()?)
+            }
+            RECORD_FLUSH_EDITS => {
+                ManifestRecord::FlushEdits(reader.usize()?, reader.vec(SstEdit::decode)?)
+            }
+            RECORD_COMPACTION_EDIT => ManifestRecord::CompactionEdit(SstEdit::decode(&mut reader)?),
+            RECORD_CREATE_COLUMN_FAMILY => ManifestRecord::CreateColumnFamily(
+                reader.usize()?,
+                reader.string()?,
+                decode_options(&mut reader)?,
+            ),
+            RECORD_DROP_COLUMN_FAMILY => ManifestRecord::DropColumnFamily(reader.usize()?),
+            RECORD_NEW_BLOB_FILE => ManifestRecord::NewBlobFile(reader.usize()?, reader.usize()?),
+            RECORD_BLOB_GARBAGE => ManifestRecord::BlobGarbage(
+                reader.usize()?,
+                reader.vec(|reader| Ok((reader.usize()?, reader.u64()?)))?,
+            ),
+            RECORD_GC_BLOB_FILE => ManifestRecord::GcBlobFile(
+                reader.usize()?,
+                reader.usize()?,
+                reader.option(RecordReader::usize)?,
+            ),
+            RECORD_TIMESTAMPS => ManifestRecord::Timestamps(reader.u64()?, reader.u64()?),
+            RECORD_SNAPSHOT => ManifestRecord::Snapshot(ManifestSnapshot {
+                column_families: reader.vec(ColumnFamilySnapshot::decode)?,
+                memtables: reader
+                    .vec(|reader| Ok((reader.usize()?, reader.option(RecordReader::u64)?)))?,
+                next_sst_id: reader.usize()?,
+                next_column_family_id: reader.usize()?,
+                commit_ts: reader.u64()?,
+                gc_watermark: reader.u64()?,
+            }),
+            x => bail!("unknown manifest record type {}", x),
+        };
+        if reader.buf.has_remaining() {
+            bail!("manifest record has trailing bytes");
+        }
+        Ok(record)
+    }
 }
 
 impl Manifest {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    fn path_of_manifest(dir: &Path, number: u64) -> PathBuf {
+        match number {
+            0 => dir.join("MANIFEST"),
+            number => dir.join(format!("MANIFEST-{:06}", number)),
+        }
+    }
+
+    /// The path of the manifest file in use.
+    pub fn path(&self) -> PathBuf {
+        Self::path_of_manifest(&self.dir, self.file.lock().number)
+    }
+
+    /// Whether the DB in `dir` has a manifest.
+    pub fn exists(dir: impl AsRef<Path>) -> bool {
+        let dir = dir.as_ref();
+        dir.join("CURRENT").exists() || Self::path_of_manifest(dir, 0).exists()
+    }
+
+    pub fn create(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let file = Self::create_file(dir, 1)?;
+        Self::set_current(dir, 1)?;
         Ok(Self {
-            file: Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create manifest")?,
-            )),
+            dir: dir.to_path_buf(),
+            file: Arc::new(Mutex::new(ManifestFile {
+                file,
+                number: 1,
+                version: MANIFEST_FORMAT_VERSION,
+                size: MANIFEST_HEADER_SIZE as u64,
+                snapshot_size: 0,
+            })),
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+    /// Create the manifest file with the number and write the header. A file left by a rotation
+    /// that did not finish is overwritten.
+    fn create_file(dir: &Path, number: u64) -> Result<File> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(Self::path_of_manifest(dir, number))
+            .context("failed to create manifest")?;
+        let mut buf = Vec::with_capacity(MANIFEST_HEADER_SIZE);
+        buf.put_u32(MANIFEST_MAGIC);
+        buf.put_u32(MANIFEST_FORMAT_VERSION);
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        Ok(file)
+    }
+
+    /// Point the `CURRENT` file to the manifest with the number, by renaming a new file over it.
+    fn set_current(dir: &Path, number: u64) -> Result<()> {
+        let tmp_path = dir.join("CURRENT.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(format!("MANIFEST-{:06}\n", number).as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, dir.join("CURRENT"))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn recover(dir: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+        let dir = dir.as_ref();
+        let number = match std::fs::read_to_string(dir.join("CURRENT")) {
+            Ok(current) => current
+                .trim_end()
+                .strip_prefix("MANIFEST-")
+                .and_then(|number| number.parse().ok())
+                .with_context(|| format!("invalid CURRENT file: {:?}", current))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
         let mut file = OpenOptions::new()
             .read(true)
             .append(true)
-            .open(path)
+            .open(Self::path_of_manifest(dir, number))
             .context("failed to recover manifest")?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
         let mut buf_ptr = buf.as_slice();
+        let version = if buf.len() >= MANIFEST_HEADER_SIZE && buf_ptr.get_u32() == MANIFEST_MAGIC {
+            let version = buf_ptr.get_u32();
+            if version > MANIFEST_FORMAT_VERSION {
+                bail!("unsupported manifest format version {}", version);
+            }
+            version
+        } else {
+            buf_ptr = buf.as_slice();
+            MANIFEST_FORMAT_V1
+        };
         let mut records = Vec::new();
+        let mut snapshot_size = 0;
         while buf_ptr.has_remaining() {
-            let len = buf_ptr.get_u64();
-            let slice = &buf_ptr[..len as usize];
-            let json = serde_json::from_slice::<ManifestRecord>(slice)?;
-            buf_ptr.advance(len as usize);
-            let checksum = buf_ptr.get_u32();
-            if checksum != crc32fast::hash(slice) {
-                bail!("checksum mismatched!");
-            }
-            records.push(json);
+            let record = if version == MANIFEST_FORMAT_V1 {
+                let len = buf_ptr.get_u64();
+                let slice = &buf_ptr[..len as usize];
+                let json = serde_json::from_slice::<ManifestRecord>(slice)?;
+                buf_ptr.advance(len as usize);
+                let checksum = buf_ptr.get_u32();
+                if checksum != crc32fast::hash(slice) {
+                    bail!("checksum mismatched!");
+                }
+                json
+            } else {
+                let mut reader = RecordReader { buf: buf_ptr };
+                match reader.payload_and_checksum() {
+                    Some((slice, checksum)) if checksum == crc32fast::hash(slice) => {
+                        buf_ptr = reader.buf;
+                        ManifestRecord::decode(slice)?
+                    }
+                    Some(_) if reader.buf.has_remaining() => bail!("checksum mismatched!"),
+                    _ => {
+                        // The last record is torn by a crash while it is written. It is removed,
+                        // so that the records added after recovery follow the ones recovered.
+                        let len = buf.len() - buf_ptr.len();
+                        println!("manifest truncated at the torn record at offset {}", len);
+                        file.set_len(len as u64)?;
+                        file.sync_all()?;
+                        buf.truncate(len);
+                        break;
+                    }
+                }
+            };
+            if matches!(record, ManifestRecord::Snapshot(_)) {
+                snapshot_size = (buf.len() - buf_ptr.len()) as u64;
+            }
+            records.push(record);
         }
         Ok((
             Self {
-                file: Arc::new(Mutex::new(file)),
+                dir: dir.to_path_buf(),
+                file: Arc::new(Mutex::new(ManifestFile {
+                    file,
+                    number,
+                    version,
+                    size: buf.len() as u64,
+                    snapshot_size,
+                })),
             },
             records,
         ))
@@ -74,12 +712,67 @@ impl Manifest {
 
     pub fn add_record_when_init(&self, record: ManifestRecord) -> Result<()> {
         let mut file = self.file.lock();
-        let mut buf = serde_json::to_vec(&record)?;
-        let hash = crc32fast::hash(&buf);
-        file.write_all(&(buf.len() as u64).to_be_bytes())?;
-        buf.put_u32(hash);
+        if file.version != MANIFEST_FORMAT_VERSION {
+            bail!(
+                "manifest in format v{} must be rotated before it is written",
+                file.version
+            );
+        }
+        file.size += Self::write_record(&mut file.file, &record)?;
+        Ok(())
+    }
+
+    /// Write a record and sync it. Returns the size of the record.
+    fn write_record(file: &mut File, record: &ManifestRecord) -> Result<u64> {
+        let mut body = Vec::new();
+        record.encode(&mut body);
+        let mut buf = Vec::with_capacity(body.len() + std::mem::size_of::<u32>() * 2);
+        buf.put_u32(body.len() as u32);
+        buf.put_slice(&body);
+        buf.put_u32(crc32fast::hash(&body));
         file.write_all(&buf)?;
         file.sync_all()?;
+        Ok(buf.len() as u64)
+    }
+
+    /// Whether the manifest is in an older format, and must be rotated before a record is added.
+    pub fn needs_upgrade(&self) -> bool {
+        self.file.lock().version != MANIFEST_FORMAT_VERSION
+    }
+
+    /// Whether the manifest is larger than `max_size`, and twice as large as the snapshot it starts
+    /// with, so that a large state is not written again for every record.
+    pub fn should_rotate(&self, max_size: usize) -> bool {
+        let file = self.file.lock();
+        file.size > (max_size as u64).max(file.snapshot_size * 2)
+    }
+
+    /// Write the snapshot of the state into a new manifest, switch to it and remove the old one.
+    /// The state must not change until it is recorded, which is ensured by the state lock.
+    pub fn rotate(
+        &self,
+        _state_lock_observer: &MutexGuard<()>,
+        snapshot: ManifestSnapshot,
+    ) -> Result<()> {
+        self.rotate_when_init(snapshot)
+    }
+
+    pub fn rotate_when_init(&self, snapshot: ManifestSnapshot) -> Result<()> {
+        let mut file = self.file.lock();
+        let number = file.number + 1;
+        let mut new_file = Self::create_file(&self.dir, number)?;
+        let size = MANIFEST_HEADER_SIZE as u64
+            + Self::write_record(&mut new_file, &ManifestRecord::Snapshot(snapshot))?;
+        Self::set_current(&self.dir, number)?;
+        let old_number = file.number;
+        *file = ManifestFile {
+            file: new_file,
+            number,
+            version: MANIFEST_FORMAT_VERSION,
+            size,
+            snapshot_size: size,
+        };
+        std::fs::remove_file(Self::path_of_manifest(&self.dir, old_number))?;
         Ok(())
     }
 }
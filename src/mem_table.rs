@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::ops::Bound;
 use std::path::Path;
 use std::sync::atomic::AtomicUsize;
@@ -8,11 +9,16 @@ use bytes::Bytes;
 use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
+use parking_lot::RwLock;
 
-use crate::iterators::StorageIterator;
+use crate::blob::BlobFileBuilder;
+use crate::column_family::DEFAULT_COLUMN_FAMILY_ID;
+use crate::iterators::{Direction, StorageIterator};
 use crate::key::{KeyBytes, KeySlice, TS_DEFAULT};
+use crate::range_tombstone::RangeTombstone;
 use crate::table::SsTableBuilder;
-use crate::wal::Wal;
+use crate::value::Value;
+use crate::wal::{TxnRecord, Wal, WalMemTable, WalRecoveryMode};
 
 /// A basic mem-table based on crossbeam-skiplist.
 ///
@@ -20,7 +26,12 @@ use crate::wal::Wal;
 /// chapters of week 1 and week 2.
 pub struct MemTable {
     pub(crate) map: Arc<SkipMap<KeyBytes, Bytes>>,
-    wal: Option<Wal>,
+    range_tombstones: RwLock<Vec<RangeTombstone>>,
+    /// The WAL, which may be shared with the memtables of other column families.
+    wal: Option<Arc<Wal>>,
+    /// The LSN of the first record of the memtable in the WAL.
+    first_lsn: u64,
+    column_family_id: usize,
     id: usize,
     approximate_size: Arc<AtomicUsize>,
 }
@@ -64,30 +75,75 @@ impl MemTable {
         Self {
             id,
             map: Arc::new(SkipMap::new()),
+            range_tombstones: RwLock::new(Vec::new()),
             wal: None,
+            first_lsn: 0,
+            column_family_id: DEFAULT_COLUMN_FAMILY_ID,
             approximate_size: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    /// Create a new mem-table with WAL
-    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self {
+    /// Create a new mem-table of a column family with a WAL shared by the column families, whose
+    /// records start at `first_lsn` of the WAL.
+    pub fn create_with_shared_wal(
+        id: usize,
+        column_family_id: usize,
+        wal: Arc<Wal>,
+        first_lsn: u64,
+    ) -> Self {
+        Self::from_recovered(
             id,
-            map: Arc::new(SkipMap::new()),
-            wal: Some(Wal::create(path.as_ref())?),
-            approximate_size: Arc::new(AtomicUsize::new(0)),
-        })
+            column_family_id,
+            (Arc::new(SkipMap::new()), Vec::new()),
+            Some(wal),
+            first_lsn,
+        )
     }
 
-    /// Create a memtable from WAL
-    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
-        let map = Arc::new(SkipMap::new());
-        Ok(Self {
+    /// Create a mem-table of a column family from the records recovered from a WAL.
+    pub(crate) fn from_recovered(
+        id: usize,
+        column_family_id: usize,
+        (map, range_tombstones): WalMemTable,
+        wal: Option<Arc<Wal>>,
+        first_lsn: u64,
+    ) -> Self {
+        Self {
             id,
-            wal: Some(Wal::recover(path.as_ref(), &map)?),
             map,
+            range_tombstones: RwLock::new(range_tombstones),
+            wal,
+            first_lsn,
+            column_family_id,
             approximate_size: Arc::new(AtomicUsize::new(0)),
-        })
+        }
+    }
+
+    /// Create the memtables of the column families from the WAL file of one memtable, which is
+    /// written before the WAL is shared by the memtables, keyed by the column family id. The
+    /// memtables have no WAL, as the file is only removed once they are flushed. The transaction
+    /// records in the WAL are added to `txn_records`. Also returns whether the later WALs must be
+    /// discarded, see `Wal::recover_file_of_memtable`.
+    pub fn recover_column_families_from_wal(
+        id: usize,
+        path: impl AsRef<Path>,
+        column_family_ids: &[usize],
+        txn_records: &mut Vec<TxnRecord>,
+        mode: WalRecoveryMode,
+    ) -> Result<(HashMap<usize, Self>, bool)> {
+        let mut recovered = column_family_ids
+            .iter()
+            .map(|column_family_id| (*column_family_id, (Arc::new(SkipMap::new()), Vec::new())))
+            .collect::<HashMap<_, _>>();
+        let stopped = Wal::recover_file_of_memtable(path, &mut recovered, txn_records, mode)?;
+        let memtables = recovered
+            .into_iter()
+            .map(|(column_family_id, recovered)| {
+                let memtable = Self::from_recovered(id, column_family_id, recovered, None, 0);
+                (column_family_id, memtable)
+            })
+            .collect();
+        Ok((memtables, stopped))
     }
 
     /// Get a value by key. Should not be used in week 3.
@@ -123,6 +179,11 @@ impl MemTable {
     /// In week 1, day 1, simply put the key-value pair into the skipmap.
     /// In week 2, day 6, also flush the data to WAL.
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
+        self.put_with_wal(key, value, true)
+    }
+
+    /// Put a key-value pair into the mem-table, and write it to the WAL only if `write_wal` is set.
+    pub(crate) fn put_with_wal(&self, key: KeySlice, value: &[u8], write_wal: bool) -> Result<()> {
         let estimated_size = key.raw_len() + value.len();
         self.map.insert(
             key.to_key_vec().into_key_bytes(),
@@ -130,12 +191,45 @@ impl MemTable {
         );
         self.approximate_size
             .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
-        if let Some(ref wal) = self.wal {
-            wal.put(key, value)?;
+        if let Some(wal) = self.wal.as_ref().filter(|_| write_wal) {
+            wal.put(self.column_family_id, key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Put a range tombstone into the mem-table, which deletes the keys in `[start, end)` written
+    /// before `ts`.
+    pub fn put_range_tombstone(&self, start: &[u8], end: &[u8], ts: u64) -> Result<()> {
+        self.put_range_tombstone_with_wal(start, end, ts, true)
+    }
+
+    pub(crate) fn put_range_tombstone_with_wal(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        ts: u64,
+        write_wal: bool,
+    ) -> Result<()> {
+        let range_tombstone = RangeTombstone::new(
+            Bytes::copy_from_slice(start),
+            Bytes::copy_from_slice(end),
+            ts,
+        );
+        let estimated_size = range_tombstone.raw_len();
+        self.range_tombstones.write().push(range_tombstone.clone());
+        self.approximate_size
+            .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
+        if let Some(wal) = self.wal.as_ref().filter(|_| write_wal) {
+            wal.put_range_tombstone(self.column_family_id, &range_tombstone)?;
         }
         Ok(())
     }
 
+    /// Get all range tombstones in the mem-table.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().clone()
+    }
+
     pub fn sync_wal(&self) -> Result<()> {
         if let Some(ref wal) = self.wal {
             wal.sync()?;
@@ -145,14 +239,37 @@ impl MemTable {
 
     /// Get an iterator over a range of keys.
     pub fn scan(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
+        self.scan_with_direction(lower, upper, Direction::Forward)
+    }
+
+    /// Get an iterator over a range of keys, positioned at the last key in the range.
+    pub fn scan_rev(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
+        self.scan_with_direction(lower, upper, Direction::Backward)
+    }
+
+    pub(crate) fn scan_with_direction(
+        &self,
+        lower: Bound<KeySlice>,
+        upper: Bound<KeySlice>,
+        direction: Direction,
+    ) -> MemTableIterator {
         let (lower, upper) = (map_key_bound(lower), map_key_bound(upper));
+        let range = (lower.clone(), upper.clone());
         let mut iter = MemTableIteratorBuilder {
             map: self.map.clone(),
-            iter_builder: |map| map.range((lower, upper)),
+            iter_builder: |map| map.range(range),
             item: (KeyBytes::new(), Bytes::new()),
+            lower,
+            upper,
+            direction,
         }
         .build();
-        let entry = iter.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
+        let entry = iter.with_iter_mut(|iter| {
+            MemTableIterator::entry_to_item(match direction {
+                Direction::Forward => iter.next(),
+                Direction::Backward => iter.next_back(),
+            })
+        });
         iter.with_mut(|x| *x.item = entry);
         iter
     }
@@ -162,6 +279,33 @@ impl MemTable {
         for entry in self.map.iter() {
             builder.add(entry.key().as_key_slice(), &entry.value()[..]);
         }
+        for range_tombstone in self.range_tombstones.read().iter() {
+            builder.add_range_tombstone(range_tombstone.clone());
+        }
+        Ok(())
+    }
+
+    /// Flush the memtable to SST, and move the values of puts that are at least `min_blob_size`
+    /// bytes into the blob file.
+    pub fn flush_with_blob_file(
+        &self,
+        builder: &mut SsTableBuilder,
+        blob_builder: &mut BlobFileBuilder,
+        min_blob_size: usize,
+    ) -> Result<()> {
+        for entry in self.map.iter() {
+            let key = entry.key().as_key_slice();
+            match Value::decode(entry.value())? {
+                Value::Put(value) if value.len() >= min_blob_size => {
+                    let pointer = blob_builder.add(key, value);
+                    builder.add(key, &Value::Blob(pointer).encode());
+                }
+                _ => builder.add(key, &entry.value()[..]),
+            }
+        }
+        for range_tombstone in self.range_tombstones.read().iter() {
+            builder.add_range_tombstone(range_tombstone.clone());
+        }
         Ok(())
     }
 
@@ -169,6 +313,14 @@ impl MemTable {
         self.id
     }
 
+    pub(crate) fn wal(&self) -> Option<&Arc<Wal>> {
+        self.wal.as_ref()
+    }
+
+    pub fn first_lsn(&self) -> u64 {
+        self.first_lsn
+    }
+
     pub fn approximate_size(&self) -> usize {
         self.approximate_size
             .load(std::sync::atomic::Ordering::Relaxed)
@@ -176,7 +328,7 @@ impl MemTable {
 
     /// Only use this function when closing the database
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.map.is_empty() && self.range_tombstones.read().is_empty()
     }
 }
 
@@ -202,6 +354,10 @@ pub struct MemTableIterator {
     iter: SkipMapRangeIter<'this>,
     /// Stores the current key-value pair.
     item: (KeyBytes, Bytes),
+    /// The range of the iterator, used to re-create the skipmap iterator when changing direction.
+    lower: Bound<KeyBytes>,
+    upper: Bound<KeyBytes>,
+    direction: Direction,
 }
 
 impl MemTableIterator {
@@ -210,6 +366,37 @@ impl MemTableIterator {
             .map(|x| (x.key().clone(), x.value().clone()))
             .unwrap_or_else(|| (KeyBytes::new(), Bytes::new()))
     }
+
+    /// Re-create the skipmap iterator so that it covers the keys on the `direction` side of the
+    /// current key. If the iterator is invalid, it covers the whole range.
+    fn switch_direction(&mut self, direction: Direction) {
+        let current = &self.borrow_item().0;
+        let (lower, upper) = match (direction, current.is_empty()) {
+            (_, true) => (self.borrow_lower().clone(), self.borrow_upper().clone()),
+            (Direction::Forward, false) => (
+                Bound::Excluded(current.clone()),
+                self.borrow_upper().clone(),
+            ),
+            (Direction::Backward, false) => (
+                self.borrow_lower().clone(),
+                Bound::Excluded(current.clone()),
+            ),
+        };
+        self.with_mut(|x| {
+            *x.iter = x.map.range((lower, upper));
+            *x.direction = direction;
+        });
+    }
+
+    /// Re-create the skipmap iterator to move forward from `lower`, and move to the first key.
+    fn seek_to_lower(&mut self, lower: Bound<KeyBytes>) {
+        let upper = self.borrow_upper().clone();
+        self.with_mut(|x| {
+            *x.iter = x.map.range((lower, upper));
+            *x.direction = Direction::Forward;
+            *x.item = MemTableIterator::entry_to_item(x.iter.next());
+        });
+    }
 }
 
 impl StorageIterator for MemTableIterator {
@@ -228,8 +415,40 @@ impl StorageIterator for MemTableIterator {
     }
 
     fn next(&mut self) -> Result<()> {
+        if *self.borrow_direction() == Direction::Backward {
+            self.switch_direction(Direction::Forward);
+        }
         let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
         self.with_mut(|x| *x.item = entry);
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if *self.borrow_direction() == Direction::Forward {
+            self.switch_direction(Direction::Backward);
+        }
+        let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next_back()));
+        self.with_mut(|x| *x.item = entry);
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.seek_to_lower(self.borrow_lower().clone());
+        Ok(())
+    }
+
+    fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        // Never move before the lower bound of the scan.
+        let lower = match self.borrow_lower() {
+            Bound::Included(lower) | Bound::Excluded(lower) if key <= lower.as_key_slice() => {
+                self.borrow_lower().clone()
+            }
+            _ => Bound::Included(KeyBytes::from_bytes_with_ts(
+                Bytes::copy_from_slice(key.key_ref()),
+                key.ts(),
+            )),
+        };
+        self.seek_to_lower(lower);
+        Ok(())
+    }
 }
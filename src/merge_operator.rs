@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+
+use crate::value::Value;
+
+/// Combines the merge operands written with `WriteBatchRecord::Merge` into a value. Operands are
+/// stored as they are written, and only combined when the key is read or compacted.
+pub trait MergeOperator: Send + Sync {
+    /// The name of the operator.
+    fn name(&self) -> &str;
+
+    /// Apply the operands, ordered from the earliest to the latest, to the existing value of the
+    /// key, which is `None` if the key does not exist. An empty result deletes the key.
+    fn full_merge(&self, key: &[u8], existing_value: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8>;
+}
+
+impl std::fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MergeOperator({})", self.name())
+    }
+}
+
+/// Append an operand to the payload of a merge value.
+fn append_operand(buf: &mut Vec<u8>, operand: &[u8]) {
+    buf.put_u32(operand.len() as u32);
+    buf.put_slice(operand);
+}
+
+/// Decode the operands in the payload of a merge value.
+pub(crate) fn decode_operands<'a>(mut payload: &'a [u8], operands: &mut Vec<&'a [u8]>) {
+    while payload.has_remaining() {
+        let len = payload.get_u32() as usize;
+        operands.push(&payload[..len]);
+        payload.advance(len);
+    }
+}
+
+/// Apply the payloads of merge values, ordered from the earliest to the latest, to the existing
+/// value of the key.
+pub(crate) fn full_merge(
+    merge_operator: Option<&Arc<dyn MergeOperator>>,
+    key: &[u8],
+    existing_value: Option<&[u8]>,
+    payloads: &[impl AsRef<[u8]>],
+) -> Result<Vec<u8>> {
+    let Some(merge_operator) = merge_operator else {
+        bail!("merge operator is not set");
+    };
+    let mut operands = Vec::new();
+    for payload in payloads {
+        decode_operands(payload.as_ref(), &mut operands);
+    }
+    Ok(merge_operator.full_merge(key, existing_value, &operands))
+}
+
+/// Apply the payloads of merge values, ordered from the earliest to the latest, to a value that is
+/// not a merge value, and return the encoded result. An expired value should be passed as
+/// `Value::Delete`. The result expires with the value it is merged into, so the operands should be
+/// written with the same timestamp as the value, otherwise they would outlive it.
+pub(crate) fn merge_into_value(
+    merge_operator: Option<&Arc<dyn MergeOperator>>,
+    key: &[u8],
+    existing_value: Value,
+    payloads: &[impl AsRef<[u8]>],
+) -> Result<Vec<u8>> {
+    let (expire_at, existing_value) = match existing_value {
+        Value::Delete => (None, None),
+        Value::Put(value) => (None, Some(value)),
+        Value::PutWithTtl(expire_at, value) => (Some(expire_at), Some(value)),
+        Value::Merge(_) => bail!("cannot merge into a merge value"),
+        Value::Blob(_) => bail!("cannot merge into a value in a blob file"),
+    };
+    let value = full_merge(merge_operator, key, existing_value, payloads)?;
+    if value.is_empty() {
+        return Ok(Vec::new());
+    }
+    match expire_at {
+        Some(expire_at) => Ok(Value::PutWithTtl(expire_at, &value).encode()),
+        None => Ok(Value::Put(&value).encode()),
+    }
+}
+
+/// Apply an operand to a value written with the same timestamp, which is `None` if the key has not
+/// been written, and return the encoded value that replaces it. An expired value should be passed
+/// as `Value::Delete`.
+pub(crate) fn merge_value(
+    merge_operator: Option<&Arc<dyn MergeOperator>>,
+    key: &[u8],
+    existing_value: Option<Value>,
+    operand: &[u8],
+) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    append_operand(&mut payload, operand);
+    match existing_value {
+        None => Ok(Value::Merge(&payload).encode()),
+        Some(Value::Merge(existing_payload)) => {
+            let mut existing_payload = existing_payload.to_vec();
+            existing_payload.extend(payload);
+            Ok(Value::Merge(&existing_payload).encode())
+        }
+        Some(existing_value) => merge_into_value(merge_operator, key, existing_value, &[payload]),
+    }
+}
@@ -1,43 +1,184 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
+pub mod conflict;
+pub mod lock_manager;
+pub mod snapshot;
 pub mod txn;
 pub mod watermark;
 
 use std::{
-    collections::{BTreeMap, HashSet},
-    sync::{atomic::AtomicBool, Arc},
+    collections::{BTreeMap, HashSet, VecDeque},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
 use crossbeam_skiplist::SkipMap;
 use parking_lot::Mutex;
 
-use crate::lsm_storage::LsmStorageInner;
+use crate::{lsm_storage::LsmStorageInner, value::now_millis, wal::TxnRecord};
 
-use self::{txn::Transaction, watermark::Watermark};
+use self::{
+    conflict::{KeyRange, KeySet},
+    lock_manager::LockManager,
+    snapshot::Snapshot,
+    txn::{Transaction, TXN_ACTIVE},
+    watermark::Watermark,
+};
+
+/// How the commit of a transaction is checked against the transactions committed after it started.
+/// Reads always see the snapshot at the start of the transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// No check, so an update may be lost if a concurrent transaction writes the same key.
+    SnapshotReads,
+    /// Reject the commit if a key it writes is written by a transaction committed after it started.
+    /// Writes outside transactions and the commits of transactions without checks are checked
+    /// against as well.
+    SnapshotIsolation,
+    /// Reject the commit if a key it reads is written by a transaction committed after it started.
+    Serializable,
+}
 
 pub(crate) struct CommittedTxnData {
-    pub(crate) key_hashes: HashSet<u32>,
+    pub(crate) write_set: KeySet,
+    /// The `[start, end)` ranges deleted.
+    pub(crate) range_deletes: Vec<KeyRange>,
     #[allow(dead_code)]
     pub(crate) read_ts: u64,
-    #[allow(dead_code)]
     pub(crate) commit_ts: u64,
 }
 
+/// A transaction prepared with two-phase commit, which is kept until it is committed or rolled back,
+/// including across restarts.
+pub(crate) struct PreparedTxn {
+    /// The values written, encoded as in the memtables.
+    pub(crate) writes: Vec<(Bytes, Bytes)>,
+    /// The `[start, end)` ranges deleted.
+    pub(crate) range_deletes: Vec<(Bytes, Bytes)>,
+    pub(crate) read_ts: u64,
+    /// The keys written, which the transactions with conflict checks cannot commit writes to until
+    /// this one is resolved.
+    pub(crate) write_set: KeySet,
+    /// The keys and ranges read in serializable mode, checked against in the same way. They are
+    /// not logged, so a recovered transaction only holds its writes.
+    pub(crate) read_set: KeySet,
+    pub(crate) read_ranges: Vec<KeyRange>,
+}
+
+impl PreparedTxn {
+    pub(crate) fn new(
+        writes: Vec<(Bytes, Bytes)>,
+        range_deletes: Vec<(Bytes, Bytes)>,
+        read_ts: u64,
+        max_key_set_size: usize,
+    ) -> Self {
+        let mut write_set = KeySet::default();
+        for (key, _) in &writes {
+            write_set.insert(key, max_key_set_size);
+        }
+        Self {
+            writes,
+            range_deletes,
+            read_ts,
+            write_set,
+            read_set: KeySet::default(),
+            read_ranges: Vec::new(),
+        }
+    }
+
+    pub(crate) fn key_ranges(&self) -> Vec<KeyRange> {
+        self.range_deletes
+            .iter()
+            .map(|(start, end)| (Bound::Included(start.clone()), Bound::Excluded(end.clone())))
+            .collect()
+    }
+
+    pub(crate) fn record(&self, xid: &str) -> TxnRecord {
+        TxnRecord::Prepare {
+            xid: xid.to_string(),
+            writes: self.writes.clone(),
+            range_deletes: self.range_deletes.clone(),
+        }
+    }
+}
+
+/// The number of the low bits of a hybrid logical clock timestamp, which count the commits in the
+/// same millisecond. The high bits are the wall-clock time in milliseconds since the Unix epoch.
+pub const HLC_LOGICAL_BITS: u32 = 16;
+
+/// The first hybrid logical clock timestamp at a wall-clock time in milliseconds.
+pub fn hlc_ts(millis: u64) -> u64 {
+    millis << HLC_LOGICAL_BITS
+}
+
+/// The wall-clock time in milliseconds of a hybrid logical clock timestamp.
+pub fn hlc_millis(ts: u64) -> u64 {
+    ts >> HLC_LOGICAL_BITS
+}
+
+/// Keeps the versions of keys that are needed to read at past timestamps, in addition to the ones
+/// needed by transactions and snapshots. A version is kept if either condition holds.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryRetention {
+    /// Keep the versions needed to read at the last `timestamps` commit timestamps. With the hybrid
+    /// clock, the commit timestamps are not consecutive, and this counts the timestamps instead.
+    pub timestamps: Option<u64>,
+    /// Keep the versions needed to read at the commit timestamps of the last `duration`.
+    pub duration: Option<Duration>,
+}
+
 pub(crate) struct LsmMvccInner {
     pub(crate) write_lock: Mutex<()>,
     pub(crate) commit_lock: Mutex<()>,
     pub(crate) ts: Arc<Mutex<(u64, Watermark)>>,
     pub(crate) committed_txns: Arc<Mutex<BTreeMap<u64, CommittedTxnData>>>,
+    pub(crate) retention: HistoryRetention,
+    /// The commit ts and the wall-clock time of the commits in the retention duration, and of the
+    /// last commit before it. The data recovered on startup is treated as committed on startup.
+    /// They are not recorded with the hybrid clock, whose timestamps are the time.
+    pub(crate) commit_times: Mutex<VecDeque<(u64, u64)>>,
+    hybrid_clock: bool,
+    /// The watermark of compaction before the storage is opened. The versions below it may have
+    /// been removed, even if the history retention keeps them now.
+    recovered_gc_watermark: u64,
+    pub(crate) lock_manager: LockManager,
+    next_txn_id: AtomicU64,
+    /// The number of the transactions checking for conflicts that are not dropped. Changed with the
+    /// ts lock held.
+    conflict_checking_txns: AtomicUsize,
+    /// The prepared transactions by id. Their records are logged again for every new memtable, so
+    /// that they survive the removal of the WAL records that are flushed.
+    pub(crate) prepared_txns: Mutex<BTreeMap<String, PreparedTxn>>,
 }
 
 impl LsmMvccInner {
-    pub fn new(initial_ts: u64) -> Self {
+    pub fn new(
+        initial_ts: u64,
+        retention: HistoryRetention,
+        hybrid_clock: bool,
+        recovered_gc_watermark: u64,
+    ) -> Self {
         Self {
             write_lock: Mutex::new(()),
             commit_lock: Mutex::new(()),
             ts: Arc::new(Mutex::new((initial_ts, Watermark::new()))),
             committed_txns: Arc::new(Mutex::new(BTreeMap::new())),
+            retention,
+            commit_times: Mutex::new(VecDeque::from([(initial_ts, now_millis())])),
+            hybrid_clock,
+            recovered_gc_watermark,
+            lock_manager: LockManager::default(),
+            next_txn_id: AtomicU64::new(0),
+            conflict_checking_txns: AtomicUsize::new(0),
+            prepared_txns: Mutex::new(BTreeMap::new()),
         }
     }
 
@@ -45,8 +186,31 @@ impl LsmMvccInner {
         self.ts.lock().0
     }
 
+    /// The ts of the next commit, which is the wall-clock time with the hybrid clock unless there
+    /// are later commits. Must be called with the write lock held.
+    pub fn next_commit_ts(&self) -> u64 {
+        let ts = self.latest_commit_ts() + 1;
+        if self.hybrid_clock {
+            ts.max(hlc_ts(now_millis()))
+        } else {
+            ts
+        }
+    }
+
     pub fn update_commit_ts(&self, ts: u64) {
         self.ts.lock().0 = ts;
+        if self.hybrid_clock {
+            return;
+        }
+        if let Some(duration) = self.retention.duration {
+            let now = now_millis();
+            let mut commit_times = self.commit_times.lock();
+            commit_times.push_back((ts, now));
+            while commit_times.len() > 1 && commit_times[1].1 + (duration.as_millis() as u64) <= now
+            {
+                commit_times.pop_front();
+            }
+        }
     }
 
     /// All ts (strictly) below this ts can be garbage collected.
@@ -55,20 +219,143 @@ impl LsmMvccInner {
         ts.1.watermark().unwrap_or(ts.0)
     }
 
-    pub fn new_txn(&self, inner: Arc<LsmStorageInner>, serializable: bool) -> Arc<Transaction> {
+    /// The watermark of compaction, which also keeps the history in the retention window. Reads
+    /// at or above this ts return the same results as when the ts was the latest commit ts.
+    pub fn gc_watermark(&self) -> u64 {
+        let ts = self.ts.lock();
+        self.gc_watermark_locked(&ts)
+    }
+
+    fn gc_watermark_locked(&self, ts: &(u64, Watermark)) -> u64 {
+        let mut watermark = ts.1.watermark().unwrap_or(ts.0);
+        if let Some(timestamps) = self.retention.timestamps {
+            watermark = watermark.min(ts.0.saturating_sub(timestamps));
+        }
+        if let Some(duration) = self.retention.duration {
+            let start = now_millis().saturating_sub(duration.as_millis() as u64);
+            if self.hybrid_clock {
+                return watermark.min(hlc_ts(start));
+            }
+            // The latest commit ts at the start of the window.
+            let commit_times = self.commit_times.lock();
+            let (commit_ts, _) = commit_times
+                .iter()
+                .take_while(|(_, time)| *time <= start)
+                .last()
+                .unwrap_or(&commit_times[0]);
+            watermark = watermark.min(*commit_ts);
+        }
+        watermark
+    }
+
+    /// Record the write set of a committed transaction for the conflict checks. Must be called
+    /// with the commit lock held.
+    pub(crate) fn add_committed_txn(&self, txn_data: CommittedTxnData) {
+        let mut committed_txns = self.committed_txns.lock();
+        let old_data = committed_txns.insert(txn_data.commit_ts, txn_data);
+        assert!(old_data.is_none());
+
+        // remove unneeded txn data
+        let watermark = self.watermark();
+        while let Some(entry) = committed_txns.first_entry() {
+            if *entry.key() < watermark {
+                entry.remove();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Whether a transaction checking for conflicts may have started before the latest commit, so
+    /// that the keys written by the commit must be recorded for the checks. Must be called after the
+    /// commit ts is updated.
+    pub(crate) fn has_conflict_checking_txns(&self) -> bool {
+        let _ts = self.ts.lock();
+        self.conflict_checking_txns.load(Ordering::Relaxed) > 0
+    }
+
+    /// Release the read ts of a transaction when it is dropped.
+    pub(crate) fn remove_txn(&self, read_ts: u64, conflict_checking: bool) {
+        let mut ts = self.ts.lock();
+        if conflict_checking {
+            self.conflict_checking_txns.fetch_sub(1, Ordering::Relaxed);
+        }
+        ts.1.remove_reader(read_ts);
+    }
+
+    pub fn new_txn(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        isolation: IsolationLevel,
+    ) -> Arc<Transaction> {
+        self.create_txn(inner, isolation, false)
+    }
+
+    /// Create a transaction that locks the keys it writes instead of checking for conflicts.
+    pub fn new_pessimistic_txn(&self, inner: Arc<LsmStorageInner>) -> Arc<Transaction> {
+        self.create_txn(inner, IsolationLevel::SnapshotReads, true)
+    }
+
+    fn create_txn(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        isolation: IsolationLevel,
+        pessimistic: bool,
+    ) -> Arc<Transaction> {
         let mut ts = self.ts.lock();
         let read_ts = ts.0;
         ts.1.add_reader(read_ts);
+        if isolation != IsolationLevel::SnapshotReads {
+            self.conflict_checking_txns.fetch_add(1, Ordering::Relaxed);
+        }
         Arc::new(Transaction {
             inner,
             read_ts,
             local_storage: Arc::new(SkipMap::new()),
-            committed: Arc::new(AtomicBool::new(false)),
-            key_hashes: if serializable {
-                Some(Mutex::new((HashSet::new(), HashSet::new())))
+            local_range_tombstones: Arc::new(Mutex::new(Vec::new())),
+            state: AtomicU8::new(TXN_ACTIVE),
+            isolation,
+            read_ranges: Mutex::new(Vec::new()),
+            key_sets: if isolation != IsolationLevel::SnapshotReads {
+                Some(Mutex::new((KeySet::default(), KeySet::default())))
             } else {
                 None
             },
+            id: self.next_txn_id.fetch_add(1, Ordering::Relaxed),
+            pessimistic,
+            locked_keys: Mutex::new(HashSet::new()),
+            savepoints: Mutex::new(Vec::new()),
+            xid: Mutex::new(None),
         })
     }
+
+    /// Create a read-only snapshot at the latest commit ts, which holds back the watermark until it
+    /// is dropped.
+    pub fn new_snapshot(&self, inner: Arc<LsmStorageInner>) -> Snapshot {
+        let mut ts = self.ts.lock();
+        let read_ts = ts.0;
+        ts.1.add_reader(read_ts);
+        Snapshot { inner, read_ts }
+    }
+
+    /// Create a read-only snapshot at a past ts, which must not be below the watermark of
+    /// compaction, as the versions needed to read at it may have been removed.
+    pub fn new_snapshot_at(&self, inner: Arc<LsmStorageInner>, read_ts: u64) -> Result<Snapshot> {
+        let mut ts = self.ts.lock();
+        if read_ts > ts.0 {
+            bail!("ts {} is ahead of the latest commit ts {}", read_ts, ts.0);
+        }
+        let gc_watermark = self
+            .gc_watermark_locked(&ts)
+            .max(self.recovered_gc_watermark);
+        if read_ts < gc_watermark {
+            bail!(
+                "ts {} is no longer retained, the earliest readable ts is {}",
+                read_ts,
+                gc_watermark
+            );
+        }
+        ts.1.add_reader(read_ts);
+        Ok(Snapshot { inner, read_ts })
+    }
 }
@@ -0,0 +1,192 @@
+use std::{collections::HashSet, fmt, ops::Bound};
+
+use bytes::Bytes;
+
+use super::IsolationLevel;
+
+/// A range of keys read or deleted by a transaction.
+pub(crate) type KeyRange = (Bound<Bytes>, Bound<Bytes>);
+
+/// The commit of a transaction is aborted because of a transaction committed after it started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictError {
+    pub isolation: IsolationLevel,
+    /// The key read (in serializable mode) or written (in snapshot isolation) by this transaction
+    /// and written by the other one. It is `None` if the conflict is only found by key hashes or
+    /// between two ranges.
+    pub key: Option<Bytes>,
+    pub other: ConflictingTxn,
+}
+
+/// The transaction that a commit conflicts with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictingTxn {
+    /// A transaction committed at the ts.
+    Committed(u64),
+    /// A transaction prepared with the id, which is not resolved yet.
+    Prepared(String),
+}
+
+impl fmt::Display for ConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let check = match self.isolation {
+            IsolationLevel::Serializable => "serializable",
+            _ => "snapshot isolation",
+        };
+        match &self.other {
+            ConflictingTxn::Committed(commit_ts) => write!(
+                f,
+                "{} check failed: conflict with the transaction committed at ts {}",
+                check, commit_ts
+            )?,
+            ConflictingTxn::Prepared(xid) => write!(
+                f,
+                "{} check failed: conflict with the prepared transaction {:?}",
+                check, xid
+            )?,
+        }
+        if let Some(key) = &self.key {
+            write!(f, " on key {:?}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConflictError {}
+
+/// The keys read or written by a transaction. The set is exact until the total size of the keys
+/// exceeds a cap, and then falls back to the hashes of the keys and the range they span, which may
+/// report false conflicts.
+#[derive(Debug, Clone)]
+pub(crate) enum KeySet {
+    Exact {
+        keys: HashSet<Bytes>,
+        size: usize,
+    },
+    Hashes {
+        hashes: HashSet<u32>,
+        first: Bytes,
+        last: Bytes,
+    },
+}
+
+impl Default for KeySet {
+    fn default() -> Self {
+        Self::Exact {
+            keys: HashSet::new(),
+            size: 0,
+        }
+    }
+}
+
+impl KeySet {
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Self::Exact { keys, .. } => keys.is_empty(),
+            Self::Hashes { .. } => false,
+        }
+    }
+
+    /// Add a key, and fall back to hashes if the size of the keys exceeds `cap`.
+    pub(crate) fn insert(&mut self, key: &[u8], cap: usize) {
+        match self {
+            Self::Exact { keys, size } => {
+                if keys.contains(key) {
+                    return;
+                }
+                keys.insert(Bytes::copy_from_slice(key));
+                *size += key.len();
+                if *size > cap {
+                    let first = keys.iter().min().unwrap().clone();
+                    let last = keys.iter().max().unwrap().clone();
+                    let hashes = keys.iter().map(|key| farmhash::hash32(key)).collect();
+                    *self = Self::Hashes {
+                        hashes,
+                        first,
+                        last,
+                    };
+                }
+            }
+            Self::Hashes {
+                hashes,
+                first,
+                last,
+            } => {
+                hashes.insert(farmhash::hash32(key));
+                if key < &first[..] {
+                    *first = Bytes::copy_from_slice(key);
+                }
+                if key > &last[..] {
+                    *last = Bytes::copy_from_slice(key);
+                }
+            }
+        }
+    }
+
+    /// Find a key in both sets. Returns `Some(None)` if the key is only found by hash.
+    pub(crate) fn find_common(&self, other: &KeySet) -> Option<Option<Bytes>> {
+        match (self, other) {
+            (Self::Exact { keys: a, .. }, Self::Exact { keys: b, .. }) => {
+                let (a, b) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+                a.iter()
+                    .find(|key| b.contains(*key))
+                    .map(|key| Some(key.clone()))
+            }
+            (Self::Exact { keys, .. }, Self::Hashes { hashes, .. })
+            | (Self::Hashes { hashes, .. }, Self::Exact { keys, .. }) => keys
+                .iter()
+                .any(|key| hashes.contains(&farmhash::hash32(key)))
+                .then_some(None),
+            (Self::Hashes { hashes: a, .. }, Self::Hashes { hashes: b, .. }) => {
+                a.iter().any(|hash| b.contains(hash)).then_some(None)
+            }
+        }
+    }
+
+    /// Find a key in a range. Returns `Some(None)` if the range overlaps the range spanned by the
+    /// hashed keys.
+    pub(crate) fn find_in_range(&self, range: &KeyRange) -> Option<Option<Bytes>> {
+        match self {
+            Self::Exact { keys, .. } => keys
+                .iter()
+                .find(|key| in_range(range, key))
+                .map(|key| Some(key.clone())),
+            Self::Hashes { first, last, .. } => {
+                let span = (
+                    Bound::Included(first.clone()),
+                    Bound::Included(last.clone()),
+                );
+                overlaps(range, &span).then_some(None)
+            }
+        }
+    }
+}
+
+fn in_range(range: &KeyRange, key: &[u8]) -> bool {
+    let after_lower = match &range.0 {
+        Bound::Included(lower) => key >= &lower[..],
+        Bound::Excluded(lower) => key > &lower[..],
+        Bound::Unbounded => true,
+    };
+    let before_upper = match &range.1 {
+        Bound::Included(upper) => key <= &upper[..],
+        Bound::Excluded(upper) => key < &upper[..],
+        Bound::Unbounded => true,
+    };
+    after_lower && before_upper
+}
+
+/// Whether a lower bound may be below an upper bound. Two excluded bounds are treated as
+/// overlapping even if no key is between them.
+fn lower_before_upper(lower: &Bound<Bytes>, upper: &Bound<Bytes>) -> bool {
+    match (lower, upper) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => true,
+        (Bound::Included(lower), Bound::Included(upper)) => lower <= upper,
+        (Bound::Included(lower) | Bound::Excluded(lower), Bound::Excluded(upper))
+        | (Bound::Excluded(lower), Bound::Included(upper)) => lower < upper,
+    }
+}
+
+pub(crate) fn overlaps(a: &KeyRange, b: &KeyRange) -> bool {
+    lower_before_upper(&a.0, &b.1) && lower_before_upper(&b.0, &a.1)
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// A key lock cannot be acquired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockError {
+    /// The lock is not granted within the lock timeout.
+    Timeout { key: Bytes },
+    /// Waiting for the lock would form a cycle in the wait-for graph. The transaction that detects
+    /// the cycle stops waiting, so that the others can proceed.
+    Deadlock { key: Bytes },
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout { key } => write!(f, "lock wait timeout on key {:?}", key),
+            Self::Deadlock { key } => write!(f, "deadlock detected on key {:?}", key),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+#[derive(Default)]
+struct KeyLock {
+    holders: HashMap<u64, LockMode>,
+    /// The transactions waiting for the lock, which is granted in order.
+    waiters: VecDeque<(u64, LockMode)>,
+}
+
+impl KeyLock {
+    fn compatible(&self, txn_id: u64, mode: LockMode) -> bool {
+        self.holders.iter().all(|(holder, held_mode)| {
+            *holder == txn_id || (mode == LockMode::Shared && *held_mode == LockMode::Shared)
+        })
+    }
+
+    /// The transactions that a waiting transaction waits for: the other holders, and the waiters
+    /// ahead of it unless it is upgrading its lock.
+    fn blockers(&self, txn_id: u64, upgrading: bool) -> HashSet<u64> {
+        let waiters_ahead = self
+            .waiters
+            .iter()
+            .map(|(waiter, _)| *waiter)
+            .take_while(|waiter| !upgrading && *waiter != txn_id);
+        self.holders
+            .keys()
+            .copied()
+            .chain(waiters_ahead)
+            .filter(|x| *x != txn_id)
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct LockTable {
+    locks: HashMap<Bytes, KeyLock>,
+    /// The wait-for graph, from a waiting transaction to the transactions it waits for.
+    waits_for: HashMap<u64, HashSet<u64>>,
+}
+
+impl LockTable {
+    /// Whether a transaction waits for itself through the wait-for graph.
+    fn has_cycle(&self, txn_id: u64) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = self
+            .waits_for
+            .get(&txn_id)
+            .map(|x| x.iter().copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        while let Some(current) = stack.pop() {
+            if current == txn_id {
+                return true;
+            }
+            if visited.insert(current) {
+                if let Some(next) = self.waits_for.get(&current) {
+                    stack.extend(next.iter().copied());
+                }
+            }
+        }
+        false
+    }
+
+    fn stop_waiting(&mut self, txn_id: u64, key: &[u8]) {
+        self.waits_for.remove(&txn_id);
+        if let Some(lock) = self.locks.get_mut(key) {
+            lock.waiters.retain(|(waiter, _)| *waiter != txn_id);
+            if lock.holders.is_empty() && lock.waiters.is_empty() {
+                self.locks.remove(key);
+            }
+        }
+    }
+}
+
+/// Shared and exclusive key locks of pessimistic transactions. A lock is granted to the waiters in
+/// the order they arrive, and the holders can upgrade a shared lock to an exclusive one.
+#[derive(Default)]
+pub(crate) struct LockManager {
+    table: Mutex<LockTable>,
+    released: Condvar,
+}
+
+impl LockManager {
+    /// Acquire a lock on a key, waiting for at most `timeout`. Acquiring a lock that is already
+    /// held in the same or a stronger mode returns immediately.
+    pub(crate) fn lock(
+        &self,
+        txn_id: u64,
+        key: &[u8],
+        mode: LockMode,
+        timeout: Duration,
+    ) -> Result<(), LockError> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock();
+        loop {
+            let lock = table.locks.entry(Bytes::copy_from_slice(key)).or_default();
+            let held_mode = lock.holders.get(&txn_id).copied();
+            if held_mode == Some(LockMode::Exclusive) || held_mode == Some(mode) {
+                return Ok(());
+            }
+            let first_in_queue = lock
+                .waiters
+                .front()
+                .is_none_or(|(waiter, _)| *waiter == txn_id);
+            // A holder upgrading its lock does not wait for the queue.
+            if lock.compatible(txn_id, mode) && (first_in_queue || held_mode.is_some()) {
+                lock.waiters.retain(|(waiter, _)| *waiter != txn_id);
+                lock.holders.insert(txn_id, mode);
+                table.waits_for.remove(&txn_id);
+                // The waiters behind may be compatible with the lock as well.
+                self.released.notify_all();
+                return Ok(());
+            }
+            if !lock.waiters.iter().any(|(waiter, _)| *waiter == txn_id) {
+                lock.waiters.push_back((txn_id, mode));
+            }
+            let blockers = lock.blockers(txn_id, held_mode.is_some());
+            table.waits_for.insert(txn_id, blockers);
+            if table.has_cycle(txn_id) {
+                table.stop_waiting(txn_id, key);
+                self.released.notify_all();
+                return Err(LockError::Deadlock {
+                    key: Bytes::copy_from_slice(key),
+                });
+            }
+            if self.released.wait_until(&mut table, deadline).timed_out() {
+                table.stop_waiting(txn_id, key);
+                self.released.notify_all();
+                return Err(LockError::Timeout {
+                    key: Bytes::copy_from_slice(key),
+                });
+            }
+        }
+    }
+
+    /// Release the locks of a transaction on the keys.
+    pub(crate) fn unlock_all<'a>(&self, txn_id: u64, keys: impl IntoIterator<Item = &'a Bytes>) {
+        let mut table = self.table.lock();
+        for key in keys {
+            if let Some(lock) = table.locks.get_mut(key) {
+                lock.holders.remove(&txn_id);
+                if lock.holders.is_empty() && lock.waiters.is_empty() {
+                    table.locks.remove(key);
+                }
+            }
+        }
+        self.released.notify_all();
+    }
+}
@@ -0,0 +1,57 @@
+use std::{ops::Bound, sync::Arc};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::{
+    lsm_iterator::{FusedIterator, LsmIterator},
+    lsm_storage::LsmStorageInner,
+};
+
+/// A read-only view of the storage at `read_ts`. The versions it needs are kept by compaction until
+/// it is dropped.
+pub struct Snapshot {
+    pub(crate) read_ts: u64,
+    pub(crate) inner: Arc<LsmStorageInner>,
+}
+
+impl Snapshot {
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.inner.get_with_ts(&self.inner.state, key, self.read_ts)
+    }
+
+    /// Get multiple keys, all at the read timestamp of the snapshot.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.inner
+            .scan_with_ts(&self.inner.state, lower, upper, self.read_ts, &[])
+    }
+
+    /// Create an iterator over a range of keys that starts from the last key in the range and
+    /// moves backward with `prev`.
+    pub fn scan_rev(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.inner
+            .scan_rev_with_ts(&self.inner.state, lower, upper, self.read_ts, &[])
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.inner.mvcc().ts.lock().1.remove_reader(self.read_ts)
+    }
+}
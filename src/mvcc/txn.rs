@@ -1,174 +1,759 @@
 use std::{
     collections::HashSet,
+    fmt,
     ops::Bound,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicU8, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
 use anyhow::{bail, Result};
 use bytes::Bytes;
 use crossbeam_skiplist::{map::Entry, SkipMap};
 use ouroboros::self_referencing;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 
 use crate::{
-    iterators::{two_merge_iterator::TwoMergeIterator, StorageIterator},
+    iterators::{step, two_merge_iterator::TwoMergeIterator, Direction, StorageIterator},
+    key::TS_MAX,
     lsm_iterator::{FusedIterator, LsmIterator},
-    lsm_storage::{LsmStorageInner, WriteBatchRecord},
+    lsm_storage::{LsmStorageInner, LsmStorageState, WriteBatchRecord, WriteOptions},
     mem_table::map_bound,
-    mvcc::CommittedTxnData,
+    merge_operator::{decode_operands, full_merge, merge_value},
+    mvcc::{
+        conflict::{overlaps, ConflictError, ConflictingTxn, KeyRange, KeySet},
+        lock_manager::LockMode,
+        CommittedTxnData, IsolationLevel, PreparedTxn,
+    },
+    range_tombstone::RangeTombstone,
+    value::{expire_at, now_millis, Value},
 };
 
 pub struct Transaction {
     pub(crate) read_ts: u64,
     pub(crate) inner: Arc<LsmStorageInner>,
+    /// Values written by the transaction, encoded in the same way as in the storage.
     pub(crate) local_storage: Arc<SkipMap<Bytes, Bytes>>,
-    pub(crate) committed: Arc<AtomicBool>,
-    /// Write set and read set
-    pub(crate) key_hashes: Option<Mutex<(HashSet<u32>, HashSet<u32>)>>,
+    /// Range deletions of the transaction. They delete every version in the storage, and the keys
+    /// written by the transaction after them are kept in `local_storage`.
+    pub(crate) local_range_tombstones: Arc<Mutex<Vec<RangeTombstone>>>,
+    /// `TXN_ACTIVE`, `TXN_PREPARED`, `TXN_COMMITTED` or `TXN_ROLLED_BACK`.
+    pub(crate) state: AtomicU8,
+    pub(crate) isolation: IsolationLevel,
+    /// Write set and read set. The read set is only recorded in serializable mode, and neither of
+    /// them is recorded with `IsolationLevel::SnapshotReads`.
+    pub(crate) key_sets: Option<Mutex<(KeySet, KeySet)>>,
+    /// The ranges scanned in serializable mode, so that the keys written into them by other
+    /// transactions are detected even if the scans did not return them. The keys returned by
+    /// scans are not added to the read set, as they are in these ranges.
+    pub(crate) read_ranges: Mutex<Vec<KeyRange>>,
+    /// The id of the transaction in the lock manager.
+    pub(crate) id: u64,
+    /// Whether the keys are locked when they are written, see `MiniLsm::new_pessimistic_txn`.
+    pub(crate) pessimistic: bool,
+    /// The keys locked by the transaction, which are unlocked when it commits or is dropped.
+    pub(crate) locked_keys: Mutex<HashSet<Bytes>>,
+    pub(crate) savepoints: Mutex<Vec<Savepoint>>,
+    /// The id given by `prepare`.
+    pub(crate) xid: Mutex<Option<String>>,
+}
+
+pub(crate) const TXN_ACTIVE: u8 = 0;
+const TXN_COMMITTED: u8 = 1;
+const TXN_ROLLED_BACK: u8 = 2;
+const TXN_PREPARED: u8 = 3;
+
+/// The writes of a transaction when a savepoint is set.
+pub(crate) struct Savepoint {
+    local_storage: Vec<(Bytes, Bytes)>,
+    local_range_tombstones: Vec<RangeTombstone>,
+    write_set: Option<KeySet>,
 }
 
+/// Misuse of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnError {
+    /// The transaction is committed, or its commit has failed.
+    Committed,
+    RolledBack,
+    /// The transaction is prepared, so it can only be committed or rolled back.
+    Prepared,
+    /// `rollback_to_savepoint` is called without a savepoint.
+    NoSavepoint,
+    /// A range deletion with an empty range.
+    EmptyRange,
+}
+
+impl fmt::Display for TxnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Committed => write!(f, "transaction is already committed"),
+            Self::RolledBack => write!(f, "transaction is already rolled back"),
+            Self::Prepared => write!(f, "transaction is prepared"),
+            Self::NoSavepoint => write!(f, "no savepoint is set"),
+            Self::EmptyRange => write!(f, "range to delete is empty"),
+        }
+    }
+}
+
+impl std::error::Error for TxnError {}
+
 impl Transaction {
+    pub fn isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
+        self.check_active()?;
+        self.add_to_read_set(key);
+        self.get_with_read_ts(key, self.read_ts)
+    }
+
+    /// Lock a key exclusively and read it. A pessimistic transaction reads the latest committed
+    /// value instead of the one at its read ts, as no other transaction that locks the key can
+    /// write it until this one commits.
+    pub fn get_for_update(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.lock(key, LockMode::Exclusive)?;
+        if self.pessimistic {
+            self.get_with_read_ts(key, self.inner.mvcc().latest_commit_ts())
+        } else {
+            self.get(key)
         }
-        if let Some(guard) = &self.key_hashes {
-            let mut guard = guard.lock();
-            let (_, read_set) = &mut *guard;
-            read_set.insert(farmhash::hash32(key));
+    }
+
+    /// Lock a key until the transaction commits or is dropped. The locks are only respected by
+    /// other transactions that lock keys, and writes outside them do not wait for the locks.
+    pub fn lock(&self, key: &[u8], mode: LockMode) -> Result<()> {
+        self.check_active()?;
+        self.lock_key(key, mode)
+    }
+
+    fn lock_key(&self, key: &[u8], mode: LockMode) -> Result<()> {
+        self.inner
+            .mvcc()
+            .lock_manager
+            .lock(self.id, key, mode, self.inner.options.lock_timeout)?;
+        self.locked_keys.lock().insert(Bytes::copy_from_slice(key));
+        Ok(())
+    }
+
+    /// Lock a key written by a pessimistic transaction before the write, so that a conflict or a
+    /// deadlock is found by the write rather than by the commit.
+    fn lock_for_write(&self, key: &[u8]) -> Result<()> {
+        if self.pessimistic {
+            self.lock_key(key, LockMode::Exclusive)?;
+        }
+        Ok(())
+    }
+
+    fn unlock_all(&self) {
+        let locked_keys = std::mem::take(&mut *self.locked_keys.lock());
+        if !locked_keys.is_empty() {
+            self.inner
+                .mvcc()
+                .lock_manager
+                .unlock_all(self.id, &locked_keys);
         }
+    }
+
+    /// Read a key at `read_ts`, including the values written by the transaction.
+    fn get_with_read_ts(&self, key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
         if let Some(entry) = self.local_storage.get(key) {
-            if entry.value().is_empty() {
-                return Ok(None);
-            } else {
-                return Ok(Some(entry.value().clone()));
-            }
+            return match Value::decode_at(entry.value(), now_millis())? {
+                Value::Delete => Ok(None),
+                Value::Put(value) | Value::PutWithTtl(_, value) => {
+                    Ok(Some(entry.value().slice_ref(value)))
+                }
+                Value::Merge(payload) => {
+                    let value = self.merge_local(key, payload, read_ts)?;
+                    Ok((!value.is_empty()).then(|| Bytes::from(value)))
+                }
+                Value::Blob(_) => unreachable!("blob pointers are only in SSTs"),
+            };
         }
-        self.inner.get_with_ts(key, self.read_ts)
+        if self.is_range_deleted_locally(key) {
+            return Ok(None);
+        }
+        self.inner.get_with_ts(&self.inner.state, key, read_ts)
     }
 
-    pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
+    fn add_to_read_set(&self, key: &[u8]) {
+        if self.isolation != IsolationLevel::Serializable {
+            return;
         }
-        let mut local_iter = TxnLocalIteratorBuilder {
-            map: self.local_storage.clone(),
-            iter_builder: |map| map.range((map_bound(lower), map_bound(upper))),
-            item: (Bytes::new(), Bytes::new()),
+        if let Some(guard) = &self.key_sets {
+            let mut guard = guard.lock();
+            let (_, read_set) = &mut *guard;
+            read_set.insert(key, self.inner.options.max_txn_key_set_size);
         }
-        .build();
-        let entry = local_iter.with_iter_mut(|iter| TxnLocalIterator::entry_to_item(iter.next()));
-        local_iter.with_mut(|x| *x.item = entry);
+    }
 
+    fn add_to_write_set(&self, key: &[u8]) {
+        if let Some(guard) = &self.key_sets {
+            let mut guard = guard.lock();
+            let (write_set, _) = &mut *guard;
+            write_set.insert(key, self.inner.options.max_txn_key_set_size);
+        }
+    }
+
+    fn add_range_to_read_set(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) {
+        if self.isolation == IsolationLevel::Serializable {
+            self.read_ranges
+                .lock()
+                .push((map_bound(lower), map_bound(upper)));
+        }
+    }
+
+    fn local_range_deletes(&self) -> Vec<KeyRange> {
+        self.local_range_tombstones
+            .lock()
+            .iter()
+            .map(|x| {
+                (
+                    Bound::Included(x.start.clone()),
+                    Bound::Excluded(x.end.clone()),
+                )
+            })
+            .collect()
+    }
+
+    fn is_range_deleted_locally(&self, key: &[u8]) -> bool {
+        self.local_range_tombstones
+            .lock()
+            .iter()
+            .any(|x| x.covers(key, self.read_ts))
+    }
+
+    /// Apply the merge operands written by the transaction to the value in the storage at
+    /// `read_ts`.
+    fn merge_local(&self, key: &[u8], payload: &[u8], read_ts: u64) -> Result<Vec<u8>> {
+        let existing_value = if self.is_range_deleted_locally(key) {
+            None
+        } else {
+            self.inner.get_with_ts(&self.inner.state, key, read_ts)?
+        };
+        full_merge(
+            self.inner.options.merge_operator.as_ref(),
+            key,
+            existing_value.as_deref(),
+            &[payload],
+        )
+    }
+
+    pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        self.scan_column_family(&self.inner.state, lower, upper)
+    }
+
+    /// Scan the state of a column family. The values written by the transaction are always in the
+    /// default column family, so this is only used with other column families on a transaction
+    /// without writes.
+    pub(crate) fn scan_column_family(
+        self: &Arc<Self>,
+        state: &RwLock<Arc<LsmStorageState>>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<TxnIterator> {
+        self.check_active()?;
+        self.add_range_to_read_set(lower, upper);
+        // The lock on the range tombstones is released before the iterator is created, which may
+        // merge the values of the transaction.
+        let storage_iter = self.inner.scan_with_ts(
+            state,
+            lower,
+            upper,
+            self.read_ts,
+            &self.local_range_tombstones.lock(),
+        )?;
         TxnIterator::create(
             self.clone(),
             TwoMergeIterator::create(
-                local_iter,
-                self.inner.scan_with_ts(lower, upper, self.read_ts)?,
+                TxnLocalIterator::create(&self.local_storage, lower, upper, Direction::Forward),
+                storage_iter,
             )?,
         )
     }
 
-    pub fn put(&self, key: &[u8], value: &[u8]) {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
-        }
-        self.local_storage
-            .insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
-        if let Some(key_hashes) = &self.key_hashes {
-            let mut key_hashes = key_hashes.lock();
-            let (write_hashes, _) = &mut *key_hashes;
-            write_hashes.insert(farmhash::hash32(key));
-        }
+    /// Create an iterator over a range of keys that starts from the last key in the range and
+    /// moves backward with `prev`.
+    pub fn scan_rev(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<TxnIterator> {
+        self.check_active()?;
+        self.add_range_to_read_set(lower, upper);
+        let storage_iter = self.inner.scan_rev_with_ts(
+            &self.inner.state,
+            lower,
+            upper,
+            self.read_ts,
+            &self.local_range_tombstones.lock(),
+        )?;
+        TxnIterator::create_reverse(
+            self.clone(),
+            TwoMergeIterator::create_reverse(
+                TxnLocalIterator::create(&self.local_storage, lower, upper, Direction::Backward),
+                storage_iter,
+            )?,
+        )
     }
 
-    pub fn delete(&self, key: &[u8]) {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
-        }
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_active()?;
+        self.lock_for_write(key)?;
+        self.local_storage.insert(
+            Bytes::copy_from_slice(key),
+            Value::Put(value).encode().into(),
+        );
+        self.add_to_write_set(key);
+        Ok(())
+    }
+
+    /// Put a key-value pair that expires after `ttl`, counted from now rather than from the commit.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.check_active()?;
+        self.lock_for_write(key)?;
+        self.local_storage.insert(
+            Bytes::copy_from_slice(key),
+            Value::PutWithTtl(expire_at(ttl), value).encode().into(),
+        );
+        self.add_to_write_set(key);
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.check_active()?;
+        self.lock_for_write(key)?;
         self.local_storage
             .insert(Bytes::copy_from_slice(key), Bytes::new());
-        if let Some(key_hashes) = &self.key_hashes {
-            let mut key_hashes = key_hashes.lock();
-            let (write_hashes, _) = &mut *key_hashes;
-            write_hashes.insert(farmhash::hash32(key));
+        self.add_to_write_set(key);
+        Ok(())
+    }
+
+    /// Apply a merge operand to a key without reading it, so that the key is not added to the read
+    /// set.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.check_active()?;
+        self.lock_for_write(key)?;
+        let entry = self.local_storage.get(key);
+        let existing_value = match &entry {
+            Some(entry) => Some(Value::decode_at(entry.value(), now_millis())?),
+            // The operand is applied to nothing if the key has been deleted by a range deletion.
+            None if self.is_range_deleted_locally(key) => Some(Value::Delete),
+            None => None,
+        };
+        let value = merge_value(
+            self.inner.options.merge_operator.as_ref(),
+            key,
+            existing_value,
+            operand,
+        )?;
+        self.local_storage
+            .insert(Bytes::copy_from_slice(key), value.into());
+        self.add_to_write_set(key);
+        Ok(())
+    }
+
+    /// Delete all keys in `[start, end)`. A pessimistic transaction locks the keys in the range as
+    /// of the latest commit. The keys written into the range by others later are not locked, as the
+    /// locks are on keys rather than ranges.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.check_active()?;
+        if start >= end {
+            return Err(TxnError::EmptyRange.into());
         }
+        if self.pessimistic {
+            let (lower, upper) = (Bound::Included(start), Bound::Excluded(end));
+            let mut iter = self.inner.scan_with_ts(
+                &self.inner.state,
+                lower,
+                upper,
+                self.inner.mvcc().latest_commit_ts(),
+                &self.local_range_tombstones.lock(),
+            )?;
+            while iter.is_valid() {
+                self.lock_key(iter.key(), LockMode::Exclusive)?;
+                iter.next()?;
+            }
+        }
+        // The keys written before in this transaction are deleted as well.
+        let keys = self
+            .local_storage
+            .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.local_storage.remove(&key);
+        }
+        self.local_range_tombstones.lock().push(RangeTombstone::new(
+            Bytes::copy_from_slice(start),
+            Bytes::copy_from_slice(end),
+            TS_MAX,
+        ));
+        Ok(())
     }
 
+    /// Commit the transaction. The locks are released whether the commit succeeds or not.
     pub fn commit(&self) -> Result<()> {
-        self.committed
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-            .expect("cannot operate on committed txn!");
+        self.commit_with_options(&WriteOptions::default())
+    }
+
+    /// Commit the transaction with the durability in `options`. A prepared transaction is always
+    /// committed with the WAL synced.
+    pub fn commit_with_options(&self, options: &WriteOptions) -> Result<()> {
+        let result = if self.finish(TXN_COMMITTED)? {
+            self.inner
+                .commit_prepared(self.xid.lock().as_ref().unwrap())
+        } else {
+            self.commit_inner(options)
+        };
+        self.unlock_all();
+        result
+    }
+
+    /// Discard the writes of the transaction and release its locks.
+    pub fn rollback(&self) -> Result<()> {
+        if self.finish(TXN_ROLLED_BACK)? {
+            self.inner
+                .rollback_prepared(self.xid.lock().as_ref().unwrap())?;
+        }
+        self.local_storage.clear();
+        self.local_range_tombstones.lock().clear();
+        self.savepoints.lock().clear();
+        self.unlock_all();
+        Ok(())
+    }
+
+    /// Prepare the transaction for two-phase commit with an id that no other prepared transaction
+    /// has. The transaction is checked for conflicts, and its writes are logged to the WAL, so that
+    /// it can be committed or rolled back by the id with `MiniLsm::commit_prepared` and
+    /// `MiniLsm::rollback_prepared` after a restart. Until then, the transactions with conflict
+    /// checks that conflict with it fail to commit. If the prepare fails, the transaction is
+    /// rolled back.
+    pub fn prepare(&self, xid: &str) -> Result<()> {
+        if !self.inner.options.enable_wal {
+            bail!("two-phase commit requires the WAL");
+        }
+        self.finish(TXN_PREPARED)?;
+        let result = self.prepare_inner(xid);
+        if result.is_err() {
+            self.state.store(TXN_ROLLED_BACK, Ordering::SeqCst);
+            self.unlock_all();
+        }
+        result
+    }
+
+    fn prepare_inner(&self, xid: &str) -> Result<()> {
+        self.lock_writes()?;
         let _commit_lock = self.inner.mvcc().commit_lock.lock();
-        let serializability_check;
-        if let Some(guard) = &self.key_hashes {
-            let guard = guard.lock();
-            let (write_set, read_set) = &*guard;
-            println!(
-                "commit txn: write_set: {:?}, read_set: {:?}",
-                write_set, read_set
-            );
-            if !write_set.is_empty() {
-                let committed_txns = self.inner.mvcc().committed_txns.lock();
-                for (_, txn_data) in committed_txns.range((self.read_ts + 1)..) {
-                    for key_hash in read_set {
-                        if txn_data.key_hashes.contains(key_hash) {
-                            bail!("serializable check failed");
-                        }
-                    }
-                }
+        self.check_conflicts()?;
+        let mut txn = PreparedTxn::new(
+            self.local_writes(),
+            self.local_range_delete_bounds(),
+            self.read_ts,
+            self.inner.options.max_txn_key_set_size,
+        );
+        if let (IsolationLevel::Serializable, Some(key_sets)) = (self.isolation, &self.key_sets) {
+            txn.read_set = key_sets.lock().1.clone();
+            txn.read_ranges = self.read_ranges.lock().clone();
+        }
+        let mut prepared_txns = self.inner.mvcc().prepared_txns.lock();
+        if prepared_txns.contains_key(xid) {
+            bail!("transaction {:?} is already prepared", xid);
+        }
+        self.inner.log_txn_record(&txn.record(xid))?;
+        prepared_txns.insert(xid.to_string(), txn);
+        *self.xid.lock() = Some(xid.to_string());
+        Ok(())
+    }
+
+    /// Move the transaction from active to `state`, or from prepared to committed or rolled back,
+    /// which can only be done once. Returns whether the transaction was prepared.
+    fn finish(&self, state: u8) -> Result<bool> {
+        let mut current = TXN_ACTIVE;
+        loop {
+            match self
+                .state
+                .compare_exchange(current, state, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Ok(current == TXN_PREPARED),
+                Err(TXN_PREPARED) if state != TXN_PREPARED => current = TXN_PREPARED,
+                Err(TXN_PREPARED) => return Err(TxnError::Prepared.into()),
+                Err(TXN_COMMITTED) => return Err(TxnError::Committed.into()),
+                Err(_) => return Err(TxnError::RolledBack.into()),
             }
-            serializability_check = true;
-        } else {
-            serializability_check = false;
         }
-        let batch = self
-            .local_storage
-            .iter()
-            .map(|entry| {
-                if entry.value().is_empty() {
-                    WriteBatchRecord::Del(entry.key().clone())
-                } else {
-                    WriteBatchRecord::Put(entry.key().clone(), entry.value().clone())
-                }
-            })
-            .collect::<Vec<_>>();
-        let ts = self.inner.write_batch_inner(&batch)?;
-        if serializability_check {
-            let mut committed_txns = self.inner.mvcc().committed_txns.lock();
-            let mut key_hashes = self.key_hashes.as_ref().unwrap().lock();
-            let (write_set, _) = &mut *key_hashes;
-
-            let old_data = committed_txns.insert(
-                ts,
-                CommittedTxnData {
-                    key_hashes: std::mem::take(write_set),
+    }
+
+    fn check_active(&self) -> Result<()> {
+        match self.state.load(Ordering::SeqCst) {
+            TXN_ACTIVE => Ok(()),
+            TXN_PREPARED => Err(TxnError::Prepared.into()),
+            TXN_COMMITTED => Err(TxnError::Committed.into()),
+            _ => Err(TxnError::RolledBack.into()),
+        }
+    }
+
+    /// Save the writes of the transaction, so that the writes after it can be undone with
+    /// `rollback_to_savepoint`. Savepoints can be nested.
+    pub fn set_savepoint(&self) -> Result<()> {
+        self.check_active()?;
+        let write_set = self
+            .key_sets
+            .as_ref()
+            .map(|key_sets| key_sets.lock().0.clone());
+        self.savepoints.lock().push(Savepoint {
+            local_storage: self.local_writes(),
+            local_range_tombstones: self.local_range_tombstones.lock().clone(),
+            write_set,
+        });
+        Ok(())
+    }
+
+    /// Undo the writes after the latest savepoint and remove it. The keys read after it stay in
+    /// the read set, and the keys locked after it stay locked.
+    pub fn rollback_to_savepoint(&self) -> Result<()> {
+        self.check_active()?;
+        let Some(savepoint) = self.savepoints.lock().pop() else {
+            return Err(TxnError::NoSavepoint.into());
+        };
+        self.local_storage.clear();
+        for (key, value) in savepoint.local_storage {
+            self.local_storage.insert(key, value);
+        }
+        *self.local_range_tombstones.lock() = savepoint.local_range_tombstones;
+        if let (Some(key_sets), Some(write_set)) = (&self.key_sets, savepoint.write_set) {
+            key_sets.lock().0 = write_set;
+        }
+        Ok(())
+    }
+
+    fn commit_inner(&self, options: &WriteOptions) -> Result<()> {
+        self.lock_writes()?;
+        let wal = {
+            let _commit_lock = self.inner.mvcc().commit_lock.lock();
+            let conflict_check = self.check_conflicts()?;
+            let batch = txn_write_batch(&self.local_writes(), &self.local_range_delete_bounds())?;
+            let (ts, wal) = self.inner.write_batch_unsynced(&batch, options)?;
+            if conflict_check {
+                let mut key_sets = self.key_sets.as_ref().unwrap().lock();
+                let (write_set, _) = &mut *key_sets;
+                self.inner.mvcc().add_committed_txn(CommittedTxnData {
+                    write_set: std::mem::take(write_set),
+                    range_deletes: self.local_range_deletes(),
                     read_ts: self.read_ts,
                     commit_ts: ts,
-                },
-            );
-            assert!(old_data.is_none());
-
-            // remove unneeded txn data
-            let watermark = self.inner.mvcc().watermark();
-            while let Some(entry) = committed_txns.first_entry() {
-                if *entry.key() < watermark {
-                    entry.remove();
-                } else {
-                    break;
-                }
+                });
+            } else {
+                self.inner.record_unchecked_commit(&batch, ts);
+            }
+            wal
+        };
+        // Sync with the commit lock released, so that the transactions committed meanwhile share
+        // the sync.
+        LsmStorageInner::sync_wal(wal.as_ref(), options)
+    }
+
+    /// Check that a pessimistic transaction holds the locks of the keys it writes. They are locked
+    /// by the writes, so this does not wait unless a write is made without its lock.
+    fn lock_writes(&self) -> Result<()> {
+        if self.pessimistic {
+            for entry in self.local_storage.iter() {
+                self.lock_key(entry.key(), LockMode::Exclusive)?;
             }
         }
         Ok(())
     }
+
+    /// The values written by the transaction.
+    fn local_writes(&self) -> Vec<(Bytes, Bytes)> {
+        self.local_storage
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    /// The `[start, end)` ranges deleted by the transaction.
+    fn local_range_delete_bounds(&self) -> Vec<(Bytes, Bytes)> {
+        self.local_range_tombstones
+            .lock()
+            .iter()
+            .map(|x| (x.start.clone(), x.end.clone()))
+            .collect()
+    }
+
+    /// Check the transaction against the transactions committed after it started and the prepared
+    /// ones, and return whether it is checked at all. Must be called with the commit lock held.
+    fn check_conflicts(&self) -> Result<bool> {
+        let Some(guard) = &self.key_sets else {
+            return Ok(false);
+        };
+        let guard = guard.lock();
+        let (write_set, read_set) = &*guard;
+        let read_ranges = self.read_ranges.lock();
+        let range_deletes = self.local_range_deletes();
+        if write_set.is_empty() && range_deletes.is_empty() {
+            return Ok(true);
+        }
+        let serializable = self.isolation == IsolationLevel::Serializable;
+        let conflict_error = |key, other| ConflictError {
+            isolation: self.isolation,
+            key,
+            other,
+        };
+        let committed_txns = self.inner.mvcc().committed_txns.lock();
+        for (commit_ts, txn_data) in committed_txns.range((self.read_ts + 1)..) {
+            let conflict = if serializable {
+                find_read_write_conflict(
+                    read_set,
+                    &read_ranges,
+                    &txn_data.write_set,
+                    &txn_data.range_deletes,
+                )
+            } else {
+                find_write_write_conflict(
+                    write_set,
+                    &range_deletes,
+                    &txn_data.write_set,
+                    &txn_data.range_deletes,
+                )
+            };
+            if let Some(key) = conflict {
+                return Err(conflict_error(key, ConflictingTxn::Committed(*commit_ts)).into());
+            }
+        }
+        // A prepared transaction cannot abort, so the keys it reads must not be written either.
+        for (xid, txn) in self.inner.mvcc().prepared_txns.lock().iter() {
+            let prepared_range_deletes = txn.key_ranges();
+            let conflict = if serializable {
+                find_read_write_conflict(
+                    read_set,
+                    &read_ranges,
+                    &txn.write_set,
+                    &prepared_range_deletes,
+                )
+            } else {
+                find_write_write_conflict(
+                    write_set,
+                    &range_deletes,
+                    &txn.write_set,
+                    &prepared_range_deletes,
+                )
+            }
+            .or_else(|| {
+                find_read_write_conflict(&txn.read_set, &txn.read_ranges, write_set, &range_deletes)
+            });
+            if let Some(key) = conflict {
+                return Err(conflict_error(key, ConflictingTxn::Prepared(xid.clone())).into());
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Convert the values written by a transaction, encoded as in `Transaction::local_storage`, and the
+/// `[start, end)` ranges it deletes into a write batch.
+pub(crate) fn txn_write_batch(
+    writes: &[(Bytes, Bytes)],
+    range_deletes: &[(Bytes, Bytes)],
+) -> Result<Vec<WriteBatchRecord<Bytes>>> {
+    let mut batch = range_deletes
+        .iter()
+        .map(|(start, end)| WriteBatchRecord::DelRange(start.clone(), end.clone()))
+        .collect::<Vec<_>>();
+    let now = now_millis();
+    for (key, value) in writes {
+        let key = key.clone();
+        match Value::decode(value)? {
+            Value::Delete => batch.push(WriteBatchRecord::Del(key)),
+            Value::Put(put_value) => {
+                batch.push(WriteBatchRecord::Put(key, value.slice_ref(put_value)))
+            }
+            // Keep the expiration time of the value, which may have passed already.
+            Value::PutWithTtl(expire_at, put_value) => batch.push(WriteBatchRecord::PutWithTtl(
+                key,
+                value.slice_ref(put_value),
+                Duration::from_millis(expire_at.saturating_sub(now)),
+            )),
+            Value::Merge(payload) => {
+                let mut operands = Vec::new();
+                decode_operands(payload, &mut operands);
+                for operand in operands {
+                    batch.push(WriteBatchRecord::Merge(
+                        key.clone(),
+                        value.slice_ref(operand),
+                    ));
+                }
+            }
+            Value::Blob(_) => unreachable!("blob pointers are only in SSTs"),
+        }
+    }
+    Ok(batch)
+}
+
+/// Find a key read by a serializable transaction that is written by another transaction,
+/// including the keys in the scanned ranges. Returns `Some(None)` if the key is unknown, see
+/// `ConflictError::key`.
+fn find_read_write_conflict(
+    read_set: &KeySet,
+    read_ranges: &[KeyRange],
+    other_write_set: &KeySet,
+    other_range_deletes: &[KeyRange],
+) -> Option<Option<Bytes>> {
+    if let Some(key) = read_set.find_common(other_write_set) {
+        return Some(key);
+    }
+    for range in other_range_deletes {
+        if let Some(key) = read_set.find_in_range(range) {
+            return Some(key);
+        }
+    }
+    for range in read_ranges {
+        if let Some(key) = other_write_set.find_in_range(range) {
+            return Some(key);
+        }
+        if other_range_deletes.iter().any(|x| overlaps(range, x)) {
+            return Some(None);
+        }
+    }
+    None
+}
+
+/// Find a key written by both a transaction in snapshot isolation and another transaction,
+/// including the keys in the deleted ranges.
+fn find_write_write_conflict(
+    write_set: &KeySet,
+    range_deletes: &[KeyRange],
+    other_write_set: &KeySet,
+    other_range_deletes: &[KeyRange],
+) -> Option<Option<Bytes>> {
+    if let Some(key) = write_set.find_common(other_write_set) {
+        return Some(key);
+    }
+    for range in other_range_deletes {
+        if let Some(key) = write_set.find_in_range(range) {
+            return Some(key);
+        }
+    }
+    for range in range_deletes {
+        if let Some(key) = other_write_set.find_in_range(range) {
+            return Some(key);
+        }
+        if other_range_deletes.iter().any(|x| overlaps(range, x)) {
+            return Some(None);
+        }
+    }
+    None
 }
 
 impl Drop for Transaction {
     fn drop(&mut self) {
-        self.inner.mvcc().ts.lock().1.remove_reader(self.read_ts)
+        self.unlock_all();
+        self.inner
+            .mvcc()
+            .remove_txn(self.read_ts, self.key_sets.is_some())
     }
 }
 
@@ -185,14 +770,76 @@ pub struct TxnLocalIterator {
     iter: SkipMapRangeIter<'this>,
     /// Stores the current key-value pair.
     item: (Bytes, Bytes),
+    /// The range of the iterator, used to re-create the skipmap iterator when changing direction.
+    lower: Bound<Bytes>,
+    upper: Bound<Bytes>,
+    direction: Direction,
 }
 
 impl TxnLocalIterator {
+    fn create(
+        map: &Arc<SkipMap<Bytes, Bytes>>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        direction: Direction,
+    ) -> Self {
+        let (lower, upper) = (map_bound(lower), map_bound(upper));
+        let range = (lower.clone(), upper.clone());
+        let mut iter = TxnLocalIteratorBuilder {
+            map: map.clone(),
+            iter_builder: |map| map.range(range),
+            item: (Bytes::new(), Bytes::new()),
+            lower,
+            upper,
+            direction,
+        }
+        .build();
+        let entry = iter.with_iter_mut(|iter| {
+            TxnLocalIterator::entry_to_item(match direction {
+                Direction::Forward => iter.next(),
+                Direction::Backward => iter.next_back(),
+            })
+        });
+        iter.with_mut(|x| *x.item = entry);
+        iter
+    }
+
     fn entry_to_item(entry: Option<Entry<'_, Bytes, Bytes>>) -> (Bytes, Bytes) {
         entry
             .map(|x| (x.key().clone(), x.value().clone()))
             .unwrap_or_else(|| (Bytes::new(), Bytes::new()))
     }
+
+    /// Re-create the skipmap iterator so that it covers the keys on the `direction` side of the
+    /// current key. If the iterator is invalid, it covers the whole range.
+    fn switch_direction(&mut self, direction: Direction) {
+        let current = &self.borrow_item().0;
+        let (lower, upper) = match (direction, current.is_empty()) {
+            (_, true) => (self.borrow_lower().clone(), self.borrow_upper().clone()),
+            (Direction::Forward, false) => (
+                Bound::Excluded(current.clone()),
+                self.borrow_upper().clone(),
+            ),
+            (Direction::Backward, false) => (
+                self.borrow_lower().clone(),
+                Bound::Excluded(current.clone()),
+            ),
+        };
+        self.with_mut(|x| {
+            *x.iter = x.map.range((lower, upper));
+            *x.direction = direction;
+        });
+    }
+
+    /// Re-create the skipmap iterator to move forward from `lower`, and move to the first key.
+    fn seek_to_lower(&mut self, lower: Bound<Bytes>) {
+        let upper = self.borrow_upper().clone();
+        self.with_mut(|x| {
+            *x.iter = x.map.range((lower, upper));
+            *x.direction = Direction::Forward;
+            *x.item = TxnLocalIterator::entry_to_item(x.iter.next());
+        });
+    }
 }
 
 impl StorageIterator for TxnLocalIterator {
@@ -211,15 +858,48 @@ impl StorageIterator for TxnLocalIterator {
     }
 
     fn next(&mut self) -> Result<()> {
+        if *self.borrow_direction() == Direction::Backward {
+            self.switch_direction(Direction::Forward);
+        }
         let entry = self.with_iter_mut(|iter| TxnLocalIterator::entry_to_item(iter.next()));
         self.with_mut(|x| *x.item = entry);
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if *self.borrow_direction() == Direction::Forward {
+            self.switch_direction(Direction::Backward);
+        }
+        let entry = self.with_iter_mut(|iter| TxnLocalIterator::entry_to_item(iter.next_back()));
+        self.with_mut(|x| *x.item = entry);
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.seek_to_lower(self.borrow_lower().clone());
+        Ok(())
+    }
+
+    fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
+        // Never move before the lower bound of the scan.
+        let lower = match self.borrow_lower() {
+            Bound::Included(lower) | Bound::Excluded(lower) if key <= &lower[..] => {
+                self.borrow_lower().clone()
+            }
+            _ => Bound::Included(Bytes::copy_from_slice(key)),
+        };
+        self.seek_to_lower(lower);
+        Ok(())
+    }
 }
 
 pub struct TxnIterator {
     txn: Arc<Transaction>,
     iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
+    /// The current value if it is merged from the merge operands written by the transaction.
+    merged_value: Option<Vec<u8>>,
+    /// The time at which the expiration of the values written by the transaction is checked.
+    now: u64,
 }
 
 impl TxnIterator {
@@ -227,35 +907,75 @@ impl TxnIterator {
         txn: Arc<Transaction>,
         iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
     ) -> Result<Self> {
-        let mut iter = Self { txn, iter };
-        iter.skip_deletes()?;
-        if iter.is_valid() {
-            iter.add_to_read_set(iter.key());
-        }
+        let mut iter = Self {
+            txn,
+            iter,
+            merged_value: None,
+            now: now_millis(),
+        };
+        iter.skip_deletes(Direction::Forward)?;
         Ok(iter)
     }
 
-    fn skip_deletes(&mut self) -> Result<()> {
-        while self.iter.is_valid() && self.iter.value().is_empty() {
-            self.iter.next()?;
-        }
-        Ok(())
+    pub fn create_reverse(
+        txn: Arc<Transaction>,
+        iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
+    ) -> Result<Self> {
+        let mut iter = Self {
+            txn,
+            iter,
+            merged_value: None,
+            now: now_millis(),
+        };
+        iter.skip_deletes(Direction::Backward)?;
+        Ok(iter)
     }
 
-    fn add_to_read_set(&self, key: &[u8]) {
-        if let Some(guard) = &self.txn.key_hashes {
-            let mut guard = guard.lock();
-            let (_, read_set) = &mut *guard;
-            read_set.insert(farmhash::hash32(key));
+    /// Move to the closest key that is not deleted. The values written by the transaction are
+    /// encoded, while the values from the storage are not.
+    fn skip_deletes(&mut self, direction: Direction) -> Result<()> {
+        self.merged_value = None;
+        while self.iter.is_valid() {
+            if !self.iter.is_from_a() {
+                if !self.iter.value().is_empty() {
+                    break;
+                }
+            } else {
+                match Value::decode_at(self.iter.value(), self.now)? {
+                    Value::Delete => {}
+                    Value::Put(_) | Value::PutWithTtl(..) => break,
+                    Value::Merge(payload) => {
+                        let value =
+                            self.txn
+                                .merge_local(self.iter.key(), payload, self.txn.read_ts)?;
+                        if !value.is_empty() {
+                            self.merged_value = Some(value);
+                            break;
+                        }
+                    }
+                    Value::Blob(_) => unreachable!("blob pointers are only in SSTs"),
+                }
+            }
+            step(&mut self.iter, direction)?;
         }
+        Ok(())
     }
 }
 
 impl StorageIterator for TxnIterator {
-    type KeyType<'a> = &'a [u8] where Self: 'a;
+    type KeyType<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
 
     fn value(&self) -> &[u8] {
-        self.iter.value()
+        if let Some(value) = &self.merged_value {
+            value
+        } else if self.iter.is_from_a() {
+            Value::put_value(self.iter.value())
+        } else {
+            self.iter.value()
+        }
     }
 
     fn key(&self) -> Self::KeyType<'_> {
@@ -268,10 +988,25 @@ impl StorageIterator for TxnIterator {
 
     fn next(&mut self) -> Result<()> {
         self.iter.next()?;
-        self.skip_deletes()?;
-        if self.is_valid() {
-            self.add_to_read_set(self.key());
-        }
+        self.skip_deletes(Direction::Forward)?;
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        self.iter.prev()?;
+        self.skip_deletes(Direction::Backward)?;
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.iter.seek_to_first()?;
+        self.skip_deletes(Direction::Forward)?;
+        Ok(())
+    }
+
+    fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
+        self.iter.seek_to_key(key)?;
+        self.skip_deletes(Direction::Forward)?;
         Ok(())
     }
 
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::block::{SIZEOF_U16, SIZEOF_U32};
+use crate::table::{get_len, SST_FORMAT_V6};
+
+/// A range tombstone deletes all versions of the keys in `[start, end)` that are older than `ts`.
+/// Versions written at `ts` (e.g., in the same write batch) are not affected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Bytes,
+    pub end: Bytes,
+    pub ts: u64,
+}
+
+impl RangeTombstone {
+    pub fn new(start: Bytes, end: Bytes, ts: u64) -> Self {
+        Self { start, end, ts }
+    }
+
+    /// Check if the version of `key` at `ts` is deleted by this tombstone.
+    pub fn covers(&self, key: &[u8], ts: u64) -> bool {
+        self.start.as_ref() <= key && key < self.end.as_ref() && ts < self.ts
+    }
+
+    /// Check if this tombstone may delete some keys within the range.
+    pub fn overlaps(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+        match upper {
+            Bound::Included(key) if key < self.start.as_ref() => return false,
+            Bound::Excluded(key) if key <= self.start.as_ref() => return false,
+            _ => {}
+        }
+        !matches!(lower, Bound::Included(key) | Bound::Excluded(key) if key >= self.end.as_ref())
+    }
+
+    /// The size of the tombstone, used to estimate the size of a memtable.
+    pub fn raw_len(&self) -> usize {
+        self.start.len() + self.end.len() + std::mem::size_of::<u64>()
+    }
+
+    /// Encode range tombstones to a buffer.
+    pub fn encode_range_tombstones(range_tombstones: &[RangeTombstone], buf: &mut Vec<u8>) {
+        let original_len = buf.len();
+        buf.put_u32(range_tombstones.len() as u32);
+        for tombstone in range_tombstones {
+            buf.put_u32(tombstone.start.len() as u32);
+            buf.put_slice(&tombstone.start);
+            buf.put_u32(tombstone.end.len() as u32);
+            buf.put_slice(&tombstone.end);
+            buf.put_u64(tombstone.ts);
+        }
+        buf.put_u32(crc32fast::hash(&buf[original_len + 4..]));
+    }
+
+    /// Decode range tombstones of an SST with the format version from a buffer. The checksum is
+    /// verified before the tombstones are read, and a length past the end of the buffer is an error.
+    pub fn decode_range_tombstones(buf: &[u8], version: u32) -> Result<Vec<RangeTombstone>> {
+        if buf.remaining() < SIZEOF_U32 * 2 {
+            bail!("range tombstones are truncated");
+        }
+        let (mut buf, mut checksum) = buf.split_at(buf.len() - SIZEOF_U32);
+        let num = buf.get_u32() as usize;
+        if crc32fast::hash(buf) != checksum.get_u32() {
+            bail!("range tombstones checksum mismatched");
+        }
+        let mut range_tombstones = Vec::new();
+        for _ in 0..num {
+            let start = Self::get_key(&mut buf, version)?;
+            let end = Self::get_key(&mut buf, version)?;
+            if buf.remaining() < std::mem::size_of::<u64>() {
+                bail!("range tombstones are truncated");
+            }
+            let ts = buf.get_u64();
+            range_tombstones.push(RangeTombstone { start, end, ts });
+        }
+        Ok(range_tombstones)
+    }
+
+    /// Read a length-prefixed start or end key of a range tombstone.
+    fn get_key(buf: &mut &[u8], version: u32) -> Result<Bytes> {
+        let len_size = if version < SST_FORMAT_V6 {
+            SIZEOF_U16
+        } else {
+            SIZEOF_U32
+        };
+        if buf.remaining() < len_size {
+            bail!("range tombstones are truncated");
+        }
+        let len = get_len(buf, version);
+        if buf.remaining() < len {
+            bail!("range tombstone key length {} is out of bounds", len);
+        }
+        Ok(buf.copy_to_bytes(len))
+    }
+}
+
+/// Range tombstones split into sorted, non-overlapping fragments. A fragment has the largest ts of
+/// the tombstones over it, as a version is deleted if it is older than any of them, so whether a
+/// key is deleted is found by a binary search rather than by checking every tombstone.
+#[derive(Clone, Debug, Default)]
+pub struct FragmentedRangeTombstones {
+    fragments: Vec<RangeTombstone>,
+}
+
+impl FragmentedRangeTombstones {
+    pub fn new(range_tombstones: &[RangeTombstone]) -> Self {
+        let mut starts = range_tombstones.iter().collect::<Vec<_>>();
+        starts.sort_by(|x, y| x.start.cmp(&y.start));
+        let mut ends = starts.clone();
+        ends.sort_by(|x, y| x.end.cmp(&y.end));
+        let mut bounds = range_tombstones
+            .iter()
+            .flat_map(|x| [&x.start, &x.end])
+            .collect::<Vec<_>>();
+        bounds.sort();
+        bounds.dedup();
+        // Sweep the bounds in order, with the number of tombstones over the span by ts.
+        let mut active = BTreeMap::<u64, usize>::new();
+        let (mut starts, mut ends) = (starts.into_iter().peekable(), ends.into_iter().peekable());
+        let mut fragments = Vec::<RangeTombstone>::new();
+        for (i, &bound) in bounds.iter().enumerate() {
+            while let Some(x) = starts.next_if(|x| x.start == *bound) {
+                *active.entry(x.ts).or_default() += 1;
+            }
+            while let Some(x) = ends.next_if(|x| x.end == *bound) {
+                let count = active.get_mut(&x.ts).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&x.ts);
+                }
+            }
+            let (Some((&ts, _)), Some(&end)) = (active.last_key_value(), bounds.get(i + 1)) else {
+                continue;
+            };
+            match fragments.last_mut() {
+                Some(last) if last.end == *bound && last.ts == ts => last.end = end.clone(),
+                _ => fragments.push(RangeTombstone::new(bound.clone(), end.clone(), ts)),
+            }
+        }
+        Self { fragments }
+    }
+
+    /// Check if the version of `key` at `ts` is deleted by any of the tombstones.
+    pub fn covers(&self, key: &[u8], ts: u64) -> bool {
+        let index = self.fragments.partition_point(|x| x.end.as_ref() <= key);
+        self.fragments.get(index).is_some_and(|x| x.covers(key, ts))
+    }
+}
@@ -1,22 +1,44 @@
 pub(crate) mod bloom;
 mod builder;
+mod compression;
 mod iterator;
 
 use std::fs::File;
+use std::ops::Bound;
 use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Result};
 pub use builder::SsTableBuilder;
 use bytes::{Buf, BufMut};
+pub use compression::{CompressionOptions, CompressionType};
 pub use iterator::SsTableIterator;
 
 use crate::block::Block;
 use crate::key::{KeyBytes, KeySlice};
 use crate::lsm_storage::BlockCache;
+use crate::range_tombstone::RangeTombstone;
 
 use self::bloom::Bloom;
 
+/// The first SST format, which has no footer and no range tombstones.
+pub(crate) const SST_FORMAT_V1: u32 = 1;
+/// Since v2, the SST ends with the version and a magic number. A v1 SST ends with the offset of the
+/// bloom filter, which is always below the magic. The range tombstones are stored in a section
+/// between the block meta and the bloom filter.
+pub(crate) const SST_FORMAT_V2: u32 = 2;
+/// Since v3, the values start with their kind, see `Value`.
+pub(crate) const SST_FORMAT_V3: u32 = 3;
+/// Since v4, the block meta has the max expiration time after the max timestamp.
+pub(crate) const SST_FORMAT_V4: u32 = 4;
+/// Since v5, each block starts with its codec, see `CompressionType`.
+pub(crate) const SST_FORMAT_V5: u32 = 5;
+/// Since v6, the lengths of keys and values and the offsets in a block are `u32` rather than
+/// `u16`.
+pub(crate) const SST_FORMAT_V6: u32 = 6;
+pub(crate) const SST_FORMAT_VERSION: u32 = SST_FORMAT_V6;
+pub(crate) const SST_MAGIC: u32 = 0xF5D1_5A7E;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
     /// Offset of this data block.
@@ -29,21 +51,27 @@ pub struct BlockMeta {
 
 impl BlockMeta {
     /// Encode block meta to a buffer.
-    pub fn encode_block_meta(block_meta: &[BlockMeta], max_ts: u64, buf: &mut Vec<u8>) {
+    pub fn encode_block_meta(
+        block_meta: &[BlockMeta],
+        max_ts: u64,
+        max_expire_at: u64,
+        buf: &mut Vec<u8>,
+    ) {
         let mut estimated_size = std::mem::size_of::<u32>(); // number of blocks
         for meta in block_meta {
             // The size of offset
             estimated_size += std::mem::size_of::<u32>();
             // The size of key length
-            estimated_size += std::mem::size_of::<u16>();
+            estimated_size += std::mem::size_of::<u32>();
             // The size of actual key
             estimated_size += meta.first_key.raw_len();
             // The size of key length
-            estimated_size += std::mem::size_of::<u16>();
+            estimated_size += std::mem::size_of::<u32>();
             // The size of actual key
             estimated_size += meta.last_key.raw_len();
         }
         estimated_size += std::mem::size_of::<u64>(); // max timestamp
+        estimated_size += std::mem::size_of::<u64>(); // max expiration time
         estimated_size += std::mem::size_of::<u32>(); // checksum
 
         // Reserve the space to improve performance, especially when the size of incoming data is
@@ -53,29 +81,31 @@ impl BlockMeta {
         buf.put_u32(block_meta.len() as u32);
         for meta in block_meta {
             buf.put_u32(meta.offset as u32);
-            buf.put_u16(meta.first_key.key_len() as u16);
+            buf.put_u32(meta.first_key.key_len() as u32);
             buf.put_slice(meta.first_key.key_ref());
             buf.put_u64(meta.first_key.ts());
-            buf.put_u16(meta.last_key.key_len() as u16);
+            buf.put_u32(meta.last_key.key_len() as u32);
             buf.put_slice(meta.last_key.key_ref());
             buf.put_u64(meta.last_key.ts());
         }
         buf.put_u64(max_ts);
+        buf.put_u64(max_expire_at);
         buf.put_u32(crc32fast::hash(&buf[original_len + 4..]));
         assert_eq!(estimated_size, buf.len() - original_len);
     }
 
-    /// Decode block meta from a buffer.
-    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
+    /// Decode block meta of an SST with the format version from a buffer, along with the max
+    /// timestamp and the max expiration time.
+    pub fn decode_block_meta(mut buf: &[u8], version: u32) -> Result<(Vec<BlockMeta>, u64, u64)> {
         let mut block_meta = Vec::new();
         let num = buf.get_u32() as usize;
         let checksum = crc32fast::hash(&buf[..buf.remaining() - 4]);
         for _ in 0..num {
             let offset = buf.get_u32() as usize;
-            let first_key_len = buf.get_u16() as usize;
+            let first_key_len = get_len(&mut buf, version);
             let first_key =
                 KeyBytes::from_bytes_with_ts(buf.copy_to_bytes(first_key_len), buf.get_u64());
-            let last_key_len: usize = buf.get_u16() as usize;
+            let last_key_len = get_len(&mut buf, version);
             let last_key =
                 KeyBytes::from_bytes_with_ts(buf.copy_to_bytes(last_key_len), buf.get_u64());
             block_meta.push(BlockMeta {
@@ -85,11 +115,25 @@ impl BlockMeta {
             });
         }
         let max_ts = buf.get_u64();
+        let max_expire_at = if version < SST_FORMAT_V4 {
+            0
+        } else {
+            buf.get_u64()
+        };
         if buf.get_u32() != checksum {
             bail!("meta checksum mismatched");
         }
 
-        Ok((block_meta, max_ts))
+        Ok((block_meta, max_ts, max_expire_at))
+    }
+}
+
+/// Read a length in an SST, which is a `u16` before v6 and a `u32` since v6.
+pub(crate) fn get_len(buf: &mut &[u8], version: u32) -> usize {
+    if version < SST_FORMAT_V6 {
+        buf.get_u16() as usize
+    } else {
+        buf.get_u32() as usize
     }
 }
 
@@ -142,6 +186,14 @@ pub struct SsTable {
     last_key: KeyBytes,
     pub(crate) bloom: Option<Bloom>,
     max_ts: u64,
+    /// The latest expiration time of the values with a TTL, or 0 if there is none.
+    max_expire_at: u64,
+    range_tombstones: Vec<RangeTombstone>,
+    /// The union of the range tombstones at the oldest timestamp of them, which is checked before
+    /// the range tombstones are.
+    range_tombstone_span: Option<RangeTombstone>,
+    /// The format version of the SST file.
+    version: u32,
 }
 impl SsTable {
     #[cfg(test)]
@@ -151,28 +203,87 @@ impl SsTable {
 
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
-        let len = file.size();
+        let mut len = file.size();
+        let raw_footer = file.read(len - 8, 8)?;
+        let mut footer = &raw_footer[..];
+        let version = if (&footer[4..]).get_u32() == SST_MAGIC {
+            len -= 8;
+            footer.get_u32()
+        } else {
+            SST_FORMAT_V1
+        };
+        if version > SST_FORMAT_VERSION {
+            bail!("unsupported SST format version {}", version);
+        }
         let raw_bloom_offset = file.read(len - 4, 4)?;
         let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
         let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
         let bloom_filter = Bloom::decode(&raw_bloom)?;
-        let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
+        let (range_tombstones, meta_end) = if version < SST_FORMAT_V2 {
+            (Vec::new(), bloom_offset)
+        } else {
+            let raw_range_tombstones_offset = file.read(bloom_offset - 4, 4)?;
+            let range_tombstones_offset = (&raw_range_tombstones_offset[..]).get_u32() as u64;
+            let raw_range_tombstones = file.read(
+                range_tombstones_offset,
+                bloom_offset - 4 - range_tombstones_offset,
+            )?;
+            let range_tombstones =
+                RangeTombstone::decode_range_tombstones(&raw_range_tombstones, version)?;
+            (range_tombstones, range_tombstones_offset)
+        };
+        let raw_meta_offset = file.read(meta_end - 4, 4)?;
         let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
-        let raw_meta = file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
-        let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        let raw_meta = file.read(block_meta_offset, meta_end - 4 - block_meta_offset)?;
+        let (block_meta, max_ts, max_expire_at) =
+            BlockMeta::decode_block_meta(&raw_meta[..], version)?;
+        let (first_key, last_key) = Self::key_range(&block_meta, &range_tombstones);
         Ok(Self {
             file,
-            first_key: block_meta.first().unwrap().first_key.clone(),
-            last_key: block_meta.last().unwrap().last_key.clone(),
+            first_key,
+            last_key,
             block_meta,
             block_meta_offset: block_meta_offset as usize,
             id,
             block_cache,
             bloom: Some(bloom_filter),
             max_ts,
+            max_expire_at,
+            range_tombstone_span: Self::range_tombstone_span(&range_tombstones),
+            range_tombstones,
+            version,
         })
     }
 
+    /// The key range of an SST is the range of its key-value pairs. For an SST with only range
+    /// tombstones, it is the range covered by the tombstones.
+    fn key_range(
+        block_meta: &[BlockMeta],
+        range_tombstones: &[RangeTombstone],
+    ) -> (KeyBytes, KeyBytes) {
+        if let (Some(first), Some(last)) = (block_meta.first(), block_meta.last()) {
+            return (first.first_key.clone(), last.last_key.clone());
+        }
+        let first_key = range_tombstones
+            .iter()
+            .map(|x| KeyBytes::from_bytes_with_ts(x.start.clone(), x.ts))
+            .min()
+            .unwrap();
+        let last_key = range_tombstones
+            .iter()
+            .map(|x| KeyBytes::from_bytes_with_ts(x.end.clone(), x.ts))
+            .max()
+            .unwrap();
+        (first_key, last_key)
+    }
+
+    fn range_tombstone_span(range_tombstones: &[RangeTombstone]) -> Option<RangeTombstone> {
+        let start = range_tombstones.iter().map(|x| &x.start).min()?;
+        let end = range_tombstones.iter().map(|x| &x.end).max()?;
+        let ts = range_tombstones.iter().map(|x| x.ts).min()?;
+        Some(RangeTombstone::new(start.clone(), end.clone(), ts))
+    }
+
     /// Create a mock SST with only first key + last key metadata
     pub fn create_meta_only(
         id: usize,
@@ -190,6 +301,10 @@ impl SsTable {
             last_key,
             bloom: None,
             max_ts: 0,
+            max_expire_at: 0,
+            range_tombstones: Vec::new(),
+            range_tombstone_span: None,
+            version: SST_FORMAT_VERSION,
         }
     }
 
@@ -209,7 +324,14 @@ impl SsTable {
         if checksum != crc32fast::hash(block_data) {
             bail!("block checksum mismatched");
         }
-        Ok(Arc::new(Block::decode(block_data)))
+        if self.version < SST_FORMAT_V5 {
+            return Ok(Arc::new(Block::decode_legacy(block_data, self.version)));
+        }
+        let block_data = CompressionType::decompress_block(block_data)?;
+        if self.version < SST_FORMAT_V6 {
+            return Ok(Arc::new(Block::decode_legacy(&block_data, self.version)));
+        }
+        Ok(Arc::new(Block::decode(&block_data)))
     }
 
     /// Read a block from disk, with block cache.
@@ -255,4 +377,31 @@ impl SsTable {
     pub fn max_ts(&self) -> u64 {
         self.max_ts
     }
+
+    /// Once this time has passed, all values with a TTL in the SST have expired.
+    pub fn max_expire_at(&self) -> u64 {
+        self.max_expire_at
+    }
+
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// The range tombstones that are visible at `read_ts` and may delete keys in the range.
+    pub fn range_tombstones_within<'a>(
+        &'a self,
+        lower: Bound<&'a [u8]>,
+        upper: Bound<&'a [u8]>,
+        read_ts: u64,
+    ) -> impl Iterator<Item = &'a RangeTombstone> + 'a {
+        let range_tombstones = match &self.range_tombstone_span {
+            Some(span) if span.ts <= read_ts && span.overlaps(lower, upper) => {
+                &self.range_tombstones[..]
+            }
+            _ => &[],
+        };
+        range_tombstones
+            .iter()
+            .filter(move |x| x.ts <= read_ts && x.overlaps(lower, upper))
+    }
 }
@@ -5,10 +5,12 @@ use anyhow::Result;
 use bytes::BufMut;
 
 use super::bloom::Bloom;
-use super::{BlockMeta, FileObject, SsTable};
+use super::{BlockMeta, CompressionType, FileObject, SsTable, SST_FORMAT_VERSION, SST_MAGIC};
 use crate::block::BlockBuilder;
 use crate::key::{KeySlice, KeyVec};
 use crate::lsm_storage::BlockCache;
+use crate::range_tombstone::RangeTombstone;
+use crate::value::Value;
 
 /// Builds an SSTable from key-value pairs.
 pub struct SsTableBuilder {
@@ -18,22 +20,33 @@ pub struct SsTableBuilder {
     data: Vec<u8>,
     pub(crate) meta: Vec<BlockMeta>,
     block_size: usize,
+    compression: CompressionType,
     key_hashes: Vec<u32>,
     max_ts: u64,
+    max_expire_at: u64,
+    range_tombstones: Vec<RangeTombstone>,
 }
 
 impl SsTableBuilder {
     /// Create a builder based on target block size.
     pub fn new(block_size: usize) -> Self {
+        Self::new_with_compression(block_size, CompressionType::None)
+    }
+
+    /// Create a builder that compresses the data blocks with the codec.
+    pub fn new_with_compression(block_size: usize, compression: CompressionType) -> Self {
         Self {
             data: Vec::new(),
             meta: Vec::new(),
             first_key: KeyVec::new(),
             last_key: KeyVec::new(),
             block_size,
+            compression,
             builder: BlockBuilder::new(block_size),
             key_hashes: Vec::new(),
             max_ts: 0,
+            max_expire_at: 0,
+            range_tombstones: Vec::new(),
         }
     }
 
@@ -46,6 +59,9 @@ impl SsTableBuilder {
         if key.ts() > self.max_ts {
             self.max_ts = key.ts();
         }
+        if let Ok(Value::PutWithTtl(expire_at, _)) = Value::decode(value) {
+            self.max_expire_at = self.max_expire_at.max(expire_at);
+        }
         self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
 
         if self.builder.add(key, value) {
@@ -62,6 +78,15 @@ impl SsTableBuilder {
         self.last_key.set_from_slice(key);
     }
 
+    /// Adds a range tombstone to SSTable. Range tombstones are stored in a dedicated section and do
+    /// not change the key range of the SSTable, unless there is no key-value pair in it.
+    pub fn add_range_tombstone(&mut self, range_tombstone: RangeTombstone) {
+        if range_tombstone.ts > self.max_ts {
+            self.max_ts = range_tombstone.ts;
+        }
+        self.range_tombstones.push(range_tombstone);
+    }
+
     /// Get the estimated size of the SSTable.
     pub fn estimated_size(&self) -> usize {
         self.data.len()
@@ -70,13 +95,15 @@ impl SsTableBuilder {
     fn finish_block(&mut self) {
         let builder = std::mem::replace(&mut self.builder, BlockBuilder::new(self.block_size));
         let encoded_block = builder.build().encode();
+        let offset = self.data.len();
         self.meta.push(BlockMeta {
-            offset: self.data.len(),
+            offset,
             first_key: std::mem::take(&mut self.first_key).into_key_bytes(),
             last_key: std::mem::take(&mut self.last_key).into_key_bytes(),
         });
-        let checksum = crc32fast::hash(&encoded_block);
-        self.data.extend(encoded_block);
+        self.compression
+            .compress_block(&encoded_block, &mut self.data);
+        let checksum = crc32fast::hash(&self.data[offset..]);
         self.data.put_u32(checksum);
     }
 
@@ -87,11 +114,17 @@ impl SsTableBuilder {
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
     ) -> Result<SsTable> {
-        self.finish_block();
+        // An SSTable may only contain range tombstones.
+        if !self.builder.is_empty() || self.range_tombstones.is_empty() {
+            self.finish_block();
+        }
         let mut buf = self.data;
         let meta_offset = buf.len();
-        BlockMeta::encode_block_meta(&self.meta, self.max_ts, &mut buf);
+        BlockMeta::encode_block_meta(&self.meta, self.max_ts, self.max_expire_at, &mut buf);
         buf.put_u32(meta_offset as u32);
+        let range_tombstones_offset = buf.len();
+        RangeTombstone::encode_range_tombstones(&self.range_tombstones, &mut buf);
+        buf.put_u32(range_tombstones_offset as u32);
         let bloom = Bloom::build_from_key_hashes(
             &self.key_hashes,
             Bloom::bloom_bits_per_key(self.key_hashes.len(), 0.01),
@@ -99,17 +132,24 @@ impl SsTableBuilder {
         let bloom_offset = buf.len();
         bloom.encode(&mut buf);
         buf.put_u32(bloom_offset as u32);
+        buf.put_u32(SST_FORMAT_VERSION);
+        buf.put_u32(SST_MAGIC);
         let file = FileObject::create(path.as_ref(), buf)?;
+        let (first_key, last_key) = SsTable::key_range(&self.meta, &self.range_tombstones);
         Ok(SsTable {
             id,
             file,
-            first_key: self.meta.first().unwrap().first_key.clone(),
-            last_key: self.meta.last().unwrap().last_key.clone(),
+            first_key,
+            last_key,
             block_meta: self.meta,
             block_meta_offset: meta_offset,
             block_cache,
             bloom: Some(bloom),
             max_ts: self.max_ts,
+            max_expire_at: self.max_expire_at,
+            range_tombstone_span: SsTable::range_tombstone_span(&self.range_tombstones),
+            range_tombstones: self.range_tombstones,
+            version: SST_FORMAT_VERSION,
         })
     }
 
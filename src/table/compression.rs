@@ -0,0 +1,75 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// The codec of a data block. It is stored as the first byte of each block in the SST since format
+/// v5, so that SSTs written with different options can be read. An older block is not compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CompressionType {
+    #[default]
+    None,
+    Lz4,
+    Snappy,
+}
+
+impl CompressionType {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Lz4 => 1,
+            CompressionType::Snappy => 2,
+        }
+    }
+
+    pub(crate) fn from_u8(x: u8) -> Result<Self> {
+        match x {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Lz4),
+            2 => Ok(CompressionType::Snappy),
+            _ => bail!("unknown compression type {}", x),
+        }
+    }
+
+    /// Compress an encoded block into `buf` with the codec byte in front. The block is stored
+    /// uncompressed if compression fails or does not make it smaller.
+    pub(crate) fn compress_block(self, block: &[u8], buf: &mut Vec<u8>) {
+        let compressed = match self {
+            CompressionType::None => None,
+            CompressionType::Lz4 => Some(lz4_flex::compress_prepend_size(block)),
+            CompressionType::Snappy => snap::raw::Encoder::new().compress_vec(block).ok(),
+        };
+        match compressed {
+            Some(compressed) if compressed.len() < block.len() => {
+                buf.push(self.to_u8());
+                buf.extend(compressed);
+            }
+            _ => {
+                buf.push(CompressionType::None.to_u8());
+                buf.extend_from_slice(block);
+            }
+        }
+    }
+
+    /// Decompress a block written by `compress_block`.
+    pub(crate) fn decompress_block(data: &[u8]) -> Result<Vec<u8>> {
+        let Some((&compression, data)) = data.split_first() else {
+            bail!("block is empty");
+        };
+        Ok(match Self::from_u8(compression)? {
+            CompressionType::None => data.to_vec(),
+            CompressionType::Lz4 => lz4_flex::decompress_size_prepended(data)?,
+            CompressionType::Snappy => snap::raw::Decoder::new().decompress_vec(data)?,
+        })
+    }
+}
+
+/// The codecs of the SSTs by where they are written. Data in the bottom level is rarely rewritten,
+/// so a slower codec with a better ratio pays off there, while L0 is rewritten soon after a flush.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompressionOptions {
+    /// SSTs flushed from memtables, including the new tiers in tiered compaction.
+    pub l0: CompressionType,
+    /// SSTs compacted into a level (or tier) that is not the bottom one.
+    pub middle_levels: CompressionType,
+    /// SSTs compacted into the bottom level (or tier).
+    pub bottom_level: CompressionType,
+}
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use anyhow::Result;
 
 use super::SsTable;
-use crate::block::BlockIterator;
+use crate::block::{Block, BlockIterator};
 use crate::iterators::StorageIterator;
 use crate::key::KeySlice;
 
@@ -15,7 +15,19 @@ pub struct SsTableIterator {
 }
 
 impl SsTableIterator {
+    /// An SSTable with only range tombstones has no block, and the iterator is always invalid.
+    fn empty_inner() -> (usize, BlockIterator) {
+        let block = Block {
+            data: Vec::new(),
+            offsets: Vec::new(),
+        };
+        (0, BlockIterator::create_and_seek_to_first(Arc::new(block)))
+    }
+
     fn seek_to_first_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok(Self::empty_inner());
+        }
         Ok((
             0,
             BlockIterator::create_and_seek_to_first(table.read_block_cached(0)?),
@@ -33,15 +45,40 @@ impl SsTableIterator {
         Ok(iter)
     }
 
-    /// Seek to the first key-value pair.
-    pub fn seek_to_first(&mut self) -> Result<()> {
-        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&self.table)?;
+    fn seek_to_last_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok(Self::empty_inner());
+        }
+        let blk_idx = table.num_of_blocks() - 1;
+        Ok((
+            blk_idx,
+            BlockIterator::create_and_seek_to_last(table.read_block_cached(blk_idx)?),
+        ))
+    }
+
+    /// Create a new iterator and seek to the last key-value pair.
+    pub fn create_and_seek_to_last(table: Arc<SsTable>) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_last_inner(&table)?;
+        let iter = Self {
+            blk_iter,
+            table,
+            blk_idx,
+        };
+        Ok(iter)
+    }
+
+    /// Seek to the last key-value pair.
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        let (blk_idx, blk_iter) = Self::seek_to_last_inner(&self.table)?;
         self.blk_idx = blk_idx;
         self.blk_iter = blk_iter;
         Ok(())
     }
 
     fn seek_to_key_inner(table: &Arc<SsTable>, key: KeySlice) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok(Self::empty_inner());
+        }
         let mut blk_idx = table.find_block_idx(key);
         let mut blk_iter =
             BlockIterator::create_and_seek_to_key(table.read_block_cached(blk_idx)?, key);
@@ -65,14 +102,6 @@ impl SsTableIterator {
         };
         Ok(iter)
     }
-
-    /// Seek to the first key-value pair which >= `key`.
-    pub fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
-        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key)?;
-        self.blk_iter = blk_iter;
-        self.blk_idx = blk_idx;
-        Ok(())
-    }
 }
 
 impl StorageIterator for SsTableIterator {
@@ -90,7 +119,30 @@ impl StorageIterator for SsTableIterator {
         self.blk_iter.is_valid()
     }
 
+    /// Seek to the first key-value pair.
+    fn seek_to_first(&mut self) -> Result<()> {
+        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&self.table)?;
+        self.blk_idx = blk_idx;
+        self.blk_iter = blk_iter;
+        Ok(())
+    }
+
+    /// Seek to the first key-value pair which >= `key`.
+    fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key)?;
+        self.blk_iter = blk_iter;
+        self.blk_idx = blk_idx;
+        Ok(())
+    }
+
     fn next(&mut self) -> Result<()> {
+        if !self.blk_iter.is_valid() {
+            // An invalid iterator that still points to a block has moved before the first key.
+            if self.blk_idx < self.table.num_of_blocks() {
+                self.seek_to_first()?;
+            }
+            return Ok(());
+        }
         self.blk_iter.next();
         if !self.blk_iter.is_valid() {
             self.blk_idx += 1;
@@ -102,4 +154,21 @@ impl StorageIterator for SsTableIterator {
         }
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.blk_iter.is_valid() {
+            // An invalid iterator that points past the last block has moved after the last key.
+            if self.blk_idx >= self.table.num_of_blocks() {
+                self.seek_to_last()?;
+            }
+            return Ok(());
+        }
+        self.blk_iter.prev();
+        if !self.blk_iter.is_valid() && self.blk_idx > 0 {
+            self.blk_idx -= 1;
+            self.blk_iter =
+                BlockIterator::create_and_seek_to_last(self.table.read_block_cached(self.blk_idx)?);
+        }
+        Ok(())
+    }
 }
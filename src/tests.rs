@@ -0,0 +1,19 @@
+//! Tests of the storage engine through `MiniLsm`, with the files written to a temporary directory.
+
+mod blob;
+mod clock;
+mod column_family;
+mod compression;
+mod format;
+mod harness;
+mod history;
+mod lock;
+mod manifest;
+mod merge;
+mod orphan;
+mod range_tombstone;
+mod scan;
+mod ttl;
+mod two_phase_commit;
+mod txn;
+mod wal;
@@ -0,0 +1,108 @@
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{collect_forward, flush_all, key, small_options, state};
+use crate::blob::BlobOptions;
+use crate::lsm_storage::MiniLsm;
+
+fn big_value(i: usize, version: usize) -> Vec<u8> {
+    let mut value = format!("value{:04}_{}", i, version).into_bytes();
+    value.resize(500, b'a' + version as u8);
+    value
+}
+
+/// Wait for the compaction thread to rewrite the blob file to `num_records` records.
+fn wait_for_blob_gc(storage: &MiniLsm, blob_id: usize, num_records: usize) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while state(storage).blob_files[&blob_id].num_records() != num_records {
+        assert!(
+            Instant::now() < deadline,
+            "blob file {} is not collected",
+            blob_id
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn test_blob_gc_liveness() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    // The values of each version are written into one blob file.
+    options.target_sst_size = 1 << 20;
+    options.blob = Some(BlobOptions {
+        min_blob_size: 100,
+        gc_live_ratio: 0.5,
+    });
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    for i in 0..100 {
+        storage.put(&key(i), &big_value(i, 0))?;
+    }
+    storage.put(b"small", b"inline")?;
+    flush_all(&storage)?;
+    let blob_ids = state(&storage)
+        .blob_files
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    assert_eq!(blob_ids.len(), 1);
+    let blob_id = blob_ids[0];
+    assert_eq!(state(&storage).blob_files[&blob_id].num_records(), 100);
+
+    // The versions read by the snapshot keep their records alive after they are overwritten.
+    let snapshot = storage.snapshot();
+    for i in 0..80 {
+        storage.put(&key(i), &big_value(i, 1))?;
+    }
+    flush_all(&storage)?;
+    storage.force_full_compaction()?;
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(state(&storage).blob_files[&blob_id].num_records(), 100);
+    assert_eq!(snapshot.get(&key(5))?, Some(big_value(5, 0).into()));
+
+    // Once the old versions are compacted away, the blob file is rewritten with the records that
+    // are still referenced, under the same id.
+    drop(snapshot);
+    storage.force_full_compaction()?;
+    wait_for_blob_gc(&storage, blob_id, 20);
+    let mut expected = (0..100)
+        .map(|i| (key(i), big_value(i, if i < 80 { 1 } else { 0 })))
+        .collect::<Vec<_>>();
+    expected.push((b"small".to_vec(), b"inline".to_vec()));
+    assert_eq!(
+        collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+
+    // All records of the first version are collected once the last keys are overwritten.
+    for i in 80..100 {
+        storage.put(&key(i), &big_value(i, 2))?;
+    }
+    flush_all(&storage)?;
+    storage.force_full_compaction()?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while state(&storage).blob_files.contains_key(&blob_id) {
+        assert!(
+            Instant::now() < deadline,
+            "blob file {} is not removed",
+            blob_id
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    for (i, (_, value)) in expected.iter_mut().enumerate().skip(80).take(20) {
+        *value = big_value(i, 2);
+    }
+    storage.close()?;
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options)?;
+    assert!(!state(&storage).blob_files.contains_key(&blob_id));
+    assert_eq!(
+        collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+    Ok(())
+}
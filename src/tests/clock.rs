@@ -0,0 +1,119 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{flush_all, key, small_options, value};
+use crate::lsm_storage::{LsmStorageOptions, MiniLsm};
+use crate::mvcc::hlc_millis;
+use crate::value::now_millis;
+
+fn clock_options(hybrid_clock: bool) -> LsmStorageOptions {
+    let mut options = small_options();
+    options.enable_wal = true;
+    options.hybrid_clock = hybrid_clock;
+    options
+}
+
+fn latest_commit_ts(storage: &MiniLsm) -> u64 {
+    storage.inner.mvcc().latest_commit_ts()
+}
+
+#[test]
+fn test_commit_ts_survives_reopen() -> Result<()> {
+    for hybrid_clock in [false, true] {
+        let dir = tempdir()?;
+        let options = clock_options(hybrid_clock);
+        let storage = MiniLsm::open(&dir, options.clone())?;
+        for i in 0..50 {
+            storage.put(&key(i), &value(i, 0))?;
+        }
+        storage.put(b"kept", b"1")?;
+        for i in 0..50 {
+            storage.delete(&key(i))?;
+        }
+        flush_all(&storage)?;
+        // The compaction removes the keys and their tombstones, so that the latest commit ts is
+        // only in the manifest.
+        storage.force_full_compaction()?;
+        let commit_ts = latest_commit_ts(&storage);
+        storage.close()?;
+        drop(storage);
+
+        let storage = MiniLsm::open(&dir, options.clone())?;
+        assert!(latest_commit_ts(&storage) >= commit_ts);
+        let snapshot = storage.snapshot();
+        storage.put(b"kept", b"2")?;
+        assert!(latest_commit_ts(&storage) > commit_ts);
+        assert_eq!(snapshot.get(b"kept")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(snapshot.get(&key(0))?, None);
+        assert_eq!(storage.get(b"kept")?.as_deref(), Some(&b"2"[..]));
+
+        // Reopening with the other clock does not move the commit ts backwards either.
+        let commit_ts = latest_commit_ts(&storage);
+        storage.close()?;
+        drop(storage);
+        let storage = MiniLsm::open(&dir, clock_options(!hybrid_clock))?;
+        assert!(latest_commit_ts(&storage) >= commit_ts);
+        storage.put(b"kept", b"3")?;
+        assert!(latest_commit_ts(&storage) > commit_ts);
+        assert_eq!(snapshot.get(b"kept")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(storage.get(b"kept")?.as_deref(), Some(&b"3"[..]));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_gc_watermark_survives_reopen() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = clock_options(false);
+    options.history_retention.timestamps = Some(1);
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    let mut timestamps = Vec::new();
+    for version in 0..5 {
+        storage.put(&key(0), &value(0, version))?;
+        timestamps.push(latest_commit_ts(&storage));
+        flush_all(&storage)?;
+    }
+    storage.force_full_compaction()?;
+    storage.close()?;
+    drop(storage);
+
+    // The versions below the watermark of the compaction may have been removed, so they cannot be
+    // read even with a longer retention.
+    options.history_retention.timestamps = Some(100);
+    let storage = MiniLsm::open(&dir, options)?;
+    for (version, ts) in timestamps.iter().enumerate() {
+        let result = storage.get_at(&key(0), *ts);
+        if version < 3 {
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains("no longer retained"), "{}", err);
+        } else {
+            assert_eq!(result?.as_deref(), Some(&value(0, version)[..]));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_hybrid_clock_monotonic() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, clock_options(true))?;
+    let start = now_millis();
+    let mut last_ts = 0;
+    // Many commits in the same millisecond get increasing logical counters.
+    for i in 0..1000 {
+        storage.put(&key(i), &value(i, 0))?;
+        let ts = latest_commit_ts(&storage);
+        assert!(ts > last_ts);
+        last_ts = ts;
+    }
+    let end = now_millis();
+    // The counters may carry into the next milliseconds, but not far ahead of the time.
+    assert!((start..=end + 1).contains(&hlc_millis(last_ts)));
+    let snapshot = storage.snapshot();
+    assert_eq!(snapshot.read_ts(), last_ts);
+    assert_eq!(
+        snapshot.get(&key(999))?.as_deref(),
+        Some(&value(999, 0)[..])
+    );
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::ops::Bound;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{collect_forward, flush_all, key, small_options, value};
+use crate::column_family::ColumnFamilyOptions;
+use crate::lsm_storage::{MiniLsm, WriteBatchRecord};
+use crate::table::{CompressionOptions, CompressionType};
+
+fn scan_cf(storage: &MiniLsm, column_family: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    collect_forward(&mut storage.scan_cf(column_family, Bound::Unbounded, Bound::Unbounded)?)
+}
+
+#[test]
+fn test_column_family_recovery() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.enable_wal = true;
+    let column_family_options = ColumnFamilyOptions {
+        block_size: 128,
+        compression: CompressionOptions {
+            l0: CompressionType::Lz4,
+            middle_levels: CompressionType::Snappy,
+            bottom_level: CompressionType::Snappy,
+        },
+        ..ColumnFamilyOptions::from_storage_options(&options)
+    };
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    storage.create_column_family("users", column_family_options.clone())?;
+    storage.create_column_family("tmp", column_family_options)?;
+    assert!(storage
+        .create_column_family("users", ColumnFamilyOptions::from_storage_options(&options))
+        .is_err());
+    for i in 0..100 {
+        storage.put(&key(i), &value(i, 0))?;
+        storage.put_cf("users", &key(i), &value(i, 1))?;
+    }
+    storage.put_cf("tmp", b"t", b"1")?;
+    flush_all(&storage)?;
+    // The records of a batch in several column families are written with the same timestamp.
+    storage.write_batch_cf(&[
+        ("users", WriteBatchRecord::Del(&key(0)[..])),
+        ("default", WriteBatchRecord::Put(&key(0)[..], &b"batch"[..])),
+        (
+            "users",
+            WriteBatchRecord::DelRange(&key(10)[..], &key(20)[..]),
+        ),
+    ])?;
+    storage.put_cf("users", b"wal", b"only")?;
+    storage.drop_column_family("tmp")?;
+    storage.sync()?;
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options)?;
+    assert_eq!(storage.list_column_families(), vec!["default", "users"]);
+    assert!(storage.get_cf("tmp", b"t").is_err());
+    let mut users = (1..10)
+        .chain(20..100)
+        .map(|i| (key(i), value(i, 1)))
+        .collect::<Vec<_>>();
+    users.push((b"wal".to_vec(), b"only".to_vec()));
+    assert_eq!(scan_cf(&storage, "users")?, users);
+    let mut default = (1..100).map(|i| (key(i), value(i, 0))).collect::<Vec<_>>();
+    default.insert(0, (key(0), b"batch".to_vec()));
+    assert_eq!(scan_cf(&storage, "default")?, default);
+
+    // The column families are recovered from their SSTs alone once the memtables are flushed.
+    flush_all(&storage)?;
+    storage.close()?;
+    drop(storage);
+    let storage = MiniLsm::open(&dir, small_options())?;
+    assert_eq!(scan_cf(&storage, "users")?, users);
+    assert_eq!(scan_cf(&storage, "default")?, default);
+    Ok(())
+}
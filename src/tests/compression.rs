@@ -0,0 +1,53 @@
+use std::ops::Bound;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{collect_forward, flush_all, key, small_options, state};
+use crate::lsm_storage::MiniLsm;
+use crate::table::{CompressionOptions, CompressionType};
+
+#[test]
+fn test_compressed_blocks() -> Result<()> {
+    let mut uncompressed_size = 0;
+    for codec in [
+        CompressionType::None,
+        CompressionType::Lz4,
+        CompressionType::Snappy,
+    ] {
+        let dir = tempdir()?;
+        let mut options = small_options();
+        options.compression = CompressionOptions {
+            l0: codec,
+            middle_levels: codec,
+            bottom_level: codec,
+        };
+        let storage = MiniLsm::open(&dir, options.clone())?;
+        let expected = (0..300)
+            .map(|i| (key(i), format!("{:0>100}", i).into_bytes()))
+            .collect::<Vec<_>>();
+        for (key, value) in &expected {
+            storage.put(key, value)?;
+        }
+        flush_all(&storage)?;
+        let size = state(&storage)
+            .sstables
+            .values()
+            .map(|x| x.table_size())
+            .sum::<u64>();
+        if codec == CompressionType::None {
+            uncompressed_size = size;
+        } else {
+            assert!(size < uncompressed_size, "{:?} does not compress", codec);
+        }
+        storage.close()?;
+        drop(storage);
+
+        let storage = MiniLsm::open(&dir, options)?;
+        assert_eq!(
+            collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+            expected
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::ops::Bound;
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{collect_backward, collect_forward, copy_dir, flush_all, small_options};
+use crate::lsm_storage::MiniLsm;
+
+/// The files written by the storage engine before the formats are versioned. Keys `key_000` to
+/// `key_019` are put and flushed, then `key_000` to `key_004` are overwritten and `key_005` to
+/// `key_007` are deleted and flushed, and `key_010`, `key_011` and `key_100` are only in the WAL.
+pub(super) const V1_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/fixtures/v1");
+
+fn v1_fixture_pairs() -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut pairs = Vec::new();
+    for i in 0..20 {
+        let value = match i {
+            0..=4 => format!("value_{}_v2", i),
+            5..=7 | 11 => continue,
+            10 => "value_10_v3".to_string(),
+            _ => format!("value_{}_v1", i),
+        };
+        pairs.push((format!("key_{:03}", i).into_bytes(), value.into_bytes()));
+    }
+    pairs.push((b"key_100".to_vec(), b"wal_only".to_vec()));
+    pairs
+}
+
+#[test]
+fn test_open_v1_fixture() -> Result<()> {
+    let dir = tempdir()?;
+    copy_dir(Path::new(V1_FIXTURE), dir.path())?;
+    let mut options = small_options();
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    let mut expected = v1_fixture_pairs();
+    assert_eq!(
+        collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+    assert_eq!(
+        collect_backward(&mut storage.scan_rev(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+    assert_eq!(storage.get(b"key_005")?, None);
+    assert_eq!(
+        storage.get(b"key_001")?.as_deref(),
+        Some(&b"value_1_v2"[..])
+    );
+
+    // The v1 SSTs and WAL are read along with the files written in the current formats.
+    storage.put(b"key_005", b"new")?;
+    storage.delete(b"key_008")?;
+    storage.sync()?;
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    expected.retain(|(key, _)| key != b"key_008");
+    expected.insert(5, (b"key_005".to_vec(), b"new".to_vec()));
+    assert_eq!(
+        collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+
+    // The compaction rewrites the v1 SSTs in the current format.
+    flush_all(&storage)?;
+    storage.force_full_compaction()?;
+    storage.close()?;
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options)?;
+    assert_eq!(
+        collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::iterators::StorageIterator;
+use crate::key::{KeySlice, TS_RANGE_BEGIN};
+use crate::lsm_storage::{LsmStorageOptions, LsmStorageState, MiniLsm};
+use crate::table::SsTableIterator;
+
+/// The key-value pairs expected in the storage.
+pub type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Options that make small SSTs and blocks, so that a few hundred keys span several of them.
+pub fn small_options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_size = 256;
+    options.target_sst_size = 4096;
+    options
+}
+
+pub fn key(i: usize) -> Vec<u8> {
+    format!("key{:04}", i).into_bytes()
+}
+
+pub fn value(i: usize, version: usize) -> Vec<u8> {
+    format!("value{:04}_{}", i, version).into_bytes()
+}
+
+/// Collect the key-value pairs from the position of `iter` to the end.
+pub fn collect_forward<I>(iter: &mut I) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+where
+    I: for<'a> StorageIterator<KeyType<'a> = &'a [u8]>,
+{
+    let mut pairs = Vec::new();
+    while iter.is_valid() {
+        pairs.push((iter.key().to_vec(), iter.value().to_vec()));
+        iter.next()?;
+    }
+    Ok(pairs)
+}
+
+/// Collect the key-value pairs from the position of `iter` to the start, in ascending order.
+pub fn collect_backward<I>(iter: &mut I) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+where
+    I: for<'a> StorageIterator<KeyType<'a> = &'a [u8]>,
+{
+    let mut pairs = Vec::new();
+    while iter.is_valid() {
+        pairs.push((iter.key().to_vec(), iter.value().to_vec()));
+        iter.prev()?;
+    }
+    pairs.reverse();
+    Ok(pairs)
+}
+
+/// The pairs of `model` in `[lower, upper]`.
+pub fn expected_range(
+    model: &Model,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    model
+        .range::<[u8], _>((lower, upper))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// A xorshift generator, so that the randomized tests are reproducible.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// Copy the files of a directory into another one.
+pub fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// The state of the default column family.
+pub fn state(storage: &MiniLsm) -> Arc<LsmStorageState> {
+    storage.inner.state.read().clone()
+}
+
+/// Freeze the memtables and flush all of them, as `MiniLsm::force_flush` only flushes one.
+pub fn flush_all(storage: &MiniLsm) -> Result<()> {
+    storage.force_flush()?;
+    while !state(storage).imm_memtables.is_empty() {
+        storage.inner.force_flush_next_imm_memtable()?;
+    }
+    Ok(())
+}
+
+/// The encoded versions of a key in the SSTs, from the latest to the earliest.
+pub fn sst_versions(storage: &MiniLsm, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut versions = Vec::new();
+    for table in state(storage).sstables.values() {
+        let mut iter = SsTableIterator::create_and_seek_to_key(
+            table.clone(),
+            KeySlice::from_slice(key, TS_RANGE_BEGIN),
+        )?;
+        while iter.is_valid() && iter.key().key_ref() == key {
+            versions.push((iter.key().ts(), iter.value().to_vec()));
+            iter.next()?;
+        }
+    }
+    versions.sort_by_key(|x| std::cmp::Reverse(x.0));
+    Ok(versions.into_iter().map(|(_, value)| value).collect())
+}
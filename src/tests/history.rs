@@ -0,0 +1,165 @@
+use std::ops::Bound;
+use std::time::Duration;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{
+    collect_forward, expected_range, flush_all, key, small_options, sst_versions, value, Model,
+};
+use crate::lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord};
+
+fn history_options(retention: u64) -> LsmStorageOptions {
+    let mut options = small_options();
+    options.enable_wal = true;
+    options.history_retention.timestamps = Some(retention);
+    options
+}
+
+/// Write three versions of 100 keys in one batch each, where the last one deletes some keys and a
+/// range of keys it does not rewrite, as a range tombstone only covers older writes. Returns the commit ts of each version and the pairs expected at it.
+fn write_versions(storage: &MiniLsm) -> Result<Vec<(u64, Model)>> {
+    let mut model = Model::new();
+    let mut versions = Vec::new();
+    for version in 0..3 {
+        let mut batch = Vec::new();
+        for i in 0..100 {
+            if version == 2 && (40..50).contains(&i) {
+                continue;
+            }
+            if version == 2 && i % 3 == 0 {
+                batch.push(WriteBatchRecord::Del(key(i)));
+                model.remove(&key(i));
+            } else {
+                batch.push(WriteBatchRecord::Put(key(i), value(i, version)));
+                model.insert(key(i), value(i, version));
+            }
+        }
+        if version == 2 {
+            batch.push(WriteBatchRecord::DelRange(key(40), key(50)));
+            model.retain(|k, _| !(key(40)..key(50)).contains(k));
+        }
+        storage.write_batch(&batch)?;
+        versions.push((storage.snapshot().read_ts(), model.clone()));
+        flush_all(storage)?;
+    }
+    Ok(versions)
+}
+
+fn check_versions(storage: &MiniLsm, versions: &[(u64, Model)]) -> Result<()> {
+    for (ts, model) in versions {
+        for i in (0..100).step_by(7) {
+            assert_eq!(
+                storage.get_at(&key(i), *ts)?.map(|x| x.to_vec()),
+                model.get(&key(i)).cloned(),
+                "key {} at ts {}",
+                i,
+                ts
+            );
+        }
+        assert_eq!(
+            collect_forward(&mut storage.scan_at(Bound::Unbounded, Bound::Unbounded, *ts)?)?,
+            expected_range(model, Bound::Unbounded, Bound::Unbounded)
+        );
+        let (lower, upper) = (key(30), key(60));
+        assert_eq!(
+            collect_forward(&mut storage.scan_at(
+                Bound::Excluded(&lower),
+                Bound::Included(&upper),
+                *ts
+            )?)?,
+            expected_range(model, Bound::Excluded(&lower), Bound::Included(&upper))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_read_at_past_timestamps() -> Result<()> {
+    let dir = tempdir()?;
+    let options = history_options(100);
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    let versions = write_versions(&storage)?;
+    check_versions(&storage, &versions)?;
+
+    // The compaction keeps the versions within the retention.
+    storage.force_full_compaction()?;
+    check_versions(&storage, &versions)?;
+    assert_eq!(sst_versions(&storage, &key(1))?.len(), 3);
+
+    // A ts ahead of the latest commit cannot be read.
+    let latest_ts = versions.last().unwrap().0;
+    assert!(storage.get_at(&key(1), latest_ts + 1).is_err());
+
+    storage.close()?;
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options)?;
+    check_versions(&storage, &versions)?;
+    Ok(())
+}
+
+#[test]
+fn test_read_below_history_retention() -> Result<()> {
+    let dir = tempdir()?;
+    let options = history_options(2);
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    let mut timestamps = Vec::new();
+    for version in 0..5 {
+        storage.put(&key(0), &value(0, version))?;
+        timestamps.push(storage.snapshot().read_ts());
+        flush_all(&storage)?;
+    }
+    let check = |storage: &MiniLsm| -> Result<()> {
+        // Only the last two commit timestamps before the latest one are retained.
+        for (version, ts) in timestamps.iter().enumerate() {
+            let result = storage.get_at(&key(0), *ts);
+            if version < 2 {
+                let err = result.unwrap_err().to_string();
+                assert!(err.contains("no longer retained"), "{}", err);
+                assert!(storage
+                    .scan_at(Bound::Unbounded, Bound::Unbounded, *ts)
+                    .is_err());
+            } else {
+                assert_eq!(result?.as_deref(), Some(&value(0, version)[..]));
+            }
+        }
+        Ok(())
+    };
+    check(&storage)?;
+
+    // The versions below the retention are removed by the compaction, except the one that is
+    // visible at the earliest retained ts.
+    storage.force_full_compaction()?;
+    check(&storage)?;
+    assert_eq!(sst_versions(&storage, &key(0))?.len(), 3);
+
+    storage.close()?;
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options)?;
+    check(&storage)?;
+    Ok(())
+}
+
+#[test]
+fn test_history_retention_by_duration() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.history_retention.duration = Some(Duration::from_secs(3600));
+    let storage = MiniLsm::open(&dir, options)?;
+    let mut timestamps = Vec::new();
+    for version in 0..5 {
+        storage.put(&key(0), &value(0, version))?;
+        timestamps.push(storage.snapshot().read_ts());
+        flush_all(&storage)?;
+    }
+    storage.force_full_compaction()?;
+    // All commits are within the last hour, so none of the versions is removed.
+    assert_eq!(sst_versions(&storage, &key(0))?.len(), 5);
+    for (version, ts) in timestamps.iter().enumerate() {
+        assert_eq!(
+            storage.get_at(&key(0), *ts)?.as_deref(),
+            Some(&value(0, version)[..])
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::small_options;
+use crate::lsm_storage::MiniLsm;
+use crate::mvcc::lock_manager::{LockError, LockMode};
+
+fn lock_error(result: Result<()>) -> Option<LockError> {
+    result.err()?.downcast_ref::<LockError>().cloned()
+}
+
+#[test]
+fn test_pessimistic_counter() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.lock_timeout = Duration::from_secs(10);
+    let storage = MiniLsm::open(&dir, options)?;
+    storage.put(b"counter", b"0")?;
+    thread::scope(|scope| {
+        let workers = (0..4)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    for _ in 0..50 {
+                        let txn = storage.new_pessimistic_txn()?;
+                        let value = txn.get_for_update(b"counter")?.unwrap();
+                        let value = std::str::from_utf8(&value)?.parse::<u64>()?;
+                        txn.put(b"counter", (value + 1).to_string().as_bytes())?;
+                        txn.commit()?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+    assert_eq!(storage.get(b"counter")?.as_deref(), Some(&b"200"[..]));
+    Ok(())
+}
+
+#[test]
+fn test_deadlock() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.lock_timeout = Duration::from_secs(10);
+    let storage = MiniLsm::open(&dir, options)?;
+    let txn1 = storage.new_pessimistic_txn()?;
+    let txn2 = storage.new_pessimistic_txn()?;
+    txn1.lock(b"a", LockMode::Exclusive)?;
+    txn2.lock(b"b", LockMode::Exclusive)?;
+    let waiter = {
+        let txn1 = txn1.clone();
+        thread::spawn(move || lock_error(txn1.lock(b"b", LockMode::Exclusive)))
+    };
+    // Wait for txn1 to block on the lock of txn2.
+    thread::sleep(Duration::from_millis(200));
+    assert!(matches!(
+        lock_error(txn2.lock(b"a", LockMode::Exclusive)),
+        Some(LockError::Deadlock { .. })
+    ));
+    drop(txn2);
+    assert_eq!(waiter.join().unwrap(), None);
+    txn1.put(b"b", b"1")?;
+    txn1.commit()?;
+    assert_eq!(storage.get(b"b")?.as_deref(), Some(&b"1"[..]));
+    Ok(())
+}
+
+#[test]
+fn test_lock_timeout() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.lock_timeout = Duration::from_millis(100);
+    let storage = MiniLsm::open(&dir, options)?;
+    storage.put(b"b", b"1")?;
+    let txn1 = storage.new_pessimistic_txn()?;
+    let txn2 = storage.new_pessimistic_txn()?;
+    txn1.lock(b"a", LockMode::Shared)?;
+    txn2.lock(b"a", LockMode::Shared)?;
+    // An upgrade waits for the other shared holder instead of reporting a deadlock.
+    assert!(matches!(
+        lock_error(txn1.lock(b"a", LockMode::Exclusive)),
+        Some(LockError::Timeout { .. })
+    ));
+    txn1.lock(b"b", LockMode::Exclusive)?;
+    // A write locks the key before it is made, rather than on commit. A range deletion locks the
+    // keys in the range.
+    assert!(matches!(
+        lock_error(txn2.put(b"b", b"2")),
+        Some(LockError::Timeout { .. })
+    ));
+    assert!(matches!(
+        lock_error(txn2.delete_range(b"a", b"c")),
+        Some(LockError::Timeout { .. })
+    ));
+    drop(txn2);
+    txn1.lock(b"a", LockMode::Exclusive)?;
+    Ok(())
+}
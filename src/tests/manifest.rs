@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::format::V1_FIXTURE;
+use super::harness::{copy_dir, flush_all, key, small_options, state, value};
+use crate::column_family::ColumnFamilyOptions;
+use crate::lsm_storage::{LsmStorageOptions, MiniLsm};
+
+fn manifest_options() -> LsmStorageOptions {
+    let mut options = small_options();
+    options.enable_wal = true;
+    options.max_manifest_size = 1024;
+    options
+}
+
+/// The names of the manifest files in `dir`.
+fn manifest_files(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("MANIFEST") {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn current(dir: &Path) -> Result<String> {
+    Ok(std::fs::read_to_string(dir.join("CURRENT"))?
+        .trim_end()
+        .to_string())
+}
+
+#[test]
+fn test_manifest_rotation() -> Result<()> {
+    let dir = tempdir()?;
+    let options = manifest_options();
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    assert_eq!(current(dir.path())?, "MANIFEST-000001");
+    storage.create_column_family("cf", ColumnFamilyOptions::from_storage_options(&options))?;
+    storage.create_column_family(
+        "dropped",
+        ColumnFamilyOptions::from_storage_options(&options),
+    )?;
+    storage.put_cf("dropped", b"a", b"1")?;
+    for i in 0..200 {
+        storage.put(&key(i), &value(i, 0))?;
+        storage.put_cf("cf", &key(i), &value(i, 1))?;
+        if i % 10 == 9 {
+            flush_all(&storage)?;
+        }
+    }
+    storage.drop_column_family("dropped")?;
+    storage.put(b"unflushed", b"1")?;
+    storage.sync()?;
+
+    // The old manifests are removed once the new one is current.
+    let current_manifest = current(dir.path())?;
+    assert_ne!(current_manifest, "MANIFEST-000001");
+    assert_eq!(manifest_files(dir.path())?, vec![current_manifest.clone()]);
+    let sstables = state(&storage).sstables.len();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    assert_eq!(state(&storage).sstables.len(), sstables);
+    for i in 0..200 {
+        assert_eq!(storage.get(&key(i))?, Some(value(i, 0).into()));
+        assert_eq!(storage.get_cf("cf", &key(i))?, Some(value(i, 1).into()));
+    }
+    assert_eq!(storage.get(b"unflushed")?.as_deref(), Some(&b"1"[..]));
+    assert!(storage.get_cf("dropped", b"a").is_err());
+    // The manifest is rotated again when it grows.
+    for i in 200..400 {
+        storage.put(&key(i), &value(i, 0))?;
+        if i % 10 == 9 {
+            flush_all(&storage)?;
+        }
+    }
+    assert_ne!(current(dir.path())?, current_manifest);
+    assert_eq!(manifest_files(dir.path())?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_json_manifest_upgrade() -> Result<()> {
+    let dir = tempdir()?;
+    copy_dir(Path::new(V1_FIXTURE), dir.path())?;
+    assert_eq!(manifest_files(dir.path())?, vec!["MANIFEST"]);
+    let storage = MiniLsm::open(&dir, manifest_options())?;
+    // The JSON manifest is replaced by a binary one, which is pointed to by `CURRENT`.
+    assert_eq!(current(dir.path())?, "MANIFEST-000001");
+    assert_eq!(manifest_files(dir.path())?, vec!["MANIFEST-000001"]);
+    storage.put(b"key_100", b"new")?;
+    flush_all(&storage)?;
+    let sstables = state(&storage).sstables.len();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, manifest_options())?;
+    assert_eq!(state(&storage).sstables.len(), sstables);
+    assert_eq!(
+        storage.get(b"key_000")?.as_deref(),
+        Some(&b"value_0_v2"[..])
+    );
+    assert_eq!(storage.get(b"key_100")?.as_deref(), Some(&b"new"[..]));
+    Ok(())
+}
+
+#[test]
+fn test_torn_manifest_record() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, manifest_options())?;
+    for i in 0..20 {
+        storage.put(&key(i), &value(i, 0))?;
+    }
+    flush_all(&storage)?;
+    drop(storage);
+    let manifest = dir.path().join(current(dir.path())?);
+
+    // A record whose payload is not written, and one whose checksum does not match. The records
+    // added after the torn one is removed are recovered.
+    for torn in [&[0, 0, 0, 100, 1, 2][..], &[0, 0, 0, 1, 1, 0, 0, 0, 0]] {
+        let data = std::fs::read(&manifest)?;
+        std::fs::write(&manifest, [&data[..], torn].concat())?;
+        let storage = MiniLsm::open(&dir, manifest_options())?;
+        assert!(std::fs::read(&manifest)?.starts_with(&data));
+        assert_eq!(storage.get(&key(0))?, Some(value(0, 0).into()));
+    }
+    let storage = MiniLsm::open(&dir, manifest_options())?;
+    storage.put(b"new", b"1")?;
+    flush_all(&storage)?;
+    let sstables = state(&storage).sstables.len();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, manifest_options())?;
+    assert_eq!(state(&storage).sstables.len(), sstables);
+    assert_eq!(storage.get(b"new")?.as_deref(), Some(&b"1"[..]));
+    drop(storage);
+
+    // A corrupted record before the end is not taken as torn.
+    let mut data = std::fs::read(&manifest)?;
+    let len = data.len();
+    data[len / 2] ^= 0xff;
+    std::fs::write(&manifest, &data)?;
+    assert!(MiniLsm::open(&dir, manifest_options()).is_err());
+    Ok(())
+}
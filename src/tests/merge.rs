@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{flush_all, small_options, sst_versions};
+use crate::lsm_storage::MiniLsm;
+use crate::merge_operator::MergeOperator;
+use crate::value::Value;
+
+/// Appends the operands to the value, separated by commas.
+struct Append;
+
+impl MergeOperator for Append {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Vec<u8> {
+        let mut parts = existing_value.into_iter().collect::<Vec<_>>();
+        parts.extend(operands);
+        parts.join(&b","[..])
+    }
+}
+
+fn open(dir: &tempfile::TempDir) -> Result<Arc<MiniLsm>> {
+    let mut options = small_options();
+    options.merge_operator = Some(Arc::new(Append));
+    MiniLsm::open(dir, options)
+}
+
+#[test]
+fn test_merge_collapse_below_watermark() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = open(&dir)?;
+    storage.put(b"a", b"x")?;
+    for operand in ["1", "2", "3"] {
+        storage.merge(b"a", operand.as_bytes())?;
+        storage.merge(b"b", operand.as_bytes())?;
+        flush_all(&storage)?;
+    }
+    let txn = storage.new_txn()?;
+    for operand in ["4", "5"] {
+        storage.merge(b"a", operand.as_bytes())?;
+        flush_all(&storage)?;
+    }
+    assert_eq!(sst_versions(&storage, b"a")?.len(), 6);
+
+    // The versions at and below the watermark are collapsed into a put, and the operands above it
+    // are kept for the latest reads.
+    storage.force_full_compaction()?;
+    assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"x,1,2,3,4,5"[..]));
+    assert_eq!(txn.get(b"a")?.as_deref(), Some(&b"x,1,2,3"[..]));
+    let versions = sst_versions(&storage, b"a")?;
+    assert_eq!(versions.len(), 3);
+    assert!(matches!(Value::decode(&versions[0])?, Value::Merge(_)));
+    assert!(matches!(Value::decode(&versions[1])?, Value::Merge(_)));
+    assert_eq!(Value::decode(&versions[2])?, Value::Put(b"x,1,2,3"));
+    // The operands of a key without a base version are applied to no value at the bottom level.
+    assert_eq!(
+        sst_versions(&storage, b"b")?
+            .iter()
+            .map(|x| Value::decode(x))
+            .collect::<Result<Vec<_>>>()?,
+        vec![Value::Put(b"1,2,3")]
+    );
+
+    drop(txn);
+    storage.force_full_compaction()?;
+    assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"x,1,2,3,4,5"[..]));
+    let versions = sst_versions(&storage, b"a")?;
+    assert_eq!(versions.len(), 1);
+    assert_eq!(Value::decode(&versions[0])?, Value::Put(b"x,1,2,3,4,5"));
+    Ok(())
+}
+
+#[test]
+fn test_merge_after_delete() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = open(&dir)?;
+    storage.put(b"a", b"x")?;
+    storage.merge(b"a", b"1")?;
+    flush_all(&storage)?;
+    storage.delete(b"a")?;
+    storage.merge(b"a", b"2")?;
+    assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"2"[..]));
+    flush_all(&storage)?;
+    storage.force_full_compaction()?;
+    assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"2"[..]));
+    assert_eq!(sst_versions(&storage, b"a")?.len(), 1);
+    Ok(())
+}
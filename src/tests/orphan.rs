@@ -0,0 +1,69 @@
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::format::V1_FIXTURE;
+use super::harness::{copy_dir, flush_all, key, small_options, state, value};
+use crate::lsm_storage::{LsmStorageOptions, MiniLsm};
+
+fn orphan_options(quarantine: bool) -> LsmStorageOptions {
+    let mut options = small_options();
+    options.enable_wal = true;
+    options.quarantine_orphan_files = quarantine;
+    options
+}
+
+/// Leave the files of a crash: an SST written by a flush that is not recorded, the manifest
+/// replaced by the upgrade, and the WAL of a memtable that is flushed. Returns their names, in order.
+fn leave_orphans(dir: &Path, max_sst_id: usize) -> Result<Vec<String>> {
+    let sst = format!("{:05}.sst", max_sst_id + 1);
+    std::fs::copy(dir.join(format!("{:05}.sst", max_sst_id)), dir.join(&sst))?;
+    for name in ["00002.wal", "MANIFEST"] {
+        std::fs::copy(Path::new(V1_FIXTURE).join(name), dir.join(name))?;
+    }
+    let mut names = vec!["00002.wal".to_string(), "MANIFEST".to_string(), sst];
+    names.sort();
+    Ok(names)
+}
+
+#[test]
+fn test_orphan_files() -> Result<()> {
+    for quarantine in [false, true] {
+        let dir = tempdir()?;
+        copy_dir(Path::new(V1_FIXTURE), dir.path())?;
+        let storage = MiniLsm::open(&dir, orphan_options(quarantine))?;
+        // The JSON manifest is replaced, and the recovered `.wal` is removed once flushed.
+        assert!(storage.orphan_files().is_empty());
+        for i in 0..100 {
+            storage.put(&key(i), &value(i, 0))?;
+        }
+        flush_all(&storage)?;
+        assert!(!dir.path().join("00002.wal").exists());
+        let max_sst_id = *state(&storage).sstables.keys().max().unwrap();
+        drop(storage);
+
+        let orphans = leave_orphans(dir.path(), max_sst_id)?;
+        let storage = MiniLsm::open(&dir, orphan_options(quarantine))?;
+        assert_eq!(storage.orphan_files(), orphans);
+        for name in &orphans {
+            assert!(!dir.path().join(name).exists());
+            assert_eq!(
+                dir.path().join("quarantine").join(name).exists(),
+                quarantine
+            );
+        }
+        for i in 0..100 {
+            assert_eq!(storage.get(&key(i))?, Some(value(i, 0).into()));
+        }
+        assert_eq!(
+            storage.get(b"key_010")?.as_deref(),
+            Some(&b"value_10_v3"[..])
+        );
+        drop(storage);
+
+        let storage = MiniLsm::open(&dir, orphan_options(quarantine))?;
+        assert!(storage.orphan_files().is_empty());
+    }
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use std::ops::Bound;
+
+use anyhow::Result;
+use bytes::BufMut;
+use tempfile::tempdir;
+
+use super::harness::{collect_forward, flush_all, key, small_options, state, value};
+use crate::iterators::StorageIterator;
+use crate::lsm_storage::{MiniLsm, WriteBatchRecord};
+use crate::mvcc::txn::TxnError;
+use crate::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
+use crate::table::{SsTableIterator, SST_FORMAT_VERSION};
+
+#[test]
+fn test_delete_empty_range() -> Result<()> {
+    for serializable in [false, true] {
+        let dir = tempdir()?;
+        let mut options = small_options();
+        options.serializable = serializable;
+        let storage = MiniLsm::open(&dir, options)?;
+        storage.put(b"a", b"1")?;
+        let err = storage.delete_range(b"b", b"a").unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&TxnError::EmptyRange));
+        let err = storage
+            .write_batch(&[
+                WriteBatchRecord::Put(&b"b"[..], &b"2"[..]),
+                WriteBatchRecord::DelRange(&b"a"[..], &b"a"[..]),
+            ])
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&TxnError::EmptyRange));
+        // The batch is not written.
+        assert_eq!(storage.get(b"b")?, None);
+        assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_delete_range_then_compact() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, small_options())?;
+    for i in 0..300 {
+        storage.put(&key(i), &value(i, 0))?;
+    }
+    flush_all(&storage)?;
+    storage.delete_range(&key(100), &key(200))?;
+    // A version written after the tombstone is not deleted by it.
+    storage.put(&key(150), &value(150, 1))?;
+    flush_all(&storage)?;
+    let txn = storage.new_txn()?;
+    storage.delete_range(&key(0), &key(50))?;
+    flush_all(&storage)?;
+    assert!(state(&storage)
+        .sstables
+        .values()
+        .any(|x| !x.range_tombstones().is_empty()));
+
+    let check = |storage: &MiniLsm| -> Result<()> {
+        let expected = (50..100)
+            .chain(200..300)
+            .map(|i| (key(i), value(i, 0)))
+            .chain([(key(150), value(150, 1))])
+            .collect::<std::collections::BTreeMap<_, _>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+            expected
+        );
+        assert_eq!(storage.get(&key(120))?, None);
+        assert_eq!(storage.get(&key(10))?, None);
+        assert_eq!(storage.get(&key(150))?, Some(value(150, 1).into()));
+        Ok(())
+    };
+    check(&storage)?;
+
+    // The last range tombstone is above the watermark, so it is kept at the bottom level.
+    storage.force_full_compaction()?;
+    check(&storage)?;
+    assert_eq!(txn.get(&key(10))?, Some(value(10, 0).into()));
+    assert_eq!(txn.get(&key(120))?, None);
+    let range_tombstones = state(&storage)
+        .sstables
+        .values()
+        .flat_map(|x| x.range_tombstones().to_vec())
+        .collect::<Vec<_>>();
+    assert_eq!(range_tombstones.len(), 1);
+    assert_eq!(range_tombstones[0].start, key(0));
+
+    // Once the transaction is released, the tombstones and the keys they delete are dropped.
+    drop(txn);
+    storage.force_full_compaction()?;
+    check(&storage)?;
+    let state = state(&storage);
+    assert!(state
+        .sstables
+        .values()
+        .all(|x| x.range_tombstones().is_empty()));
+    let mut num_keys = 0;
+    for table in state.sstables.values() {
+        let mut iter = SsTableIterator::create_and_seek_to_first(table.clone())?;
+        while iter.is_valid() {
+            num_keys += 1;
+            iter.next()?;
+        }
+    }
+    assert_eq!(num_keys, 151);
+    Ok(())
+}
+
+#[test]
+fn test_delete_range_recovery() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    for i in 0..100 {
+        storage.put(&key(i), &value(i, 0))?;
+    }
+    flush_all(&storage)?;
+    storage.delete_range(&key(10), &key(20))?;
+    flush_all(&storage)?;
+    storage.delete_range(&key(30), &key(40))?;
+    storage.sync()?;
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options)?;
+    let keys = collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect::<Vec<_>>();
+    let expected = (0..10)
+        .chain(20..30)
+        .chain(40..100)
+        .map(key)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+#[test]
+fn test_decode_corrupted_range_tombstones() -> Result<()> {
+    let range_tombstones = vec![
+        RangeTombstone::new(key(0).into(), key(10).into(), 5),
+        RangeTombstone::new(key(20).into(), key(30).into(), 7),
+    ];
+    let mut buf = Vec::new();
+    RangeTombstone::encode_range_tombstones(&range_tombstones, &mut buf);
+    assert_eq!(
+        RangeTombstone::decode_range_tombstones(&buf, SST_FORMAT_VERSION)?,
+        range_tombstones
+    );
+    // A truncated or corrupted section is an error rather than a panic.
+    for len in 0..buf.len() {
+        assert!(RangeTombstone::decode_range_tombstones(&buf[..len], SST_FORMAT_VERSION).is_err());
+    }
+    for i in 0..buf.len() {
+        let mut corrupted = buf.clone();
+        corrupted[i] ^= 0xff;
+        assert!(RangeTombstone::decode_range_tombstones(&corrupted, SST_FORMAT_VERSION).is_err());
+    }
+    // A length past the end of the section is found even if the checksum matches.
+    let mut buf = Vec::new();
+    buf.put_u32(1);
+    buf.put_u32(u32::MAX);
+    buf.put_u32(crc32fast::hash(&buf[4..]));
+    assert!(RangeTombstone::decode_range_tombstones(&buf, SST_FORMAT_VERSION).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_fragmented_range_tombstones() {
+    // Overlapping and nested tombstones, some with the same ts or sharing bounds.
+    let range_tombstones = [
+        (10, 50, 3),
+        (20, 30, 8),
+        (30, 60, 5),
+        (40, 45, 1),
+        (60, 70, 5),
+    ]
+    .into_iter()
+    .chain((0..10).map(|i| (i * 10 + 5, i * 10 + 15, i as u64 % 4)))
+    .map(|(start, end, ts)| RangeTombstone::new(key(start).into(), key(end).into(), ts))
+    .collect::<Vec<_>>();
+    let fragmented = FragmentedRangeTombstones::new(&range_tombstones);
+    for i in 0..120 {
+        for ts in 0..10 {
+            let expected = range_tombstones.iter().any(|x| x.covers(&key(i), ts));
+            assert_eq!(
+                fragmented.covers(&key(i), ts),
+                expected,
+                "key {} ts {}",
+                i,
+                ts
+            );
+        }
+    }
+    assert!(!FragmentedRangeTombstones::new(&[]).covers(&key(0), 0));
+}
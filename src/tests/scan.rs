@@ -0,0 +1,193 @@
+use std::ops::Bound;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{
+    collect_backward, collect_forward, expected_range, flush_all, key, small_options, value, Model,
+    Rng,
+};
+use crate::iterators::StorageIterator;
+use crate::lsm_storage::MiniLsm;
+
+/// Write versions of 200 keys across two SSTs and the memtable, with point deletes and a range
+/// delete. Returns the model before and after the second version.
+fn fill(storage: &MiniLsm) -> Result<(Model, Model)> {
+    let mut model = Model::new();
+    for i in 0..200 {
+        storage.put(&key(i), &value(i, 0))?;
+        model.insert(key(i), value(i, 0));
+    }
+    flush_all(storage)?;
+    let old_model = model.clone();
+    for i in (0..200).step_by(2) {
+        storage.put(&key(i), &value(i, 1))?;
+        model.insert(key(i), value(i, 1));
+    }
+    for i in (0..200).step_by(3) {
+        storage.delete(&key(i))?;
+        model.remove(&key(i));
+    }
+    storage.delete_range(&key(50), &key(80))?;
+    for i in 50..80 {
+        model.remove(&key(i));
+    }
+    flush_all(storage)?;
+    for i in (0..200).step_by(5) {
+        storage.put(&key(i), &value(i, 2))?;
+        model.insert(key(i), value(i, 2));
+    }
+    storage.delete(&key(101))?;
+    model.remove(&key(101));
+    Ok((old_model, model))
+}
+
+fn check_ranges(storage: &MiniLsm, model: &Model) -> Result<()> {
+    let bounds = [
+        (Bound::Unbounded, Bound::Unbounded),
+        (Bound::Included(key(50)), Bound::Excluded(key(80))),
+        (Bound::Excluded(key(45)), Bound::Included(key(120))),
+        (Bound::Included(key(199)), Bound::Unbounded),
+        (Bound::Unbounded, Bound::Excluded(key(0))),
+    ];
+    for (lower, upper) in &bounds {
+        let (lower, upper) = (
+            lower.as_ref().map(|x| &x[..]),
+            upper.as_ref().map(|x| &x[..]),
+        );
+        let expected = expected_range(model, lower, upper);
+        assert_eq!(collect_forward(&mut storage.scan(lower, upper)?)?, expected);
+        assert_eq!(
+            collect_backward(&mut storage.scan_rev(lower, upper)?)?,
+            expected
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_scan_across_tombstones_and_versions() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, small_options())?;
+    storage.put(&key(0), &value(0, 0))?;
+    let txn = storage.new_txn()?;
+    let (_, model) = fill(&storage)?;
+    check_ranges(&storage, &model)?;
+
+    // The transaction reads the version before the writes, and its own writes.
+    let mut txn_model = Model::new();
+    txn_model.insert(key(0), value(0, 0));
+    txn.put(&key(7), b"txn")?;
+    txn_model.insert(key(7).to_vec(), b"txn".to_vec());
+    let expected = expected_range(&txn_model, Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(
+        collect_forward(&mut txn.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+    assert_eq!(
+        collect_backward(&mut txn.scan_rev(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+    drop(txn);
+
+    storage.force_full_compaction()?;
+    check_ranges(&storage, &model)?;
+    Ok(())
+}
+
+#[test]
+fn test_scan_of_snapshot_after_compaction() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, small_options())?;
+    let mut old_model = Model::new();
+    for i in 0..200 {
+        storage.put(&key(i), &value(i, 0))?;
+        old_model.insert(key(i), value(i, 0));
+    }
+    let snapshot = storage.snapshot();
+    let (_, model) = fill(&storage)?;
+    storage.force_full_compaction()?;
+    let expected = expected_range(&old_model, Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(
+        collect_forward(&mut snapshot.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+    assert_eq!(
+        collect_backward(&mut snapshot.scan_rev(Bound::Unbounded, Bound::Unbounded)?)?,
+        expected
+    );
+    check_ranges(&storage, &model)?;
+    Ok(())
+}
+
+/// Move iterators back and forth and seek them at random, and check each position against the
+/// model.
+#[test]
+fn test_seek_and_switch_direction() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, small_options())?;
+    let (_, model) = fill(&storage)?;
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for round in 0..200 {
+        let lower = key(rng.below(220));
+        let upper = key(rng.below(220));
+        if lower > upper {
+            continue;
+        }
+        let (lower, upper) = (Bound::Included(&lower[..]), Bound::Excluded(&upper[..]));
+        let expected = expected_range(&model, lower, upper);
+        let reverse = rng.below(2) == 0;
+        let mut iter = if reverse {
+            storage.scan_rev(lower, upper)?
+        } else {
+            storage.scan(lower, upper)?
+        };
+        let mut pos = if reverse {
+            expected.len() as i64 - 1
+        } else {
+            0
+        };
+        for _ in 0..50 {
+            match usize::try_from(pos).ok().and_then(|pos| expected.get(pos)) {
+                Some((k, v)) => {
+                    assert!(iter.is_valid(), "round {}", round);
+                    assert_eq!(iter.key(), &k[..], "round {}", round);
+                    assert_eq!(iter.value(), &v[..], "round {}", round);
+                }
+                None => assert!(!iter.is_valid(), "round {}", round),
+            }
+            match rng.below(6) {
+                0 | 1 => {
+                    iter.next()?;
+                    pos = (pos + 1).min(expected.len() as i64);
+                }
+                2 | 3 => {
+                    iter.prev()?;
+                    pos = (pos - 1).max(-1);
+                }
+                4 => {
+                    let target = key(rng.below(220));
+                    iter.seek_to_key(&target)?;
+                    pos = expected.partition_point(|(k, _)| k < &target) as i64;
+                }
+                _ => {
+                    iter.seek_to_first()?;
+                    pos = 0;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_scan_after_reopen() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, small_options())?;
+    let (_, model) = fill(&storage)?;
+    storage.close()?;
+    drop(storage);
+    let storage = MiniLsm::open(&dir, small_options())?;
+    check_ranges(&storage, &model)?;
+    Ok(())
+}
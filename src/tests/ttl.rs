@@ -0,0 +1,78 @@
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{collect_forward, flush_all, key, small_options, sst_versions, state, value};
+use crate::compact::{CompactionOptions, SimpleLeveledCompactionOptions};
+use crate::lsm_storage::MiniLsm;
+
+#[test]
+fn test_ttl_expiry_compaction() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.enable_wal = true;
+    // The size-based compaction is never triggered.
+    options.compaction_options = CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 100,
+        max_levels: 3,
+    });
+    let storage = MiniLsm::open(&dir, options.clone())?;
+    for i in 0..50 {
+        storage.put_with_ttl(&key(i), &value(i, 0), Duration::from_millis(500))?;
+    }
+    storage.put(b"keep", b"1")?;
+    flush_all(&storage)?;
+    assert_eq!(storage.get(&key(0))?, Some(value(0, 0).into()));
+    let state_before = state(&storage);
+    assert!(state_before
+        .sstables
+        .values()
+        .all(|x| x.max_expire_at() > 0));
+
+    // The expiration time is kept in the SSTs across a restart.
+    storage.close()?;
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options)?;
+    let max_expire_at = state(&storage)
+        .sstables
+        .values()
+        .map(|x| x.max_expire_at())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        max_expire_at,
+        state_before
+            .sstables
+            .values()
+            .map(|x| x.max_expire_at())
+            .collect::<Vec<_>>()
+    );
+
+    std::thread::sleep(Duration::from_millis(600));
+    assert_eq!(storage.get(&key(0))?, None);
+    let keep = vec![(b"keep".to_vec(), b"1".to_vec())];
+    assert_eq!(
+        collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        keep
+    );
+
+    // The compaction thread rewrites the SSTs without the expired values.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while state(&storage)
+        .sstables
+        .values()
+        .any(|x| x.max_expire_at() != 0)
+    {
+        assert!(Instant::now() < deadline, "expired SSTs are not compacted");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(sst_versions(&storage, &key(0))?.is_empty());
+    assert_eq!(sst_versions(&storage, b"keep")?.len(), 1);
+    assert_eq!(
+        collect_forward(&mut storage.scan(Bound::Unbounded, Bound::Unbounded)?)?,
+        keep
+    );
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::{flush_all, small_options};
+use crate::lsm_storage::{LsmStorageOptions, MiniLsm};
+use crate::mvcc::conflict::{ConflictError, ConflictingTxn};
+use crate::mvcc::IsolationLevel;
+
+fn wal_options() -> LsmStorageOptions {
+    let mut options = small_options();
+    options.enable_wal = true;
+    options
+}
+
+#[test]
+fn test_prepared_txn_recovery() -> Result<()> {
+    let dir = tempdir()?;
+    {
+        let storage = MiniLsm::open(&dir, wal_options())?;
+        storage.put(b"a", b"0")?;
+        let txn = storage.new_txn_with_isolation(IsolationLevel::SnapshotIsolation)?;
+        txn.put(b"a", b"1")?;
+        txn.delete_range(b"c", b"d")?;
+        txn.prepare("committed")?;
+        let txn = storage.new_txn()?;
+        txn.put(b"b", b"1")?;
+        txn.prepare("rolled back")?;
+        drop(txn);
+        // The prepare records are logged again in the WAL of the new memtables.
+        storage.put(b"c1", b"1")?;
+        flush_all(&storage)?;
+        storage.put(b"c2", b"1")?;
+        flush_all(&storage)?;
+        let txn = storage.new_txn()?;
+        txn.put(b"e", b"1")?;
+        txn.prepare("done")?;
+        txn.commit()?;
+        storage.close()?;
+    }
+    {
+        let storage = MiniLsm::open(&dir, wal_options())?;
+        assert_eq!(storage.prepared_txns(), vec!["committed", "rolled back"]);
+        assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"0"[..]));
+        assert_eq!(storage.get(b"e")?.as_deref(), Some(&b"1"[..]));
+        // The recovered transactions are still checked against.
+        let txn = storage.new_txn_with_isolation(IsolationLevel::SnapshotIsolation)?;
+        txn.put(b"b", b"2")?;
+        let err = txn.commit().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConflictError>().map(|err| &err.other),
+            Some(&ConflictingTxn::Prepared("rolled back".to_string()))
+        );
+        storage.commit_prepared("committed")?;
+        assert!(storage.commit_prepared("committed").is_err());
+        storage.rollback_prepared("rolled back")?;
+        assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(storage.get(b"c1")?, None);
+        storage.close()?;
+    }
+    let storage = MiniLsm::open(&dir, wal_options())?;
+    assert!(storage.prepared_txns().is_empty());
+    assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(storage.get(b"b")?, None);
+    assert_eq!(storage.get(b"c2")?, None);
+    Ok(())
+}
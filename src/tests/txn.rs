@@ -0,0 +1,206 @@
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use super::harness::small_options;
+use crate::iterators::StorageIterator;
+use crate::lsm_storage::{MiniLsm, WriteBatchRecord};
+use crate::mvcc::conflict::ConflictError;
+use crate::mvcc::lock_manager::{LockError, LockMode};
+use crate::mvcc::txn::{Transaction, TxnError};
+use crate::mvcc::IsolationLevel;
+
+fn is_conflict(result: Result<()>) -> bool {
+    result.is_err_and(|err| err.downcast_ref::<ConflictError>().is_some())
+}
+
+fn txn_error(result: Result<()>) -> Option<TxnError> {
+    result.err()?.downcast_ref::<TxnError>().cloned()
+}
+
+/// Scan the keys in `[a, c)` in a transaction and write their count.
+fn count_keys(txn: &Arc<Transaction>) -> Result<()> {
+    let mut iter = txn.scan(Bound::Included(b"a"), Bound::Excluded(b"c"))?;
+    let mut count = 0;
+    while iter.is_valid() {
+        count += 1;
+        iter.next()?;
+    }
+    txn.put(b"count", count.to_string().as_bytes())
+}
+
+#[test]
+fn test_snapshot_isolation_against_plain_writes() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, small_options())?;
+    storage.put(b"a", b"1")?;
+    // Plain writes are not recorded without a transaction checking for conflicts.
+    assert!(storage.inner.mvcc().committed_txns.lock().is_empty());
+
+    let writes: [&dyn Fn() -> Result<()>; 5] = [
+        &|| storage.put(b"a", b"2"),
+        &|| storage.write_batch(&[WriteBatchRecord::Del(b"a")]),
+        &|| storage.delete_range(b"0", b"b"),
+        &|| storage.write_batch_cf(&[("default", WriteBatchRecord::Put(b"a", b"3"))]),
+        &|| {
+            let txn = storage.new_txn_with_isolation(IsolationLevel::SnapshotReads)?;
+            txn.put(b"a", b"4")?;
+            txn.commit()
+        },
+    ];
+    for write in writes {
+        let txn = storage.new_txn_with_isolation(IsolationLevel::SnapshotIsolation)?;
+        let value = txn.get(b"a")?.unwrap_or_default();
+        write()?;
+        txn.put(b"a", &[value.as_ref(), b"x"].concat())?;
+        assert!(is_conflict(txn.commit()));
+    }
+
+    let txn = storage.new_txn_with_isolation(IsolationLevel::SnapshotIsolation)?;
+    storage.put(b"b", b"1")?;
+    txn.put(b"a", b"5")?;
+    txn.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_phantom_conflicts() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options)?;
+    storage.put(b"a1", b"1")?;
+
+    for (key, conflict) in [(&b"b5"[..], true), (b"c", false), (b"0", false)] {
+        let txn = storage.new_txn()?;
+        count_keys(&txn)?;
+        storage.put(key, b"x")?;
+        assert_eq!(is_conflict(txn.commit()), conflict, "{:?}", key);
+        storage.delete(key)?;
+    }
+
+    let txn = storage.new_txn()?;
+    count_keys(&txn)?;
+    storage.delete_range(b"b", b"bb")?;
+    assert!(is_conflict(txn.commit()));
+
+    // Snapshot isolation does not check the ranges read.
+    let txn = storage.new_txn_with_isolation(IsolationLevel::SnapshotIsolation)?;
+    count_keys(&txn)?;
+    storage.put(b"b7", b"x")?;
+    txn.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_savepoints() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, small_options())?;
+    storage.put(b"a", b"0")?;
+    storage.put(b"d", b"0")?;
+    let txn = storage.new_txn()?;
+    assert_eq!(
+        txn_error(txn.rollback_to_savepoint()),
+        Some(TxnError::NoSavepoint)
+    );
+    txn.put(b"a", b"1")?;
+    txn.set_savepoint()?;
+    txn.put(b"a", b"2")?;
+    txn.put(b"b", b"2")?;
+    txn.set_savepoint()?;
+    txn.delete(b"b")?;
+    txn.delete_range(b"c", b"e")?;
+    assert_eq!(txn.get(b"b")?, None);
+    assert_eq!(txn.get(b"d")?, None);
+
+    // The savepoints are nested, so the latest one is rolled back to first.
+    txn.rollback_to_savepoint()?;
+    assert_eq!(txn.get(b"a")?.as_deref(), Some(&b"2"[..]));
+    assert_eq!(txn.get(b"b")?.as_deref(), Some(&b"2"[..]));
+    assert_eq!(txn.get(b"d")?.as_deref(), Some(&b"0"[..]));
+    txn.rollback_to_savepoint()?;
+    assert_eq!(txn.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(txn.get(b"b")?, None);
+    assert_eq!(
+        txn_error(txn.rollback_to_savepoint()),
+        Some(TxnError::NoSavepoint)
+    );
+
+    // The writes undone by a savepoint are not committed, and do not conflict.
+    txn.set_savepoint()?;
+    txn.put(b"c", b"1")?;
+    txn.rollback_to_savepoint()?;
+    storage.put(b"c", b"2")?;
+    txn.commit()?;
+    assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(storage.get(b"b")?, None);
+    assert_eq!(storage.get(b"c")?.as_deref(), Some(&b"2"[..]));
+    assert_eq!(storage.get(b"d")?.as_deref(), Some(&b"0"[..]));
+    Ok(())
+}
+
+#[test]
+fn test_rollback_releases_locks() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.lock_timeout = Duration::from_millis(100);
+    let storage = MiniLsm::open(&dir, options)?;
+    let txn1 = storage.new_pessimistic_txn()?;
+    let txn2 = storage.new_pessimistic_txn()?;
+    txn1.put(b"a", b"1")?;
+    txn1.lock(b"b", LockMode::Shared)?;
+    assert!(matches!(
+        txn2.put(b"a", b"2")
+            .err()
+            .and_then(|err| err.downcast_ref::<LockError>().cloned()),
+        Some(LockError::Timeout { .. })
+    ));
+    txn1.rollback()?;
+    txn2.put(b"a", b"2")?;
+    txn2.lock(b"b", LockMode::Exclusive)?;
+    txn2.commit()?;
+    assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"2"[..]));
+    Ok(())
+}
+
+#[test]
+fn test_finished_txn_errors() -> Result<()> {
+    let dir = tempdir()?;
+    let mut options = small_options();
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options)?;
+
+    let txn = storage.new_txn()?;
+    txn.put(b"a", b"1")?;
+    txn.commit()?;
+    assert_eq!(txn_error(txn.put(b"a", b"2")), Some(TxnError::Committed));
+    assert_eq!(txn_error(txn.set_savepoint()), Some(TxnError::Committed));
+    assert_eq!(txn_error(txn.commit()), Some(TxnError::Committed));
+    assert_eq!(txn_error(txn.rollback()), Some(TxnError::Committed));
+
+    let txn = storage.new_txn()?;
+    txn.put(b"a", b"2")?;
+    txn.rollback()?;
+    assert_eq!(txn_error(txn.delete(b"a")), Some(TxnError::RolledBack));
+    assert_eq!(
+        txn_error(txn.rollback_to_savepoint()),
+        Some(TxnError::RolledBack)
+    );
+    assert_eq!(txn_error(txn.commit()), Some(TxnError::RolledBack));
+    assert_eq!(txn_error(txn.prepare("x")), Some(TxnError::RolledBack));
+
+    // A prepared transaction can only be committed or rolled back.
+    let txn = storage.new_txn()?;
+    txn.put(b"a", b"3")?;
+    txn.prepare("x")?;
+    assert_eq!(txn_error(txn.put(b"a", b"4")), Some(TxnError::Prepared));
+    assert_eq!(txn_error(txn.set_savepoint()), Some(TxnError::Prepared));
+    assert_eq!(txn_error(txn.prepare("y")), Some(TxnError::Prepared));
+    txn.commit()?;
+    assert_eq!(txn_error(txn.rollback()), Some(TxnError::Committed));
+    assert_eq!(storage.get(b"a")?.as_deref(), Some(&b"3"[..]));
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::Result;
+use tempfile::{tempdir, TempDir};
+
+use super::harness::{copy_dir, flush_all, key, small_options, state, value};
+use crate::lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord, WriteOptions};
+use crate::wal::{WalRecoveryMode, WAL_BLOCK_SIZE};
+
+const SYNC: WriteOptions = WriteOptions {
+    sync: true,
+    disable_wal: false,
+};
+
+fn wal_options(mode: WalRecoveryMode) -> LsmStorageOptions {
+    let mut options = small_options();
+    options.target_sst_size = 1 << 20;
+    options.enable_wal = true;
+    options.wal_recovery_mode = mode;
+    options
+}
+
+/// The last segment of the shared WAL in `dir`.
+fn last_segment(dir: &Path) -> Result<PathBuf> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            segments.push(path);
+        }
+    }
+    segments.sort();
+    Ok(segments.pop().unwrap())
+}
+
+fn file_len(path: &Path) -> Result<usize> {
+    Ok(std::fs::metadata(path)?.len() as usize)
+}
+
+fn put_synced(storage: &MiniLsm, key: &[u8], value: &[u8]) -> Result<()> {
+    storage.write_batch_with_options(&[WriteBatchRecord::Put(key, value)], &SYNC)
+}
+
+/// Open a copy of the files in `dir`, as the storage opened in `dir` is not closed.
+fn open_copy(dir: &Path, mode: WalRecoveryMode) -> Result<(Arc<MiniLsm>, TempDir)> {
+    let copy = tempdir()?;
+    copy_dir(dir, copy.path())?;
+    Ok((MiniLsm::open(&copy, wal_options(mode))?, copy))
+}
+
+#[test]
+fn test_record_ends_in_block_trailer() -> Result<()> {
+    for gap in 1..7 {
+        let dir = tempdir()?;
+        let storage = MiniLsm::open(&dir, wal_options(WalRecoveryMode::AbsoluteConsistency))?;
+        put_synced(&storage, b"key", b"v")?;
+        let segment = last_segment(dir.path())?;
+        let start = file_len(&segment)?;
+        put_synced(&storage, b"key", b"v")?;
+        let overhead = file_len(&segment)? - start - 1;
+        // Leave `gap` bytes at the end of the first block, which are too few for a fragment.
+        let value = vec![b'v'; WAL_BLOCK_SIZE - gap - file_len(&segment)? - overhead];
+        put_synced(&storage, b"key", &value)?;
+        assert_eq!(file_len(&segment)?, WAL_BLOCK_SIZE - gap);
+
+        let (recovered, copy) = open_copy(dir.path(), WalRecoveryMode::AbsoluteConsistency)?;
+        assert_eq!(recovered.get(b"key")?.as_deref(), Some(&value[..]));
+        // The next record starts in the next block.
+        put_synced(&recovered, b"next", b"1")?;
+        let (recovered, _copy) = open_copy(copy.path(), WalRecoveryMode::AbsoluteConsistency)?;
+        assert_eq!(recovered.get(b"key")?.as_deref(), Some(&value[..]));
+        assert_eq!(recovered.get(b"next")?.as_deref(), Some(&b"1"[..]));
+    }
+    Ok(())
+}
+
+/// The number of the first `n` keys that are recovered, which must be a prefix if `prefix` is set.
+fn count_keys(storage: &MiniLsm, n: usize, prefix: bool) -> Result<usize> {
+    let mut count = 0;
+    for i in 0..n {
+        if storage.get(&key(i))?.is_some() {
+            assert!(!prefix || count == i);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[test]
+fn test_torn_wal_recovery() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, wal_options(WalRecoveryMode::default()))?;
+    for i in 0..2000 {
+        storage.put(&key(i), b"value-value-value")?;
+    }
+    // A record of several fragments.
+    let big = vec![b'x'; 100_000];
+    storage.put(b"big", &big)?;
+    storage.put(b"last", b"1")?;
+    storage.sync()?;
+    let segment = last_segment(dir.path())?;
+    assert!(file_len(&segment)? > 4 * WAL_BLOCK_SIZE);
+
+    // The last record is torn by a crash.
+    let torn = tempdir()?;
+    copy_dir(dir.path(), torn.path())?;
+    let torn_segment = last_segment(torn.path())?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&torn_segment)?
+        .set_len(file_len(&segment)? as u64 - 3)?;
+    assert!(open_copy(torn.path(), WalRecoveryMode::AbsoluteConsistency).is_err());
+    for mode in [
+        WalRecoveryMode::TolerateCorruptedTailRecords,
+        WalRecoveryMode::PointInTime,
+        WalRecoveryMode::SkipAnyCorruptedRecords,
+    ] {
+        let (recovered, copy) = open_copy(torn.path(), mode)?;
+        assert_eq!(recovered.get(b"last")?, None);
+        assert_eq!(recovered.get(b"big")?.as_deref(), Some(&big[..]));
+        assert_eq!(count_keys(&recovered, 2000, true)?, 2000);
+        // The torn record is truncated, so the records written after recovery can be read.
+        put_synced(&recovered, b"after", b"1")?;
+        let (recovered, _copy) = open_copy(copy.path(), WalRecoveryMode::AbsoluteConsistency)?;
+        assert_eq!(recovered.get(b"after")?.as_deref(), Some(&b"1"[..]));
+    }
+
+    // A record in the second block is corrupted.
+    let corrupted = tempdir()?;
+    copy_dir(dir.path(), corrupted.path())?;
+    let corrupted_segment = last_segment(corrupted.path())?;
+    let mut data = std::fs::read(&corrupted_segment)?;
+    data[WAL_BLOCK_SIZE + 100] ^= 0xff;
+    std::fs::write(&corrupted_segment, &data)?;
+    for mode in [
+        WalRecoveryMode::AbsoluteConsistency,
+        WalRecoveryMode::TolerateCorruptedTailRecords,
+    ] {
+        assert!(open_copy(corrupted.path(), mode).is_err());
+    }
+    let (recovered, _copy) = open_copy(corrupted.path(), WalRecoveryMode::PointInTime)?;
+    let count = count_keys(&recovered, 2000, true)?;
+    assert!(count > 0 && count < 2000);
+    assert_eq!(recovered.get(b"big")?, None);
+    assert_eq!(recovered.get(b"last")?, None);
+    // The rest of the block of the corrupted fragment is skipped.
+    let (recovered, _copy) = open_copy(corrupted.path(), WalRecoveryMode::SkipAnyCorruptedRecords)?;
+    let count = count_keys(&recovered, 2000, false)?;
+    assert!(count > 1000 && count < 2000);
+    assert_eq!(recovered.get(b"big")?.as_deref(), Some(&big[..]));
+    assert_eq!(recovered.get(b"last")?.as_deref(), Some(&b"1"[..]));
+    Ok(())
+}
+
+#[test]
+fn test_group_commit() -> Result<()> {
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, wal_options(WalRecoveryMode::AbsoluteConsistency))?;
+    let wal = state(&storage).memtable.wal().unwrap().clone();
+    let syncs = wal.sync_count();
+    thread::scope(|scope| {
+        let writers = (0..8)
+            .map(|thread| {
+                let storage = &storage;
+                scope.spawn(move || -> Result<()> {
+                    for i in 0..50 {
+                        let key = key(thread * 50 + i);
+                        put_synced(storage, &key, &value(thread * 50 + i, 0))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        writers
+            .into_iter()
+            .try_for_each(|writer| writer.join().unwrap())
+    })?;
+    // The writers waiting for a sync share the next one.
+    assert!(wal.sync_count() - syncs < 400);
+
+    let (recovered, _copy) = open_copy(dir.path(), WalRecoveryMode::AbsoluteConsistency)?;
+    for i in 0..400 {
+        assert_eq!(recovered.get(&key(i))?.as_deref(), Some(&value(i, 0)[..]));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_write_with_wal_disabled() -> Result<()> {
+    const NO_WAL: WriteOptions = WriteOptions {
+        sync: false,
+        disable_wal: true,
+    };
+    let dir = tempdir()?;
+    let storage = MiniLsm::open(&dir, wal_options(WalRecoveryMode::AbsoluteConsistency))?;
+    put_synced(&storage, b"a", b"1")?;
+    storage.write_batch_with_options(&[WriteBatchRecord::Put(b"b", b"1")], &NO_WAL)?;
+    put_synced(&storage, b"c", b"1")?;
+    assert_eq!(storage.get(b"b")?.as_deref(), Some(&b"1"[..]));
+
+    // The write is lost without a flush, but the writes around it are not.
+    let (recovered, _copy) = open_copy(dir.path(), WalRecoveryMode::AbsoluteConsistency)?;
+    assert_eq!(recovered.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(recovered.get(b"b")?, None);
+    assert_eq!(recovered.get(b"c")?.as_deref(), Some(&b"1"[..]));
+
+    flush_all(&storage)?;
+    let (recovered, _copy) = open_copy(dir.path(), WalRecoveryMode::AbsoluteConsistency)?;
+    assert_eq!(recovered.get(b"b")?.as_deref(), Some(&b"1"[..]));
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+
+use crate::blob::BlobPointer;
+
+const VALUE_KIND_PUT: u8 = 0;
+const VALUE_KIND_MERGE: u8 = 1;
+const VALUE_KIND_PUT_WITH_TTL: u8 = 2;
+const VALUE_KIND_BLOB: u8 = 3;
+
+/// A value as stored in memtables, WALs and SSTs. A non-empty value starts with a one-byte kind
+/// followed by the payload, and an empty value is a delete tombstone. The kind is stored since v3 of
+/// the SST and WAL formats, and is added to the values of older files when they are read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value<'a> {
+    Delete,
+    Put(&'a [u8]),
+    /// Encoded merge operands, see `crate::merge_operator`.
+    Merge(&'a [u8]),
+    /// A put that expires at the given time, in milliseconds since the unix epoch.
+    PutWithTtl(u64, &'a [u8]),
+    /// A put whose value is in a blob file. It only appears in SSTs.
+    Blob(BlobPointer),
+}
+
+impl<'a> Value<'a> {
+    pub fn decode(raw: &'a [u8]) -> Result<Self> {
+        let Some((kind, mut payload)) = raw.split_first() else {
+            return Ok(Value::Delete);
+        };
+        match *kind {
+            VALUE_KIND_PUT => Ok(Value::Put(payload)),
+            VALUE_KIND_MERGE => Ok(Value::Merge(payload)),
+            VALUE_KIND_PUT_WITH_TTL => {
+                if payload.len() < std::mem::size_of::<u64>() {
+                    bail!("invalid value with ttl");
+                }
+                let expire_at = payload.get_u64();
+                Ok(Value::PutWithTtl(expire_at, payload))
+            }
+            VALUE_KIND_BLOB => Ok(Value::Blob(BlobPointer::decode(payload)?)),
+            kind => bail!("unknown value kind {}", kind),
+        }
+    }
+
+    /// Decode a value as it is seen at `now`, where an expired put is a delete tombstone.
+    pub fn decode_at(raw: &'a [u8], now: u64) -> Result<Self> {
+        match Self::decode(raw)? {
+            Value::PutWithTtl(expire_at, _) if expire_at <= now => Ok(Value::Delete),
+            value => Ok(value),
+        }
+    }
+
+    /// The user value of an encoded put, with or without a TTL, that is not in a blob file.
+    pub fn put_value(raw: &[u8]) -> &[u8] {
+        match Value::decode(raw) {
+            Ok(Value::Put(value) | Value::PutWithTtl(_, value)) => value,
+            _ => panic!("not a put"),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = match self {
+            Value::Delete => return Vec::new(),
+            Value::Put(payload) => (VALUE_KIND_PUT, payload),
+            Value::Merge(payload) => (VALUE_KIND_MERGE, payload),
+            Value::PutWithTtl(expire_at, payload) => {
+                let mut buf = Vec::with_capacity(payload.len() + 9);
+                buf.push(VALUE_KIND_PUT_WITH_TTL);
+                buf.put_u64(*expire_at);
+                buf.extend_from_slice(payload);
+                return buf;
+            }
+            Value::Blob(pointer) => {
+                let mut buf = Vec::with_capacity(BlobPointer::ENCODED_LEN + 1);
+                buf.push(VALUE_KIND_BLOB);
+                pointer.encode(&mut buf);
+                return buf;
+            }
+        };
+        let mut buf = Vec::with_capacity(payload.len() + 1);
+        buf.push(kind);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Convert a value of an SST or a WAL before v3, which is the raw value of a put or empty for a
+    /// delete tombstone.
+    pub fn upgrade_legacy(raw: &[u8]) -> Vec<u8> {
+        if raw.is_empty() {
+            Vec::new()
+        } else {
+            Value::Put(raw).encode()
+        }
+    }
+}
+
+/// The current time in milliseconds since the unix epoch, which is compared against the expiration
+/// time of a `Value::PutWithTtl`.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The expiration time of a value written now that lives for `ttl`.
+pub fn expire_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
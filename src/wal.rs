@@ -1,94 +1,1042 @@
+use std::collections::{HashMap, VecDeque};
 use std::fs::{File, OpenOptions};
 use std::hash::Hasher;
 use std::io::{BufWriter, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
 use bytes::{Buf, BufMut, Bytes};
 use crossbeam_skiplist::SkipMap;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 
+use crate::column_family::DEFAULT_COLUMN_FAMILY_ID;
 use crate::key::{KeyBytes, KeySlice};
+use crate::range_tombstone::RangeTombstone;
+use crate::value::Value;
 
+/// The key-value pairs and range tombstones of a column family recovered from a WAL.
+pub type WalMemTable = (Arc<SkipMap<KeyBytes, Bytes>>, Vec<RangeTombstone>);
+
+/// The first WAL format, which has no header.
+const WAL_FORMAT_V1: u32 = 1;
+/// Since v2, the WAL starts with a magic number and the version, and a record with an empty key is
+/// a range tombstone. A v1 WAL starts with the length of a key, which is only taken as the magic if
+/// the key is over 61 KiB and starts with the last two bytes of the magic.
+const WAL_FORMAT_V2: u32 = 2;
+/// Since v3, the values start with their kind, see `Value`.
+const WAL_FORMAT_V3: u32 = 3;
+/// Since v4, a record starts with a column family id. The records of an older WAL are in the
+/// default column family.
+const WAL_FORMAT_V4: u32 = 4;
+/// Since v5, the lengths of keys and values are `u32` rather than `u16`.
+const WAL_FORMAT_V5: u32 = 5;
+/// Since v6, the WAL may contain the records of two-phase commit transactions.
+const WAL_FORMAT_V6: u32 = 6;
+/// Since v7, a write batch is logged as one record with one checksum, see `Wal::put_batch`.
+const WAL_FORMAT_V7: u32 = 7;
+/// Since v8, the WAL is split into blocks of `WAL_BLOCK_SIZE` bytes, and a record is written in
+/// fragments that do not cross blocks, each with a checksum. A partial write is found by the
+/// checksum of a fragment, and a corrupted fragment only loses the rest of its block.
+const WAL_FORMAT_V8: u32 = 8;
+const WAL_FORMAT_VERSION: u32 = WAL_FORMAT_V8;
+const WAL_MAGIC: u32 = 0xF5D1_5A7E;
+/// The column family id that marks a transaction record, which no column family has.
+const TXN_RECORD: u32 = u32::MAX;
+/// The column family id that marks a write batch record.
+const BATCH_RECORD: u32 = u32::MAX - 1;
+const BATCH_PUT: u8 = 0;
+const BATCH_DEL_RANGE: u8 = 1;
+const TXN_PREPARE: u8 = 0;
+const TXN_COMMIT: u8 = 1;
+const TXN_ROLLBACK: u8 = 2;
+pub(crate) const WAL_BLOCK_SIZE: usize = 32 * 1024;
+/// The checksum, the length and the type of a fragment.
+const FRAGMENT_HEADER_SIZE: usize = 7;
+const FRAGMENT_FULL: u8 = 1;
+const FRAGMENT_FIRST: u8 = 2;
+const FRAGMENT_MIDDLE: u8 = 3;
+const FRAGMENT_LAST: u8 = 4;
+
+/// How to recover the WALs with corrupted records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalRecoveryMode {
+    /// Fail on any corrupted record, including a record torn by a crash at the end of a WAL.
+    AbsoluteConsistency,
+    /// Remove the records torn at the end of a WAL, and fail on a corrupted record before them.
+    #[default]
+    TolerateCorruptedTailRecords,
+    /// Recover the records before the first corrupted one, and discard the ones after it,
+    /// including the records in the later WAL files.
+    PointInTime,
+    /// Skip the corrupted records and recover the rest. In a WAL split into blocks, the rest of
+    /// the block of a corrupted fragment is skipped. In an older WAL, the records after a corrupted
+    /// one cannot be found and are discarded.
+    SkipAnyCorruptedRecords,
+}
+
+/// A record of a transaction with two-phase commit, see `Transaction::prepare`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxnRecord {
+    /// The writes of a prepared transaction, with the values encoded as in the memtables, and the
+    /// `[start, end)` ranges it deletes.
+    Prepare {
+        xid: String,
+        writes: Vec<(Bytes, Bytes)>,
+        range_deletes: Vec<(Bytes, Bytes)>,
+    },
+    /// The decision to commit a prepared transaction, which is logged before its writes.
+    Commit {
+        xid: String,
+        commit_ts: u64,
+    },
+    Rollback {
+        xid: String,
+    },
+}
+
+/// A record of a write batch in a column family, with the value encoded as in the memtables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchRecord {
+    Put(Bytes, Bytes),
+    /// Delete the keys in `[start, end)`.
+    DelRange(Bytes, Bytes),
+}
+
+/// A record decoded from a WAL.
+enum WalRecord {
+    Put(usize, KeyBytes, Bytes),
+    RangeTombstone(usize, RangeTombstone),
+    Txn(TxnRecord),
+    Batch(u64, Vec<(usize, BatchRecord)>),
+}
+
+/// Why a record or a fragment cannot be decoded.
+enum Corruption {
+    /// It ends past the end of the WAL.
+    Truncated,
+    /// The checksum does not match, or a field is invalid, before `end`.
+    Mismatch { end: usize },
+}
+
+impl Corruption {
+    /// Whether the corruption is at the end of `len` bytes, where a crash may have torn a write.
+    fn is_tail(&self, len: usize) -> bool {
+        match self {
+            Self::Truncated => true,
+            Self::Mismatch { end } => *end >= len,
+        }
+    }
+}
+
+/// A corrupted record found in recovery.
+struct CorruptRecord {
+    /// The offset of the record, where the WAL is truncated if the recovery stops at it.
+    offset: usize,
+    /// Whether the record is at the end of the WAL.
+    tail: bool,
+    /// The offset of the next record that can be read, if any.
+    resume: Option<usize>,
+}
+
+/// Reads the fields of a record and adds them to the checksum. A read past the end is an error
+/// rather than a panic, as the record may be torn by a crash.
+struct RecordReader<'a> {
+    buf: &'a [u8],
+    len: usize,
+    hasher: crc32fast::Hasher,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            len: buf.len(),
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    fn consumed(&self) -> usize {
+        self.len - self.buf.len()
+    }
+
+    fn corrupted(&self) -> Corruption {
+        Corruption::Mismatch {
+            end: self.consumed(),
+        }
+    }
+
+    /// Read bytes without adding them to the checksum.
+    fn raw(&mut self, len: usize) -> Result<&'a [u8], Corruption> {
+        if self.buf.len() < len {
+            return Err(Corruption::Truncated);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn raw_u16(&mut self) -> Result<u16, Corruption> {
+        Ok(self.raw(2)?.get_u16())
+    }
+
+    fn raw_u32(&mut self) -> Result<u32, Corruption> {
+        Ok(self.raw(4)?.get_u32())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Corruption> {
+        let bytes = self.raw(len)?;
+        self.hasher.write(bytes);
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Corruption> {
+        let value = self.raw(1)?[0];
+        self.hasher.write_u8(value);
+        Ok(value)
+    }
+
+    fn u32(&mut self) -> Result<u32, Corruption> {
+        let value = self.raw_u32()?;
+        self.hasher.write_u32(value);
+        Ok(value)
+    }
+
+    fn u64(&mut self) -> Result<u64, Corruption> {
+        let value = self.raw(8)?.get_u64();
+        self.hasher.write_u64(value);
+        Ok(value)
+    }
+
+    /// Read a length, which is a `u16` before v5 and a `u32` since v5.
+    fn len(&mut self, version: u32) -> Result<usize, Corruption> {
+        if version < WAL_FORMAT_V5 {
+            let len = self.raw_u16()?;
+            self.hasher.write_u16(len);
+            Ok(len as usize)
+        } else {
+            Ok(self.u32()? as usize)
+        }
+    }
+
+    /// Read a length-prefixed byte string.
+    fn bytes(&mut self, version: u32) -> Result<Bytes, Corruption> {
+        let len = self.len(version)?;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+
+    /// Read the checksum at the end of a record and check it.
+    fn check(&mut self) -> Result<(), Corruption> {
+        let checksum = self.hasher.clone().finalize();
+        if self.raw_u32()? != checksum {
+            return Err(self.corrupted());
+        }
+        Ok(())
+    }
+}
+
+/// The log shared by the memtables of all column families. It is a sequence of segment files, each
+/// named by the log sequence number (LSN) of its first byte, which is its offset in the log. A
+/// memtable covers the records from the LSN where it is created to the one where it is frozen, and
+/// the segments are removed once the memtables that cover them are flushed.
 pub struct Wal {
-    file: Arc<Mutex<BufWriter<File>>>,
+    dir: PathBuf,
+    segment_size: u64,
+    segment: Mutex<Segment>,
+    /// The start LSNs of the segments that are not removed, the last of which is being written.
+    segments: Mutex<VecDeque<u64>>,
+    /// The LSN after the last record, including the bytes in the buffer.
+    written: AtomicU64,
+    group_sync: Mutex<GroupSync>,
+    synced: Condvar,
+}
+
+/// The segment being written.
+struct Segment {
+    file: BufWriter<File>,
+    /// Another handle of the file, which is synced without blocking the writes.
+    sync_file: Arc<File>,
+    start_lsn: u64,
+    size: u64,
+}
+
+/// The state of group commit. One caller of `Wal::sync` syncs the file at a time, and the others
+/// wait for it and return if it has synced the bytes written before they are called.
+#[derive(Default)]
+struct GroupSync {
+    synced: u64,
+    syncing: bool,
+    /// The number of times the file is synced.
+    syncs: u64,
 }
 
 impl Wal {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create WAL")?,
-            ))),
+    /// Create a WAL in `dir`, which starts a new segment once the current one reaches
+    /// `segment_size` bytes.
+    pub fn create(dir: impl AsRef<Path>, segment_size: usize) -> Result<Self> {
+        let dir = dir.as_ref();
+        let segment = Self::create_segment(dir, 0)?;
+        Ok(Self::new(dir, segment_size, VecDeque::new(), segment))
+    }
+
+    fn new(dir: &Path, segment_size: usize, mut segments: VecDeque<u64>, segment: Segment) -> Self {
+        segments.push_back(segment.start_lsn);
+        Self {
+            dir: dir.to_path_buf(),
+            segment_size: segment_size as u64,
+            written: AtomicU64::new(segment.start_lsn + segment.size),
+            segment: Mutex::new(segment),
+            segments: Mutex::new(segments),
+            group_sync: Mutex::new(GroupSync::default()),
+            synced: Condvar::new(),
+        }
+    }
+
+    pub fn path_of_segment(dir: impl AsRef<Path>, start_lsn: u64) -> PathBuf {
+        dir.as_ref().join(format!("{:020}.log", start_lsn))
+    }
+
+    /// The start LSNs of the segment files in `dir`, in order.
+    fn list_segments(dir: &Path) -> Result<Vec<u64>> {
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "log") {
+                if let Some(start_lsn) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse().ok())
+                {
+                    segments.push(start_lsn);
+                }
+            }
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    fn create_segment(dir: &Path, start_lsn: u64) -> Result<Segment> {
+        let mut file = BufWriter::new(
+            OpenOptions::new()
+                .read(true)
+                .create_new(true)
+                .write(true)
+                .open(Self::path_of_segment(dir, start_lsn))
+                .context("failed to create WAL segment")?,
+        );
+        Self::write_header(&mut file)?;
+        Ok(Segment {
+            sync_file: Arc::new(file.get_ref().try_clone()?),
+            file,
+            start_lsn,
+            size: std::mem::size_of::<u32>() as u64 * 2,
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
-        let path = path.as_ref();
+    fn write_header(file: &mut impl Write) -> Result<()> {
+        let mut buf = Vec::with_capacity(std::mem::size_of::<u32>() * 2);
+        buf.put_u32(WAL_MAGIC);
+        buf.put_u32(WAL_FORMAT_VERSION);
+        file.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read the next record at `*pos`, and move `*pos` past it. Returns `None` at the end of the
+    /// WAL.
+    fn next_record(
+        data: &[u8],
+        pos: &mut usize,
+        version: u32,
+    ) -> Result<Option<WalRecord>, CorruptRecord> {
+        if version < WAL_FORMAT_V8 {
+            if *pos == data.len() {
+                return Ok(None);
+            }
+            let offset = *pos;
+            let (record, len) =
+                Self::decode_record(&data[offset..], version).map_err(|corruption| {
+                    CorruptRecord {
+                        offset,
+                        tail: corruption.is_tail(data.len() - offset),
+                        resume: None,
+                    }
+                })?;
+            *pos += len;
+            return Ok(Some(record));
+        }
+        let Some((offset, payload)) = Self::read_segmented_record(data, pos)? else {
+            return Ok(None);
+        };
+        // The fragments are checked, so the payload is only corrupted if it is written wrong.
+        match Self::decode_record(&payload, version) {
+            Ok((record, len)) if len == payload.len() => Ok(Some(record)),
+            _ => Err(CorruptRecord {
+                offset,
+                tail: false,
+                resume: Some(*pos),
+            }),
+        }
+    }
+
+    /// Read the fragments of the next record in a WAL split into blocks. Returns the offset and
+    /// the payload of the record.
+    fn read_segmented_record(
+        data: &[u8],
+        pos: &mut usize,
+    ) -> Result<Option<(usize, Vec<u8>)>, CorruptRecord> {
+        let mut record: Option<(usize, Vec<u8>)> = None;
+        loop {
+            let fragment_offset = *pos;
+            let offset = record
+                .as_ref()
+                .map_or(fragment_offset, |(offset, _)| *offset);
+            let (kind, payload) = match Self::read_fragment(data, pos) {
+                Ok(Some(fragment)) => fragment,
+                Ok(None) if record.is_none() => return Ok(None),
+                // The last fragments of the record are not written.
+                Ok(None) => {
+                    return Err(CorruptRecord {
+                        offset,
+                        tail: true,
+                        resume: None,
+                    })
+                }
+                // The rest of the block is skipped, as the length of the fragment is unknown.
+                Err(corruption) => {
+                    return Err(CorruptRecord {
+                        offset,
+                        tail: corruption.is_tail(data.len() - *pos),
+                        resume: Some((*pos / WAL_BLOCK_SIZE + 1) * WAL_BLOCK_SIZE),
+                    })
+                }
+            };
+            match (kind, record.is_some()) {
+                (FRAGMENT_FULL, false) => return Ok(Some((fragment_offset, payload.to_vec()))),
+                (FRAGMENT_FIRST, false) => record = Some((fragment_offset, payload.to_vec())),
+                (FRAGMENT_MIDDLE, true) => record.as_mut().unwrap().1.extend_from_slice(payload),
+                (FRAGMENT_LAST, true) => {
+                    let (offset, mut buf) = record.take().unwrap();
+                    buf.extend_from_slice(payload);
+                    return Ok(Some((offset, buf)));
+                }
+                // A new record starts before the last one ends, so the new one is read again when
+                // the last one is skipped.
+                (FRAGMENT_FULL | FRAGMENT_FIRST, true) => {
+                    return Err(CorruptRecord {
+                        offset,
+                        tail: false,
+                        resume: Some(fragment_offset),
+                    })
+                }
+                _ => {
+                    return Err(CorruptRecord {
+                        offset,
+                        tail: false,
+                        resume: Some(*pos),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Read the fragment at `*pos`, skipping the padding at the end of a block. Returns the type
+    /// and the payload of the fragment.
+    fn read_fragment<'a>(
+        data: &'a [u8],
+        pos: &mut usize,
+    ) -> Result<Option<(u8, &'a [u8])>, Corruption> {
+        let left = WAL_BLOCK_SIZE - *pos % WAL_BLOCK_SIZE;
+        if left < FRAGMENT_HEADER_SIZE {
+            if *pos + left >= data.len() {
+                // The last record ends in the trailer of the block, and the padding is written with
+                // the next record.
+                return Ok(None);
+            }
+            *pos += left;
+        }
+        if *pos == data.len() {
+            return Ok(None);
+        }
+        let mut reader = RecordReader::new(&data[*pos..]);
+        let checksum = reader.raw_u32()?;
+        let len = reader.raw_u16()? as usize;
+        let kind = reader.u8()?;
+        if len > WAL_BLOCK_SIZE - *pos % WAL_BLOCK_SIZE - FRAGMENT_HEADER_SIZE {
+            return Err(reader.corrupted());
+        }
+        let payload = reader.take(len)?;
+        if reader.hasher.clone().finalize() != checksum {
+            return Err(reader.corrupted());
+        }
+        *pos += FRAGMENT_HEADER_SIZE + len;
+        Ok(Some((kind, payload)))
+    }
+
+    /// Decode a record at the start of `buf`. Returns the record and its length.
+    fn decode_record(buf: &[u8], version: u32) -> Result<(WalRecord, usize), Corruption> {
+        let mut reader = RecordReader::new(buf);
+        if version < WAL_FORMAT_V4 {
+            let record = Self::decode_put_record(&mut reader, DEFAULT_COLUMN_FAMILY_ID, version)?;
+            return Ok((record, reader.consumed()));
+        }
+        let column_family_id = reader.u32()?;
+        let record = match column_family_id {
+            BATCH_RECORD if version >= WAL_FORMAT_V7 => {
+                // The length and the checksum cover the whole batch, so that a torn batch is
+                // found before any record of it is recovered.
+                let len = reader.u32()? as usize;
+                let body = reader.take(len)?;
+                let data_len = reader.consumed();
+                if crc32fast::hash(&buf[..data_len]) != reader.raw_u32()? {
+                    return Err(reader.corrupted());
+                }
+                Self::decode_batch(body)?
+            }
+            TXN_RECORD if version >= WAL_FORMAT_V6 => {
+                let record = Self::decode_txn_record(&mut reader)?;
+                reader.check()?;
+                WalRecord::Txn(record)
+            }
+            _ => Self::decode_put_record(&mut reader, column_family_id as usize, version)?,
+        };
+        Ok((record, reader.consumed()))
+    }
+
+    /// Decode a put or a range tombstone record after its column family id. A record before v4 is
+    /// in the default column family, and has no column family id.
+    fn decode_put_record(
+        reader: &mut RecordReader,
+        column_family_id: usize,
+        version: u32,
+    ) -> Result<WalRecord, Corruption> {
+        let key_len = reader.len(version)?;
+        if key_len == 0 && version >= WAL_FORMAT_V2 {
+            // Keys cannot be empty, so an empty key marks a range tombstone record.
+            let start = reader.bytes(version)?;
+            let end = reader.bytes(version)?;
+            let ts = reader.u64()?;
+            reader.check()?;
+            return Ok(WalRecord::RangeTombstone(
+                column_family_id,
+                RangeTombstone::new(start, end, ts),
+            ));
+        }
+        let key = Bytes::copy_from_slice(reader.take(key_len)?);
+        let ts = reader.u64()?;
+        let mut value = reader.bytes(version)?;
+        reader.check()?;
+        if version < WAL_FORMAT_V3 {
+            value = Value::upgrade_legacy(&value).into();
+        }
+        Ok(WalRecord::Put(
+            column_family_id,
+            KeyBytes::from_bytes_with_ts(key, ts),
+            value,
+        ))
+    }
+
+    /// Decode a transaction record after its column family id.
+    fn decode_txn_record(reader: &mut RecordReader) -> Result<TxnRecord, Corruption> {
+        let kind = reader.u8()?;
+        let xid = String::from_utf8(reader.bytes(WAL_FORMAT_V6)?.to_vec())
+            .map_err(|_| reader.corrupted())?;
+        let record = match kind {
+            TXN_PREPARE => {
+                let mut pairs = [Vec::new(), Vec::new()];
+                for pairs in pairs.iter_mut() {
+                    let count = reader.u32()?;
+                    for _ in 0..count {
+                        let first = reader.bytes(WAL_FORMAT_V6)?;
+                        let second = reader.bytes(WAL_FORMAT_V6)?;
+                        pairs.push((first, second));
+                    }
+                }
+                let [writes, range_deletes] = pairs;
+                TxnRecord::Prepare {
+                    xid,
+                    writes,
+                    range_deletes,
+                }
+            }
+            TXN_COMMIT => {
+                let commit_ts = reader.u64()?;
+                TxnRecord::Commit { xid, commit_ts }
+            }
+            TXN_ROLLBACK => TxnRecord::Rollback { xid },
+            _ => return Err(reader.corrupted()),
+        };
+        Ok(record)
+    }
+
+    /// Decode the body of a write batch record.
+    fn decode_batch(body: &[u8]) -> Result<WalRecord, Corruption> {
+        // The checksum of the whole record is checked before.
+        let mut reader = RecordReader::new(body);
+        let ts = reader.u64()?;
+        let count = reader.u32()?;
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let column_family_id = reader.u32()? as usize;
+            let kind = reader.u8()?;
+            let first = reader.bytes(WAL_FORMAT_V7)?;
+            let second = reader.bytes(WAL_FORMAT_V7)?;
+            let record = match kind {
+                BATCH_PUT => BatchRecord::Put(first, second),
+                BATCH_DEL_RANGE => BatchRecord::DelRange(first, second),
+                _ => return Err(reader.corrupted()),
+            };
+            records.push((column_family_id, record));
+        }
+        Ok(WalRecord::Batch(ts, records))
+    }
+
+    /// Add a recovered record to the memtables or the transaction records.
+    fn apply_record(
+        record: WalRecord,
+        memtables: &mut HashMap<usize, WalMemTable>,
+        txn_records: &mut Vec<TxnRecord>,
+    ) {
+        match record {
+            WalRecord::Put(column_family_id, key, value) => {
+                if let Some((skiplist, _)) = memtables.get(&column_family_id) {
+                    skiplist.insert(key, value);
+                }
+            }
+            WalRecord::RangeTombstone(column_family_id, range_tombstone) => {
+                if let Some((_, range_tombstones)) = memtables.get_mut(&column_family_id) {
+                    range_tombstones.push(range_tombstone);
+                }
+            }
+            WalRecord::Txn(record) => txn_records.push(record),
+            WalRecord::Batch(ts, records) => {
+                for (column_family_id, record) in records {
+                    match record {
+                        BatchRecord::Put(key, value) => {
+                            if let Some((skiplist, _)) = memtables.get(&column_family_id) {
+                                skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+                            }
+                        }
+                        BatchRecord::DelRange(start, end) => {
+                            if let Some((_, range_tombstones)) =
+                                memtables.get_mut(&column_family_id)
+                            {
+                                range_tombstones.push(RangeTombstone::new(start, end, ts));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read the records of a WAL file, and handle the corrupted ones by `mode`. The file is
+    /// truncated if the recovery stops at a corrupted record, so that the records written after
+    /// recovery follow the last record recovered. A corrupted record at the end of the file is
+    /// only taken as torn by a crash if `last` is set, as a segment of the shared WAL is synced
+    /// before the next one is created. `apply` is called with the offset of each record. Returns
+    /// whether the recovery stopped at a corrupted record in point-in-time mode, after which the
+    /// later records must be discarded.
+    fn recover_file(
+        path: &Path,
+        mode: WalRecoveryMode,
+        last: bool,
+        mut apply: impl FnMut(usize, WalRecord),
+    ) -> Result<bool> {
         let mut file = OpenOptions::new()
             .read(true)
-            .append(true)
+            .write(true)
             .open(path)
             .context("failed to recover from WAL")?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
-        let mut rbuf: &[u8] = buf.as_slice();
-        while rbuf.has_remaining() {
-            let mut hasher = crc32fast::Hasher::new();
-            let key_len = rbuf.get_u16() as usize;
-            hasher.write_u16(key_len as u16);
-            let key = Bytes::copy_from_slice(&rbuf[..key_len]);
-            hasher.write(&key);
-            rbuf.advance(key_len);
-            let ts = rbuf.get_u64();
-            hasher.write_u64(ts);
-            let value_len = rbuf.get_u16() as usize;
-            hasher.write_u16(value_len as u16);
-            let value = Bytes::copy_from_slice(&rbuf[..value_len]);
-            hasher.write(&value);
-            rbuf.advance(value_len);
-            let checksum = rbuf.get_u32();
-            if hasher.finalize() != checksum {
-                bail!("checksum mismatch");
-            }
-            skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
-        }
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
+        let header_len = std::mem::size_of::<u32>() * 2;
+        let (version, mut pos) = if buf.is_empty() {
+            // The header has not been written before the crash.
+            (WAL_FORMAT_VERSION, 0)
+        } else if buf.len() >= header_len && (&buf[..]).get_u32() == WAL_MAGIC {
+            ((&buf[std::mem::size_of::<u32>()..]).get_u32(), header_len)
+        } else {
+            (WAL_FORMAT_V1, 0)
+        };
+        if version > WAL_FORMAT_VERSION {
+            bail!("unsupported WAL format version {}", version);
+        }
+        let mut stopped = false;
+        let mut skipped = 0;
+        loop {
+            let offset = pos;
+            let corrupt = match Self::next_record(&buf, &mut pos, version) {
+                Ok(Some(record)) => {
+                    apply(offset, record);
+                    continue;
+                }
+                Ok(None) => break,
+                Err(corrupt) => corrupt,
+            };
+            match (mode, corrupt.tail && last, corrupt.resume) {
+                (WalRecoveryMode::AbsoluteConsistency, _, _)
+                | (WalRecoveryMode::TolerateCorruptedTailRecords, false, _) => bail!(
+                    "corrupted record at offset {} of WAL {}",
+                    corrupt.offset,
+                    path.display()
+                ),
+                (WalRecoveryMode::SkipAnyCorruptedRecords, _, Some(resume)) if !corrupt.tail => {
+                    skipped += 1;
+                    pos = resume;
+                }
+                _ => {
+                    println!(
+                        "WAL {} truncated at the corrupted record at offset {}",
+                        path.display(),
+                        corrupt.offset
+                    );
+                    file.set_len(corrupt.offset as u64)?;
+                    file.sync_all()?;
+                    stopped = mode == WalRecoveryMode::PointInTime;
+                    break;
+                }
+            }
+        }
+        if skipped > 0 {
+            println!(
+                "{} corrupted records skipped in WAL {}",
+                skipped,
+                path.display()
+            );
+        }
+        Ok(stopped)
+    }
+
+    /// Recover the records of the column families in `memtables` from a WAL file of one memtable,
+    /// which is written before the WAL is shared by the memtables. `memtables` maps a column family
+    /// id to its skiplist and range tombstones. The records of other column families are skipped,
+    /// and the transaction records are added to `txn_records` in order. A write batch is recovered
+    /// all or nothing. Returns whether the recovery stopped at a corrupted record in point-in-time
+    /// mode, after which the later records must be discarded.
+    pub fn recover_file_of_memtable(
+        path: impl AsRef<Path>,
+        memtables: &mut HashMap<usize, WalMemTable>,
+        txn_records: &mut Vec<TxnRecord>,
+        mode: WalRecoveryMode,
+    ) -> Result<bool> {
+        Self::recover_file(path.as_ref(), mode, true, |_, record| {
+            Self::apply_record(record, memtables, txn_records)
         })
     }
 
-    pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-        let mut file = self.file.lock();
+    /// Recover the shared WAL in `dir`. `memtables` has the first LSN and the column families of
+    /// each memtable that is not flushed, in order, and a record goes to the last memtable that
+    /// starts at or before it. The records before the first memtable are flushed and skipped, and
+    /// the segments that only have such records are removed. If `discard` is set, the records are
+    /// after a corrupted record of an earlier WAL file in point-in-time recovery, and are all
+    /// discarded. The records written after recovery go to a new segment.
+    pub fn recover(
+        dir: impl AsRef<Path>,
+        segment_size: usize,
+        memtables: &mut [(u64, HashMap<usize, WalMemTable>)],
+        txn_records: &mut Vec<TxnRecord>,
+        mode: WalRecoveryMode,
+        discard: bool,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        let first_lsn = memtables.first().map_or(u64::MAX, |(lsn, _)| *lsn);
+        // The new segment starts after the segments and the LSNs of the memtables, even if the
+        // segments are removed or truncated.
+        let mut end_lsn = memtables.last().map_or(0, |(lsn, _)| *lsn);
+        let mut stopped = discard;
+        let mut kept = VecDeque::new();
+        let segments = Self::list_segments(dir)?;
+        for (i, &start_lsn) in segments.iter().enumerate() {
+            let path = Self::path_of_segment(dir, start_lsn);
+            let next_lsn = segments.get(i + 1).copied();
+            let size = std::fs::metadata(&path)?.len();
+            end_lsn = end_lsn.max(start_lsn + size);
+            // The records of a segment are all flushed if it ends before the first memtable.
+            if stopped || next_lsn.unwrap_or(start_lsn + size) <= first_lsn {
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            stopped = Self::recover_file(&path, mode, next_lsn.is_none(), |offset, record| {
+                let lsn = start_lsn + offset as u64;
+                if lsn >= first_lsn {
+                    let index = memtables.partition_point(|(first_lsn, _)| *first_lsn <= lsn);
+                    Self::apply_record(record, &mut memtables[index - 1].1, txn_records);
+                }
+            })?;
+            if std::fs::metadata(&path)?.len() == 0 {
+                // The segment is created before the crash, and the new segment replaces it.
+                std::fs::remove_file(&path)?;
+            } else {
+                kept.push_back(start_lsn);
+            }
+        }
+        let segment = Self::create_segment(dir, end_lsn)?;
+        File::open(dir)?.sync_all()?;
+        Ok(Self::new(dir, segment_size, kept, segment))
+    }
+
+    /// Remove all records of a WAL file of one memtable, which is after a corrupted record of an
+    /// earlier WAL file in point-in-time recovery.
+    pub fn discard_records(path: impl AsRef<Path>) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .context("failed to discard WAL")?;
+        file.set_len(0)?;
+        Self::write_header(&mut file)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// The LSN after the last record written.
+    pub fn lsn(&self) -> u64 {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    /// Remove the segments whose records are all before `lsn`, which are flushed to SSTs. The
+    /// segment being written is kept.
+    pub fn remove_segments_before(&self, lsn: u64) -> Result<()> {
+        let mut segments = self.segments.lock();
+        while segments.len() > 1 && segments[1] <= lsn {
+            std::fs::remove_file(Self::path_of_segment(&self.dir, segments[0]))?;
+            segments.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Sync the current segment and start a new one at the end of it.
+    fn roll_segment(&self, segment: &mut Segment) -> Result<()> {
+        segment.file.flush()?;
+        segment.sync_file.sync_all()?;
+        *segment = Self::create_segment(&self.dir, segment.start_lsn + segment.size)?;
+        File::open(&self.dir)?.sync_all()?;
+        self.segments.lock().push_back(segment.start_lsn);
+        self.written
+            .store(segment.start_lsn + segment.size, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Write a record in fragments that do not cross blocks, under the segment lock. A new segment
+    /// is started before the record if the current one is full.
+    fn append(&self, segment: &mut Segment, record: &[u8]) -> Result<()> {
+        if segment.size >= self.segment_size {
+            self.roll_segment(segment)?;
+        }
+        let mut buf = Vec::with_capacity(record.len() + FRAGMENT_HEADER_SIZE);
+        let mut offset = segment.size as usize;
+        let mut rest = record;
+        let mut first = true;
+        loop {
+            let left = WAL_BLOCK_SIZE - offset % WAL_BLOCK_SIZE;
+            if left < FRAGMENT_HEADER_SIZE {
+                // The block is padded if there is no room for a fragment header.
+                buf.put_bytes(0, left);
+                offset += left;
+                continue;
+            }
+            let len = rest.len().min(left - FRAGMENT_HEADER_SIZE);
+            let last = len == rest.len();
+            let kind = match (first, last) {
+                (true, true) => FRAGMENT_FULL,
+                (true, false) => FRAGMENT_FIRST,
+                (false, false) => FRAGMENT_MIDDLE,
+                (false, true) => FRAGMENT_LAST,
+            };
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.write_u8(kind);
+            hasher.write(&rest[..len]);
+            buf.put_u32(hasher.finalize());
+            buf.put_u16(len as u16);
+            buf.put_u8(kind);
+            buf.put_slice(&rest[..len]);
+            offset += FRAGMENT_HEADER_SIZE + len;
+            rest = &rest[len..];
+            first = false;
+            if last {
+                break;
+            }
+        }
+        segment.file.write_all(&buf)?;
+        segment.size += buf.len() as u64;
+        self.written
+            .store(segment.start_lsn + segment.size, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn put_bytes(buf: &mut Vec<u8>, hasher: &mut crc32fast::Hasher, bytes: &[u8]) {
+        hasher.write_u32(bytes.len() as u32);
+        buf.put_u32(bytes.len() as u32);
+        hasher.write(bytes);
+        buf.put_slice(bytes);
+    }
+
+    /// Write a batch of records of column families with its commit ts as one record. The record
+    /// starts with the marker and the length of the batch, and ends with the checksum of all of it.
+    pub fn put_batch(&self, ts: u64, records: &[(usize, BatchRecord)]) -> Result<()> {
+        let mut body: Vec<u8> = Vec::new();
+        body.put_u64(ts);
+        body.put_u32(records.len() as u32);
+        for (column_family_id, record) in records {
+            body.put_u32(*column_family_id as u32);
+            let (kind, first, second) = match record {
+                BatchRecord::Put(key, value) => (BATCH_PUT, key, value),
+                BatchRecord::DelRange(start, end) => (BATCH_DEL_RANGE, start, end),
+            };
+            body.put_u8(kind);
+            for bytes in [first, second] {
+                body.put_u32(bytes.len() as u32);
+                body.put_slice(bytes);
+            }
+        }
+        let mut buf: Vec<u8> = Vec::with_capacity(body.len() + std::mem::size_of::<u32>() * 3);
+        buf.put_u32(BATCH_RECORD);
+        buf.put_u32(body.len() as u32);
+        buf.put_slice(&body);
+        buf.put_u32(crc32fast::hash(&buf));
+        let mut segment = self.segment.lock();
+        self.append(&mut segment, &buf)
+    }
+
+    /// Write a key-value pair of a column family.
+    pub fn put(&self, column_family_id: usize, key: KeySlice, value: &[u8]) -> Result<()> {
+        let mut segment = self.segment.lock();
         let mut buf: Vec<u8> =
-            Vec::with_capacity(key.raw_len() + value.len() + std::mem::size_of::<u16>());
+            Vec::with_capacity(key.raw_len() + value.len() + std::mem::size_of::<u32>() * 4);
         let mut hasher = crc32fast::Hasher::new();
-        hasher.write_u16(key.key_len() as u16);
-        buf.put_u16(key.key_len() as u16);
+        hasher.write_u32(column_family_id as u32);
+        buf.put_u32(column_family_id as u32);
+        hasher.write_u32(key.key_len() as u32);
+        buf.put_u32(key.key_len() as u32);
         hasher.write(key.key_ref());
         buf.put_slice(key.key_ref());
         hasher.write_u64(key.ts());
         buf.put_u64(key.ts());
-        hasher.write_u16(value.len() as u16);
-        buf.put_u16(value.len() as u16);
+        hasher.write_u32(value.len() as u32);
+        buf.put_u32(value.len() as u32);
         buf.put_slice(value);
         hasher.write(value);
         // add checksum: week 2 day 7
         buf.put_u32(hasher.finalize());
-        file.write_all(&buf)?;
-        Ok(())
+        self.append(&mut segment, &buf)
     }
 
+    /// Write a range tombstone of a column family. The key of the record is empty to tell it apart
+    /// from a key-value pair.
+    pub fn put_range_tombstone(
+        &self,
+        column_family_id: usize,
+        range_tombstone: &RangeTombstone,
+    ) -> Result<()> {
+        let mut segment = self.segment.lock();
+        let mut buf: Vec<u8> =
+            Vec::with_capacity(range_tombstone.raw_len() + std::mem::size_of::<u32>() * 5);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.write_u32(column_family_id as u32);
+        buf.put_u32(column_family_id as u32);
+        hasher.write_u32(0);
+        buf.put_u32(0);
+        hasher.write_u32(range_tombstone.start.len() as u32);
+        buf.put_u32(range_tombstone.start.len() as u32);
+        hasher.write(&range_tombstone.start);
+        buf.put_slice(&range_tombstone.start);
+        hasher.write_u32(range_tombstone.end.len() as u32);
+        buf.put_u32(range_tombstone.end.len() as u32);
+        hasher.write(&range_tombstone.end);
+        buf.put_slice(&range_tombstone.end);
+        hasher.write_u64(range_tombstone.ts);
+        buf.put_u64(range_tombstone.ts);
+        buf.put_u32(hasher.finalize());
+        self.append(&mut segment, &buf)
+    }
+
+    /// Write a record of a transaction with two-phase commit.
+    pub fn put_txn_record(&self, record: &TxnRecord) -> Result<()> {
+        let mut segment = self.segment.lock();
+        let mut buf: Vec<u8> = Vec::new();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.write_u32(TXN_RECORD);
+        buf.put_u32(TXN_RECORD);
+        let (kind, xid) = match record {
+            TxnRecord::Prepare { xid, .. } => (TXN_PREPARE, xid),
+            TxnRecord::Commit { xid, .. } => (TXN_COMMIT, xid),
+            TxnRecord::Rollback { xid } => (TXN_ROLLBACK, xid),
+        };
+        hasher.write_u8(kind);
+        buf.put_u8(kind);
+        Self::put_bytes(&mut buf, &mut hasher, xid.as_bytes());
+        match record {
+            TxnRecord::Prepare {
+                writes,
+                range_deletes,
+                ..
+            } => {
+                for pairs in [writes, range_deletes] {
+                    hasher.write_u32(pairs.len() as u32);
+                    buf.put_u32(pairs.len() as u32);
+                    for (first, second) in pairs {
+                        Self::put_bytes(&mut buf, &mut hasher, first);
+                        Self::put_bytes(&mut buf, &mut hasher, second);
+                    }
+                }
+            }
+            TxnRecord::Commit { commit_ts, .. } => {
+                hasher.write_u64(*commit_ts);
+                buf.put_u64(*commit_ts);
+            }
+            TxnRecord::Rollback { .. } => {}
+        }
+        buf.put_u32(hasher.finalize());
+        self.append(&mut segment, &buf)
+    }
+
+    /// Sync the records written so far. Concurrent calls are coalesced: one of them flushes the
+    /// records of all callers in one write and syncs the file once, and the others wait for it.
     pub fn sync(&self) -> Result<()> {
-        let mut file = self.file.lock();
-        file.flush()?;
-        file.get_mut().sync_all()?;
-        Ok(())
+        let target = self.written.load(Ordering::SeqCst);
+        let mut group_sync = self.group_sync.lock();
+        loop {
+            if group_sync.synced >= target {
+                return Ok(());
+            }
+            if !group_sync.syncing {
+                break;
+            }
+            self.synced.wait(&mut group_sync);
+        }
+        group_sync.syncing = true;
+        drop(group_sync);
+        let result = self.flush_and_sync();
+        let mut group_sync = self.group_sync.lock();
+        group_sync.syncing = false;
+        group_sync.syncs += 1;
+        if let Ok(synced) = result {
+            group_sync.synced = group_sync.synced.max(synced);
+        }
+        self.synced.notify_all();
+        result.map(|_| ())
+    }
+
+    /// The number of syncs made by `sync`, which is less than the number of calls when they are
+    /// coalesced.
+    #[cfg(test)]
+    pub(crate) fn sync_count(&self) -> u64 {
+        self.group_sync.lock().syncs
+    }
+
+    /// Flush the buffer and sync the current segment, and return the LSN synced to. The segment is
+    /// synced with the buffer unlocked, so that the writes go on into the buffer. The earlier
+    /// segments are synced when they are full.
+    fn flush_and_sync(&self) -> Result<u64> {
+        let (written, sync_file) = {
+            let mut segment = self.segment.lock();
+            segment.file.flush()?;
+            (
+                self.written.load(Ordering::SeqCst),
+                segment.sync_file.clone(),
+            )
+        };
+        sync_file.sync_all()?;
+        Ok(written)
     }
 }